@@ -2,12 +2,24 @@
 //!
 //! Handles scheduled tasks like blocklist refresh, cache warming, and analytics
 
+use crate::blocklist_sources::{refresh_blocklists, RefreshScope};
+use crate::rollups::RollupStore;
+use crate::webhooks::WebhookManager;
+use chrono::Utc;
+use shield_db::{DbBlocklistRefresh, DbQuotaUsage, SqliteDb};
+use shield_dns_core::blocklist_fetcher::BlocklistStats;
 use shield_dns_core::unified_filter::UnifiedFilter;
+use shield_dns_core::usage::UsageTracker;
 use shield_metrics::MetricsCollector;
+use shield_profiles::DeviceRegistry;
+use shield_tiers::{Tier, TierManager};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::watch;
-use tracing::{debug, error, info, warn};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, warn};
+
+/// Number of blocklist refreshes kept in the history table
+pub const BLOCKLIST_HISTORY_LIMIT: usize = 100;
 
 /// Configuration for background tasks
 #[derive(Clone)]
@@ -18,9 +30,20 @@ pub struct BackgroundTasksConfig {
     pub metrics_aggregation_interval: Duration,
     /// Interval for cache stats logging (default: 5 minutes)
     pub cache_stats_interval: Duration,
+    /// Interval for sweeping expired allowlist entries and pauses (default: 30 seconds)
+    pub override_expiry_interval: Duration,
+    /// Interval for saving device registry last-seen times (default: 1 minute)
+    pub device_flush_interval: Duration,
+    /// Interval for pruning the query log to each tier's history (default: 1 hour)
+    pub query_log_retention_interval: Duration,
+    /// Interval for flushing query rollups to the database (default: 1 minute)
+    pub rollup_flush_interval: Duration,
+    /// Interval for saving usage budget counters (default: 1 minute)
+    pub quota_usage_flush_interval: Duration,
     /// Enable blocklist auto-refresh
     pub enable_blocklist_refresh: bool,
     /// Enable cache warming
+    #[allow(dead_code)]
     pub enable_cache_warming: bool,
 }
 
@@ -28,8 +51,13 @@ impl Default for BackgroundTasksConfig {
     fn default() -> Self {
         Self {
             blocklist_refresh_interval: Duration::from_secs(6 * 60 * 60), // 6 hours
-            metrics_aggregation_interval: Duration::from_secs(60),         // 1 minute
-            cache_stats_interval: Duration::from_secs(5 * 60),             // 5 minutes
+            metrics_aggregation_interval: Duration::from_secs(60),        // 1 minute
+            cache_stats_interval: Duration::from_secs(5 * 60),            // 5 minutes
+            override_expiry_interval: Duration::from_secs(30),            // 30 seconds
+            device_flush_interval: Duration::from_secs(60),               // 1 minute
+            query_log_retention_interval: Duration::from_secs(60 * 60),   // 1 hour
+            rollup_flush_interval: Duration::from_secs(60),               // 1 minute
+            quota_usage_flush_interval: Duration::from_secs(60),          // 1 minute
             enable_blocklist_refresh: true,
             enable_cache_warming: true,
         }
@@ -57,14 +85,31 @@ impl BackgroundTasks {
         &self,
         unified_filter: Arc<UnifiedFilter>,
         metrics: Arc<MetricsCollector>,
+        db: Arc<SqliteDb>,
+        webhooks: Arc<WebhookManager>,
+        devices: Arc<DeviceRegistry>,
+        tiers: Arc<TierManager>,
     ) {
         info!("Starting background tasks");
 
         // Start blocklist auto-refresh task
         if self.config.enable_blocklist_refresh {
-            self.start_blocklist_refresh(unified_filter.clone());
+            self.start_blocklist_refresh(unified_filter.clone(), db.clone(), webhooks.clone());
         }
 
+        // Start temporary allowlist / pause expiry task
+        self.start_override_expiry(unified_filter.clone(), db.clone());
+
+        // Start query log retention task
+        self.start_query_log_retention(db.clone(), tiers);
+
+        // Start usage budget notification and persistence tasks
+        self.start_quota_notifications(&unified_filter, webhooks.clone());
+        self.start_quota_usage_flush(unified_filter.clone(), db);
+
+        // Start device registry flush task
+        self.start_device_flush(devices);
+
         // Start metrics aggregation task
         self.start_metrics_aggregation(metrics.clone());
 
@@ -75,7 +120,12 @@ impl BackgroundTasks {
     }
 
     /// Start the blocklist auto-refresh background task
-    fn start_blocklist_refresh(&self, unified_filter: Arc<UnifiedFilter>) {
+    fn start_blocklist_refresh(
+        &self,
+        unified_filter: Arc<UnifiedFilter>,
+        db: Arc<SqliteDb>,
+        webhooks: Arc<WebhookManager>,
+    ) {
         let interval = self.config.blocklist_refresh_interval;
         let mut shutdown_rx = self.shutdown_tx.subscribe();
 
@@ -89,22 +139,228 @@ impl BackgroundTasks {
                 tokio::select! {
                     _ = tokio::time::sleep(interval) => {
                         info!("Scheduled blocklist refresh starting...");
-                        match unified_filter.init_blocklists("config/blocklist-sources.json").await {
-                            Ok(stats) => {
-                                info!(
-                                    "Scheduled blocklist refresh complete: {} domains from {} sources ({} failed)",
-                                    stats.total_domains,
-                                    stats.sources_loaded,
-                                    stats.sources_failed
-                                );
-                            }
-                            Err(e) => {
-                                error!("Scheduled blocklist refresh failed: {}", e);
+                        let stats = refresh_blocklists(&unified_filter, &db, &webhooks, RefreshScope::All).await;
+                        info!(
+                            "Scheduled blocklist refresh complete: {} domains from {} sources ({} failed)",
+                            stats.total_domains,
+                            stats.sources_loaded,
+                            stats.sources_failed
+                        );
+                    }
+                    _ = shutdown_rx.changed() => {
+                        info!("Blocklist refresh task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that expires temporary allowlist entries and pauses
+    fn start_override_expiry(&self, unified_filter: Arc<UnifiedFilter>, db: Arc<SqliteDb>) {
+        let interval = self.config.override_expiry_interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            debug!(
+                "Override expiry task started (interval: {} seconds)",
+                interval.as_secs()
+            );
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        let now = Utc::now();
+                        let expired = unified_filter.overrides().expire(now);
+                        if expired > 0 {
+                            info!("Expired {} temporary allowlist entries and pauses", expired);
+                        }
+                        if let Err(e) = db.delete_expired_overrides(now) {
+                            warn!("Failed to delete expired overrides from database: {}", e);
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        info!("Override expiry task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that prunes the query log to each owner's tier history
+    fn start_query_log_retention(&self, db: Arc<SqliteDb>, tiers: Arc<TierManager>) {
+        let interval = self.config.query_log_retention_interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            debug!(
+                "Query log retention task started (interval: {} seconds)",
+                interval.as_secs()
+            );
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        let (db, tiers) = (db.clone(), tiers.clone());
+                        match tokio::task::spawn_blocking(move || prune_query_log(&db, &tiers)).await {
+                            Ok(deleted) if deleted > 0 => info!("Pruned {} query log entries", deleted),
+                            Ok(_) => {}
+                            Err(e) => warn!("Query log retention failed: {}", e),
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        info!("Query log retention task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that flushes query rollups and drops aged-out buckets
+    pub fn start_rollup_flush(&self, rollups: Arc<RollupStore>) {
+        let interval = self.config.rollup_flush_interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        // Downsample roughly once an hour
+        let downsample_every = (3600 / interval.as_secs().max(1)).max(1);
+
+        tokio::spawn(async move {
+            debug!(
+                "Rollup flush task started (interval: {} seconds)",
+                interval.as_secs()
+            );
+            let mut ticks: u64 = 0;
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        ticks += 1;
+                        let downsample = ticks.is_multiple_of(downsample_every);
+                        let store = rollups.clone();
+                        let result = tokio::task::spawn_blocking(move || {
+                            let flushed = store.flush()?;
+                            let deleted = if downsample {
+                                store.downsample(Utc::now().timestamp() as u64)?
+                            } else {
+                                0
+                            };
+                            Ok::<_, shield_db::DbError>((flushed, deleted))
+                        })
+                        .await;
+                        match result {
+                            Ok(Ok((flushed, deleted))) => {
+                                if flushed > 0 {
+                                    debug!("Flushed {} query rollup buckets", flushed);
+                                }
+                                if deleted > 0 {
+                                    info!("Downsampled {} query rollup buckets", deleted);
+                                }
                             }
+                            Ok(Err(e)) => warn!("Query rollup flush failed: {}", e),
+                            Err(e) => warn!("Query rollup flush failed: {}", e),
                         }
                     }
                     _ = shutdown_rx.changed() => {
-                        info!("Blocklist refresh task shutting down");
+                        if let Err(e) = rollups.flush() {
+                            warn!("Query rollup flush failed: {}", e);
+                        }
+                        info!("Rollup flush task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that sends a webhook when a usage budget runs out
+    fn start_quota_notifications(
+        &self,
+        unified_filter: &UnifiedFilter,
+        webhooks: Arc<WebhookManager>,
+    ) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        unified_filter.usage().set_notifier(tx);
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            debug!("Quota notification task started");
+
+            loop {
+                tokio::select! {
+                    Some(event) = rx.recv() => {
+                        info!(
+                            "Profile '{}' spent its {} minute budget for {}",
+                            event.profile_name,
+                            event.daily_minutes,
+                            event.target.name()
+                        );
+                        webhooks.notify_quota_exhausted(&event).await;
+                    }
+                    _ = shutdown_rx.changed() => {
+                        info!("Quota notification task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that saves usage budget counters so restarts keep them
+    fn start_quota_usage_flush(&self, unified_filter: Arc<UnifiedFilter>, db: Arc<SqliteDb>) {
+        let interval = self.config.quota_usage_flush_interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            debug!(
+                "Quota usage flush task started (interval: {} seconds)",
+                interval.as_secs()
+            );
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        let (unified_filter, db) = (unified_filter.clone(), db.clone());
+                        match tokio::task::spawn_blocking(move || save_quota_usage(unified_filter.usage(), &db)).await {
+                            Ok(saved) if saved > 0 => debug!("Saved {} usage budget counters", saved),
+                            Ok(_) => {}
+                            Err(e) => warn!("Quota usage flush failed: {}", e),
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        save_quota_usage(unified_filter.usage(), &db);
+                        info!("Quota usage flush task shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Start the task that saves newly discovered devices and last-seen times
+    fn start_device_flush(&self, devices: Arc<DeviceRegistry>) {
+        let interval = self.config.device_flush_interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            debug!(
+                "Device flush task started (interval: {} seconds)",
+                interval.as_secs()
+            );
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        let registry = devices.clone();
+                        match tokio::task::spawn_blocking(move || registry.flush()).await {
+                            Ok(flushed) if flushed > 0 => debug!("Saved {} devices", flushed),
+                            Ok(_) => {}
+                            Err(e) => warn!("Device flush failed: {}", e),
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        devices.flush();
+                        info!("Device flush task shutting down");
                         break;
                     }
                 }
@@ -176,12 +432,114 @@ impl BackgroundTasks {
     }
 
     /// Signal shutdown to all background tasks
+    #[allow(dead_code)]
     pub fn shutdown(&self) {
         info!("Signaling shutdown to background tasks");
         let _ = self.shutdown_tx.send(true);
     }
 }
 
+/// Save changed usage budget counters and drop those from past days
+///
+/// Returns the number of counters saved.
+pub fn save_quota_usage(usage: &UsageTracker, db: &SqliteDb) -> usize {
+    let records: Vec<DbQuotaUsage> = usage
+        .take_changed()
+        .into_iter()
+        .map(|record| DbQuotaUsage {
+            profile_id: record.profile_id,
+            target: serde_json::to_string(&record.target).unwrap_or_default(),
+            day: record.day.to_string(),
+            used_secs: record.used_secs,
+            last_seen: record.last_seen,
+            notified: record.notified,
+        })
+        .collect();
+
+    // Profile-local days run up to a day either side of UTC
+    let stale_before = (Utc::now() - chrono::Duration::days(2))
+        .date_naive()
+        .to_string();
+    if let Err(e) = db.delete_quota_usage_before(&stale_before) {
+        warn!("Failed to delete old usage budget counters: {}", e);
+    }
+    if records.is_empty() {
+        return 0;
+    }
+    match db.upsert_quota_usage(&records) {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!(
+                "Failed to save {} usage budget counters: {}",
+                records.len(),
+                e
+            );
+            0
+        }
+    }
+}
+
+/// Record a blocklist refresh in the history table and notify webhooks
+/// subscribed to `blocklist_updated` if anything changed
+pub async fn publish_blocklist_refresh(
+    stats: &BlocklistStats,
+    db: &SqliteDb,
+    webhooks: &WebhookManager,
+) {
+    // No sources were fetched (e.g. the config file is missing)
+    if stats.sources.is_empty() {
+        return;
+    }
+
+    let record = DbBlocklistRefresh {
+        id: 0,
+        refreshed_at: Utc::now(),
+        total_domains: stats.total_domains as i64,
+        sources_loaded: stats.sources_loaded as i64,
+        sources_failed: stats.sources_failed as i64,
+        domains_added: stats.diff.added as i64,
+        domains_removed: stats.diff.removed as i64,
+        diff: serde_json::to_string(&stats.diff).unwrap_or_else(|_| "{}".to_string()),
+    };
+    if let Err(e) = db.record_blocklist_refresh(&record, BLOCKLIST_HISTORY_LIMIT) {
+        warn!("Failed to record blocklist refresh: {}", e);
+    }
+
+    if !stats.diff.is_empty() {
+        webhooks
+            .notify_blocklist_updated(&stats.diff, stats.diff.by_source.len())
+            .await;
+    }
+}
+
+/// Delete query log entries older than their owner's tier keeps history for;
+/// entries without an owner get the free tier's history
+pub fn prune_query_log(db: &SqliteDb, tiers: &TierManager) -> usize {
+    let users = match db.get_query_log_users() {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Failed to list query log owners: {}", e);
+            return 0;
+        }
+    };
+
+    users
+        .iter()
+        .map(|user_id| {
+            let tier = match user_id {
+                Some(user_id) => tiers.get_subscription(user_id).tier,
+                None => Tier::Free,
+            };
+            let days = tier.limits().history_days as i64;
+            db.clean_old_user_queries(user_id.as_deref(), days)
+                .unwrap_or_else(|e| {
+                    warn!("Failed to prune query log for {:?}: {}", user_id, e);
+                    0
+                })
+        })
+        .sum()
+}
+
 /// Popular domains for cache warming
 pub const POPULAR_DOMAINS: &[&str] = &[
     // Search & Navigation
@@ -272,4 +630,67 @@ mod tests {
         assert!(!POPULAR_DOMAINS.is_empty());
         assert!(POPULAR_DOMAINS.len() >= 20);
     }
+
+    #[tokio::test]
+    async fn test_prune_keeps_pro_history() {
+        use crate::state::AppState;
+        use shield_db::models::DbQueryLog;
+        use shield_dns_core::unified_filter::DeviceProfile;
+        use shield_profiles::ProtectionLevel;
+
+        let state = AppState::for_tests().await;
+        let user = state
+            .auth
+            .register("pro@example.com", "correct-horse-battery")
+            .unwrap();
+        state.tiers.upgrade(&user.id, Tier::Pro).unwrap();
+        let profile_id = state.profiles.create_profile_for_user(
+            "Kids".to_string(),
+            ProtectionLevel::Kid,
+            &user.id,
+        );
+        state.unified_filter.assign_profile_to_ip(
+            "10.0.1.20".parse().unwrap(),
+            DeviceProfile {
+                id: profile_id.to_string(),
+                ..Default::default()
+            },
+        );
+
+        // Past the free tier's day of history, well within Pro's 30
+        let entry = |domain: &str, client_ip: &str| DbQueryLog {
+            id: 0,
+            domain: domain.to_string(),
+            client_ip: client_ip.to_string(),
+            blocked: false,
+            cached: false,
+            response_time_ms: 0,
+            timestamp: chrono::Utc::now() - chrono::Duration::days(3),
+            user_id: None,
+            qtype: Some(1),
+            reason: None,
+            category: None,
+            profile_id: None,
+            device_id: None,
+            rcode: Some(0),
+            answers: vec![],
+            upstream: None,
+            dnssec: None,
+            protocol: Some("doh".to_string()),
+        };
+        state.query_log.record(entry("games.example", "10.0.1.20"));
+        state.query_log.record(entry("news.example", "10.0.9.9"));
+        for _ in 0..100 {
+            if state.db.get_recent_queries(10).unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(prune_query_log(&state.db, &state.tiers), 1);
+        let kept = state.db.get_recent_queries(10).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].domain, "games.example");
+        assert_eq!(kept[0].user_id.as_deref(), Some(user.id.as_str()));
+    }
 }
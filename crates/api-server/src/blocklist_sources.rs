@@ -0,0 +1,242 @@
+//! Blocklist source management
+//!
+//! Sources are stored in SQLite. `config/blocklist-sources.json` still provides
+//! the global settings (categories, presets, cache directory) and its sources
+//! are imported once on first boot.
+
+use crate::background_tasks::publish_blocklist_refresh;
+use crate::webhooks::WebhookManager;
+use chrono::{DateTime, Utc};
+use shield_db::{DbBlocklistSource, SqliteDb};
+use shield_dns_core::blocklist_fetcher::{
+    BlocklistConfig, BlocklistManager, BlocklistSource, BlocklistStats,
+};
+use shield_dns_core::unified_filter::UnifiedFilter;
+use tracing::{info, warn};
+
+/// Global blocklist settings and first-boot source list
+pub const BLOCKLIST_CONFIG_PATH: &str = "config/blocklist-sources.json";
+
+/// Settings key marking that the JSON sources were imported
+const IMPORTED_SETTING: &str = "blocklist_sources_imported";
+
+/// Settings key holding the categories of the last globally applied preset
+const GLOBAL_CATEGORIES_SETTING: &str = "blocklist_global_categories";
+
+/// Which sources a refresh downloads
+#[derive(Debug, Clone, Copy)]
+pub enum RefreshScope<'a> {
+    /// Download every enabled source
+    All,
+    /// Download one source (by URL); reuse loaded copies of the rest
+    Source(&'a str),
+    /// Download nothing; rebuild from cached or loaded copies
+    Reload,
+}
+
+/// Import the JSON file's sources into SQLite if this is the first boot
+///
+/// Returns the number of sources imported.
+pub fn import_json_sources(db: &SqliteDb, path: &str) -> usize {
+    match db.get_setting(IMPORTED_SETTING) {
+        Ok(Some(_)) => return 0,
+        Ok(None) => {}
+        Err(e) => {
+            warn!("Failed to read blocklist import marker: {}", e);
+            return 0;
+        }
+    }
+
+    let mut imported = 0;
+    let existing = db.get_blocklist_sources().map(|s| s.len()).unwrap_or(0);
+    if existing == 0 {
+        match BlocklistManager::load_config(path) {
+            Ok(config) => {
+                for source in &config.sources {
+                    if let Err(e) = source.validate() {
+                        warn!("Skipping blocklist source '{}': {}", source.name, e);
+                        continue;
+                    }
+                    let record = to_db_source(uuid::Uuid::new_v4().to_string(), source);
+                    match db.create_blocklist_source(&record) {
+                        Ok(()) => imported += 1,
+                        Err(e) => {
+                            warn!("Failed to import blocklist source '{}': {}", source.name, e)
+                        }
+                    }
+                }
+                info!("Imported {} blocklist sources from {}", imported, path);
+            }
+            Err(e) => warn!("No blocklist sources imported from {}: {}", path, e),
+        }
+    }
+
+    if let Err(e) = db.set_setting(IMPORTED_SETTING, &Utc::now().to_rfc3339()) {
+        warn!("Failed to record blocklist import marker: {}", e);
+    }
+    imported
+}
+
+/// Enable a preset's categories globally and keep them across restarts
+pub fn apply_global_categories(
+    unified_filter: &UnifiedFilter,
+    db: &SqliteDb,
+    categories: &[String],
+) -> Result<(), shield_db::DbError> {
+    let value = serde_json::to_string(categories).unwrap_or_else(|_| "[]".to_string());
+    db.set_setting(GLOBAL_CATEGORIES_SETTING, &value)?;
+    unified_filter.set_global_categories(categories);
+    Ok(())
+}
+
+/// Re-enable the globally applied preset's categories, if one was applied
+pub fn restore_global_categories(unified_filter: &UnifiedFilter, db: &SqliteDb) {
+    match db.get_setting(GLOBAL_CATEGORIES_SETTING) {
+        Ok(Some(value)) => match serde_json::from_str::<Vec<String>>(&value) {
+            Ok(categories) => {
+                info!("Restored global blocklist categories: {:?}", categories);
+                unified_filter.set_global_categories(&categories);
+            }
+            Err(e) => warn!("Ignoring stored global blocklist categories: {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read global blocklist categories: {}", e),
+    }
+}
+
+/// Build the fetcher config: global settings from the JSON file, sources
+/// from SQLite
+pub fn load_config(db: &SqliteDb, path: &str) -> BlocklistConfig {
+    let mut config = BlocklistManager::load_config(path).unwrap_or_else(|e| {
+        warn!("Using default blocklist settings ({}): {}", path, e);
+        BlocklistConfig::default()
+    });
+    match db.get_blocklist_sources() {
+        Ok(sources) => config.sources = sources.iter().map(from_db_source).collect(),
+        Err(e) => warn!("Failed to load blocklist sources, using {}: {}", path, e),
+    }
+    config
+}
+
+/// Refresh blocklists from the stored sources, record per-source status and
+/// the refresh history, and notify webhooks
+pub async fn refresh_blocklists(
+    unified_filter: &UnifiedFilter,
+    db: &SqliteDb,
+    webhooks: &WebhookManager,
+    scope: RefreshScope<'_>,
+) -> BlocklistStats {
+    let config = load_config(db, BLOCKLIST_CONFIG_PATH);
+    let manager = unified_filter.blocklist_manager();
+    let stats = match scope {
+        RefreshScope::All => manager.fetch_blocklists(&config).await,
+        RefreshScope::Source(url) => manager.fetch_source(&config, url).await,
+        RefreshScope::Reload => manager.reload_blocklists(&config).await,
+    };
+
+    for status in &stats.sources {
+        let checked_at = status
+            .checked_at
+            .and_then(|t| DateTime::<Utc>::from_timestamp(t as i64, 0));
+        if let Err(e) = db.update_blocklist_source_status(
+            &status.url,
+            status.freshness.as_str(),
+            status.error.as_deref(),
+            status.entries as i64,
+            checked_at,
+        ) {
+            warn!("Failed to record status of {}: {}", status.name, e);
+        }
+    }
+
+    publish_blocklist_refresh(&stats, db, webhooks).await;
+    stats
+}
+
+/// Convert a stored source to the fetcher's representation
+pub fn from_db_source(source: &DbBlocklistSource) -> BlocklistSource {
+    BlocklistSource {
+        name: source.name.clone(),
+        url: source.url.clone(),
+        category: source.category.clone(),
+        format: source.format.clone(),
+        enabled: source.enabled,
+        description: source.description.clone(),
+        sha256: source.sha256.clone(),
+        max_bytes: source.max_bytes.map(|v| v.max(0) as u64),
+        min_entries: source.min_entries.map(|v| v.max(0) as usize),
+    }
+}
+
+/// Convert a fetcher source to a new stored record
+pub fn to_db_source(id: String, source: &BlocklistSource) -> DbBlocklistSource {
+    let now = Utc::now();
+    DbBlocklistSource {
+        id,
+        name: source.name.clone(),
+        url: source.url.clone(),
+        category: source.category.clone(),
+        format: source.format.clone(),
+        enabled: source.enabled,
+        description: source.description.clone(),
+        sha256: source.sha256.clone(),
+        max_bytes: source.max_bytes.map(|v| v as i64),
+        min_entries: source.min_entries.map(|v| v as i64),
+        last_status: None,
+        last_error: None,
+        last_entries: 0,
+        last_checked_at: None,
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_runs_once() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let path = std::env::temp_dir().join(format!("shield-sources-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"sources": [
+                {"name": "Ads", "url": "https://lists.example.com/ads.txt", "category": "ads", "format": "domains", "enabled": true},
+                {"name": "Bad", "url": "ftp://lists.example.com/bad.txt", "category": "ads", "format": "domains", "enabled": true}
+            ]}"#,
+        )
+        .unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        // Invalid sources are skipped
+        assert_eq!(import_json_sources(&db, &path), 1);
+        let config = load_config(&db, &path);
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.sources[0].url, "https://lists.example.com/ads.txt");
+
+        // Deleting every source doesn't trigger a re-import
+        let id = db.get_blocklist_sources().unwrap()[0].id.clone();
+        db.delete_blocklist_source(&id).unwrap();
+        assert_eq!(import_json_sources(&db, &path), 0);
+        assert!(load_config(&db, &path).sources.is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_global_categories_survive_restart() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let legacy = std::sync::Arc::new(shield_dns_core::filter::FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy.clone());
+        let categories = vec!["ads".to_string(), "malware".to_string()];
+        apply_global_categories(&filter, &db, &categories).unwrap();
+
+        let restarted = UnifiedFilter::new(legacy);
+        assert_ne!(restarted.get_enabled_categories().len(), 2);
+        restore_global_categories(&restarted, &db);
+        let mut enabled = restarted.get_enabled_categories();
+        enabled.sort();
+        assert_eq!(enabled, categories);
+    }
+}
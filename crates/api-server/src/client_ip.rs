@@ -0,0 +1,152 @@
+//! Client address of API and DoH requests
+//!
+//! Behind a reverse proxy (Fly.io, a load balancer) the socket peer is the
+//! proxy, and the client's address arrives in `Fly-Client-IP` or
+//! `X-Forwarded-For`. Those headers are only believed when the peer is in
+//! `TRUSTED_PROXIES` (comma-separated CIDRs); otherwise any client could
+//! claim any address and pick up another device's profile.
+
+use crate::state::AppState;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Networks whose forwarding headers are trusted
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Read `TRUSTED_PROXIES`; empty (trust nobody) when unset
+    pub fn from_env() -> Self {
+        Self::parse(&std::env::var("TRUSTED_PROXIES").unwrap_or_default())
+    }
+
+    fn parse(value: &str) -> Self {
+        let nets = value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                // Bare addresses are single-host ranges
+                let parsed = s
+                    .parse::<IpNet>()
+                    .or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+                if parsed.is_err() {
+                    warn!("Ignoring invalid TRUSTED_PROXIES entry '{}'", s);
+                }
+                parsed.ok()
+            })
+            .collect();
+        Self { nets }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client behind `peer`, taken from the forwarding headers when the
+    /// peer is a trusted proxy
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?.to_canonical();
+        if !self.contains(&peer) {
+            return Some(peer);
+        }
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+        if let Some(ip) = header("fly-client-ip").and_then(|v| v.trim().parse::<IpAddr>().ok()) {
+            return Some(ip.to_canonical());
+        }
+        // Each proxy appends the address it received from, so the client is
+        // the rightmost entry not added by one of our own proxies
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|entry| entry.trim().parse::<IpAddr>().ok())
+            .map(|ip| ip.to_canonical())
+            .collect();
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.contains(ip))
+            .or(forwarded.first())
+            .copied()
+            .or(Some(peer))
+    }
+}
+
+/// Extractor for the requesting client's address, `None` when the
+/// connection has no peer address (e.g. in tests)
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[axum::async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientIp(
+            state.trusted_proxies.client_ip(peer, &parts.headers),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        let proxies = TrustedProxies::parse("172.16.0.0/12, fdaa::/16, 10.0.0.1, bogus");
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxy = Some(ip("172.19.0.2"));
+
+        // Headers from untrusted peers are ignored
+        let spoofed = headers(&[("fly-client-ip", "192.168.1.20")]);
+        assert_eq!(
+            proxies.client_ip(Some(ip("203.0.113.5")), &spoofed),
+            Some(ip("203.0.113.5"))
+        );
+
+        assert_eq!(proxies.client_ip(proxy, &spoofed), Some(ip("192.168.1.20")));
+        // The client can prepend anything; the rightmost untrusted hop wins
+        let chain = headers(&[
+            ("x-forwarded-for", "1.2.3.4, 198.51.100.7"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ]);
+        assert_eq!(
+            proxies.client_ip(Some(ip("fdaa::3")), &chain),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(proxies.client_ip(proxy, &HeaderMap::new()), proxy);
+        assert_eq!(
+            proxies.client_ip(Some(ip("::ffff:172.19.0.2")), &spoofed),
+            Some(ip("192.168.1.20"))
+        );
+        assert_eq!(TrustedProxies::default().client_ip(proxy, &spoofed), proxy);
+        assert_eq!(proxies.client_ip(None, &spoofed), None);
+    }
+}
@@ -0,0 +1,536 @@
+//! dnstap output
+//!
+//! Client queries and responses are encoded as dnstap and handed to a
+//! bounded queue. A background task writes them as Frame Streams to a
+//! collector's Unix socket or TCP endpoint, or to a rotating file. Like the
+//! query log, a full queue or a lost collector drops frames and counts them
+//! rather than holding up answers.
+
+use shield_dns_core::dnstap::{self, ControlType, Message, MessageType};
+use shield_dns_core::resolver::Protocol;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Frames waiting to be written before new ones are dropped
+pub const QUEUE_CAPACITY: usize = 10_000;
+/// Largest number of frames written at once
+pub const BATCH_SIZE: usize = 256;
+/// How long a collector has to accept the stream
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Reconnect delays after a collector goes away
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where frames go
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String),
+    /// Rotated to `path.1` … `path.keep` once it passes `max_bytes`
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnstapConfig {
+    pub output: Output,
+    /// Fraction of queries emitted, from 0 to 1
+    pub sample_rate: f64,
+    /// Server identity written into every message
+    pub identity: String,
+}
+
+impl DnstapConfig {
+    /// Read `DNSTAP_UNIX_SOCKET`, `DNSTAP_TCP` or `DNSTAP_FILE` (first set
+    /// wins), `DNSTAP_FILE_MAX_BYTES`, `DNSTAP_FILE_KEEP`,
+    /// `DNSTAP_SAMPLE_RATE` and `DNSTAP_IDENTITY`. `None` when no output
+    /// is configured.
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|name| std::env::var(name).ok().filter(|v| !v.trim().is_empty()))
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        #[cfg(unix)]
+        let unix = var("DNSTAP_UNIX_SOCKET").map(|path| Output::Unix(PathBuf::from(path)));
+        #[cfg(not(unix))]
+        let unix = None;
+
+        let output = unix
+            .or_else(|| var("DNSTAP_TCP").map(Output::Tcp))
+            .or_else(|| {
+                var("DNSTAP_FILE").map(|path| Output::File {
+                    path: PathBuf::from(path),
+                    max_bytes: var("DNSTAP_FILE_MAX_BYTES")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(100 * 1024 * 1024),
+                    keep: var("DNSTAP_FILE_KEEP")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(5),
+                })
+            })?;
+
+        Some(Self {
+            output,
+            sample_rate: var("DNSTAP_SAMPLE_RATE")
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|rate| rate.is_finite())
+                .map_or(1.0, |rate| rate.clamp(0.0, 1.0)),
+            identity: var("DNSTAP_IDENTITY").unwrap_or_else(|| "shield-ai".to_string()),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Writer counters
+#[derive(Debug, Clone, Copy)]
+pub struct DnstapStats {
+    pub queued: usize,
+    pub written: u64,
+    pub dropped: u64,
+}
+
+/// A client exchange to emit as a query and a response message. The wire
+/// messages are only set when the client sent or received wire format.
+pub struct Exchange<'a> {
+    pub protocol: Protocol,
+    pub client_ip: Option<IpAddr>,
+    pub query_time: SystemTime,
+    pub response_time: SystemTime,
+    pub query_message: Option<&'a [u8]>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+/// Sampled, back-pressured dnstap emitter
+pub struct DnstapWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    counters: Arc<Counters>,
+    sample_rate: f64,
+    identity: Vec<u8>,
+    version: Vec<u8>,
+}
+
+impl DnstapWriter {
+    /// Create the writer and spawn its background task
+    pub fn start(config: DnstapConfig) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let counters = Arc::new(Counters::default());
+        info!(
+            "dnstap output to {:?} (sample rate {})",
+            config.output, config.sample_rate
+        );
+        tokio::spawn(run(rx, config.output, counters.clone()));
+        Self {
+            tx,
+            counters,
+            sample_rate: config.sample_rate,
+            identity: config.identity.into_bytes(),
+            version: format!("shield-ai {}", env!("CARGO_PKG_VERSION")).into_bytes(),
+        }
+    }
+
+    /// Whether to emit the next exchange; decided once so a query and
+    /// its response are kept or skipped together
+    pub fn sampled(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Queue the query and response messages of an exchange; never waits
+    pub fn emit(&self, exchange: &Exchange) {
+        let query = Message {
+            kind: MessageType::ClientQuery,
+            protocol: exchange.protocol,
+            client_ip: exchange.client_ip,
+            query_time: exchange.query_time,
+            response_time: None,
+            query_message: exchange.query_message,
+            response_message: None,
+        };
+        let response = Message {
+            kind: MessageType::ClientResponse,
+            response_time: Some(exchange.response_time),
+            query_message: None,
+            response_message: exchange.response_message,
+            ..query.clone()
+        };
+        for message in [query, response] {
+            let payload = dnstap::encode(&self.identity, &self.version, &message);
+            if self.tx.try_send(dnstap::data_frame(&payload)).is_err() {
+                let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!("dnstap queue full, {} frames dropped so far", dropped);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> DnstapStats {
+        DnstapStats {
+            queued: QUEUE_CAPACITY - self.tx.capacity(),
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// An open Frame Streams output
+enum Sink {
+    /// Bidirectional stream to a collector
+    Socket(Box<dyn Stream>),
+    File(FileSink),
+}
+
+/// Unidirectional stream into a size-capped file
+struct FileSink {
+    file: tokio::fs::File,
+    path: PathBuf,
+    written: u64,
+    frames: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl Sink {
+    async fn open(output: &Output) -> std::io::Result<Self> {
+        match output {
+            #[cfg(unix)]
+            Output::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                Self::handshake(Box::new(stream)).await
+            }
+            Output::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Self::handshake(Box::new(stream)).await
+            }
+            Output::File {
+                path,
+                max_bytes,
+                keep,
+            } => {
+                // A file holds exactly one stream, so never append to an old one
+                if tokio::fs::metadata(path).await.is_ok_and(|m| m.len() > 0) {
+                    rotate(path, *keep).await?;
+                }
+                Ok(Sink::File(
+                    FileSink::create(path.clone(), *max_bytes, *keep).await?,
+                ))
+            }
+        }
+    }
+
+    /// READY, wait for a matching ACCEPT, then START
+    async fn handshake(mut stream: Box<dyn Stream>) -> std::io::Result<Self> {
+        stream
+            .write_all(&dnstap::control_frame(ControlType::Ready, true))
+            .await?;
+        let accept = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_control(&mut stream))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "no ACCEPT from collector")
+            })??;
+        let accepted = accept.kind == ControlType::Accept
+            && (accept.content_types.is_empty()
+                || accept
+                    .content_types
+                    .iter()
+                    .any(|c| c == dnstap::CONTENT_TYPE));
+        if !accepted {
+            return Err(std::io::Error::other("collector did not accept dnstap"));
+        }
+        stream
+            .write_all(&dnstap::control_frame(ControlType::Start, true))
+            .await?;
+        stream.flush().await?;
+        Ok(Sink::Socket(stream))
+    }
+
+    async fn write(&mut self, frames: &[Vec<u8>]) -> std::io::Result<()> {
+        match self {
+            Sink::Socket(stream) => {
+                stream.write_all(&frames.concat()).await?;
+                stream.flush().await
+            }
+            Sink::File(file) => {
+                for frame in frames {
+                    file.write(frame).await?;
+                }
+                file.file.flush().await
+            }
+        }
+    }
+
+    /// End the stream with STOP, waiting briefly for a collector's FINISH
+    async fn finish(self) {
+        let stop = dnstap::control_frame(ControlType::Stop, false);
+        let result = match self {
+            Sink::Socket(mut stream) => match stream.write_all(&stop).await {
+                Ok(()) => {
+                    let _ =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, read_control(&mut stream)).await;
+                    stream.shutdown().await
+                }
+                Err(e) => Err(e),
+            },
+            Sink::File(mut file) => file.stop().await,
+        };
+        if let Err(e) = result {
+            warn!("Failed to close dnstap output: {}", e);
+        }
+    }
+}
+
+impl FileSink {
+    async fn create(path: PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let mut file = tokio::fs::File::create(&path).await?;
+        let start = dnstap::control_frame(ControlType::Start, true);
+        file.write_all(&start).await?;
+        Ok(Self {
+            file,
+            path,
+            written: start.len() as u64,
+            frames: 0,
+            max_bytes,
+            keep,
+        })
+    }
+
+    /// Write a frame, first starting a new file if it would pass the cap
+    async fn write(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        if self.frames > 0 && self.written + frame.len() as u64 > self.max_bytes {
+            self.stop().await?;
+            rotate(&self.path, self.keep).await?;
+            *self = Self::create(self.path.clone(), self.max_bytes, self.keep).await?;
+        }
+        self.file.write_all(frame).await?;
+        self.written += frame.len() as u64;
+        self.frames += 1;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> std::io::Result<()> {
+        self.file
+            .write_all(&dnstap::control_frame(ControlType::Stop, false))
+            .await?;
+        self.file.flush().await
+    }
+}
+
+/// Read one escaped control frame
+async fn read_control(stream: &mut Box<dyn Stream>) -> std::io::Result<dnstap::Control> {
+    let invalid =
+        |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    if stream.read_u32().await? != 0 {
+        return Err(invalid("expected a control frame"));
+    }
+    let len = stream.read_u32().await? as usize;
+    if len > dnstap::MAX_CONTROL_FRAME {
+        return Err(invalid("control frame too large"));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    dnstap::parse_control(&body).ok_or_else(|| invalid("malformed control frame"))
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2` and so on, keeping `keep`
+async fn rotate(path: &Path, keep: usize) -> std::io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        return tokio::fs::remove_file(path).await;
+    }
+    let _ = tokio::fs::remove_file(numbered(keep)).await;
+    for n in (1..keep).rev() {
+        let _ = tokio::fs::rename(numbered(n), numbered(n + 1)).await;
+    }
+    tokio::fs::rename(path, numbered(1)).await
+}
+
+/// Connect, write batches and reconnect until every sender is gone
+async fn run(mut rx: mpsc::Receiver<Vec<u8>>, output: Output, counters: Arc<Counters>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let mut sink = match Sink::open(&output).await {
+            Ok(sink) => {
+                debug!("dnstap output {:?} opened", output);
+                backoff = MIN_BACKOFF;
+                sink
+            }
+            Err(e) => {
+                if rx.is_closed() {
+                    break;
+                }
+                warn!(
+                    "dnstap output {:?} unavailable: {}; retrying in {:?}",
+                    output, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+
+        loop {
+            if rx.recv_many(&mut batch, BATCH_SIZE).await == 0 {
+                sink.finish().await;
+                debug!("dnstap writer shutting down");
+                return;
+            }
+            match sink.write(&batch).await {
+                Ok(()) => {
+                    counters
+                        .written
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    batch.clear();
+                }
+                Err(e) => {
+                    counters
+                        .dropped
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    warn!("dnstap output {:?} failed: {}", output, e);
+                    batch.clear();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn exchange() -> Exchange<'static> {
+        Exchange {
+            protocol: Protocol::Doh,
+            client_ip: Some("192.168.1.20".parse().unwrap()),
+            query_time: SystemTime::now(),
+            response_time: SystemTime::now(),
+            query_message: Some(b"query"),
+            response_message: Some(b"response"),
+        }
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        let config = DnstapConfig::from_vars(|name| match name {
+            "DNSTAP_FILE" => Some("/tmp/dnstap.fstrm".to_string()),
+            "DNSTAP_FILE_KEEP" => Some("2".to_string()),
+            "DNSTAP_SAMPLE_RATE" => Some("1.5".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(
+            config.output,
+            Output::File {
+                path: PathBuf::from("/tmp/dnstap.fstrm"),
+                max_bytes: 100 * 1024 * 1024,
+                keep: 2
+            }
+        );
+        assert_eq!(config.sample_rate, 1.0);
+        assert!(DnstapConfig::from_vars(|_| None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let writer = DnstapWriter::start(DnstapConfig {
+            output: Output::Tcp(listener.local_addr().unwrap().to_string()),
+            sample_rate: 1.0,
+            identity: "test".to_string(),
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket: Box<dyn Stream> = Box::new(socket);
+        let ready = read_control(&mut socket).await.unwrap();
+        assert_eq!(ready.kind, ControlType::Ready);
+        assert_eq!(ready.content_types, vec![dnstap::CONTENT_TYPE.to_vec()]);
+        socket
+            .write_all(&dnstap::control_frame(ControlType::Accept, true))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_control(&mut socket).await.unwrap().kind,
+            ControlType::Start
+        );
+
+        writer.emit(&exchange());
+        for _ in 0..2 {
+            let len = socket.read_u32().await.unwrap();
+            assert!(len > 0);
+            let mut payload = vec![0; len as usize];
+            socket.read_exact(&mut payload).await.unwrap();
+            assert!(payload.windows(4).any(|w| w == b"test"));
+        }
+
+        drop(writer);
+        assert_eq!(
+            read_control(&mut socket).await.unwrap().kind,
+            ControlType::Stop
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("shield-dnstap-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.fstrm");
+        let writer = DnstapWriter::start(DnstapConfig {
+            output: Output::File {
+                path: path.clone(),
+                max_bytes: 200,
+                keep: 1,
+            },
+            sample_rate: 1.0,
+            identity: "test".to_string(),
+        });
+        for _ in 0..3 {
+            writer.emit(&exchange());
+        }
+
+        let counters = writer.counters.clone();
+        drop(writer);
+        for _ in 0..100 {
+            let stop = dnstap::control_frame(ControlType::Stop, false);
+            let done = std::fs::read(&path).is_ok_and(|bytes| bytes.ends_with(&stop));
+            if done && counters.written.load(Ordering::Relaxed) == 6 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Both files are complete streams, and older ones past `keep` are gone
+        let start = dnstap::control_frame(ControlType::Start, true);
+        for file in [path.clone(), dir.join("dnstap.fstrm.1")] {
+            let bytes = std::fs::read(&file).unwrap();
+            assert!(bytes.starts_with(&start), "{:?}", file);
+        }
+        assert!(!dir.join("dnstap.fstrm.2").exists());
+        assert_eq!(counters.written.load(Ordering::Relaxed), 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
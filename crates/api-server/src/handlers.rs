@@ -3,30 +3,50 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        ConnectInfo, Path, Query, State,
+        Path, Query, State,
     },
     http::StatusCode,
-    response::Response,
+    response::{IntoResponse, Response},
     Json,
 };
-use std::net::SocketAddr;
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use shield_metrics::QueryLogEntry;
+use shield_dns_core::resolver::{Protocol, Resolution, RCODE_NOERROR, RCODE_NXDOMAIN};
+use shield_metrics::openmetrics::{self, Exposition};
+use shield_metrics::rollup::{Dimension, Granularity, RollupCounts};
+use shield_metrics::sketch::{self, HeavyHitter};
+use shield_metrics::{ClientStats, QueryLogEntry};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, error, info, warn};
 
+use crate::blocklist_sources::{self, RefreshScope};
+use crate::client_ip::ClientIp;
+use crate::dnstap::Exchange;
+use crate::live::{
+    self, ClientMessage, DeviceEvent, DeviceEventKind, LiveEvent, ServerMessage, Subscriptions,
+    ThreatAlert, Topic, THREAT_CATEGORIES,
+};
 use crate::rate_limiter::{RateLimitError, RateLimitResult, RateLimiterStats};
+use crate::rollups::RollupStore;
 use crate::state::AppState;
 use chrono::Utc;
-use shield_db::models::{DbAllowlistEntry, DbBlocklistEntry};
+use shield_db::models::{
+    DbAllowlistEntry, DbBlocklistEntry, DbBlocklistSource, DbQueryLog, QueryLogFilter,
+};
+use shield_dns_core::rpz::RpzAction;
 
 // Re-exports for API responses
+use shield_dns_core::schedule::{self, RuleTarget};
+use shield_dns_core::services;
 pub use shield_dns_core::unified_filter::FilterReason;
+use shield_dns_core::usage::QuotaUsage;
 pub use shield_ml_engine::{AnalyticsSnapshot, DeepRiskAnalysis};
-pub use shield_profiles::{Profile, ProfileStats, ProtectionLevel};
+use shield_profiles::{DeviceError, DeviceType, NetworkDevice};
+pub use shield_profiles::{
+    Profile, ProfileStats, ProtectionLevel, RuleAction, TimeRule, UsageQuota,
+};
 pub use shield_threat_intel::{ThreatAnalysis, ThreatCategory};
 pub use shield_tiers::{FeatureCheck, Subscription, Tier, UsageStats};
 
@@ -88,19 +108,23 @@ pub struct DohQuery {
     pub record_type: Option<String>,
 }
 
-/// Parse domain name from DNS wire format query
-fn parse_dns_wire_query(dns_base64: &str) -> Option<(String, u16)> {
+/// Decode the base64url `dns` parameter of a DoH GET
+fn decode_dns_param(dns_base64: &str) -> Option<Vec<u8>> {
     use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 
     // Decode base64url (with or without padding)
-    let data = URL_SAFE_NO_PAD.decode(dns_base64)
+    URL_SAFE_NO_PAD
+        .decode(dns_base64)
         .or_else(|_| {
             // Try standard base64 as fallback
             use base64::engine::general_purpose::STANDARD;
             STANDARD.decode(dns_base64)
         })
-        .ok()?;
+        .ok()
+}
 
+/// Parse domain name from DNS wire format query
+fn parse_dns_wire_query(data: &[u8]) -> Option<(String, u16)> {
     // DNS header is 12 bytes, need at least that plus some question
     if data.len() < 13 {
         return None;
@@ -233,6 +257,35 @@ pub struct DohAnswer {
     pub data: String,
 }
 
+/// Build a DoH response for an RPZ NODATA or local-data policy
+fn policy_response(domain: &str, record_type: u16, rewrite: &RpzAction) -> DohResponse {
+    let answer = match rewrite {
+        RpzAction::LocalData(records) => records
+            .iter()
+            .filter(|r| r.record_type == record_type || r.record_type == 5) // CNAME answers any type
+            .map(|r| DohAnswer {
+                name: domain.to_string(),
+                record_type: r.record_type,
+                ttl: r.ttl,
+                data: r.data.clone(),
+            })
+            .collect(),
+        _ => vec![],
+    };
+
+    DohResponse {
+        status: 0, // NOERROR
+        truncated: false,
+        recursion_desired: true,
+        recursion_available: true,
+        question: vec![DohQuestion {
+            name: domain.to_string(),
+            record_type,
+        }],
+        answer,
+    }
+}
+
 #[derive(Serialize)]
 pub struct AnalyticsResponse {
     pub period: String,
@@ -243,6 +296,9 @@ pub struct AnalyticsResponse {
     pub top_blocked_domains: Vec<DomainCount>,
     pub top_allowed_domains: Vec<DomainCount>,
     pub queries_by_hour: Vec<HourlyStats>,
+    /// Estimated distinct domains and clients (at most the last 24 hours)
+    pub unique_domains: u64,
+    pub unique_clients: u64,
 }
 
 #[derive(Serialize)]
@@ -289,6 +345,10 @@ pub async fn health_check(State(state): State<Arc<AppState>>) -> Json<HealthResp
 
 /// DNS statistics endpoint
 pub async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
+    Json(stats_response(&state))
+}
+
+fn stats_response(state: &AppState) -> StatsResponse {
     let snapshot = state.metrics.snapshot();
 
     let block_rate = if snapshot.total_queries > 0 {
@@ -297,7 +357,7 @@ pub async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse
         0.0
     };
 
-    Json(StatsResponse {
+    StatsResponse {
         total_queries: snapshot.total_queries,
         blocked_queries: snapshot.blocked_queries,
         cache_hits: snapshot.cache_hits,
@@ -305,15 +365,344 @@ pub async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse
         cache_hit_rate: snapshot.cache_hit_rate,
         block_rate,
         blocklist_size: state.filter.blocklist_size(),
-    })
+    }
 }
 
-/// Query history endpoint
+/// Query history endpoint; public, so entries are anonymized
 pub async fn get_query_history(State(state): State<Arc<AppState>>) -> Json<QueryHistoryResponse> {
-    let queries = state.metrics.get_query_history(100);
+    let queries = state
+        .metrics
+        .get_query_history(100)
+        .into_iter()
+        .map(anonymize_history_entry)
+        .collect();
     Json(QueryHistoryResponse { queries })
 }
 
+/// Cut the client address to its /24 (IPv4) or /48 (IPv6) network and drop
+/// the profile, device, answers and upstream; owners see full entries
+/// through `/api/query-log`
+fn anonymize_history_entry(mut entry: QueryLogEntry) -> QueryLogEntry {
+    entry.client_ip = entry
+        .client_ip
+        .parse::<std::net::IpAddr>()
+        .ok()
+        .map(|ip| {
+            let ip = ip.to_canonical();
+            let prefix = if ip.is_ipv4() { 24 } else { 48 };
+            ipnet::IpNet::new(ip, prefix)
+                .map(|net| net.trunc().to_string())
+                .unwrap_or_default()
+        })
+        .unwrap_or_default();
+    entry.profile_id = None;
+    entry.device_id = None;
+    entry.answers.clear();
+    entry.upstream = None;
+    entry
+}
+
+// ============================================================================
+// Query Log Search & Export
+// ============================================================================
+
+/// Default and largest page size for query log search
+const QUERY_LOG_PAGE_SIZE: usize = 100;
+const QUERY_LOG_MAX_PAGE_SIZE: usize = 1000;
+/// Rows read from SQLite per export chunk
+const QUERY_LOG_EXPORT_CHUNK: usize = 1000;
+
+/// Query log search criteria; every parameter is optional
+#[derive(Deserialize)]
+pub struct QueryLogSearchQuery {
+    /// Domain contains this text
+    pub domain: Option<String>,
+    /// Domain is this name or a subdomain of it
+    pub suffix: Option<String>,
+    /// Client IP address
+    pub client: Option<String>,
+    /// Device registry ID
+    pub device: Option<String>,
+    /// Profile ID
+    pub profile: Option<String>,
+    /// `blocked` or `allowed`
+    pub decision: Option<String>,
+    /// Filter reason, e.g. `category_block`
+    pub reason: Option<String>,
+    pub category: Option<String>,
+    /// Record type name (`AAAA`) or number (`28`)
+    pub qtype: Option<String>,
+    /// RFC 3339 lower bound (inclusive)
+    pub since: Option<String>,
+    /// RFC 3339 upper bound (exclusive)
+    pub until: Option<String>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    /// Export format: `csv` or `ndjson` (default)
+    pub format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct QueryLogSearchResponse {
+    /// Matching entries, newest first
+    pub entries: Vec<DbQueryLog>,
+    /// Pass as `cursor` to get the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Build search criteria from query parameters
+fn query_log_filter(
+    query: &QueryLogSearchQuery,
+) -> Result<QueryLogFilter, (StatusCode, Json<ErrorResponse>)> {
+    let error = |error: &str, message: &str| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: error.to_string(),
+                message: message.to_string(),
+            }),
+        )
+    };
+    let time = |value: &Option<String>, name: &str| {
+        value
+            .as_deref()
+            .map(|value| {
+                chrono::DateTime::parse_from_rfc3339(value)
+                    .map(|t| t.with_timezone(&Utc))
+                    .map_err(|_| {
+                        error(
+                            "invalid_time",
+                            &format!("`{}` must be an RFC 3339 timestamp", name),
+                        )
+                    })
+            })
+            .transpose()
+    };
+
+    let blocked = match query.decision.as_deref() {
+        None => None,
+        Some("blocked") => Some(true),
+        Some("allowed") => Some(false),
+        Some(_) => {
+            return Err(error(
+                "invalid_decision",
+                "`decision` must be `blocked` or `allowed`",
+            ))
+        }
+    };
+    let qtype = query
+        .qtype
+        .as_deref()
+        .map(|qtype| {
+            qtype
+                .parse::<u16>()
+                .ok()
+                .or_else(|| shield_dns_core::adblock::qtype_from_name(qtype))
+                .ok_or_else(|| {
+                    error(
+                        "invalid_qtype",
+                        "`qtype` must be a record type name or number",
+                    )
+                })
+        })
+        .transpose()?;
+    let before_id = query
+        .cursor
+        .as_deref()
+        .map(|cursor| {
+            cursor
+                .parse::<i64>()
+                .map_err(|_| error("invalid_cursor", "Invalid pagination cursor"))
+        })
+        .transpose()?;
+
+    Ok(QueryLogFilter {
+        domain: query.domain.clone(),
+        domain_suffix: query.suffix.clone(),
+        client_ip: query.client.clone(),
+        device_id: query.device.clone(),
+        profile_id: query.profile.clone(),
+        blocked,
+        reason: query.reason.clone(),
+        category: query.category.clone(),
+        qtype,
+        since: time(&query.since, "since")?,
+        until: time(&query.until, "until")?,
+        before_id,
+        user_id: None,
+    })
+}
+
+/// Search the caller's entries in the durable query log with cursor pagination
+pub async fn search_query_log(
+    Query(query): Query<QueryLogSearchQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<QueryLogSearchResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let mut filter = query_log_filter(&query)?;
+    filter.user_id = Some(claims.sub);
+    let limit = query
+        .limit
+        .unwrap_or(QUERY_LOG_PAGE_SIZE)
+        .clamp(1, QUERY_LOG_MAX_PAGE_SIZE);
+
+    let db = state.db.clone();
+    let entries = tokio::task::spawn_blocking(move || db.search_queries(&filter, limit))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .map_err(|e| {
+            error!("Query log search failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: "database_error".to_string(),
+                    message: "Failed to search the query log".to_string(),
+                }),
+            )
+        })?;
+
+    let next_cursor = (entries.len() == limit)
+        .then(|| entries.last().map(|q| q.id.to_string()))
+        .flatten();
+    Ok(Json(QueryLogSearchResponse {
+        entries,
+        next_cursor,
+    }))
+}
+
+/// Quote a CSV field when it needs it
+///
+/// Fields a spreadsheet would run as a formula, including ones behind a
+/// leading tab or carriage return, get a leading `'`.
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    let value: std::borrow::Cow<'_, str> = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value).into()
+    } else {
+        value.into()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value
+    }
+}
+
+const QUERY_LOG_CSV_HEADER: &str =
+    "id,timestamp,domain,client_ip,device_id,profile_id,protocol,qtype,blocked,reason,category,rcode,answers,upstream,cached,dnssec,response_time_ms\n";
+
+fn query_log_csv_row(q: &DbQueryLog) -> String {
+    let optional = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(csv_field)
+            .unwrap_or_default()
+            .into_owned()
+    };
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        q.id,
+        q.timestamp.to_rfc3339(),
+        csv_field(&q.domain),
+        csv_field(&q.client_ip),
+        optional(&q.device_id),
+        optional(&q.profile_id),
+        optional(&q.protocol),
+        q.qtype.map(|t| t.to_string()).unwrap_or_default(),
+        q.blocked,
+        optional(&q.reason),
+        optional(&q.category),
+        q.rcode.map(|r| r.to_string()).unwrap_or_default(),
+        csv_field(&q.answers.join(" ")),
+        optional(&q.upstream),
+        q.cached,
+        optional(&q.dnssec),
+        q.response_time_ms
+    )
+}
+
+/// Stream every matching query log entry of the caller's as CSV or NDJSON,
+/// newest first
+pub async fn export_query_log(
+    Query(query): Query<QueryLogSearchQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<impl axum::response::IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let mut filter = query_log_filter(&query)?;
+    filter.user_id = Some(claims.sub);
+    let csv = match query.format.as_deref() {
+        None | Some("ndjson") => false,
+        Some("csv") => true,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: "invalid_format".to_string(),
+                    message: "`format` must be `csv` or `ndjson`".to_string(),
+                }),
+            ))
+        }
+    };
+
+    // Page through the log by ID so rows written during the export are skipped
+    let db = state.db.clone();
+    let pages = futures::stream::unfold(Some(filter), move |filter| {
+        let db = db.clone();
+        async move {
+            let mut filter = filter?;
+            let page_filter = filter.clone();
+            let page = tokio::task::spawn_blocking(move || {
+                db.search_queries(&page_filter, QUERY_LOG_EXPORT_CHUNK)
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|result| result.map_err(std::io::Error::other));
+            let page = match page {
+                Ok(page) if page.is_empty() => return None,
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Query log export failed: {}", e);
+                    return Some((Err(e), None));
+                }
+            };
+            filter.before_id = page.last().map(|q| q.id);
+            let next = (page.len() == QUERY_LOG_EXPORT_CHUNK).then_some(filter);
+            let chunk: String = page
+                .iter()
+                .map(|q| {
+                    if csv {
+                        query_log_csv_row(q)
+                    } else {
+                        serde_json::to_string(q).unwrap_or_default() + "\n"
+                    }
+                })
+                .collect();
+            Some((Ok(chunk), next))
+        }
+    });
+    let header = futures::stream::iter(csv.then(|| Ok(QUERY_LOG_CSV_HEADER.to_string())));
+    let body = axum::body::Body::from_stream(header.chain(pages));
+
+    let (content_type, filename) = if csv {
+        ("text/csv", "query-log.csv")
+    } else {
+        ("application/x-ndjson", "query-log.ndjson")
+    };
+    Ok((
+        StatusCode::OK,
+        [
+            (axum::http::header::CONTENT_TYPE, content_type.to_string()),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    ))
+}
+
 /// Blocklist stats endpoint
 pub async fn get_blocklist_stats(
     State(state): State<Arc<AppState>>,
@@ -363,6 +752,143 @@ pub async fn analyze_domain(
 // DNS-over-HTTPS (DoH) Endpoint
 // ============================================================================
 
+/// A query being answered, recorded once the answer is known
+struct LoggedQuery<'a> {
+    protocol: Protocol,
+    domain: &'a str,
+    client_ip: Option<std::net::IpAddr>,
+    /// Registry device the client maps to
+    device_id: Option<uuid::Uuid>,
+    qtype: Option<u16>,
+    filter_result: &'a FilterResult,
+    /// When the query arrived
+    started: Instant,
+    /// Time spent in the filter
+    filter_time: Duration,
+    /// Wire-format query and response, when the client spoke wire format
+    query_message: Option<&'a [u8]>,
+    response_message: Option<&'a [u8]>,
+}
+
+impl LoggedQuery<'_> {
+    /// Record the answer in the live metrics and the durable query log.
+    /// `resolution` is `None` for answers made locally (blocks and rewrites).
+    fn record(
+        &self,
+        state: &AppState,
+        rcode: u16,
+        answers: Vec<String>,
+        resolution: Option<&Resolution>,
+    ) {
+        let elapsed = self.started.elapsed();
+        let response_time_ms = elapsed.as_millis() as u64;
+        state.metrics.record_stage("filter", self.filter_time);
+        if let Some(resolution) = resolution {
+            if resolution.cached {
+                state.metrics.record_cache_hit();
+                state.metrics.record_stage("cache", resolution.elapsed);
+            } else {
+                state.metrics.record_cache_miss();
+                state.metrics.record_stage("upstream", resolution.elapsed);
+            }
+        }
+        state.metrics.record_response_time(elapsed);
+        self.tap(state, elapsed);
+
+        let now = Utc::now();
+        let entry = QueryLogEntry {
+            timestamp: now.timestamp() as u64,
+            domain: self.domain.to_string(),
+            client_ip: self
+                .client_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            blocked: self.filter_result.decision == shield_dns_core::filter::FilterDecision::Block,
+            response_time_ms,
+            qtype: self.qtype,
+            rcode,
+            answers,
+            reason: Some(self.filter_result.reason.as_str().to_string()),
+            category: self.filter_result.category.clone(),
+            profile_id: self.filter_result.profile_id.clone(),
+            device_id: self.device_id.map(|id| id.to_string()),
+            upstream: resolution.and_then(|r| r.upstream.clone()),
+            cached: resolution.is_some_and(|r| r.cached),
+            dnssec: resolution.map(|r| r.dnssec.as_str().to_string()),
+            protocol: self.protocol.as_str().to_string(),
+        };
+        state.query_log.record(DbQueryLog {
+            id: 0,
+            domain: entry.domain.clone(),
+            client_ip: entry.client_ip.clone(),
+            blocked: entry.blocked,
+            cached: entry.cached,
+            response_time_ms: response_time_ms as i64,
+            timestamp: now,
+            user_id: None,
+            qtype: entry.qtype,
+            reason: entry.reason.clone(),
+            category: entry.category.clone(),
+            profile_id: entry.profile_id.clone(),
+            device_id: entry.device_id.clone(),
+            rcode: Some(rcode),
+            answers: entry.answers.clone(),
+            upstream: entry.upstream.clone(),
+            dnssec: entry.dnssec.clone(),
+            protocol: Some(entry.protocol.clone()),
+        });
+        if let Some(category) = entry
+            .category
+            .as_deref()
+            .filter(|c| entry.blocked && THREAT_CATEGORIES.contains(c))
+        {
+            state.live.publish(|| {
+                LiveEvent::Alert(ThreatAlert {
+                    timestamp: entry.timestamp,
+                    domain: entry.domain.clone(),
+                    category: category.to_string(),
+                    client_ip: entry.client_ip.clone(),
+                    profile_id: entry.profile_id.clone(),
+                })
+            });
+        }
+        state.live.publish(|| LiveEvent::Query(entry.clone()));
+        state.metrics.record_query_entry(entry);
+    }
+
+    /// Emit the exchange to dnstap unless it is sampled out or the
+    /// client's profile opted out
+    fn tap(&self, state: &AppState, elapsed: Duration) {
+        let Some(dnstap) = &state.dnstap else {
+            return;
+        };
+        if !dnstap.sampled() || state.unified_filter.dnstap_opt_out(self.client_ip) {
+            return;
+        }
+
+        let response_time = SystemTime::now();
+        dnstap.emit(&Exchange {
+            protocol: self.protocol,
+            client_ip: self.client_ip,
+            query_time: response_time - elapsed,
+            response_time,
+            query_message: self.query_message,
+            response_message: self.response_message,
+        });
+    }
+
+    /// Record a DoH JSON answer
+    fn record_doh(
+        &self,
+        state: &AppState,
+        response: &DohResponse,
+        resolution: Option<&Resolution>,
+    ) {
+        let answers = response.answer.iter().map(|a| a.data.clone()).collect();
+        self.record(state, response.status as u16, answers, resolution);
+    }
+}
+
 /// DNS-over-HTTPS endpoint (RFC 8484)
 /// Supports both:
 /// - Wire format: GET /dns-query?dns=base64url_encoded_query (for iOS/macOS)
@@ -370,9 +896,8 @@ pub async fn analyze_domain(
 pub async fn doh_query(
     Query(params): Query<DohQuery>,
     State(state): State<Arc<AppState>>,
-    connect_info: Option<ConnectInfo<SocketAddr>>,
+    ClientIp(client_ip): ClientIp,
 ) -> Result<Json<DohResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let client_ip = connect_info.map(|ci| ci.0.ip());
 
     // Check for wire format first (iOS/macOS use this)
     if let Some(ref dns_query) = params.dns {
@@ -394,34 +919,43 @@ pub async fn doh_query(
     };
 
     let record_type = params.record_type.unwrap_or_else(|| "A".to_string());
-    let record_type_num: u16 = match record_type.to_uppercase().as_str() {
-        "A" => 1,
-        "AAAA" => 28,
-        "CNAME" => 5,
-        "MX" => 15,
-        "TXT" => 16,
-        "NS" => 2,
-        _ => 1,
-    };
+    let record_type_num: u16 = shield_dns_core::adblock::qtype_from_name(&record_type).unwrap_or(1);
 
     info!("DoH query (JSON): {} type={}", domain, record_type);
 
     // Use unified filter with client IP for profile-aware blocking
-    let filter_result = state.unified_filter.check(&domain, client_ip);
+    let device_id = observe_client(&state, client_ip);
+    let started = Instant::now();
+    let filter_result = state
+        .unified_filter
+        .check_query(&domain, client_ip, Some(record_type_num));
+    let logged = LoggedQuery {
+        protocol: Protocol::Doh,
+        domain: &domain,
+        client_ip,
+        device_id,
+        qtype: Some(record_type_num),
+        filter_result: &filter_result,
+        started,
+        filter_time: started.elapsed(),
+        query_message: None,
+        response_message: None,
+    };
 
-    if filter_result.decision == shield_dns_core::filter::FilterDecision::Block {
-        let client_ip_str = client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
-        state
-            .metrics
-            .record_query_with_details(domain.clone(), client_ip_str, true, 0);
+    if let Some(rewrite) = &filter_result.rewrite {
+        let response = policy_response(&domain, record_type_num, rewrite);
+        logged.record_doh(&state, &response, None);
 
         debug!(
-            "DoH blocked: {} (reason: {:?}, category: {:?})",
-            domain, filter_result.reason, filter_result.category
+            "DoH policy rewrite: {} (category: {:?})",
+            domain, filter_result.category
         );
+        return Ok(Json(response));
+    }
 
-        return Ok(Json(DohResponse {
-            status: 3, // NXDOMAIN
+    if filter_result.decision == shield_dns_core::filter::FilterDecision::Block {
+        let response = DohResponse {
+            status: RCODE_NXDOMAIN as u32,
             truncated: false,
             recursion_desired: true,
             recursion_available: true,
@@ -430,15 +964,23 @@ pub async fn doh_query(
                 record_type: record_type_num,
             }],
             answer: vec![],
-        }));
+        };
+        logged.record_doh(&state, &response, None);
+
+        debug!(
+            "DoH blocked: {} (reason: {:?}, category: {:?})",
+            domain, filter_result.reason, filter_result.category
+        );
+
+        return Ok(Json(response));
     }
 
     // Resolve domain
-    let start = std::time::Instant::now();
-    match state.resolver.resolve(&domain).await {
-        Ok(ips) => {
-            let query_time_ms = start.elapsed().as_millis() as u64;
-            let answers: Vec<DohAnswer> = ips
+    let resolution = state.resolver.lookup(&domain).await;
+    match &resolution.error {
+        None => {
+            let answers: Vec<DohAnswer> = resolution
+                .ips
                 .iter()
                 .map(|ip| {
                     let ip_str = ip.to_string();
@@ -451,15 +993,8 @@ pub async fn doh_query(
                 })
                 .collect();
 
-            state.metrics.record_query_with_details(
-                domain.clone(),
-                "0.0.0.0".to_string(),
-                false,
-                query_time_ms,
-            );
-
-            Ok(Json(DohResponse {
-                status: 0, // NOERROR
+            let response = DohResponse {
+                status: RCODE_NOERROR as u32,
                 truncated: false,
                 recursion_desired: true,
                 recursion_available: true,
@@ -468,9 +1003,13 @@ pub async fn doh_query(
                     record_type: record_type_num,
                 }],
                 answer: answers,
-            }))
+            };
+            logged.record_doh(&state, &response, Some(&resolution));
+
+            Ok(Json(response))
         }
-        Err(e) => {
+        Some(e) => {
+            logged.record(&state, resolution.rcode, vec![], Some(&resolution));
             error!("DoH resolution failed for {}: {}", domain, e);
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -487,18 +1026,12 @@ pub async fn doh_query(
 /// iOS/macOS send POST requests with binary DNS message in body
 pub async fn doh_query_post(
     State(state): State<Arc<AppState>>,
-    connect_info: Option<ConnectInfo<SocketAddr>>,
+    ClientIp(client_ip): ClientIp,
     body: axum::body::Bytes,
 ) -> Result<impl axum::response::IntoResponse, (StatusCode, Json<ErrorResponse>)> {
-    use base64::{engine::general_purpose::STANDARD, Engine};
-
-    let client_ip = connect_info.map(|ci| ci.0.ip());
-
-    // Convert body bytes to base64 for parsing
-    let dns_base64 = STANDARD.encode(&body);
 
     // Parse the DNS query
-    let (domain, record_type_num) = match parse_dns_wire_query(&dns_base64) {
+    let (domain, record_type_num) = match parse_dns_wire_query(&body) {
         Some(parsed) => parsed,
         None => {
             return Err((
@@ -514,39 +1047,60 @@ pub async fn doh_query_post(
     info!("DoH POST query: {} type={}", domain, record_type_num);
 
     // Use unified filter with client IP for profile-aware blocking
-    let filter_result = state.unified_filter.check(&domain, client_ip);
+    let device_id = observe_client(&state, client_ip);
+    let started = Instant::now();
+    let filter_result = state
+        .unified_filter
+        .check_query(&domain, client_ip, Some(record_type_num));
+    let mut logged = LoggedQuery {
+        protocol: Protocol::Doh,
+        domain: &domain,
+        client_ip,
+        device_id,
+        qtype: Some(record_type_num),
+        filter_result: &filter_result,
+        started,
+        filter_time: started.elapsed(),
+        query_message: Some(&body),
+        response_message: None,
+    };
     let blocked = filter_result.decision == shield_dns_core::filter::FilterDecision::Block;
 
     if blocked {
-        let client_ip_str = client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
-        state
-            .metrics
-            .record_query_with_details(domain.clone(), client_ip_str, true, 0);
-
         debug!(
             "DoH POST blocked: {} (reason: {:?}, category: {:?})",
             domain, filter_result.reason, filter_result.category
         );
     }
 
-    // Resolve domain if not blocked
-    let ips: Vec<std::net::IpAddr> = if blocked {
-        vec![]
-    } else {
-        state.resolver.resolve(&domain).await.unwrap_or_default()
+    // Resolve domain if not blocked; RPZ policies answer locally
+    let resolution = match &filter_result.rewrite {
+        None if !blocked => Some(state.resolver.lookup(&domain).await),
+        _ => None,
+    };
+    let ips: Vec<std::net::IpAddr> = match (&filter_result.rewrite, &resolution) {
+        (Some(RpzAction::LocalData(records)), _) => {
+            records.iter().filter_map(|r| r.data.parse().ok()).collect()
+        }
+        (_, Some(resolution)) => resolution.ips.clone(),
+        _ => vec![],
     };
 
-    if !blocked {
-        state.metrics.record_query_with_details(
-            domain.clone(),
-            "0.0.0.0".to_string(),
-            false,
-            0,
-        );
-    }
-
-    // Build wire format response
-    let response_bytes = build_dns_wire_response(&body, &domain, &ips, blocked);
+    // Build wire format response (NODATA policies answer NOERROR with no records)
+    let nxdomain = blocked && filter_result.rewrite.is_none();
+    let response_bytes = build_dns_wire_response(&body, &domain, &ips, nxdomain);
+    let rcode = if nxdomain {
+        RCODE_NXDOMAIN
+    } else {
+        RCODE_NOERROR
+    };
+    logged.response_message = Some(&response_bytes);
+    logged.record(
+        &state,
+        rcode,
+        ips.iter().map(|ip| ip.to_string()).collect(),
+        resolution.as_ref(),
+    );
 
     Ok((
         StatusCode::OK,
@@ -562,7 +1116,8 @@ async fn doh_query_wire_format(
     client_ip: Option<std::net::IpAddr>,
 ) -> Result<Json<DohResponse>, (StatusCode, Json<ErrorResponse>)> {
     // Parse the DNS query
-    let (domain, record_type_num) = match parse_dns_wire_query(dns_base64) {
+    let query = decode_dns_param(dns_base64);
+    let (domain, record_type_num) = match query.as_deref().and_then(parse_dns_wire_query) {
         Some(parsed) => parsed,
         None => {
             return Err((
@@ -578,21 +1133,38 @@ async fn doh_query_wire_format(
     info!("DoH query (wire): {} type={}", domain, record_type_num);
 
     // Use unified filter with client IP for profile-aware blocking
-    let filter_result = state.unified_filter.check(&domain, client_ip);
+    let device_id = observe_client(&state, client_ip);
+    let started = Instant::now();
+    let filter_result = state
+        .unified_filter
+        .check_query(&domain, client_ip, Some(record_type_num));
+    let logged = LoggedQuery {
+        protocol: Protocol::Doh,
+        domain: &domain,
+        client_ip,
+        device_id,
+        qtype: Some(record_type_num),
+        filter_result: &filter_result,
+        started,
+        filter_time: started.elapsed(),
+        query_message: query.as_deref(),
+        response_message: None,
+    };
 
-    if filter_result.decision == shield_dns_core::filter::FilterDecision::Block {
-        let client_ip_str = client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
-        state
-            .metrics
-            .record_query_with_details(domain.clone(), client_ip_str, true, 0);
+    if let Some(rewrite) = &filter_result.rewrite {
+        let response = policy_response(&domain, record_type_num, rewrite);
+        logged.record_doh(&state, &response, None);
 
         debug!(
-            "DoH wire blocked: {} (reason: {:?}, category: {:?})",
-            domain, filter_result.reason, filter_result.category
+            "DoH policy rewrite: {} (category: {:?})",
+            domain, filter_result.category
         );
+        return Ok(Json(response));
+    }
 
-        return Ok(Json(DohResponse {
-            status: 3, // NXDOMAIN
+    if filter_result.decision == shield_dns_core::filter::FilterDecision::Block {
+        let response = DohResponse {
+            status: RCODE_NXDOMAIN as u32,
             truncated: false,
             recursion_desired: true,
             recursion_available: true,
@@ -601,15 +1173,23 @@ async fn doh_query_wire_format(
                 record_type: record_type_num,
             }],
             answer: vec![],
-        }));
+        };
+        logged.record_doh(&state, &response, None);
+
+        debug!(
+            "DoH wire blocked: {} (reason: {:?}, category: {:?})",
+            domain, filter_result.reason, filter_result.category
+        );
+
+        return Ok(Json(response));
     }
 
     // Resolve domain
-    let start = std::time::Instant::now();
-    match state.resolver.resolve(&domain).await {
-        Ok(ips) => {
-            let query_time_ms = start.elapsed().as_millis() as u64;
-            let answers: Vec<DohAnswer> = ips
+    let resolution = state.resolver.lookup(&domain).await;
+    match &resolution.error {
+        None => {
+            let answers: Vec<DohAnswer> = resolution
+                .ips
                 .iter()
                 .map(|ip| {
                     let ip_str = ip.to_string();
@@ -622,15 +1202,8 @@ async fn doh_query_wire_format(
                 })
                 .collect();
 
-            state.metrics.record_query_with_details(
-                domain.clone(),
-                "0.0.0.0".to_string(),
-                false,
-                query_time_ms,
-            );
-
-            Ok(Json(DohResponse {
-                status: 0, // NOERROR
+            let response = DohResponse {
+                status: RCODE_NOERROR as u32,
                 truncated: false,
                 recursion_desired: true,
                 recursion_available: true,
@@ -639,9 +1212,13 @@ async fn doh_query_wire_format(
                     record_type: record_type_num,
                 }],
                 answer: answers,
-            }))
+            };
+            logged.record_doh(&state, &response, Some(&resolution));
+
+            Ok(Json(response))
         }
-        Err(e) => {
+        Some(e) => {
+            logged.record(&state, resolution.rcode, vec![], Some(&resolution));
             error!("DoH resolution failed for {}: {}", domain, e);
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -658,72 +1235,116 @@ async fn doh_query_wire_format(
 // Analytics Endpoint
 // ============================================================================
 
-/// Query analytics endpoint
-pub async fn get_analytics(State(state): State<Arc<AppState>>) -> Json<AnalyticsResponse> {
-    let snapshot = state.metrics.snapshot();
-    let history = state.metrics.get_query_history(1000);
+#[derive(Deserialize)]
+pub struct AnalyticsParams {
+    /// Window in hours (default 24, at most 90 days)
+    pub hours: Option<u32>,
+}
 
-    // Calculate top domains from history
-    let mut blocked_counts: HashMap<String, u64> = HashMap::new();
-    let mut allowed_counts: HashMap<String, u64> = HashMap::new();
-    let mut hourly_stats: HashMap<u8, (u64, u64)> = HashMap::new();
+/// Longest analytics window, bounded by how long hourly rollups are kept
+const MAX_ANALYTICS_HOURS: u32 = (crate::rollups::HOUR_RETENTION_SECS / 3600) as u32;
 
-    for query in &history {
-        let hour = ((query.timestamp % 86400) / 3600) as u8;
-        let entry = hourly_stats.entry(hour).or_insert((0, 0));
-        entry.0 += 1;
-        if query.blocked {
-            entry.1 += 1;
-            *blocked_counts.entry(query.domain.clone()).or_insert(0) += 1;
-        } else {
-            *allowed_counts.entry(query.domain.clone()).or_insert(0) += 1;
-        }
-    }
+/// Most points a trends request may return
+const MAX_TREND_POINTS: u64 = 5_000;
 
-    // Sort and take top 10
-    let mut top_blocked: Vec<_> = blocked_counts.into_iter().collect();
-    top_blocked.sort_by(|a, b| b.1.cmp(&a.1));
-    let top_blocked_domains: Vec<DomainCount> = top_blocked
-        .into_iter()
-        .take(10)
-        .map(|(domain, count)| DomainCount { domain, count })
-        .collect();
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-    let mut top_allowed: Vec<_> = allowed_counts.into_iter().collect();
-    top_allowed.sort_by(|a, b| b.1.cmp(&a.1));
-    let top_allowed_domains: Vec<DomainCount> = top_allowed
-        .into_iter()
-        .take(10)
-        .map(|(domain, count)| DomainCount { domain, count })
-        .collect();
+/// Run a rollup store read off the async runtime
+async fn read_rollups<T, F>(
+    state: &AppState,
+    read: F,
+) -> Result<T, (StatusCode, Json<ErrorResponse>)>
+where
+    T: Send + 'static,
+    F: FnOnce(&RollupStore) -> Result<T, shield_db::DbError> + Send + 'static,
+{
+    let rollups = state.rollups.clone();
+    tokio::task::spawn_blocking(move || read(&rollups))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .map_err(|e| {
+            error!("Rollup read failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: "database_error".to_string(),
+                    message: "Failed to read query statistics".to_string(),
+                }),
+            )
+        })
+}
 
-    let queries_by_hour: Vec<HourlyStats> = (0..24)
-        .map(|hour| {
-            let (queries, blocked) = hourly_stats.get(&hour).copied().unwrap_or((0, 0));
-            HourlyStats {
-                hour,
-                queries,
-                blocked,
-            }
+/// Query analytics endpoint
+pub async fn get_analytics(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AnalyticsParams>,
+) -> Result<Json<AnalyticsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let hours = params.hours.unwrap_or(24).clamp(1, MAX_ANALYTICS_HOURS);
+    let now = unix_now();
+    let since = now.saturating_sub(hours as u64 * 3600);
+    let series = read_rollups(&state, move |rollups| {
+        rollups.series(Granularity::Hour, Dimension::All, None, since, now + 1)
+    })
+    .await?;
+
+    let mut totals = RollupCounts::default();
+    let mut hourly_stats = [(0u64, 0u64); 24];
+    for point in &series {
+        totals.add(&point.counts);
+        let hour = ((point.timestamp % 86400) / 3600) as usize;
+        hourly_stats[hour].0 += point.counts.queries;
+        hourly_stats[hour].1 += point.counts.blocked;
+    }
+
+    // Top domains come from the sketches, which cover the last 24 hours
+    let sketches = state.metrics.sketches();
+    let top = |blocked: bool| -> Vec<DomainCount> {
+        sketches
+            .top_domains(since, Some(blocked), None, 10)
+            .into_iter()
+            .map(|hitter| DomainCount {
+                domain: hitter.item,
+                count: hitter.count,
+            })
+            .collect()
+    };
+    let top_blocked_domains = top(true);
+    let top_allowed_domains = top(false);
+
+    let queries_by_hour: Vec<HourlyStats> = hourly_stats
+        .iter()
+        .enumerate()
+        .map(|(hour, &(queries, blocked))| HourlyStats {
+            hour: hour as u8,
+            queries,
+            blocked,
         })
         .collect();
 
-    let block_rate = if snapshot.total_queries > 0 {
-        snapshot.blocked_queries as f64 / snapshot.total_queries as f64
+    let cache_hit_rate = if totals.queries > 0 {
+        totals.cached as f64 / totals.queries as f64
     } else {
         0.0
     };
 
-    Json(AnalyticsResponse {
-        period: "last_1000_queries".to_string(),
-        total_queries: snapshot.total_queries,
-        blocked_queries: snapshot.blocked_queries,
-        block_rate,
-        cache_hit_rate: snapshot.cache_hit_rate,
+    Ok(Json(AnalyticsResponse {
+        period: format!("last_{}_hours", hours),
+        total_queries: totals.queries,
+        blocked_queries: totals.blocked,
+        block_rate: totals.block_rate(),
+        cache_hit_rate,
         top_blocked_domains,
         top_allowed_domains,
         queries_by_hour,
-    })
+        unique_domains: sketches.unique_domains(since),
+        unique_clients: sketches.unique_clients(since),
+    }))
 }
 
 // ============================================================================
@@ -797,28 +1418,348 @@ pub async fn get_allowlist(State(state): State<Arc<AppState>>) -> Json<Vec<Strin
 }
 
 // ============================================================================
-// Blocklist Management Endpoints
+// Temporary Allowlist and Blocking Pause Endpoints
 // ============================================================================
 
-#[derive(Deserialize)]
-pub struct BlocklistRequest {
-    pub domain: String,
-    #[serde(default = "default_category")]
-    pub category: String,
+/// Longest temporary allowlist entry or pause (one week)
+const MAX_OVERRIDE_MINUTES: u32 = 7 * 24 * 60;
+
+type OverrideApiError = (StatusCode, Json<ErrorResponse>);
+
+fn override_error(status: StatusCode, error: &str, message: impl Into<String>) -> OverrideApiError {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            message: message.into(),
+        }),
+    )
 }
 
-fn default_category() -> String {
-    "custom".to_string()
+fn check_override_minutes(minutes: u32) -> Result<chrono::DateTime<Utc>, OverrideApiError> {
+    if minutes == 0 || minutes > MAX_OVERRIDE_MINUTES {
+        return Err(override_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_duration",
+            format!("minutes must be between 1 and {}", MAX_OVERRIDE_MINUTES),
+        ));
+    }
+    Ok(Utc::now() + chrono::Duration::minutes(minutes as i64))
 }
 
-#[derive(Serialize)]
-pub struct BlocklistResponse {
-    pub success: bool,
-    pub message: String,
-    pub blocklist_size: usize,
+fn remaining_secs(expires_at: chrono::DateTime<Utc>) -> i64 {
+    (expires_at - Utc::now()).num_seconds().max(0)
 }
 
-/// Add domain to blocklist
+/// Check the caller may change overrides for `profile_id`: one of their own
+/// profiles, or every profile (`None`) for administrators
+fn check_override_scope(
+    state: &AppState,
+    user_id: &str,
+    profile_id: Option<&str>,
+) -> Result<(), OverrideApiError> {
+    match profile_id {
+        Some(id) => uuid::Uuid::parse_str(id)
+            .ok()
+            .filter(|uuid| owns_profile(state, user_id, uuid))
+            .map(|_| ())
+            .ok_or_else(|| override_error(StatusCode::NOT_FOUND, "not_found", "Profile not found")),
+        None if state.is_admin(user_id) => Ok(()),
+        None => Err(override_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only administrators can change every profile; pass one of your profile_ids",
+        )),
+    }
+}
+
+/// Overrides the caller can see: their profiles' and the global ones
+fn override_visible(state: &AppState, user_id: &str, profile_id: Option<&str>) -> bool {
+    profile_id.is_none_or(|id| {
+        uuid::Uuid::parse_str(id).is_ok_and(|uuid| owns_profile(state, user_id, &uuid))
+    })
+}
+
+/// Scope query for removing a temporary entry or ending a pause
+#[derive(Deserialize)]
+pub struct OverrideScopeQuery {
+    pub profile_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TemporaryAllowRequest {
+    pub domain: String,
+    pub minutes: u32,
+    /// Profile to allow the domain for (omit for every profile)
+    pub profile_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TemporaryAllowInfo {
+    pub domain: String,
+    pub profile_id: Option<String>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub remaining_secs: i64,
+}
+
+#[derive(Serialize)]
+pub struct TemporaryAllowlistResponse {
+    pub entries: Vec<TemporaryAllowInfo>,
+}
+
+#[derive(Serialize)]
+pub struct OverrideRemovedResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+/// List active temporary allowlist entries, global or on the caller's
+/// profiles, with their remaining time
+pub async fn get_temporary_allowlist(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Json<TemporaryAllowlistResponse> {
+    let entries = state
+        .unified_filter
+        .overrides()
+        .allows(Utc::now())
+        .into_iter()
+        .filter(|entry| override_visible(&state, &claims.sub, entry.profile_id.as_deref()))
+        .map(|entry| TemporaryAllowInfo {
+            remaining_secs: remaining_secs(entry.expires_at),
+            domain: entry.domain,
+            profile_id: entry.profile_id,
+            expires_at: entry.expires_at,
+        })
+        .collect();
+    Json(TemporaryAllowlistResponse { entries })
+}
+
+/// Allow a domain for a number of minutes on one of the caller's profiles,
+/// or globally for administrators
+pub async fn add_temporary_allow(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<TemporaryAllowRequest>,
+) -> Result<Json<TemporaryAllowInfo>, OverrideApiError> {
+    let domain = request.domain.trim().trim_end_matches('.').to_lowercase();
+    if domain.is_empty() || domain.len() > 253 {
+        return Err(override_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_domain",
+            "Invalid domain name",
+        ));
+    }
+    let expires_at = check_override_minutes(request.minutes)?;
+    let profile_id = request.profile_id.filter(|p| !p.is_empty());
+    check_override_scope(&state, &claims.sub, profile_id.as_deref())?;
+
+    state
+        .unified_filter
+        .overrides()
+        .allow(&domain, profile_id.as_deref(), expires_at);
+    let entry = shield_db::DbTemporaryAllow {
+        domain: domain.clone(),
+        profile_id: profile_id.clone(),
+        expires_at,
+        created_at: Utc::now(),
+    };
+    if let Err(e) = state.db.upsert_temporary_allow(&entry) {
+        warn!(
+            "Failed to persist temporary allowlist entry to database: {}",
+            e
+        );
+    }
+
+    info!(
+        "Temporarily allowed {} for {} minutes ({})",
+        domain,
+        request.minutes,
+        profile_id.as_deref().unwrap_or("all profiles")
+    );
+    Ok(Json(TemporaryAllowInfo {
+        domain,
+        profile_id,
+        expires_at,
+        remaining_secs: remaining_secs(expires_at),
+    }))
+}
+
+/// Remove a temporary allowlist entry before it expires
+pub async fn remove_temporary_allow(
+    Path(domain): Path<String>,
+    Query(query): Query<OverrideScopeQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<OverrideRemovedResponse>, OverrideApiError> {
+    let domain = domain.to_lowercase();
+    let profile_id = query.profile_id.filter(|p| !p.is_empty());
+    check_override_scope(&state, &claims.sub, profile_id.as_deref())?;
+
+    if !state
+        .unified_filter
+        .overrides()
+        .remove_allow(&domain, profile_id.as_deref())
+    {
+        return Err(override_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No temporary allowlist entry for {}", domain),
+        ));
+    }
+    if let Err(e) = state
+        .db
+        .delete_temporary_allow(&domain, profile_id.as_deref())
+    {
+        warn!(
+            "Failed to remove temporary allowlist entry from database: {}",
+            e
+        );
+    }
+
+    info!("Removed temporary allowlist entry for {}", domain);
+    Ok(Json(OverrideRemovedResponse {
+        success: true,
+        message: format!("Removed temporary allowlist entry for {}", domain),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PauseRequest {
+    pub minutes: u32,
+    /// Profile to pause (omit to pause every profile)
+    pub profile_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PauseInfo {
+    pub profile_id: Option<String>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub remaining_secs: i64,
+}
+
+#[derive(Serialize)]
+pub struct PauseStatusResponse {
+    /// Whether blocking is paused for every profile
+    pub paused: bool,
+    pub pauses: Vec<PauseInfo>,
+}
+
+/// List active blocking pauses, global or on the caller's profiles, with
+/// their remaining time
+pub async fn get_pause_status(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Json<PauseStatusResponse> {
+    let pauses: Vec<PauseInfo> = state
+        .unified_filter
+        .overrides()
+        .pauses(Utc::now())
+        .into_iter()
+        .filter(|pause| override_visible(&state, &claims.sub, pause.profile_id.as_deref()))
+        .map(|pause| PauseInfo {
+            remaining_secs: remaining_secs(pause.expires_at),
+            profile_id: pause.profile_id,
+            expires_at: pause.expires_at,
+        })
+        .collect();
+    Json(PauseStatusResponse {
+        paused: pauses.iter().any(|p| p.profile_id.is_none()),
+        pauses,
+    })
+}
+
+/// Pause blocking for a number of minutes on one of the caller's profiles,
+/// or globally for administrators
+pub async fn pause_blocking(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<PauseRequest>,
+) -> Result<Json<PauseInfo>, OverrideApiError> {
+    let expires_at = check_override_minutes(request.minutes)?;
+    let profile_id = request.profile_id.filter(|p| !p.is_empty());
+    check_override_scope(&state, &claims.sub, profile_id.as_deref())?;
+
+    state
+        .unified_filter
+        .overrides()
+        .pause(profile_id.as_deref(), expires_at);
+    let pause = shield_db::DbBlockingPause {
+        profile_id: profile_id.clone(),
+        expires_at,
+        created_at: Utc::now(),
+    };
+    if let Err(e) = state.db.upsert_blocking_pause(&pause) {
+        warn!("Failed to persist blocking pause to database: {}", e);
+    }
+
+    info!(
+        "Blocking paused for {} minutes ({})",
+        request.minutes,
+        profile_id.as_deref().unwrap_or("all profiles")
+    );
+    Ok(Json(PauseInfo {
+        profile_id,
+        expires_at,
+        remaining_secs: remaining_secs(expires_at),
+    }))
+}
+
+/// End a blocking pause early
+pub async fn resume_blocking(
+    Query(query): Query<OverrideScopeQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<OverrideRemovedResponse>, OverrideApiError> {
+    let profile_id = query.profile_id.filter(|p| !p.is_empty());
+    check_override_scope(&state, &claims.sub, profile_id.as_deref())?;
+    let scope = profile_id.as_deref().unwrap_or("all profiles").to_string();
+
+    if !state
+        .unified_filter
+        .overrides()
+        .resume(profile_id.as_deref())
+    {
+        return Err(override_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("Blocking is not paused for {}", scope),
+        ));
+    }
+    if let Err(e) = state.db.delete_blocking_pause(profile_id.as_deref()) {
+        warn!("Failed to remove blocking pause from database: {}", e);
+    }
+
+    info!("Blocking resumed ({})", scope);
+    Ok(Json(OverrideRemovedResponse {
+        success: true,
+        message: format!("Blocking resumed for {}", scope),
+    }))
+}
+
+// ============================================================================
+// Blocklist Management Endpoints
+// ============================================================================
+
+#[derive(Deserialize)]
+pub struct BlocklistRequest {
+    pub domain: String,
+    #[serde(default = "default_category")]
+    pub category: String,
+}
+
+fn default_category() -> String {
+    "custom".to_string()
+}
+
+#[derive(Serialize)]
+pub struct BlocklistResponse {
+    pub success: bool,
+    pub message: String,
+    pub blocklist_size: usize,
+}
+
+/// Add domain to blocklist
 pub async fn add_to_blocklist(
     State(state): State<Arc<AppState>>,
     Json(request): Json<BlocklistRequest>,
@@ -942,6 +1883,372 @@ pub async fn bulk_add_to_blocklist(
     })
 }
 
+// ============================================================================
+// Blocklist Source Management Endpoints
+// ============================================================================
+
+/// Blocklist source create/update/validate request
+#[derive(Deserialize)]
+pub struct BlocklistSourceRequest {
+    pub name: String,
+    /// http(s):// URL or file:// path under the configured file root
+    pub url: String,
+    pub category: String,
+    /// "hosts", "domains", "adblock", "rpz" or "regex" (default: domains)
+    #[serde(default = "default_source_format")]
+    pub format: String,
+    #[serde(default = "default_source_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub min_entries: Option<usize>,
+}
+
+fn default_source_format() -> String {
+    "domains".to_string()
+}
+
+fn default_source_enabled() -> bool {
+    true
+}
+
+impl BlocklistSourceRequest {
+    fn to_source(&self) -> shield_dns_core::blocklist_fetcher::BlocklistSource {
+        shield_dns_core::blocklist_fetcher::BlocklistSource {
+            name: self.name.trim().to_string(),
+            url: self.url.trim().to_string(),
+            category: self.category.trim().to_lowercase(),
+            format: self.format.trim().to_lowercase(),
+            enabled: self.enabled,
+            description: self.description.clone(),
+            sha256: self
+                .sha256
+                .as_ref()
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty()),
+            max_bytes: self.max_bytes,
+            min_entries: self.min_entries,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BlocklistSourcesResponse {
+    pub sources: Vec<DbBlocklistSource>,
+    pub total: usize,
+}
+
+#[derive(Serialize)]
+pub struct BlocklistSourceFetchResponse {
+    pub source: Option<DbBlocklistSource>,
+    /// Result of this fetch
+    pub status: Option<shield_dns_core::blocklist_fetcher::SourceStatus>,
+    pub total_domains: usize,
+}
+
+#[derive(Serialize)]
+pub struct BlocklistSourceDeleteResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+type SourceApiError = (StatusCode, Json<ErrorResponse>);
+
+fn source_error(status: StatusCode, error: &str, message: impl Into<String>) -> SourceApiError {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            message: message.into(),
+        }),
+    )
+}
+
+/// Sources filter every user and make the server download URLs, so only
+/// administrators may change, preview or fetch them
+fn require_source_admin(state: &AppState, user_id: &str) -> Result<(), SourceApiError> {
+    if state.is_admin(user_id) {
+        Ok(())
+    } else {
+        Err(source_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "Only administrators can manage blocklist sources",
+        ))
+    }
+}
+
+fn source_db_error(e: shield_db::DbError) -> SourceApiError {
+    warn!("Blocklist source database error: {}", e);
+    source_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "database_error",
+        "Failed to access blocklist sources",
+    )
+}
+
+/// Validate a request and make sure no other source uses its URL
+fn check_source_request(
+    state: &AppState,
+    request: &BlocklistSourceRequest,
+    id: Option<&str>,
+) -> Result<shield_dns_core::blocklist_fetcher::BlocklistSource, SourceApiError> {
+    let source = request.to_source();
+    source
+        .validate()
+        .map_err(|e| source_error(StatusCode::BAD_REQUEST, "invalid_source", e))?;
+
+    let existing = state.db.get_blocklist_sources().map_err(source_db_error)?;
+    if existing
+        .iter()
+        .any(|s| s.url == source.url && Some(s.id.as_str()) != id)
+    {
+        return Err(source_error(
+            StatusCode::CONFLICT,
+            "duplicate_source",
+            format!("A source for {} already exists", source.url),
+        ));
+    }
+    Ok(source)
+}
+
+/// Rebuild blocklists in the background after a source change
+fn spawn_source_refresh(state: &Arc<AppState>, fetch_url: Option<String>) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let scope = match &fetch_url {
+            Some(url) => RefreshScope::Source(url),
+            None => RefreshScope::Reload,
+        };
+        blocklist_sources::refresh_blocklists(
+            &state.unified_filter,
+            &state.db,
+            &state.webhooks,
+            scope,
+        )
+        .await;
+    });
+}
+
+/// List blocklist sources with their last load status
+pub async fn list_blocklist_sources(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<BlocklistSourcesResponse>, SourceApiError> {
+    let sources = state.db.get_blocklist_sources().map_err(source_db_error)?;
+    Ok(Json(BlocklistSourcesResponse {
+        total: sources.len(),
+        sources,
+    }))
+}
+
+/// Get a blocklist source
+pub async fn get_blocklist_source(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DbBlocklistSource>, SourceApiError> {
+    state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?
+        .map(Json)
+        .ok_or_else(|| {
+            source_error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Blocklist source not found",
+            )
+        })
+}
+
+/// Add a blocklist source; enabled sources are fetched in the background
+pub async fn create_blocklist_source(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<BlocklistSourceRequest>,
+) -> Result<(StatusCode, Json<DbBlocklistSource>), SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    let source = check_source_request(&state, &request, None)?;
+    let record = blocklist_sources::to_db_source(uuid::Uuid::new_v4().to_string(), &source);
+    state
+        .db
+        .create_blocklist_source(&record)
+        .map_err(source_db_error)?;
+    info!("Added blocklist source '{}' ({})", record.name, record.url);
+
+    if record.enabled {
+        spawn_source_refresh(&state, Some(record.url.clone()));
+    }
+    Ok((StatusCode::CREATED, Json(record)))
+}
+
+/// Replace a blocklist source's configuration
+pub async fn update_blocklist_source(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<BlocklistSourceRequest>,
+) -> Result<Json<DbBlocklistSource>, SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    let existing = state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?
+        .ok_or_else(|| {
+            source_error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Blocklist source not found",
+            )
+        })?;
+    let source = check_source_request(&state, &request, Some(&id))?;
+
+    let mut record = blocklist_sources::to_db_source(id.clone(), &source);
+    record.created_at = existing.created_at;
+    state
+        .db
+        .update_blocklist_source(&record)
+        .map_err(source_db_error)?;
+    info!(
+        "Updated blocklist source '{}' ({})",
+        record.name, record.url
+    );
+
+    // A new URL has nothing cached yet, so fetch it; other edits only reload
+    let refetch = record.enabled && (record.url != existing.url || !existing.enabled);
+    spawn_source_refresh(&state, refetch.then(|| record.url.clone()));
+
+    let updated = state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?;
+    Ok(Json(updated.unwrap_or(record)))
+}
+
+/// Remove a blocklist source and unload its entries
+pub async fn delete_blocklist_source(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<BlocklistSourceDeleteResponse>, SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    if !state
+        .db
+        .delete_blocklist_source(&id)
+        .map_err(source_db_error)?
+    {
+        return Err(source_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "Blocklist source not found",
+        ));
+    }
+    info!("Deleted blocklist source {}", id);
+    spawn_source_refresh(&state, None);
+
+    Ok(Json(BlocklistSourceDeleteResponse {
+        success: true,
+        message: "Blocklist source deleted".to_string(),
+    }))
+}
+
+/// Validate a source and preview what it would load, without saving it
+pub async fn validate_blocklist_source(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<BlocklistSourceRequest>,
+) -> Result<Json<shield_dns_core::blocklist_fetcher::SourcePreview>, SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    let source = check_source_request(&state, &request, None)?;
+    preview_source(&state, &source).await
+}
+
+/// Preview what a stored source currently serves, without loading it
+pub async fn preview_blocklist_source(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<shield_dns_core::blocklist_fetcher::SourcePreview>, SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    let record = state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?
+        .ok_or_else(|| {
+            source_error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Blocklist source not found",
+            )
+        })?;
+    preview_source(&state, &blocklist_sources::from_db_source(&record)).await
+}
+
+async fn preview_source(
+    state: &AppState,
+    source: &shield_dns_core::blocklist_fetcher::BlocklistSource,
+) -> Result<Json<shield_dns_core::blocklist_fetcher::SourcePreview>, SourceApiError> {
+    let config =
+        blocklist_sources::load_config(&state.db, blocklist_sources::BLOCKLIST_CONFIG_PATH);
+    state
+        .unified_filter
+        .blocklist_manager()
+        .preview_source(source, &config)
+        .await
+        .map(Json)
+        .map_err(|e| source_error(StatusCode::UNPROCESSABLE_ENTITY, "preview_failed", e))
+}
+
+/// Fetch one source now and report the outcome
+pub async fn fetch_blocklist_source(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<BlocklistSourceFetchResponse>, SourceApiError> {
+    require_source_admin(&state, &claims.sub)?;
+    let record = state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?
+        .ok_or_else(|| {
+            source_error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Blocklist source not found",
+            )
+        })?;
+    if !record.enabled {
+        return Err(source_error(
+            StatusCode::CONFLICT,
+            "source_disabled",
+            "Enable the source before fetching it",
+        ));
+    }
+
+    let stats = blocklist_sources::refresh_blocklists(
+        &state.unified_filter,
+        &state.db,
+        &state.webhooks,
+        RefreshScope::Source(&record.url),
+    )
+    .await;
+    let status = stats.sources.into_iter().find(|s| s.url == record.url);
+    let source = state
+        .db
+        .get_blocklist_source(&id)
+        .map_err(source_db_error)?;
+
+    Ok(Json(BlocklistSourceFetchResponse {
+        source,
+        status,
+        total_domains: stats.total_domains,
+    }))
+}
+
 // ============================================================================
 // DNS Resolution Endpoint
 // ============================================================================
@@ -950,10 +2257,9 @@ pub async fn bulk_add_to_blocklist(
 pub async fn resolve_domain(
     Path(domain): Path<String>,
     State(state): State<Arc<AppState>>,
-    connect_info: Option<ConnectInfo<SocketAddr>>,
+    ClientIp(client_ip): ClientIp,
 ) -> Result<Json<DnsResolveResponse>, Json<ErrorResponse>> {
-    let start = std::time::Instant::now();
-    let client_ip = connect_info.map(|ci| ci.0.ip());
+    let start = Instant::now();
 
     // Validate domain
     if domain.is_empty() || domain.len() > 253 {
@@ -977,17 +2283,25 @@ pub async fn resolve_domain(
     }
 
     // Use unified filter with client IP for profile-aware filtering
+    let device_id = observe_client(&state, client_ip);
+    let filter_start = Instant::now();
     let filter_result = state.unified_filter.check(&domain, client_ip);
+    let logged = LoggedQuery {
+        protocol: Protocol::Api,
+        domain: &domain,
+        client_ip,
+        device_id,
+        qtype: None,
+        filter_result: &filter_result,
+        started: start,
+        filter_time: filter_start.elapsed(),
+        query_message: None,
+        response_message: None,
+    };
 
     if filter_result.decision == shield_dns_core::filter::FilterDecision::Block {
         let query_time_ms = start.elapsed().as_millis() as u64;
-        let client_ip_str = client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
-        state.metrics.record_query_with_details(
-            domain.clone(),
-            client_ip_str,
-            true,
-            query_time_ms,
-        );
+        logged.record(&state, RCODE_NXDOMAIN, vec![], None);
 
         debug!(
             "Blocked domain: {} in {}ms (reason: {:?}, category: {:?})",
@@ -1003,59 +2317,33 @@ pub async fn resolve_domain(
         }));
     }
 
-    // Get cache stats before resolution to detect cache hit
-    let (hits_before, _) = state.resolver.cache_stats();
-
     // Perform DNS resolution
-    match state.resolver.resolve(&domain).await {
-        Ok(ips) => {
-            let query_time_ms = start.elapsed().as_millis() as u64;
-
-            // Check if it was a cache hit
-            let (hits_after, _) = state.resolver.cache_stats();
-            let cached = hits_after > hits_before;
-
-            if cached {
-                state.metrics.record_cache_hit();
-            } else {
-                state.metrics.record_cache_miss();
-            }
-
-            // Log query
-            state.metrics.record_query_with_details(
-                domain.clone(),
-                "0.0.0.0".to_string(),
-                false,
-                query_time_ms,
-            );
-
-            let ip_addresses: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+    let resolution = state.resolver.lookup(&domain).await;
+    let query_time_ms = start.elapsed().as_millis() as u64;
+    let ip_addresses: Vec<String> = resolution.ips.iter().map(|ip| ip.to_string()).collect();
+    logged.record(
+        &state,
+        resolution.rcode,
+        ip_addresses.clone(),
+        Some(&resolution),
+    );
 
+    match resolution.error {
+        None => {
             debug!(
                 "Resolved {} to {:?} in {}ms (cached: {})",
-                domain, ip_addresses, query_time_ms, cached
+                domain, ip_addresses, query_time_ms, resolution.cached
             );
 
             Ok(Json(DnsResolveResponse {
                 domain,
                 ip_addresses,
                 blocked: false,
-                cached,
+                cached: resolution.cached,
                 query_time_ms,
             }))
         }
-        Err(e) => {
-            let query_time_ms = start.elapsed().as_millis() as u64;
-            state.metrics.record_cache_miss();
-
-            // Log failed query
-            state.metrics.record_query_with_details(
-                domain.clone(),
-                "0.0.0.0".to_string(),
-                false,
-                query_time_ms,
-            );
-
+        Some(e) => {
             error!("DNS resolution failed for {}: {}", domain, e);
 
             Err(Json(ErrorResponse {
@@ -1070,144 +2358,317 @@ pub async fn resolve_domain(
 // Prometheus Metrics Endpoint
 // ============================================================================
 
-/// Prometheus metrics endpoint
-pub async fn metrics(State(state): State<Arc<AppState>>) -> String {
-    let snapshot = state.metrics.snapshot();
-    let mut output = String::with_capacity(2048);
-
-    output.push_str("# HELP dns_queries_total Total number of DNS queries\n");
-    output.push_str("# TYPE dns_queries_total counter\n");
-    output.push_str(&format!("dns_queries_total {}\n", snapshot.total_queries));
-
-    output.push_str("# HELP dns_queries_blocked_total Total blocked queries\n");
-    output.push_str("# TYPE dns_queries_blocked_total counter\n");
-    output.push_str(&format!(
-        "dns_queries_blocked_total {}\n",
-        snapshot.blocked_queries
-    ));
-
-    output.push_str("# HELP dns_cache_hits_total Total cache hits\n");
-    output.push_str("# TYPE dns_cache_hits_total counter\n");
-    output.push_str(&format!("dns_cache_hits_total {}\n", snapshot.cache_hits));
-
-    output.push_str("# HELP dns_cache_misses_total Total cache misses\n");
-    output.push_str("# TYPE dns_cache_misses_total counter\n");
-    output.push_str(&format!(
-        "dns_cache_misses_total {}\n",
-        snapshot.cache_misses
-    ));
-
-    output.push_str("# HELP dns_cache_hit_rate Cache hit rate\n");
-    output.push_str("# TYPE dns_cache_hit_rate gauge\n");
-    output.push_str(&format!(
-        "dns_cache_hit_rate {:.4}\n",
-        snapshot.cache_hit_rate
-    ));
-
-    output.push_str("# HELP dns_blocklist_size Number of blocked domains\n");
-    output.push_str("# TYPE dns_blocklist_size gauge\n");
-    output.push_str(&format!(
-        "dns_blocklist_size {}\n",
-        state.filter.blocklist_size()
-    ));
-
-    output.push_str("# HELP dns_uptime_seconds Server uptime in seconds\n");
-    output.push_str("# TYPE dns_uptime_seconds gauge\n");
-    output.push_str(&format!(
-        "dns_uptime_seconds {}\n",
-        state.start_time.elapsed().as_secs()
-    ));
-
-    output
+/// Prometheus metrics endpoint (OpenMetrics text format)
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    let mut out = Exposition::new();
+    state.metrics.encode(&mut out);
+
+    out.gauge(
+        "dns_cache_entries",
+        "Names in the DNS cache",
+        state.resolver.cache_size(),
+    );
+    out.gauge(
+        "dns_blocklist_size",
+        "Number of blocked domains",
+        state.filter.blocklist_size(),
+    );
+
+    // Freshness of each enabled blocklist source as of its last refresh
+    let db = state.db.clone();
+    match tokio::task::spawn_blocking(move || db.get_blocklist_sources()).await {
+        Ok(Ok(sources)) => {
+            let now = Utc::now();
+            let sources: Vec<DbBlocklistSource> =
+                sources.into_iter().filter(|s| s.enabled).collect();
+            let by_source =
+                |value: fn(&DbBlocklistSource, chrono::DateTime<Utc>) -> Option<i64>| {
+                    sources
+                        .iter()
+                        .filter_map(|source| Some((vec![source.name.clone()], value(source, now)?)))
+                        .collect::<Vec<_>>()
+                };
+            out.gauge_vec(
+                "dns_blocklist_source_entries",
+                "Entries loaded from each blocklist source",
+                &["source"],
+                by_source(|source, _| Some(source.last_entries)),
+            );
+            out.gauge_vec(
+                "dns_blocklist_source_last_checked_timestamp_seconds",
+                "When each blocklist source's copy was last confirmed current",
+                &["source"],
+                by_source(|source, _| source.last_checked_at.map(|t| t.timestamp())),
+            );
+            out.gauge_vec(
+                "dns_blocklist_source_age_seconds",
+                "Seconds since each blocklist source's copy was last confirmed current",
+                &["source"],
+                by_source(|source, now| {
+                    source
+                        .last_checked_at
+                        .map(|t| (now - t).num_seconds().max(0))
+                }),
+            );
+        }
+        Ok(Err(e)) => warn!("Failed to read blocklist sources for metrics: {}", e),
+        Err(e) => warn!("Failed to read blocklist sources for metrics: {}", e),
+    }
+
+    out.counter(
+        "dns_rate_limited_requests",
+        "Requests refused by the rate limiter",
+        state.rate_limiter.stats().limited_requests,
+    );
+
+    let query_log = state.query_log.stats();
+    out.gauge(
+        "dns_query_log_queued",
+        "Query log entries waiting to be written",
+        query_log.queued,
+    );
+    out.counter(
+        "dns_query_log_written",
+        "Query log entries written to disk",
+        query_log.written,
+    );
+    out.counter(
+        "dns_query_log_dropped",
+        "Query log entries dropped because the queue was full",
+        query_log.dropped,
+    );
+    out.counter(
+        "dns_query_log_failed",
+        "Query log entries lost to write errors",
+        query_log.failed,
+    );
+
+    if let Some(dnstap) = &state.dnstap {
+        let dnstap = dnstap.stats();
+        out.gauge(
+            "dns_dnstap_queued",
+            "dnstap frames waiting to be written",
+            dnstap.queued,
+        );
+        out.counter(
+            "dns_dnstap_frames_written",
+            "dnstap frames written to the output",
+            dnstap.written,
+        );
+        out.counter(
+            "dns_dnstap_frames_dropped",
+            "dnstap frames dropped because the queue was full or the output failed",
+            dnstap.dropped,
+        );
+    }
+
+    out.gauge(
+        "dns_uptime_seconds",
+        "Server uptime in seconds",
+        state.start_time.elapsed().as_secs(),
+    );
+
+    (
+        [(axum::http::header::CONTENT_TYPE, openmetrics::CONTENT_TYPE)],
+        out.finish(),
+    )
+        .into_response()
 }
 
 // ============================================================================
 // WebSocket Handler for Real-time Updates
 // ============================================================================
 
-/// WebSocket handler for real-time stats updates
-pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    /// Access token, for clients that can't set headers on the upgrade
+    pub token: Option<String>,
+}
+
+/// WebSocket handler for real-time updates
+///
+/// The upgrade needs an access token, as a Bearer `Authorization` header
+/// or a `token` query parameter (browsers can't set headers on it).
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<WsParams>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or(params.token);
+    let Some(token) = token else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({
+                "error": "missing_token",
+                "message": "Bearer token or token parameter required"
+            })),
+        ));
+    };
+    let claims = state.auth.validate_token(&token).map_err(|e| {
+        warn!("WebSocket token validation failed: {}", e);
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({
+                "error": "invalid_token",
+                "message": "Invalid or expired token"
+            })),
+        )
+    })?;
+
     info!("WebSocket connection requested");
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, claims.sub)))
+}
+
+/// Whether `user_id` owns the profile
+fn owns_profile(state: &AppState, user_id: &str, profile_id: &uuid::Uuid) -> bool {
+    state
+        .db
+        .get_profile(&profile_id.to_string())
+        .ok()
+        .flatten()
+        .is_some_and(|p| p.user_id.as_deref() == Some(user_id))
+}
+
+/// Parse a client address whose queries fall under one of the caller's
+/// profiles; anything else is reported as not found
+fn owned_client(
+    state: &AppState,
+    user_id: &str,
+    ip: &str,
+) -> Result<std::net::IpAddr, (StatusCode, Json<ErrorResponse>)> {
+    ip.parse()
+        .ok()
+        .filter(|ip| {
+            let profile_id = state.unified_filter.profile_id_for_client(Some(*ip));
+            uuid::Uuid::parse_str(&profile_id).is_ok_and(|id| owns_profile(state, user_id, &id))
+        })
+        .ok_or_else(|| profile_error(StatusCode::NOT_FOUND, "not_found", "Client not found"))
+}
+
+/// IDs of the profiles `user_id` owns
+fn owned_profile_ids(state: &AppState, user_id: &str) -> std::collections::HashSet<String> {
+    state
+        .profiles
+        .list_profiles_for_user(user_id)
+        .into_iter()
+        .map(|p| p.id.to_string())
+        .collect()
+}
+
+/// Longest a single message may take to send before the client is
+/// treated as stalled and disconnected
+const WS_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Next event from the broadcast, or never when not subscribed
+async fn next_live_event(
+    events: &mut Option<tokio::sync::broadcast::Receiver<Arc<LiveEvent>>>,
+) -> Result<Arc<LiveEvent>, tokio::sync::broadcast::error::RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
 }
 
 /// Handle WebSocket connection
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+///
+/// Until the client sends its first `subscribe` message it gets a bare
+/// `StatsResponse` every 2 seconds, as before topics existed. Afterwards
+/// it gets the topics it subscribed to, wrapped as
+/// `{"type": "event", "topic", "data"}`, plus control messages. Queries,
+/// alerts and devices are limited to `user_id`'s profiles, re-read on
+/// every client message.
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, user_id: String) {
     let (mut sender, mut receiver) = socket.split();
 
     info!("WebSocket connection established");
 
-    // Spawn a task to handle incoming messages
-    let recv_task = tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            match msg {
-                Ok(Message::Close(_)) => {
+    let mut subscriptions: Option<Subscriptions> = None;
+    let mut events = None;
+    let mut stats_period = live::DEFAULT_STATS_INTERVAL;
+    let mut stats_interval = tokio::time::interval(stats_period);
+    let mut cap_interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let outgoing = tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let subs = subscriptions.get_or_insert_with(Subscriptions::default);
+                    subs.set_profiles(owned_profile_ids(&state, &user_id));
+                    let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => subs.handle(message, Instant::now()),
+                        Err(e) => ServerMessage::Error { message: format!("Invalid message: {}", e) },
+                    };
+
+                    // Only listen to the broadcast while an event topic is wanted
+                    if subs.wants_events() != events.is_some() {
+                        events = subs.wants_events().then(|| state.live.subscribe());
+                    }
+                    let period = subs.stats_interval().unwrap_or(live::DEFAULT_STATS_INTERVAL);
+                    if period != stats_period {
+                        stats_period = period;
+                        stats_interval = tokio::time::interval(period);
+                    }
+                    reply.to_json()
+                }
+                Some(Ok(Message::Close(_))) | None => {
                     debug!("WebSocket client closed connection");
                     break;
                 }
-                Ok(Message::Ping(data)) => {
-                    debug!("Received ping from client");
-                    // Pong is handled automatically by axum
-                    let _ = data;
-                }
-                Err(e) => {
+                Some(Ok(_)) => continue, // Pings are answered by axum
+                Some(Err(e)) => {
                     warn!("WebSocket receive error: {}", e);
-                    break;
-                }
-                _ => {}
-            }
-        }
-    });
-
-    // Send stats updates every 2 seconds
-    let mut interval = tokio::time::interval(Duration::from_secs(2));
-    let mut consecutive_errors = 0;
-
-    loop {
-        interval.tick().await;
-
-        let snapshot = state.metrics.snapshot();
-        let block_rate = if snapshot.total_queries > 0 {
-            snapshot.blocked_queries as f64 / snapshot.total_queries as f64
-        } else {
-            0.0
-        };
-
-        let stats = StatsResponse {
-            total_queries: snapshot.total_queries,
-            blocked_queries: snapshot.blocked_queries,
-            cache_hits: snapshot.cache_hits,
-            cache_misses: snapshot.cache_misses,
-            cache_hit_rate: snapshot.cache_hit_rate,
-            block_rate,
-            blocklist_size: state.filter.blocklist_size(),
-        };
-
-        match serde_json::to_string(&stats) {
-            Ok(json) => {
-                if let Err(e) = sender.send(Message::Text(json)).await {
-                    warn!("Failed to send WebSocket message: {}", e);
-                    consecutive_errors += 1;
-                    if consecutive_errors >= 3 {
+                    break;
+                }
+            },
+            event = next_live_event(&mut events) => match event {
+                Ok(event) if subscriptions.as_mut().is_some_and(|s| s.accept(&event, Instant::now())) => {
+                    event.to_json()
+                }
+                Ok(_) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(count)) => {
+                    debug!("WebSocket client fell behind by {} events", count);
+                    ServerMessage::Dropped { topic: None, reason: "lagged", count }.to_json()
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            },
+            _ = stats_interval.tick() => match &subscriptions {
+                None => match serde_json::to_string(&stats_response(&state)) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        error!("Failed to serialize stats: {}", e);
                         break;
                     }
-                } else {
-                    consecutive_errors = 0;
+                },
+                Some(subs) if subs.stats_interval().is_some() => {
+                    live::event_json(Topic::Stats, &stats_response(&state))
                 }
+                Some(_) => continue,
+            },
+            _ = cap_interval.tick() => match subscriptions.as_mut().and_then(|s| s.take_dropped(Instant::now())) {
+                Some(dropped) => dropped.to_json(),
+                None => continue,
+            },
+        };
+
+        match tokio::time::timeout(WS_SEND_TIMEOUT, sender.send(Message::Text(outgoing))).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("Failed to send WebSocket message: {}", e);
+                break;
             }
-            Err(e) => {
-                error!("Failed to serialize stats: {}", e);
+            Err(_) => {
+                warn!("WebSocket client stalled; disconnecting");
                 break;
             }
         }
-
-        // Check if receiver task is done
-        if recv_task.is_finished() {
-            break;
-        }
     }
 
-    recv_task.abort();
     info!("WebSocket connection closed");
 }
 
@@ -1306,14 +2767,17 @@ pub struct CreateProfileRequest {
     pub protection_level: ProtectionLevel,
 }
 
-/// Create a new profile
+/// Create a new profile owned by the caller
 pub async fn create_profile(
     State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
     Json(request): Json<CreateProfileRequest>,
 ) -> Json<ProfileResponse> {
-    let id = state
-        .profiles
-        .create_profile(request.name.clone(), request.protection_level);
+    let id = state.profiles.create_profile_for_user(
+        request.name.clone(),
+        request.protection_level,
+        &claims.sub,
+    );
     let profile = state.profiles.get_profile(&id);
     Json(ProfileResponse {
         success: true,
@@ -1329,35 +2793,430 @@ pub struct ProfileResponse {
     pub profile: Option<Profile>,
 }
 
-/// List all profiles
-pub async fn list_profiles(State(state): State<Arc<AppState>>) -> Json<Vec<Profile>> {
-    Json(state.profiles.list_profiles())
+fn profile_error(
+    status: StatusCode,
+    error: &str,
+    message: &str,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            message: message.to_string(),
+        }),
+    )
+}
+
+/// One of the caller's profiles; other accounts' profiles are reported as
+/// not found
+fn owned_profile(
+    state: &AppState,
+    user_id: &str,
+    id: &str,
+) -> Result<Profile, (StatusCode, Json<ErrorResponse>)> {
+    let uuid = uuid::Uuid::parse_str(id).map_err(|_| {
+        profile_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_id",
+            "Invalid profile ID format",
+        )
+    })?;
+    state
+        .profiles
+        .get_profile(&uuid)
+        .filter(|_| owns_profile(state, user_id, &uuid))
+        .ok_or_else(|| profile_error(StatusCode::NOT_FOUND, "not_found", "Profile not found"))
+}
+
+/// List the caller's profiles
+pub async fn list_profiles(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Json<Vec<Profile>> {
+    Json(state.profiles.list_profiles_for_user(&claims.sub))
 }
 
-/// Get profile by ID
+/// Get one of the caller's profiles by ID
 pub async fn get_profile(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Profile>, Json<ErrorResponse>> {
-    let uuid = uuid::Uuid::parse_str(&id).map_err(|_| {
-        Json(ErrorResponse {
-            error: "invalid_id".to_string(),
-            message: "Invalid profile ID format".to_string(),
-        })
-    })?;
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<Profile>, (StatusCode, Json<ErrorResponse>)> {
+    owned_profile(&state, &claims.sub, &id).map(Json)
+}
 
-    state.profiles.get_profile(&uuid).map(Json).ok_or_else(|| {
-        Json(ErrorResponse {
-            error: "not_found".to_string(),
-            message: "Profile not found".to_string(),
+/// Fields to change on a profile; omitted fields keep their value
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub name: Option<String>,
+    /// Changing the level resets categories to its defaults unless
+    /// `blocked_categories` is also given
+    pub protection_level: Option<ProtectionLevel>,
+    pub blocked_categories: Option<Vec<String>>,
+    pub custom_blocklist: Option<Vec<String>>,
+    pub custom_allowlist: Option<Vec<String>>,
+    pub time_rules: Option<Vec<TimeRule>>,
+    /// IANA timezone name, e.g. "Europe/London"
+    pub timezone: Option<String>,
+    pub quotas: Option<Vec<UsageQuota>>,
+    /// Profile to inherit from; an empty string detaches the profile
+    pub parent_id: Option<String>,
+    /// Categories to unblock even when a parent blocks them
+    pub allowed_categories: Option<Vec<String>>,
+    /// Keep the profile's queries out of the dnstap stream
+    pub dnstap_opt_out: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+/// Check budget lengths, service names and that no target repeats
+fn validate_quotas(quotas: &[UsageQuota]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for quota in quotas {
+        if quota.daily_minutes == 0 || quota.daily_minutes > 24 * 60 {
+            return Err(format!(
+                "Budget for '{}' must be between 1 and 1440 minutes",
+                quota.target.name()
+            ));
+        }
+        if let shield_profiles::QuotaTarget::Service(id) = &quota.target {
+            if services::get(id).is_none() {
+                return Err(format!("Unknown service '{}'", id));
+            }
+        }
+        if !seen.insert(&quota.target) {
+            return Err(format!("Duplicate budget for '{}'", quota.target.name()));
+        }
+    }
+    Ok(())
+}
+
+/// Check that each rule names known targets
+fn validate_time_rules(
+    rules: &[TimeRule],
+    known_categories: &std::collections::HashSet<String>,
+) -> Result<(), String> {
+    for rule in rules {
+        if !rule.has_targets() {
+            return Err(format!(
+                "Time rule '{}' needs domain_patterns, categories or services",
+                rule.name
+            ));
+        }
+        if rule.days_of_week.is_empty() {
+            return Err(format!("Time rule '{}' has no days_of_week", rule.name));
+        }
+        if let Some(unknown) = rule.services.iter().find(|s| services::get(s).is_none()) {
+            return Err(format!(
+                "Time rule '{}' names unknown service '{}'",
+                rule.name, unknown
+            ));
+        }
+        if let Some(unknown) = rule
+            .categories
+            .iter()
+            .find(|c| !known_categories.contains(c.as_str()) && !services::is_group(c))
+        {
+            return Err(format!(
+                "Time rule '{}' names unknown category '{}'",
+                rule.name, unknown
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn normalize_list(entries: Vec<String>) -> Vec<String> {
+    let mut entries: Vec<String> = entries
+        .into_iter()
+        .map(|e| e.trim().trim_end_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect();
+    let mut seen = std::collections::HashSet::new();
+    entries.retain(|e| seen.insert(e.clone()));
+    entries
+}
+
+/// Update one of the caller's profiles; the changes apply to its devices'
+/// DNS filtering immediately
+pub async fn update_profile(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<UpdateProfileRequest>,
+) -> Result<Json<ProfileResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let error = profile_error;
+    let mut profile = owned_profile(&state, &claims.sub, &id)?;
+    let uuid = profile.id;
+
+    if let Some(name) = request.name {
+        if name.trim().is_empty() {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "invalid_name",
+                "Profile name cannot be empty",
+            ));
+        }
+        profile.name = name.trim().to_string();
+    }
+    if let Some(level) = request.protection_level {
+        if level != profile.protection_level && request.blocked_categories.is_none() {
+            profile.blocked_categories = level
+                .default_block_categories()
+                .iter()
+                .map(|c| c.to_string())
+                .collect();
+        }
+        profile.protection_level = level;
+    }
+    if let Some(categories) = request.blocked_categories {
+        profile.blocked_categories = normalize_list(categories);
+    }
+    if let Some(categories) = request.allowed_categories {
+        profile.allowed_categories = normalize_list(categories);
+    }
+    if let Some(opt_out) = request.dnstap_opt_out {
+        profile.dnstap_opt_out = opt_out;
+    }
+    if let Some(parent_id) = request.parent_id {
+        profile.parent_id = if parent_id.trim().is_empty() {
+            None
+        } else {
+            let parent_id = uuid::Uuid::parse_str(parent_id.trim()).map_err(|_| {
+                error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_parent",
+                    "Invalid parent profile ID format",
+                )
+            })?;
+            if !owns_profile(&state, &claims.sub, &parent_id) {
+                return Err(error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_parent",
+                    "Parent profile not found",
+                ));
+            }
+            state
+                .profiles
+                .check_parent(&uuid, &parent_id)
+                .map_err(|e| error(StatusCode::BAD_REQUEST, "invalid_parent", &e.to_string()))?;
+            Some(parent_id)
+        };
+    }
+    if let Some(blocklist) = request.custom_blocklist {
+        profile.custom_blocklists = normalize_list(blocklist);
+    }
+    if let Some(allowlist) = request.custom_allowlist {
+        profile.custom_allowlists = normalize_list(allowlist);
+    }
+    if let Some(time_rules) = request.time_rules {
+        validate_time_rules(&time_rules, &known_categories(&state))
+            .map_err(|message| error(StatusCode::BAD_REQUEST, "invalid_time_rule", &message))?;
+        profile.time_rules = time_rules;
+    }
+    if let Some(quotas) = request.quotas {
+        validate_quotas(&quotas)
+            .map_err(|message| error(StatusCode::BAD_REQUEST, "invalid_quota", &message))?;
+        profile.quotas = quotas;
+    }
+    if let Some(timezone) = request.timezone {
+        profile.timezone = timezone.parse().map_err(|_| {
+            error(
+                StatusCode::BAD_REQUEST,
+                "invalid_timezone",
+                "Timezone must be an IANA name such as Europe/London",
+            )
+        })?;
+    }
+    if let Some(enabled) = request.enabled {
+        profile.enabled = enabled;
+    }
+
+    if !state.profiles.update_profile(profile.clone()) {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "Profile not found",
+        ));
+    }
+    Ok(Json(ProfileResponse {
+        success: true,
+        message: format!("Updated profile '{}'", profile.name),
+        profile: Some(profile),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ProfileSummary {
+    pub id: uuid::Uuid,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct EffectiveProfileResponse {
+    /// Parent chain the policy was merged from, nearest first
+    pub inherited_from: Vec<ProfileSummary>,
+    /// The profile with inherited lists, categories, rules and budgets
+    /// merged in, as DNS filtering evaluates it
+    pub profile: Profile,
+}
+
+/// Show the resolved policy after inheritance of one of the caller's profiles
+pub async fn get_effective_profile(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<EffectiveProfileResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let profile = owned_profile(&state, &claims.sub, &id)?;
+    let uuid = profile.id;
+    let inherited_from = state
+        .profiles
+        .ancestors(&profile)
+        .into_iter()
+        .map(|p| ProfileSummary {
+            id: p.id,
+            name: p.name,
         })
-    })
+        .collect();
+    let profile = state.profiles.effective_profile(&uuid).unwrap_or(profile);
+
+    Ok(Json(EffectiveProfileResponse {
+        inherited_from,
+        profile,
+    }))
+}
+
+/// Preview a profile's schedule at a given moment
+#[derive(Deserialize)]
+pub struct SchedulePreviewQuery {
+    /// RFC 3339 timestamp (default: now)
+    pub at: Option<String>,
+    /// Domain to resolve against the active rules
+    pub domain: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SchedulePreviewResponse {
+    pub profile_id: String,
+    pub timezone: String,
+    /// The preview moment in the profile's timezone
+    pub local_time: String,
+    /// Rules active at that moment, in resolution order
+    pub active_rules: Vec<TimeRule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<ScheduleDomainPreview>,
+}
+
+#[derive(Serialize)]
+pub struct ScheduleDomainPreview {
+    pub domain: String,
+    /// Action of the deciding rule, or null when no rule applies
+    pub action: Option<RuleAction>,
+    pub rule_id: Option<uuid::Uuid>,
+    pub rule_name: Option<String>,
+    pub target: Option<RuleTarget>,
+    /// Blocklist categories listing the domain
+    pub categories: Vec<String>,
+    pub service: Option<String>,
+}
+
+/// Show which time rules are active now (or at `?at=`) on one of the
+/// caller's profiles, and which one decides `?domain=`
+pub async fn preview_profile_schedule(
+    Path(id): Path<String>,
+    Query(query): Query<SchedulePreviewQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<SchedulePreviewResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let error = profile_error;
+    let profile = owned_profile(&state, &claims.sub, &id)?;
+    let profile = state
+        .profiles
+        .effective_profile(&profile.id)
+        .unwrap_or(profile);
+    let at = match query.at {
+        Some(at) => chrono::DateTime::parse_from_rfc3339(&at)
+            .map_err(|_| {
+                error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_time",
+                    "`at` must be an RFC 3339 timestamp",
+                )
+            })?
+            .with_timezone(&Utc),
+        None => Utc::now(),
+    };
+    let local = at.with_timezone(&profile.timezone);
+
+    let domain = query.domain.map(|domain| {
+        let domain = domain.trim().trim_end_matches('.').to_lowercase();
+        let categories = state
+            .unified_filter
+            .blocklist_manager()
+            .domain_categories(&domain);
+        let matched = schedule::resolve(&profile.time_rules, &local, &domain, &categories);
+        ScheduleDomainPreview {
+            action: matched.as_ref().map(|m| m.rule.action),
+            rule_id: matched.as_ref().map(|m| m.rule.id),
+            rule_name: matched.as_ref().map(|m| m.rule.name.clone()),
+            target: matched.map(|m| m.target),
+            service: services::service_for(&domain).map(|s| s.id.to_string()),
+            categories,
+            domain,
+        }
+    });
+
+    Ok(Json(SchedulePreviewResponse {
+        profile_id: profile.id.to_string(),
+        timezone: profile.timezone.name().to_string(),
+        local_time: local.to_rfc3339(),
+        active_rules: schedule::active_rules(&profile.time_rules, &local)
+            .into_iter()
+            .cloned()
+            .collect(),
+        domain,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ProfileUsageResponse {
+    pub profile_id: String,
+    pub timezone: String,
+    pub quotas: Vec<QuotaUsage>,
+}
+
+/// Today's usage and remaining time for each of the budgets on one of the
+/// caller's profiles
+pub async fn get_profile_usage(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<ProfileUsageResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let profile = owned_profile(&state, &claims.sub, &id)?;
+    let profile = state
+        .profiles
+        .effective_profile(&profile.id)
+        .unwrap_or(profile);
+
+    Ok(Json(ProfileUsageResponse {
+        profile_id: profile.id.to_string(),
+        timezone: profile.timezone.name().to_string(),
+        quotas: state
+            .unified_filter
+            .usage()
+            .usage(&profile.to_device_profile(), Utc::now()),
+    }))
+}
+
+/// List the service catalog that time rules can target
+pub async fn list_services() -> Json<&'static [services::Service]> {
+    Json(services::SERVICES)
 }
 
-/// Delete a profile
+/// Delete one of the caller's profiles
 pub async fn delete_profile(
     Path(id): Path<String>,
     State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
 ) -> Json<ProfileResponse> {
     let uuid = match uuid::Uuid::parse_str(&id) {
         Ok(u) => u,
@@ -1370,7 +3229,7 @@ pub async fn delete_profile(
         }
     };
 
-    let success = state.profiles.delete_profile(&uuid);
+    let success = owns_profile(&state, &claims.sub, &uuid) && state.profiles.delete_profile(&uuid);
     Json(ProfileResponse {
         success,
         message: if success {
@@ -1388,9 +3247,12 @@ pub struct AssignDeviceRequest {
     pub profile_id: String,
 }
 
-/// Assign a device to a profile
+/// Assign a device to one of the caller's profiles
+///
+/// A device already on another account's profile is left where it is.
 pub async fn assign_device(
     State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
     Json(request): Json<AssignDeviceRequest>,
 ) -> Json<ProfileResponse> {
     let profile_uuid = match uuid::Uuid::parse_str(&request.profile_id) {
@@ -1404,9 +3266,15 @@ pub async fn assign_device(
         }
     };
 
-    let success = state
+    let taken = state
         .profiles
-        .assign_device(request.device_id.clone(), &profile_uuid);
+        .get_device_profile(&request.device_id)
+        .is_some_and(|current| !owns_profile(&state, &claims.sub, &current.id));
+    let success = owns_profile(&state, &claims.sub, &profile_uuid)
+        && !taken
+        && state
+            .profiles
+            .assign_device(request.device_id.clone(), &profile_uuid);
     Json(ProfileResponse {
         success,
         message: if success {
@@ -1414,7 +3282,9 @@ pub async fn assign_device(
         } else {
             "Profile not found".to_string()
         },
-        profile: state.profiles.get_profile(&profile_uuid),
+        profile: success
+            .then(|| state.profiles.get_profile(&profile_uuid))
+            .flatten(),
     })
 }
 
@@ -1427,7 +3297,11 @@ pub async fn profile_stats(State(state): State<Arc<AppState>>) -> Json<ProfileSt
 // Unified Filter Management Endpoints
 // ============================================================================
 
-use shield_dns_core::unified_filter::{DeviceProfile, UnifiedFilterStats};
+use shield_dns_core::blocklist_fetcher::TraceMatch;
+use shield_dns_core::prefix_map::Overlap;
+use shield_dns_core::unified_filter::{
+    DeviceProfile, FilterResult, RangeAssignment, TraceStage, UnifiedFilterStats,
+};
 
 /// Get unified filter statistics
 pub async fn unified_filter_stats(State(state): State<Arc<AppState>>) -> Json<UnifiedFilterStats> {
@@ -1442,23 +3316,66 @@ pub struct BlockCheckResponse {
     pub reason: Option<FilterReason>,
     pub category: Option<String>,
     pub profile_name: Option<String>,
+    /// Filter stages consulted, in order; the last one decided
+    pub stages: Vec<TraceStage>,
+    /// Every list entry matching the domain, with its source list and line
+    pub matches: Vec<TraceMatch>,
+}
+
+/// Evaluate as a given client and query type instead of the caller
+///
+/// `client_ip` is honored for signed-in callers who own the profile it
+/// resolves to, and for administrators; anyone else is evaluated as
+/// themselves.
+#[derive(Deserialize)]
+pub struct BlockCheckQuery {
+    pub client_ip: Option<String>,
+    pub qtype: Option<u16>,
 }
 
 pub async fn check_domain_blocking(
     Path(domain): Path<String>,
+    Query(query): Query<BlockCheckQuery>,
     State(state): State<Arc<AppState>>,
-    connect_info: Option<ConnectInfo<SocketAddr>>,
-) -> Json<BlockCheckResponse> {
-    let client_ip = connect_info.map(|ci| ci.0.ip());
-    let result = state.unified_filter.check(&domain, client_ip);
+    ClientIp(peer_ip): ClientIp,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<BlockCheckResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let client_ip = match query.client_ip {
+        Some(ip) => {
+            let parsed: std::net::IpAddr = ip.parse().map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(ErrorResponse {
+                        error: "invalid_ip".to_string(),
+                        message: "Invalid IP address format".to_string(),
+                    }),
+                )
+            })?;
+            // Another client's profile and policy are only shown to its owner
+            let allowed = bearer_claims(&state, &headers).is_some_and(|claims| {
+                state.is_admin(&claims.sub) || owned_client(&state, &claims.sub, &ip).is_ok()
+            });
+            if allowed {
+                Some(parsed)
+            } else {
+                peer_ip
+            }
+        }
+        None => peer_ip,
+    };
+    let trace = state
+        .unified_filter
+        .trace_query(&domain, client_ip, query.qtype);
 
-    Json(BlockCheckResponse {
+    Ok(Json(BlockCheckResponse {
         domain,
-        blocked: result.decision == shield_dns_core::filter::FilterDecision::Block,
-        reason: Some(result.reason),
-        category: result.category,
-        profile_name: result.profile_name,
-    })
+        blocked: trace.result.decision == shield_dns_core::filter::FilterDecision::Block,
+        reason: Some(trace.result.reason),
+        category: trace.result.category,
+        profile_name: trace.result.profile_name,
+        stages: trace.stages,
+        matches: trace.matches,
+    }))
 }
 
 /// Assign a device profile by IP address
@@ -1471,46 +3388,244 @@ pub struct AssignProfileToIpRequest {
     pub custom_allowlist: Option<Vec<String>>,
 }
 
-#[derive(Serialize)]
-pub struct AssignProfileResponse {
-    pub success: bool,
-    pub message: String,
+#[derive(Serialize)]
+pub struct AssignProfileResponse {
+    pub success: bool,
+    pub message: String,
+}
+
+pub async fn assign_profile_to_ip(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<AssignProfileToIpRequest>,
+) -> Json<AssignProfileResponse> {
+    let ip: std::net::IpAddr = match request.ip_address.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return Json(AssignProfileResponse {
+                success: false,
+                message: "Invalid IP address format".to_string(),
+            });
+        }
+    };
+
+    let profile = DeviceProfile {
+        id: format!("ip-{}", request.ip_address),
+        name: request.profile_name.clone(),
+        blocked_categories: request.blocked_categories,
+        custom_blocklist: request.custom_blocklist.unwrap_or_default(),
+        custom_allowlist: request.custom_allowlist.unwrap_or_default(),
+        ..Default::default()
+    };
+
+    state.unified_filter.assign_profile_to_ip(ip, profile);
+
+    Json(AssignProfileResponse {
+        success: true,
+        message: format!("Profile '{}' assigned to IP {}", request.profile_name, request.ip_address),
+    })
+}
+
+#[derive(Serialize)]
+pub struct RangeOverlapInfo {
+    pub range: String,
+    pub profile_id: String,
+    pub profile_name: String,
+    /// How the assigned range relates to the checked one
+    pub relation: Overlap,
+}
+
+#[derive(Serialize)]
+pub struct RangeInfo {
+    pub range: String,
+    pub profile_id: String,
+    pub profile_name: String,
+    /// Other assigned ranges overlapping this one
+    pub overlaps: Vec<RangeOverlapInfo>,
+}
+
+/// Whether a range assignment is on one of the caller's profiles
+fn owns_range(state: &AppState, user_id: &str, assignment: &RangeAssignment) -> bool {
+    uuid::Uuid::parse_str(&assignment.profile_id).is_ok_and(|id| owns_profile(state, user_id, &id))
+}
+
+fn overlap_info(
+    state: &AppState,
+    user_id: &str,
+    range: &ipnet::IpNet,
+    skip_equal: bool,
+) -> Vec<RangeOverlapInfo> {
+    state
+        .unified_filter
+        .overlapping_ranges(range)
+        .into_iter()
+        .filter(|(_, relation)| !(skip_equal && *relation == Overlap::Equal))
+        .filter(|(assignment, _)| owns_range(state, user_id, assignment))
+        .map(|(assignment, relation)| RangeOverlapInfo {
+            range: assignment.range.to_string(),
+            profile_id: assignment.profile_id,
+            profile_name: assignment.profile_name,
+            relation,
+        })
+        .collect()
+}
+
+fn parse_range(cidr: &str) -> Result<ipnet::IpNet, (StatusCode, Json<ErrorResponse>)> {
+    cidr.trim()
+        .parse::<ipnet::IpNet>()
+        .map(|net| net.trunc())
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: "invalid_cidr".to_string(),
+                    message: format!("Invalid CIDR range '{}'", cidr),
+                }),
+            )
+        })
+}
+
+/// List the caller's profile assignments by IP range, most specific first
+pub async fn list_range_assignments(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Json<Vec<RangeInfo>> {
+    let ranges = state
+        .unified_filter
+        .range_assignments()
+        .into_iter()
+        .filter(|assignment| owns_range(&state, &claims.sub, assignment))
+        .map(|assignment| RangeInfo {
+            overlaps: overlap_info(&state, &claims.sub, &assignment.range, true),
+            range: assignment.range.to_string(),
+            profile_id: assignment.profile_id,
+            profile_name: assignment.profile_name,
+        })
+        .collect();
+    Json(ranges)
+}
+
+#[derive(Deserialize)]
+pub struct RangeCheckQuery {
+    /// Range to check for overlaps with assigned ranges
+    pub cidr: Option<String>,
+    /// Address to find the matching range for
+    pub ip: Option<std::net::IpAddr>,
+}
+
+#[derive(Serialize)]
+pub struct RangeCheckResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+    pub overlaps: Vec<RangeOverlapInfo>,
+    /// Most specific assigned range containing `ip`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matching_range: Option<RangeAssignment>,
+}
+
+/// Check a range against the caller's assigned ones, or find which of them
+/// an IP falls in
+pub async fn check_range(
+    Query(query): Query<RangeCheckQuery>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<Json<RangeCheckResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let range = query.cidr.as_deref().map(parse_range).transpose()?;
+    Ok(Json(RangeCheckResponse {
+        overlaps: range
+            .map(|range| overlap_info(&state, &claims.sub, &range, false))
+            .unwrap_or_default(),
+        range: range.map(|range| range.to_string()),
+        matching_range: query
+            .ip
+            .and_then(|ip| state.unified_filter.matching_range(&ip))
+            .filter(|assignment| owns_range(&state, &claims.sub, assignment)),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct AssignRangeRequest {
+    pub cidr: String,
+    pub profile_id: uuid::Uuid,
+}
+
+#[derive(Serialize)]
+pub struct AssignRangeResponse {
+    pub success: bool,
+    pub range: String,
+    /// Assigned ranges that overlap the new one; the most specific range
+    /// wins for addresses in both
+    pub overlaps: Vec<RangeOverlapInfo>,
+}
+
+/// Assign one of the caller's profiles to an IP range; the assignment is
+/// stored on the profile
+pub async fn assign_range(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<AssignRangeRequest>,
+) -> Result<Json<AssignRangeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let range = parse_range(&request.cidr)?;
+    if !owns_profile(&state, &claims.sub, &request.profile_id)
+        || !state
+            .profiles
+            .assign_device(range.to_string(), &request.profile_id)
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "not_found".to_string(),
+                message: "Profile not found".to_string(),
+            }),
+        ));
+    }
+
+    Ok(Json(AssignRangeResponse {
+        success: true,
+        overlaps: overlap_info(&state, &claims.sub, &range, true),
+        range: range.to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    pub cidr: String,
 }
 
-pub async fn assign_profile_to_ip(
+/// Remove a range assignment made through `POST /api/filter/ranges`
+///
+/// Ranges belonging to registry devices are changed through the device.
+/// Only ranges on the caller's profiles can be removed.
+pub async fn remove_range(
+    Query(query): Query<RangeQuery>,
     State(state): State<Arc<AppState>>,
-    Json(request): Json<AssignProfileToIpRequest>,
-) -> Json<AssignProfileResponse> {
-    let ip: std::net::IpAddr = match request.ip_address.parse() {
-        Ok(ip) => ip,
-        Err(_) => {
-            return Json(AssignProfileResponse {
-                success: false,
-                message: "Invalid IP address format".to_string(),
-            });
-        }
-    };
-
-    let profile = DeviceProfile {
-        id: format!("ip-{}", request.ip_address),
-        name: request.profile_name.clone(),
-        blocked_categories: request.blocked_categories,
-        custom_blocklist: request.custom_blocklist.unwrap_or_default(),
-        custom_allowlist: request.custom_allowlist.unwrap_or_default(),
-        enabled: true,
-    };
-
-    state.unified_filter.assign_profile_to_ip(ip, profile);
-
-    Json(AssignProfileResponse {
-        success: true,
-        message: format!("Profile '{}' assigned to IP {}", request.profile_name, request.ip_address),
-    })
+    axum::Extension(claims): axum::Extension<Claims>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let range = parse_range(&query.cidr)?;
+    let owned = state
+        .unified_filter
+        .range_assignments()
+        .iter()
+        .any(|a| a.range == range && owns_range(&state, &claims.sub, a));
+    if owned && state.profiles.unassign_device(&range.to_string()) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "not_found".to_string(),
+                message: format!("No profile is assigned to {}", range),
+            }),
+        ))
+    }
 }
 
 /// Get available blocking categories
 pub async fn get_blocking_categories() -> Json<Vec<CategoryInfo>> {
-    Json(vec![
+    Json(builtin_categories())
+}
+
+fn builtin_categories() -> Vec<CategoryInfo> {
+    vec![
         CategoryInfo { name: "ads".to_string(), description: "Advertising and ad networks".to_string(), default_enabled: true },
         CategoryInfo { name: "tracking".to_string(), description: "Analytics and user tracking".to_string(), default_enabled: true },
         CategoryInfo { name: "malware".to_string(), description: "Malicious domains and malware".to_string(), default_enabled: true },
@@ -1519,7 +3634,18 @@ pub async fn get_blocking_categories() -> Json<Vec<CategoryInfo>> {
         CategoryInfo { name: "gambling".to_string(), description: "Gambling sites".to_string(), default_enabled: false },
         CategoryInfo { name: "social".to_string(), description: "Social media".to_string(), default_enabled: false },
         CategoryInfo { name: "cryptominers".to_string(), description: "Cryptocurrency mining scripts".to_string(), default_enabled: true },
-    ])
+    ]
+}
+
+/// Built-in categories plus those of configured blocklist sources
+fn known_categories(state: &AppState) -> std::collections::HashSet<String> {
+    let mut known: std::collections::HashSet<String> =
+        builtin_categories().into_iter().map(|c| c.name).collect();
+    match state.db.get_blocklist_sources() {
+        Ok(sources) => known.extend(sources.into_iter().map(|s| s.category)),
+        Err(e) => warn!("Failed to read blocklist source categories: {}", e),
+    }
+    known
 }
 
 #[derive(Serialize)]
@@ -1551,7 +3677,11 @@ pub async fn toggle_category(
         message: format!(
             "Category '{}' {}",
             category,
-            if request.enabled { "enabled" } else { "disabled" }
+            if request.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
         ),
     })
 }
@@ -1561,6 +3691,134 @@ pub async fn get_enabled_categories(State(state): State<Arc<AppState>>) -> Json<
     Json(state.unified_filter.get_enabled_categories())
 }
 
+/// Blocklist preset with whether it matches the current global categories
+#[derive(Serialize)]
+pub struct PresetInfo {
+    pub name: String,
+    pub description: String,
+    pub enabled_categories: Vec<String>,
+    pub active: bool,
+}
+
+/// List the blocklist presets from the configuration
+pub async fn list_presets(State(state): State<Arc<AppState>>) -> Json<Vec<PresetInfo>> {
+    let config =
+        blocklist_sources::load_config(&state.db, blocklist_sources::BLOCKLIST_CONFIG_PATH);
+    let mut enabled = state.unified_filter.get_enabled_categories();
+    enabled.sort();
+
+    let mut presets: Vec<PresetInfo> = config
+        .presets
+        .into_iter()
+        .map(|(name, preset)| {
+            let mut categories = preset.enabled_categories.clone();
+            categories.sort();
+            categories.dedup();
+            PresetInfo {
+                name,
+                description: preset.description,
+                enabled_categories: preset.enabled_categories,
+                active: categories == enabled,
+            }
+        })
+        .collect();
+    presets.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(presets)
+}
+
+/// Apply a preset to one of the caller's profiles and everything assigned
+/// to it, or globally for administrators
+#[derive(Deserialize, Default)]
+pub struct ApplyPresetRequest {
+    pub profile_id: Option<uuid::Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct ApplyPresetResponse {
+    pub success: bool,
+    pub preset: String,
+    pub enabled_categories: Vec<String>,
+    pub message: String,
+}
+
+pub async fn apply_preset(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    request: Option<Json<ApplyPresetRequest>>,
+) -> Result<Json<ApplyPresetResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let config =
+        blocklist_sources::load_config(&state.db, blocklist_sources::BLOCKLIST_CONFIG_PATH);
+    let Some(preset) = config.presets.get(&name) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "not_found".to_string(),
+                message: format!("Preset '{}' not found", name),
+            }),
+        ));
+    };
+    let categories = preset.enabled_categories.clone();
+    let request = request.map(|Json(r)| r).unwrap_or_default();
+
+    let target = match request.profile_id {
+        Some(profile_id) => {
+            let Some(mut profile) = state
+                .profiles
+                .get_profile(&profile_id)
+                .filter(|_| owns_profile(&state, &claims.sub, &profile_id))
+            else {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    Json(ErrorResponse {
+                        error: "not_found".to_string(),
+                        message: format!("Profile {} not found", profile_id),
+                    }),
+                ));
+            };
+            // Saving through the manager persists the profile and re-syncs
+            // its devices, ranges and child profiles
+            profile.blocked_categories = categories.clone();
+            state.profiles.update_profile(profile);
+            format!("profile {}", profile_id)
+        }
+        None if !state.is_admin(&claims.sub) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse {
+                    error: "forbidden".to_string(),
+                    message: "Only administrators can apply a preset globally; pass one of your profile_ids".to_string(),
+                }),
+            ));
+        }
+        None => {
+            blocklist_sources::apply_global_categories(
+                &state.unified_filter,
+                &state.db,
+                &categories,
+            )
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse {
+                        error: "database_error".to_string(),
+                        message: e.to_string(),
+                    }),
+                )
+            })?;
+            "global configuration".to_string()
+        }
+    };
+
+    info!("Applied preset '{}' to {}", name, target);
+    Ok(Json(ApplyPresetResponse {
+        success: true,
+        preset: name.clone(),
+        enabled_categories: categories,
+        message: format!("Preset '{}' applied to {}", name, target),
+    }))
+}
+
 /// Refresh blocklists from remote sources
 #[derive(Serialize)]
 pub struct BlocklistRefreshResponse {
@@ -1569,42 +3827,151 @@ pub struct BlocklistRefreshResponse {
     pub sources_loaded: usize,
     pub sources_failed: usize,
     pub by_category: HashMap<String, usize>,
+    /// Per-source freshness (fresh, not_modified, cached, failed)
+    pub sources: Vec<shield_dns_core::blocklist_fetcher::SourceStatus>,
+    /// Entries added and removed since the previous refresh
+    pub diff: shield_dns_core::blocklist_fetcher::RefreshDiff,
     pub message: String,
 }
 
-pub async fn refresh_blocklists(State(state): State<Arc<AppState>>) -> Json<BlocklistRefreshResponse> {
+pub async fn refresh_blocklists(
+    State(state): State<Arc<AppState>>,
+) -> Json<BlocklistRefreshResponse> {
     info!("Manual blocklist refresh triggered");
 
-    match state.unified_filter.init_blocklists("config/blocklist-sources.json").await {
-        Ok(stats) => {
-            info!(
-                "Blocklist refresh complete: {} domains from {} sources",
-                stats.total_domains, stats.sources_loaded
-            );
-            Json(BlocklistRefreshResponse {
-                success: true,
-                total_domains: stats.total_domains,
-                sources_loaded: stats.sources_loaded,
-                sources_failed: stats.sources_failed,
-                by_category: stats.by_category,
-                message: format!(
-                    "Loaded {} domains from {} sources ({} failed)",
-                    stats.total_domains, stats.sources_loaded, stats.sources_failed
-                ),
-            })
-        }
-        Err(e) => {
-            warn!("Blocklist refresh failed: {}", e);
-            Json(BlocklistRefreshResponse {
-                success: false,
-                total_domains: 0,
-                sources_loaded: 0,
-                sources_failed: 0,
-                by_category: HashMap::new(),
-                message: format!("Failed to refresh blocklists: {}", e),
-            })
-        }
+    let stats = blocklist_sources::refresh_blocklists(
+        &state.unified_filter,
+        &state.db,
+        &state.webhooks,
+        RefreshScope::All,
+    )
+    .await;
+    info!(
+        "Blocklist refresh complete: {} domains from {} sources",
+        stats.total_domains, stats.sources_loaded
+    );
+    Json(BlocklistRefreshResponse {
+        success: stats.sources_failed == 0 || stats.sources_loaded > 0,
+        total_domains: stats.total_domains,
+        sources_loaded: stats.sources_loaded,
+        sources_failed: stats.sources_failed,
+        by_category: stats.by_category,
+        message: format!(
+            "Loaded {} domains from {} sources ({} failed, +{} / -{})",
+            stats.total_domains,
+            stats.sources_loaded,
+            stats.sources_failed,
+            stats.diff.added,
+            stats.diff.removed
+        ),
+        sources: stats.sources,
+        diff: stats.diff,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RpzExportQuery {
+    /// Zone origin (default: rpz.shield.local)
+    pub origin: Option<String>,
+}
+
+/// Export the effective filter policy as an RPZ zone file for BIND/Unbound
+pub async fn export_rpz(
+    Query(params): Query<RpzExportQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl axum::response::IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let origin = params
+        .origin
+        .unwrap_or_else(|| "rpz.shield.local".to_string());
+    if origin.is_empty()
+        || origin.len() > 253
+        || !origin
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "invalid_origin".to_string(),
+                message: "Zone origin must be a valid domain name".to_string(),
+            }),
+        ));
     }
+
+    let zone = state.unified_filter.export_rpz(&origin);
+
+    Ok((
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_TYPE, "text/dns; charset=utf-8")],
+        zone,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct BlocklistHistoryQuery {
+    /// Maximum refreshes to return (default: 20, max: 100)
+    pub limit: Option<usize>,
+}
+
+/// A recorded blocklist refresh
+#[derive(Serialize)]
+pub struct BlocklistRefreshRecord {
+    pub id: i64,
+    pub refreshed_at: String,
+    pub total_domains: i64,
+    pub sources_loaded: i64,
+    pub sources_failed: i64,
+    pub domains_added: i64,
+    pub domains_removed: i64,
+    /// Per-source and per-category added/removed counts with samples
+    pub diff: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct BlocklistHistoryResponse {
+    pub refreshes: Vec<BlocklistRefreshRecord>,
+    pub total: usize,
+}
+
+/// Get recent blocklist refreshes, newest first
+pub async fn get_blocklist_history(
+    Query(params): Query<BlocklistHistoryQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<BlocklistHistoryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let limit = params
+        .limit
+        .unwrap_or(20)
+        .min(crate::background_tasks::BLOCKLIST_HISTORY_LIMIT);
+
+    let history = state.db.get_blocklist_history(limit).map_err(|e| {
+        warn!("Failed to read blocklist history: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: "database_error".to_string(),
+                message: "Failed to read blocklist history".to_string(),
+            }),
+        )
+    })?;
+
+    let refreshes: Vec<BlocklistRefreshRecord> = history
+        .into_iter()
+        .map(|r| BlocklistRefreshRecord {
+            id: r.id,
+            refreshed_at: r.refreshed_at.to_rfc3339(),
+            total_domains: r.total_domains,
+            sources_loaded: r.sources_loaded,
+            sources_failed: r.sources_failed,
+            domains_added: r.domains_added,
+            domains_removed: r.domains_removed,
+            diff: serde_json::from_str(&r.diff).unwrap_or(serde_json::Value::Null),
+        })
+        .collect();
+
+    Ok(Json(BlocklistHistoryResponse {
+        total: refreshes.len(),
+        refreshes,
+    }))
 }
 
 // ============================================================================
@@ -1660,23 +4027,18 @@ pub async fn get_realtime_analytics(State(state): State<Arc<AppState>>) -> Json<
         .map(|(k, v)| (k, v as u64))
         .collect();
 
-    // Get top blocked domains from query history
-    let history = state.metrics.get_query_history(100);
-    let mut domain_counts: HashMap<String, u64> = HashMap::new();
-    for entry in history.iter().filter(|e| e.blocked) {
-        *domain_counts.entry(entry.domain.clone()).or_insert(0) += 1;
-    }
-
-    let mut top_blocked: Vec<TopDomain> = domain_counts
+    // Top blocked domains and active clients over the last hour
+    let hour_ago = unix_now().saturating_sub(3600);
+    let sketches = state.metrics.sketches();
+    let top_blocked: Vec<TopDomain> = sketches
+        .top_domains(hour_ago, Some(true), None, 10)
         .into_iter()
-        .map(|(domain, count)| TopDomain {
-            category: state.unified_filter.get_blocking_category(&domain),
-            domain,
-            count,
+        .map(|hitter| TopDomain {
+            category: state.unified_filter.get_blocking_category(&hitter.item),
+            domain: hitter.item,
+            count: hitter.count,
         })
         .collect();
-    top_blocked.sort_by(|a, b| b.count.cmp(&a.count));
-    top_blocked.truncate(10);
 
     Json(RealTimeAnalytics {
         queries_per_minute,
@@ -1685,11 +4047,90 @@ pub async fn get_realtime_analytics(State(state): State<Arc<AppState>>) -> Json<
         current_cache_hit_rate: snapshot.cache_hit_rate,
         top_blocked_domains: top_blocked,
         blocking_by_category,
-        active_clients: snapshot.unique_clients,
+        active_clients: sketches.unique_clients(hour_ago) as usize,
         uptime_seconds: uptime,
     })
 }
 
+#[derive(Deserialize)]
+pub struct TopDomainsParams {
+    /// Window in hours (default 1, at most 24)
+    pub hours: Option<u32>,
+    /// Refused here; see `get_client_top_domains`
+    pub client: Option<String>,
+    /// `blocked` or `allowed` (both if omitted)
+    pub decision: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Top domains response
+#[derive(Serialize)]
+pub struct TopDomainsResponse {
+    pub hours: u32,
+    pub client: Option<String>,
+    pub decision: Option<String>,
+    /// Estimated counts, each at most `error` above the true count
+    pub domains: Vec<HeavyHitter>,
+    /// Estimated distinct domains and clients (across all clients)
+    pub unique_domains: u64,
+    pub unique_clients: u64,
+}
+
+/// Get the most queried domains over recent hours across all clients
+///
+/// One client's top list needs an account and is served from
+/// `/api/clients/:ip/top`.
+pub async fn get_top_domains(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TopDomainsParams>,
+) -> Result<Json<TopDomainsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if params.client.is_some() {
+        return Err(analytics_error(
+            "Sign in and use /api/clients/:ip/top for one client's domains",
+        ));
+    }
+    read_top_domains(&state, params, None)
+}
+
+/// Most queried domains of a client whose queries fall under one of the
+/// caller's profiles
+pub async fn get_client_top_domains(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Query(params): Query<TopDomainsParams>,
+) -> Result<Json<TopDomainsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = owned_client(&state, &claims.sub, &ip)?;
+    read_top_domains(&state, params, Some(ip.to_string()))
+}
+
+fn read_top_domains(
+    state: &AppState,
+    params: TopDomainsParams,
+    client: Option<String>,
+) -> Result<Json<TopDomainsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let blocked = match params.decision.as_deref() {
+        None => None,
+        Some("blocked") => Some(true),
+        Some("allowed") => Some(false),
+        Some(_) => return Err(analytics_error("decision must be blocked or allowed")),
+    };
+    let max_hours = (sketch::WINDOWS as u64 * sketch::WINDOW_SECS / 3600) as u32;
+    let hours = params.hours.unwrap_or(1).clamp(1, max_hours);
+    let limit = params.limit.unwrap_or(10).clamp(1, 100);
+    let since = unix_now().saturating_sub(hours as u64 * 3600);
+
+    let sketches = state.metrics.sketches();
+    Ok(Json(TopDomainsResponse {
+        hours,
+        domains: sketches.top_domains(since, blocked, client.as_deref(), limit),
+        client,
+        decision: params.decision,
+        unique_domains: sketches.unique_domains(since),
+        unique_clients: sketches.unique_clients(since),
+    }))
+}
+
 /// Time series data point
 #[derive(Serialize)]
 pub struct TimeSeriesPoint {
@@ -1703,58 +4144,129 @@ pub struct QueryTrends {
     pub total_queries: Vec<TimeSeriesPoint>,
     pub blocked_queries: Vec<TimeSeriesPoint>,
     pub period_minutes: u32,
+    pub granularity: Granularity,
+}
+
+fn analytics_error(message: &str) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(ErrorResponse {
+            error: "invalid_request".to_string(),
+            message: message.to_string(),
+        }),
+    )
 }
 
-/// Get query trends over time
+/// Get query trends over time, overall or for one category
+///
+/// Per-client and per-profile trends need an account and are served from
+/// `/api/clients/:ip/trends` and `/api/profiles/:id/trends`.
 pub async fn get_query_trends(
     State(state): State<Arc<AppState>>,
     Query(params): Query<TrendsParams>,
-) -> Json<QueryTrends> {
-    let period_minutes = params.period.unwrap_or(60);
-    let history = state.metrics.get_query_history(1000);
+) -> Result<Json<QueryTrends>, (StatusCode, Json<ErrorResponse>)> {
+    if params.client.is_some() || params.profile.is_some() {
+        return Err(analytics_error(
+            "Sign in and use /api/clients/:ip/trends or /api/profiles/:id/trends for one client or profile",
+        ));
+    }
+    let (dimension, key) = match params.category.clone() {
+        Some(category) => (Dimension::Category, Some(category)),
+        None => (Dimension::All, None),
+    };
+    read_trends(&state, &params, dimension, key).await
+}
 
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+/// Query trends for a client whose queries fall under one of the caller's
+/// profiles
+pub async fn get_client_trends(
+    Path(ip): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Query(params): Query<TrendsParams>,
+) -> Result<Json<QueryTrends>, (StatusCode, Json<ErrorResponse>)> {
+    let ip = owned_client(&state, &claims.sub, &ip)?;
+    read_trends(&state, &params, Dimension::Client, Some(ip.to_string())).await
+}
 
-    let bucket_size_secs = (period_minutes as u64 * 60) / 12; // 12 buckets
-    let mut total_buckets: HashMap<u64, u64> = HashMap::new();
-    let mut blocked_buckets: HashMap<u64, u64> = HashMap::new();
+/// Query trends for one of the caller's profiles
+pub async fn get_profile_trends(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Query(params): Query<TrendsParams>,
+) -> Result<Json<QueryTrends>, (StatusCode, Json<ErrorResponse>)> {
+    let profile = owned_profile(&state, &claims.sub, &id)?;
+    read_trends(
+        &state,
+        &params,
+        Dimension::Profile,
+        Some(profile.id.to_string()),
+    )
+    .await
+}
 
-    for entry in history {
-        let bucket = (entry.timestamp / bucket_size_secs) * bucket_size_secs;
-        *total_buckets.entry(bucket).or_insert(0) += 1;
-        if entry.blocked {
-            *blocked_buckets.entry(bucket).or_insert(0) += 1;
-        }
+async fn read_trends(
+    state: &AppState,
+    params: &TrendsParams,
+    dimension: Dimension,
+    key: Option<String>,
+) -> Result<Json<QueryTrends>, (StatusCode, Json<ErrorResponse>)> {
+    let max_minutes = (crate::rollups::DAY_RETENTION_SECS / 60) as u32;
+    let period_minutes = params.period.unwrap_or(60).clamp(1, max_minutes);
+    let granularity = match params.granularity.as_deref() {
+        Some(value) => Granularity::parse(value)
+            .ok_or_else(|| analytics_error("granularity must be minute, hour or day"))?,
+        None if period_minutes <= 6 * 60 => Granularity::Minute,
+        None if period_minutes <= 14 * 24 * 60 => Granularity::Hour,
+        None => Granularity::Day,
+    };
+    if period_minutes as u64 * 60 / granularity.seconds() > MAX_TREND_POINTS {
+        return Err(analytics_error(
+            "Too many points; use a coarser granularity",
+        ));
     }
 
-    let start_time = now.saturating_sub(period_minutes as u64 * 60);
-    let mut total_queries: Vec<TimeSeriesPoint> = total_buckets
-        .into_iter()
-        .filter(|(t, _)| *t >= start_time)
-        .map(|(timestamp, value)| TimeSeriesPoint { timestamp, value })
-        .collect();
-    total_queries.sort_by_key(|p| p.timestamp);
+    let now = unix_now();
+    let since = now.saturating_sub(period_minutes as u64 * 60);
+    let series = read_rollups(state, move |rollups| {
+        rollups.series(granularity, dimension, key.as_deref(), since, now + 1)
+    })
+    .await?;
 
-    let mut blocked_queries: Vec<TimeSeriesPoint> = blocked_buckets
-        .into_iter()
-        .filter(|(t, _)| *t >= start_time)
-        .map(|(timestamp, value)| TimeSeriesPoint { timestamp, value })
+    let total_queries = series
+        .iter()
+        .map(|p| TimeSeriesPoint {
+            timestamp: p.timestamp,
+            value: p.counts.queries,
+        })
+        .collect();
+    let blocked_queries = series
+        .iter()
+        .map(|p| TimeSeriesPoint {
+            timestamp: p.timestamp,
+            value: p.counts.blocked,
+        })
         .collect();
-    blocked_queries.sort_by_key(|p| p.timestamp);
 
-    Json(QueryTrends {
+    Ok(Json(QueryTrends {
         total_queries,
         blocked_queries,
         period_minutes,
-    })
+        granularity,
+    }))
 }
 
 #[derive(Deserialize)]
 pub struct TrendsParams {
     pub period: Option<u32>, // minutes
+    /// minute, hour or day (chosen from the period if omitted)
+    pub granularity: Option<String>,
+    /// Refused here; see `get_client_trends` and `get_profile_trends`
+    pub client: Option<String>,
+    pub profile: Option<String>,
+    /// Only this blocking category (overall trends only)
+    pub category: Option<String>,
 }
 
 /// Threat detection summary
@@ -1842,6 +4354,7 @@ pub async fn register_webhook(
             "threat_blocked" => Some(WebhookEvent::ThreatBlocked),
             "high_risk_detected" => Some(WebhookEvent::HighRiskDetected),
             "blocklist_updated" => Some(WebhookEvent::BlocklistUpdated),
+            "quota_exhausted" => Some(WebhookEvent::QuotaExhausted),
             "all" => Some(WebhookEvent::All),
             _ => None,
         })
@@ -1915,6 +4428,8 @@ pub async fn test_webhook(
                 client_ip: None,
                 risk_score: Some(0.0),
                 details: Some("This is a test notification from Shield AI".to_string()),
+                diff: None,
+                quota: None,
             };
 
             state.webhooks.notify_threat(notification).await;
@@ -2186,37 +4701,37 @@ pub struct TopTracker {
 }
 
 /// Privacy metrics endpoint for dashboard
-pub async fn get_privacy_metrics(State(state): State<Arc<AppState>>) -> Json<PrivacyMetrics> {
-    let snapshot = state.metrics.snapshot();
-    let history = state.metrics.get_query_history(1000);
-
-    // Calculate tracker categories from blocked domains
-    let mut tracker_counts: HashMap<String, u64> = HashMap::new();
-    for query in &history {
-        if query.blocked {
-            *tracker_counts.entry(query.domain.clone()).or_insert(0) += 1;
-        }
-    }
+pub async fn get_privacy_metrics(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PrivacyMetrics>, (StatusCode, Json<ErrorResponse>)> {
+    let now = unix_now();
+    let since = now.saturating_sub(24 * 3600);
+    let (series, categories) = read_rollups(&state, move |rollups| {
+        Ok((
+            rollups.series(Granularity::Hour, Dimension::All, None, since, now + 1)?,
+            rollups.totals(Granularity::Hour, Dimension::Category, since, now + 1)?,
+        ))
+    })
+    .await?;
 
-    // Sort and get top trackers
-    let mut sorted_trackers: Vec<_> = tracker_counts.into_iter().collect();
-    sorted_trackers.sort_by(|a, b| b.1.cmp(&a.1));
-    let top_trackers: Vec<TopTracker> = sorted_trackers
+    let top_trackers: Vec<TopTracker> = state
+        .metrics
+        .sketches()
+        .top_domains(since, Some(true), None, 5)
         .into_iter()
-        .take(5)
-        .map(|(domain, blocked_count)| TopTracker {
-            domain,
-            blocked_count,
+        .map(|hitter| TopTracker {
+            domain: hitter.item,
+            blocked_count: hitter.count,
         })
         .collect();
 
-    // Calculate privacy score based on block rate
-    let block_rate = if snapshot.total_queries > 0 {
-        snapshot.blocked_queries as f64 / snapshot.total_queries as f64
-    } else {
-        0.0
-    };
-    let privacy_score = (70.0 + block_rate * 30.0).min(100.0) as u32;
+    // Score is based on the block rate over the window
+    let score = |counts: &RollupCounts| (70.0 + counts.block_rate() * 30.0).min(100.0);
+    let mut totals = RollupCounts::default();
+    for point in &series {
+        totals.add(&point.counts);
+    }
+    let privacy_score = score(&totals) as u32;
 
     let privacy_grade = match privacy_score {
         90..=100 => "A",
@@ -2227,29 +4742,34 @@ pub async fn get_privacy_metrics(State(state): State<Arc<AppState>>) -> Json<Pri
     }
     .to_string();
 
-    // Generate trend data (last 24 hours)
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("system time before Unix epoch")
-        .as_secs();
-    let trend_data: Vec<PrivacyTrendPoint> = (0..24)
-        .map(|i| {
-            let hour_ago = now - (23 - i) * 3600;
-            let time = chrono::DateTime::from_timestamp(hour_ago as i64, 0)
+    // Hourly trend over the last 24 hours, skipping hours without queries
+    let trend_data: Vec<PrivacyTrendPoint> = series
+        .iter()
+        .filter(|p| p.counts.queries > 0)
+        .map(|p| {
+            let time = chrono::DateTime::from_timestamp(p.timestamp as i64, 0)
                 .map(|dt| dt.format("%I %p").to_string())
-                .unwrap_or_else(|| format!("{}h", i));
+                .unwrap_or_default();
             PrivacyTrendPoint {
                 time,
-                score: (privacy_score as f64) + (rand::random::<f64>() - 0.5) * 10.0,
+                score: score(&p.counts),
             }
         })
         .collect();
 
-    // Categorize blocked domains (simplified estimation)
-    let ad_blocked = snapshot.blocked_queries * 60 / 100;
-    let analytics_blocked = snapshot.blocked_queries * 25 / 100;
-    let social_blocked = snapshot.blocked_queries * 10 / 100;
-    let other_blocked = snapshot.blocked_queries - ad_blocked - analytics_blocked - social_blocked;
+    // Blocked queries by the category of the list that blocked them
+    let blocked_in = |category: &str| {
+        categories
+            .iter()
+            .find(|(name, _)| name == category)
+            .map_or(0, |(_, counts)| counts.blocked)
+    };
+    let ad_blocked = blocked_in("ads");
+    let analytics_blocked = blocked_in("tracking");
+    let social_blocked = blocked_in("social");
+    let other_blocked = totals
+        .blocked
+        .saturating_sub(ad_blocked + analytics_blocked + social_blocked);
 
     let tracker_categories = vec![
         TrackerCategory {
@@ -2270,16 +4790,16 @@ pub async fn get_privacy_metrics(State(state): State<Arc<AppState>>) -> Json<Pri
         },
     ];
 
-    Json(PrivacyMetrics {
+    Ok(Json(PrivacyMetrics {
         privacy_score,
-        trackers_blocked: snapshot.blocked_queries,
+        trackers_blocked: totals.blocked,
         ad_requests_blocked: ad_blocked,
         analytics_blocked,
         privacy_grade,
         trend_data,
         tracker_categories,
         top_trackers,
-    })
+    }))
 }
 
 // ============================================================================
@@ -2290,95 +4810,509 @@ pub async fn get_privacy_metrics(State(state): State<Arc<AppState>>) -> Json<Pri
 pub struct Device {
     pub id: String,
     pub name: String,
+    /// First of the device's IPs, or empty if it has none
     pub ip_address: String,
     pub mac_address: Option<String>,
     #[serde(rename = "type")]
-    pub device_type: String,
+    pub device_type: DeviceType,
+    pub ips: Vec<std::net::IpAddr>,
+    pub cidrs: Vec<String>,
+    pub client_ids: Vec<String>,
+    pub macs: Vec<String>,
+    pub registration_id: Option<uuid::Uuid>,
+    pub first_seen: u64,
     pub last_seen: u64,
     pub query_count: u64,
     pub blocked_count: u64,
+    /// Name of the assigned profile
     pub profile: Option<String>,
+    pub profile_id: Option<uuid::Uuid>,
     pub online: bool,
 }
 
-#[derive(Serialize)]
-pub struct DevicesResponse {
-    pub devices: Vec<Device>,
+#[derive(Serialize)]
+pub struct DevicesResponse {
+    pub devices: Vec<Device>,
+}
+
+/// Query and block counts per client IP from recent history
+type DeviceQueryStats = HashMap<String, (u64, u64)>;
+
+fn device_response(state: &AppState, device: NetworkDevice, stats: &DeviceQueryStats) -> Device {
+    let (query_count, blocked_count) = device
+        .ips
+        .iter()
+        .filter_map(|ip| stats.get(&ip.to_string()))
+        .fold((0, 0), |(q, b), (queries, blocked)| {
+            (q + queries, b + blocked)
+        });
+    let profile = state.profiles.profile_for_device(&device);
+    let online = Utc::now() - device.last_seen < chrono::Duration::minutes(5);
+
+    Device {
+        id: device.id.to_string(),
+        name: device.name,
+        ip_address: device
+            .ips
+            .first()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default(),
+        mac_address: device.macs.first().cloned(),
+        device_type: device.device_type,
+        ips: device.ips,
+        cidrs: device.cidrs.iter().map(|net| net.to_string()).collect(),
+        client_ids: device.client_ids,
+        macs: device.macs,
+        registration_id: device.registration_id,
+        first_seen: device.first_seen.timestamp().max(0) as u64,
+        last_seen: device.last_seen.timestamp().max(0) as u64,
+        query_count,
+        blocked_count,
+        profile: profile.as_ref().map(|p| p.name.clone()),
+        profile_id: profile.map(|p| p.id),
+        online,
+    }
+}
+
+fn device_query_stats(state: &AppState) -> DeviceQueryStats {
+    let mut stats = DeviceQueryStats::new();
+    for query in state.metrics.get_query_history(1000) {
+        let entry = stats.entry(query.client_ip).or_insert((0, 0));
+        entry.0 += 1;
+        if query.blocked {
+            entry.1 += 1;
+        }
+    }
+    stats
+}
+
+/// Record a DNS client in the device registry
+fn observe_client(state: &AppState, client_ip: Option<std::net::IpAddr>) -> Option<uuid::Uuid> {
+    let ip = client_ip?;
+    let (id, discovered) = state.profiles.devices().observe(ip, Utc::now())?;
+    if discovered {
+        let profile = state
+            .profiles
+            .devices()
+            .get(&id)
+            .and_then(|d| state.profiles.profile_for_device(&d));
+        publish_device_event(
+            state,
+            DeviceEventKind::Discovered,
+            &id.to_string(),
+            None,
+            Some(ip),
+            profile.map(|p| p.id),
+        );
+    }
+    Some(id)
+}
+
+/// Tell `/ws` subscribers about a device change
+fn publish_device_event(
+    state: &AppState,
+    event: DeviceEventKind,
+    device_id: &str,
+    name: Option<&str>,
+    ip: Option<std::net::IpAddr>,
+    profile_id: Option<uuid::Uuid>,
+) {
+    state.live.publish(|| {
+        LiveEvent::Device(DeviceEvent {
+            event,
+            timestamp: Utc::now().timestamp().max(0) as u64,
+            device_id: device_id.to_string(),
+            name: name.map(str::to_string),
+            ip: ip.map(|ip| ip.to_string()),
+            profile_id: profile_id.map(|id| id.to_string()),
+        })
+    });
+}
+
+/// Whether the caller may see and change a device: they added or claimed
+/// it, or it's on one of their profiles. Devices nobody has claimed and on
+/// no profile are open to administrators, and to callers on the device's
+/// local network so they can claim it.
+fn can_manage_device(
+    state: &AppState,
+    user_id: &str,
+    client_ip: Option<std::net::IpAddr>,
+    device: &NetworkDevice,
+) -> bool {
+    if device.user_id.as_deref() == Some(user_id) {
+        return true;
+    }
+    match state.profiles.profile_for_device(device) {
+        Some(profile) => owns_profile(state, user_id, &profile.id),
+        None => {
+            device.user_id.is_none()
+                && (state.is_admin(user_id)
+                    || client_ip.is_some_and(|ip| device.shares_local_network(ip)))
+        }
+    }
+}
+
+/// List the registry devices the caller may manage
+pub async fn get_devices(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    ClientIp(client_ip): ClientIp,
+) -> Json<DevicesResponse> {
+    let stats = device_query_stats(&state);
+    let devices = state
+        .profiles
+        .devices()
+        .list()
+        .into_iter()
+        .filter(|device| can_manage_device(&state, &claims.sub, client_ip, device))
+        .map(|device| device_response(&state, device, &stats))
+        .collect();
+
+    Json(DevicesResponse { devices })
 }
 
+/// Device fields to set; omitted fields keep their value
 #[derive(Deserialize)]
 pub struct UpdateDeviceRequest {
     pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub device_type: Option<DeviceType>,
+    pub ips: Option<Vec<std::net::IpAddr>>,
+    pub cidrs: Option<Vec<String>>,
+    pub client_ids: Option<Vec<String>>,
+    pub macs: Option<Vec<String>>,
+    /// App registration ID; an empty string unlinks it
+    pub registration_id: Option<String>,
+    /// Profile ID to assign; an empty string unassigns the device
     pub profile: Option<String>,
 }
 
-/// Get all detected devices
-pub async fn get_devices(State(state): State<Arc<AppState>>) -> Json<DevicesResponse> {
-    let history = state.metrics.get_query_history(1000);
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("system time before Unix epoch")
-        .as_secs();
-
-    // Aggregate device stats from query history by client IP
-    let mut device_stats: HashMap<String, (u64, u64, u64)> = HashMap::new(); // (queries, blocked, last_seen)
-    for query in &history {
-        let entry = device_stats
-            .entry(query.client_ip.clone())
-            .or_insert((0, 0, 0));
-        entry.0 += 1;
-        if query.blocked {
-            entry.1 += 1;
+#[derive(Serialize)]
+pub struct DeviceResponse {
+    pub success: bool,
+    pub message: String,
+    pub device: Device,
+}
+
+fn device_error(
+    status: StatusCode,
+    error: &str,
+    message: &str,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            message: message.to_string(),
+        }),
+    )
+}
+
+/// Apply a create or update request to a device and save it, claiming it
+/// for the caller if nobody has; the app registration and profile must be
+/// the caller's
+fn save_device(
+    state: &AppState,
+    user_id: &str,
+    mut device: NetworkDevice,
+    request: UpdateDeviceRequest,
+) -> Result<NetworkDevice, (StatusCode, Json<ErrorResponse>)> {
+    if let Some(name) = request.name {
+        if name.trim().is_empty() {
+            return Err(device_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_name",
+                "Device name cannot be empty",
+            ));
+        }
+        device.name = name.trim().to_string();
+    }
+    if let Some(device_type) = request.device_type {
+        device.device_type = device_type;
+    }
+    if let Some(ips) = request.ips {
+        device.ips = ips;
+    }
+    if let Some(cidrs) = request.cidrs {
+        device.cidrs = cidrs
+            .iter()
+            .map(|net| {
+                net.trim().parse().map_err(|_| {
+                    device_error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_cidr",
+                        &format!("Invalid CIDR range '{}'", net),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(client_ids) = request.client_ids {
+        device.client_ids = client_ids;
+    }
+    if let Some(macs) = request.macs {
+        device.macs = macs;
+    }
+    if let Some(registration_id) = request.registration_id {
+        device.registration_id = if registration_id.trim().is_empty() {
+            None
+        } else {
+            let registered = state
+                .db
+                .get_device(registration_id.trim())
+                .ok()
+                .flatten()
+                .filter(|d| d.user_id == user_id);
+            let id = registered
+                .and_then(|d| uuid::Uuid::parse_str(&d.id).ok())
+                .ok_or_else(|| {
+                    device_error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_registration",
+                        "App registration not found",
+                    )
+                })?;
+            Some(id)
+        };
+    }
+    let profile_id = match request.profile.as_deref().map(str::trim) {
+        Some("") | None => None,
+        Some(id) => {
+            let id = uuid::Uuid::parse_str(id)
+                .ok()
+                .filter(|id| {
+                    state.profiles.get_profile(id).is_some() && owns_profile(state, user_id, id)
+                })
+                .ok_or_else(|| {
+                    device_error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_profile",
+                        "Profile not found",
+                    )
+                })?;
+            Some(id)
+        }
+    };
+
+    device.user_id.get_or_insert_with(|| user_id.to_string());
+    let device = state.profiles.save_device(device).map_err(|e| match e {
+        DeviceError::NotFound(_) => {
+            device_error(StatusCode::NOT_FOUND, "not_found", &e.to_string())
         }
-        if query.timestamp > entry.2 {
-            entry.2 = query.timestamp;
+        _ => device_error(StatusCode::CONFLICT, "device_conflict", &e.to_string()),
+    })?;
+    let device_id = device.id.to_string();
+    match (request.profile, profile_id) {
+        (_, Some(profile_id)) => {
+            state.profiles.assign_device(device_id, &profile_id);
+        }
+        (Some(_), None) => {
+            state.profiles.unassign_device(&device_id);
         }
+        (None, None) => {}
     }
+    Ok(device)
+}
 
-    let devices: Vec<Device> = device_stats
-        .into_iter()
-        .enumerate()
-        .map(|(idx, (ip, (queries, blocked, last_seen)))| {
-            let is_online = now - last_seen < 300; // Online if seen in last 5 minutes
-            Device {
-                id: format!("{}", idx + 1),
-                name: format!("Device {}", idx + 1),
-                ip_address: ip,
-                mac_address: None,
-                device_type: "other".to_string(),
-                last_seen,
-                query_count: queries,
-                blocked_count: blocked,
-                profile: Some("Default".to_string()),
-                online: is_online,
-            }
-        })
-        .collect();
+/// Add a device to the registry
+pub async fn create_device(
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Json(request): Json<UpdateDeviceRequest>,
+) -> Result<(StatusCode, Json<DeviceResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let name = request.name.clone().unwrap_or_default();
+    let device = save_device(
+        &state,
+        &claims.sub,
+        NetworkDevice::new(name, DeviceType::Other),
+        request,
+    )?;
+    publish_device_event(
+        &state,
+        DeviceEventKind::Created,
+        &device.id.to_string(),
+        Some(&device.name),
+        device.ips.first().copied(),
+        state.profiles.profile_for_device(&device).map(|p| p.id),
+    );
 
-    Json(DevicesResponse { devices })
+    let stats = device_query_stats(&state);
+    Ok((
+        StatusCode::CREATED,
+        Json(DeviceResponse {
+            success: true,
+            message: format!("Device '{}' created", device.name),
+            device: device_response(&state, device, &stats),
+        }),
+    ))
 }
 
-/// Update device settings
+/// Update a device's name, type, identifiers, app registration or profile
 pub async fn update_device(
     Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    ClientIp(client_ip): ClientIp,
     Json(request): Json<UpdateDeviceRequest>,
-) -> Json<DeviceUpdateResponse> {
-    // In a real implementation, this would update a database
-    // For now, we just acknowledge the request
-    Json(DeviceUpdateResponse {
+) -> Result<Json<DeviceResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let device = uuid::Uuid::parse_str(&id)
+        .ok()
+        .and_then(|id| state.profiles.devices().get(&id))
+        .filter(|d| can_manage_device(&state, &claims.sub, client_ip, d))
+        .ok_or_else(|| device_error(StatusCode::NOT_FOUND, "not_found", "Device not found"))?;
+    let device = save_device(&state, &claims.sub, device, request)?;
+    publish_device_event(
+        &state,
+        DeviceEventKind::Updated,
+        &device.id.to_string(),
+        Some(&device.name),
+        device.ips.first().copied(),
+        state.profiles.profile_for_device(&device).map(|p| p.id),
+    );
+
+    let stats = device_query_stats(&state);
+    Ok(Json(DeviceResponse {
         success: true,
         message: format!("Device {} updated", id),
-        name: request.name,
-        profile: request.profile,
-    })
+        device: device_response(&state, device, &stats),
+    }))
+}
+
+/// Remove a device from the registry
+pub async fn delete_device(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    ClientIp(client_ip): ClientIp,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let device = uuid::Uuid::parse_str(&id)
+        .ok()
+        .and_then(|id| state.profiles.devices().get(&id))
+        .filter(|d| can_manage_device(&state, &claims.sub, client_ip, d));
+    // Look up the owner before the assignment goes with the device
+    let profile = device
+        .as_ref()
+        .and_then(|d| state.profiles.profile_for_device(d));
+    if device.is_some_and(|d| state.profiles.delete_device(&d.id)) {
+        publish_device_event(
+            &state,
+            DeviceEventKind::Deleted,
+            &id,
+            None,
+            None,
+            profile.map(|p| p.id),
+        );
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(device_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            "Device not found",
+        ))
+    }
+}
+
+// ============================================================================
+// Device and Profile Statistics
+// ============================================================================
+
+#[derive(Deserialize)]
+pub struct ClientStatsParams {
+    /// Window in hours (default 24, at most 90 days)
+    pub hours: Option<u32>,
+    /// Entries per list (default 10, at most 100)
+    pub limit: Option<usize>,
 }
 
 #[derive(Serialize)]
-pub struct DeviceUpdateResponse {
-    pub success: bool,
-    pub message: String,
-    pub name: Option<String>,
-    pub profile: Option<String>,
+pub struct ClientStatsResponse {
+    pub id: String,
+    pub name: String,
+    pub hours: u32,
+    #[serde(flatten)]
+    pub stats: ClientStats,
+}
+
+/// Read a device's or profile's stats over the requested window
+async fn read_client_stats(
+    state: &AppState,
+    dimension: Dimension,
+    key: String,
+    params: &ClientStatsParams,
+) -> Result<(u32, ClientStats), (StatusCode, Json<ErrorResponse>)> {
+    let hours = params.hours.unwrap_or(24).clamp(1, MAX_ANALYTICS_HOURS);
+    let limit = params.limit.unwrap_or(10).clamp(1, 100);
+    let now = unix_now();
+    let since = now.saturating_sub(hours as u64 * 3600);
+    let stats = read_rollups(state, move |rollups| {
+        rollups.client_stats(dimension, &key, since, now + 1, limit)
+    })
+    .await?;
+    Ok((hours, stats))
+}
+
+/// Query counts, top domains and categories for a registry device on one
+/// of the caller's profiles
+pub async fn get_device_stats(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Query(params): Query<ClientStatsParams>,
+) -> Result<Json<ClientStatsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let device = uuid::Uuid::parse_str(&id)
+        .ok()
+        .and_then(|id| state.profiles.devices().get(&id))
+        .filter(|device| {
+            state
+                .profiles
+                .profile_for_device(device)
+                .is_some_and(|p| owns_profile(&state, &claims.sub, &p.id))
+        })
+        .ok_or_else(|| device_error(StatusCode::NOT_FOUND, "not_found", "Device not found"))?;
+    let (hours, mut stats) =
+        read_client_stats(&state, Dimension::Device, device.id.to_string(), &params).await?;
+
+    // The registry knows about queries made before the device had stats
+    let seen = device.last_seen.timestamp().max(0) as u64;
+    stats.last_seen = stats.last_seen.max(Some(seen));
+
+    Ok(Json(ClientStatsResponse {
+        id: device.id.to_string(),
+        name: device.name,
+        hours,
+        stats,
+    }))
+}
+
+/// Query counts, top domains and categories for the devices on one of the
+/// caller's profiles
+pub async fn get_profile_stats(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    axum::Extension(claims): axum::Extension<Claims>,
+    Query(params): Query<ClientStatsParams>,
+) -> Result<Json<ClientStatsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let uuid = uuid::Uuid::parse_str(&id).map_err(|_| {
+        device_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_id",
+            "Invalid profile ID format",
+        )
+    })?;
+    let profile = state
+        .profiles
+        .get_profile(&uuid)
+        .filter(|_| owns_profile(&state, &claims.sub, &uuid))
+        .ok_or_else(|| device_error(StatusCode::NOT_FOUND, "not_found", "Profile not found"))?;
+    let (hours, stats) =
+        read_client_stats(&state, Dimension::Profile, uuid.to_string(), &params).await?;
+
+    Ok(Json(ClientStatsResponse {
+        id: uuid.to_string(),
+        name: profile.name,
+        hours,
+        stats,
+    }))
 }
 
 // ============================================================================
@@ -2391,6 +5325,17 @@ use shield_auth::{
     UpdatePushTokenRequest, UserInfo,
 };
 
+/// Claims of a valid bearer token, for public routes that show more to
+/// signed-in callers
+fn bearer_claims(state: &AppState, headers: &axum::http::HeaderMap) -> Option<Claims> {
+    let token = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    state.auth.validate_token(token).ok()
+}
+
 /// Authentication middleware for protected routes
 pub async fn auth_middleware(
     State(state): State<Arc<AppState>>,
@@ -2578,13 +5523,31 @@ pub async fn auth_register_device(
     Json(request): Json<DeviceRegistrationRequest>,
 ) -> (StatusCode, Json<serde_json::Value>) {
     match state.auth.register_device(&claims.sub, request) {
-        Ok(device) => (
-            StatusCode::CREATED,
-            Json(serde_json::json!({
-                "success": true,
-                "device": device
-            })),
-        ),
+        Ok(device) => {
+            // Track the app's device in the registry, linked to the registration
+            let mut network_device = NetworkDevice::new(
+                device.device_name.clone(),
+                match device.platform {
+                    shield_auth::Platform::iOS | shield_auth::Platform::Android => {
+                        DeviceType::Phone
+                    }
+                    shield_auth::Platform::Desktop => DeviceType::Computer,
+                    _ => DeviceType::Other,
+                },
+            );
+            network_device.registration_id = Some(device.device_id);
+            if let Err(e) = state.profiles.save_device(network_device) {
+                warn!("Failed to add registered device to the registry: {}", e);
+            }
+
+            (
+                StatusCode::CREATED,
+                Json(serde_json::json!({
+                    "success": true,
+                    "device": device
+                })),
+            )
+        }
         Err(e) => {
             warn!("Device registration failed: {}", e);
             (
@@ -2642,3 +5605,758 @@ pub async fn auth_update_push_token(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Extension;
+
+    /// Register an account and return the claims of a fresh login
+    fn login(state: &AppState, email: &str) -> Claims {
+        state.auth.register(email, "correct-horse-battery").unwrap();
+        let tokens = state.auth.login(email, "correct-horse-battery").unwrap();
+        state.auth.validate_token(&tokens.access_token).unwrap()
+    }
+
+    /// Status a handler responded with
+    fn status<T>(result: Result<T, (StatusCode, Json<ErrorResponse>)>) -> StatusCode {
+        result.map_or_else(|(status, _)| status, |_| StatusCode::OK)
+    }
+
+    async fn create_test_profile(state: &Arc<AppState>, claims: &Claims, name: &str) -> uuid::Uuid {
+        let Json(created) = create_profile(
+            State(state.clone()),
+            Extension(claims.clone()),
+            Json(CreateProfileRequest {
+                name: name.to_string(),
+                protection_level: ProtectionLevel::Kid,
+            }),
+        )
+        .await;
+        created.profile.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn test_created_profile_is_owned_by_caller() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+
+        let Json(listed) = list_profiles(State(state.clone()), Extension(parent.clone())).await;
+        assert_eq!(listed.iter().map(|p| p.id).collect::<Vec<_>>(), vec![id]);
+        let Json(listed) = list_profiles(State(state.clone()), Extension(stranger.clone())).await;
+        assert!(listed.is_empty());
+
+        let query = |timestamp: u64, blocked: bool| QueryLogEntry {
+            timestamp,
+            domain: "games.example".to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            profile_id: Some(id.to_string()),
+            blocked,
+            category: blocked.then(|| "gaming".to_string()),
+            ..Default::default()
+        };
+        state.metrics.record_query_entry(query(unix_now(), false));
+        state.metrics.record_query_entry(query(unix_now(), true));
+
+        let stats = |claims: Claims| {
+            get_profile_stats(
+                Path(id.to_string()),
+                State(state.clone()),
+                Extension(claims),
+                Query(ClientStatsParams {
+                    hours: None,
+                    limit: None,
+                }),
+            )
+        };
+        let Ok(Json(own)) = stats(parent.clone()).await else {
+            panic!("owner can't read the profile's stats");
+        };
+        assert_eq!(own.name, "Kids");
+        assert_eq!((own.stats.total_queries, own.stats.blocked_queries), (2, 1));
+        assert_eq!(status(stats(stranger.clone()).await), StatusCode::NOT_FOUND);
+
+        // Other accounts can neither read, change nor delete the profile
+        let read = get_profile(
+            Path(id.to_string()),
+            State(state.clone()),
+            Extension(stranger.clone()),
+        )
+        .await;
+        assert_eq!(status(read), StatusCode::NOT_FOUND);
+        let Json(deleted) = delete_profile(
+            Path(id.to_string()),
+            State(state.clone()),
+            Extension(stranger),
+        )
+        .await;
+        assert!(!deleted.success);
+        assert!(state.profiles.get_profile(&id).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_assign_owned_profile_to_range_and_device() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+
+        let assign = |claims: Claims, cidr: &str| {
+            assign_range(
+                State(state.clone()),
+                Extension(claims),
+                Json(AssignRangeRequest {
+                    cidr: cidr.to_string(),
+                    profile_id: id,
+                }),
+            )
+        };
+        assert_eq!(
+            status(assign(stranger.clone(), "10.0.0.0/24").await),
+            StatusCode::NOT_FOUND
+        );
+        let Ok(Json(assigned)) = assign(parent.clone(), "10.0.1.7/24").await else {
+            panic!("owner can't assign a range to their profile");
+        };
+        assert_eq!(assigned.range, "10.0.1.0/24");
+        assert_eq!(
+            state
+                .unified_filter
+                .profile_id_for_client(Some("10.0.1.50".parse().unwrap())),
+            id.to_string()
+        );
+
+        let request = UpdateDeviceRequest {
+            name: Some("Tablet".to_string()),
+            device_type: Some(DeviceType::Tablet),
+            ips: Some(vec!["192.168.1.30".parse().unwrap()]),
+            cidrs: None,
+            client_ids: None,
+            macs: None,
+            registration_id: None,
+            profile: Some(id.to_string()),
+        };
+        let Ok((_, Json(created))) =
+            create_device(State(state.clone()), Extension(parent), Json(request)).await
+        else {
+            panic!("owner can't put a device on their profile");
+        };
+        assert_eq!(created.device.profile_id, Some(id));
+    }
+
+    #[tokio::test]
+    async fn test_devices_scoped_to_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let neighbour = login(&state, "neighbour@example.com");
+        let admin = login(&state, "admin@example.com");
+        let (id, _) = state
+            .profiles
+            .devices()
+            .observe("192.168.1.20".parse().unwrap(), Utc::now())
+            .unwrap();
+        let lan = |ip: &str| ClientIp(Some(ip.parse().unwrap()));
+
+        let listed = |claims: &Claims, client_ip: ClientIp| {
+            get_devices(State(state.clone()), Extension(claims.clone()), client_ip)
+        };
+        let ids = |Json(response): Json<DevicesResponse>| -> Vec<String> {
+            response.devices.into_iter().map(|d| d.id).collect()
+        };
+        // Unclaimed devices are only open to administrators and the LAN
+        assert!(ids(listed(&parent, ClientIp(None)).await).is_empty());
+        assert!(ids(listed(&parent, lan("192.168.2.5")).await).is_empty());
+        assert_eq!(
+            ids(listed(&admin, ClientIp(None)).await),
+            vec![id.to_string()]
+        );
+        assert_eq!(
+            ids(listed(&parent, lan("192.168.1.5")).await),
+            vec![id.to_string()]
+        );
+
+        // Naming it from the LAN claims it
+        let update = |claims: &Claims| {
+            update_device(
+                Path(id.to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+                lan("192.168.1.5"),
+                Json(serde_json::from_value(serde_json::json!({"name": "Tablet"})).unwrap()),
+            )
+        };
+        assert_eq!(status(update(&parent).await), StatusCode::OK);
+        let device = state.profiles.devices().get(&id).unwrap();
+        assert_eq!(device.user_id.as_deref(), Some(parent.sub.as_str()));
+        assert_eq!(
+            ids(listed(&parent, ClientIp(None)).await),
+            vec![id.to_string()]
+        );
+
+        // Once claimed, nobody else on the network can see or change it
+        assert!(ids(listed(&neighbour, lan("192.168.1.6")).await).is_empty());
+        assert_eq!(status(update(&neighbour).await), StatusCode::NOT_FOUND);
+        let deleted = delete_device(
+            Path(id.to_string()),
+            State(state.clone()),
+            Extension(neighbour.clone()),
+            lan("192.168.1.6"),
+        )
+        .await;
+        assert_eq!(status(deleted), StatusCode::NOT_FOUND);
+
+        // Devices someone adds are theirs from the start
+        let request = serde_json::from_value(serde_json::json!({"name": "Laptop"})).unwrap();
+        let Ok((_, Json(created))) = create_device(
+            State(state.clone()),
+            Extension(neighbour.clone()),
+            Json(request),
+        )
+        .await
+        else {
+            panic!("device creation failed");
+        };
+        assert!(!ids(listed(&parent, lan("192.168.1.5")).await).contains(&created.device.id));
+        assert!(ids(listed(&neighbour, ClientIp(None)).await).contains(&created.device.id));
+    }
+
+    #[tokio::test]
+    async fn test_profiles_stay_within_account() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let kids = create_test_profile(&state, &parent, "Kids").await;
+        let teens = create_test_profile(&state, &parent, "Teens").await;
+        let other = create_test_profile(&state, &stranger, "Other").await;
+
+        let set_parent = |child: uuid::Uuid, parent_id: uuid::Uuid| {
+            update_profile(
+                Path(child.to_string()),
+                State(state.clone()),
+                Extension(parent.clone()),
+                Json(serde_json::from_value(serde_json::json!({"parent_id": parent_id})).unwrap()),
+            )
+        };
+        assert_eq!(
+            status(set_parent(teens, other).await),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(set_parent(teens, kids).await), StatusCode::OK);
+
+        let assign = |claims: &Claims, profile_id: uuid::Uuid| {
+            assign_device(
+                State(state.clone()),
+                Extension(claims.clone()),
+                Json(AssignDeviceRequest {
+                    device_id: "10.0.1.20".to_string(),
+                    profile_id: profile_id.to_string(),
+                }),
+            )
+        };
+        assert!(!assign(&stranger, kids).await.success);
+        assert!(assign(&parent, kids).await.success);
+        // Another account can't pull the device onto its own profile
+        assert!(!assign(&stranger, other).await.success);
+        assert_eq!(
+            state.profiles.get_device_profile("10.0.1.20").unwrap().id,
+            kids
+        );
+        assert!(assign(&parent, teens).await.success);
+    }
+
+    #[tokio::test]
+    async fn test_profile_views_limited_to_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+
+        let schedule = |claims: &Claims| {
+            preview_profile_schedule(
+                Path(id.to_string()),
+                Query(serde_json::from_value(serde_json::json!({})).unwrap()),
+                State(state.clone()),
+                Extension(claims.clone()),
+            )
+        };
+        assert_eq!(status(schedule(&parent).await), StatusCode::OK);
+        assert_eq!(status(schedule(&stranger).await), StatusCode::NOT_FOUND);
+
+        let usage = |claims: &Claims| {
+            get_profile_usage(
+                Path(id.to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+            )
+        };
+        assert_eq!(status(usage(&parent).await), StatusCode::OK);
+        assert_eq!(status(usage(&stranger).await), StatusCode::NOT_FOUND);
+
+        let effective = |claims: &Claims| {
+            get_effective_profile(
+                Path(id.to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+            )
+        };
+        assert_eq!(status(effective(&parent).await), StatusCode::OK);
+        assert_eq!(status(effective(&stranger).await), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_filtered_trends_need_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+        state.unified_filter.assign_profile_to_ip(
+            "192.168.1.20".parse().unwrap(),
+            DeviceProfile {
+                id: id.to_string(),
+                ..Default::default()
+            },
+        );
+        state.metrics.record_query_entry(QueryLogEntry {
+            timestamp: unix_now(),
+            domain: "games.example".to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            profile_id: Some(id.to_string()),
+            ..Default::default()
+        });
+
+        let params = |client: Option<&str>| TrendsParams {
+            period: None,
+            granularity: None,
+            client: client.map(str::to_string),
+            profile: None,
+            category: None,
+        };
+        let public =
+            get_query_trends(State(state.clone()), Query(params(Some("192.168.1.20")))).await;
+        assert_eq!(status(public), StatusCode::BAD_REQUEST);
+
+        let client = |claims: &Claims| {
+            get_client_trends(
+                Path("192.168.1.20".to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+                Query(params(None)),
+            )
+        };
+        let Ok(Json(trends)) = client(&parent).await else {
+            panic!("owner can't read the client's trends");
+        };
+        let total: u64 = trends.total_queries.iter().map(|p| p.value).sum();
+        assert_eq!(total, 1);
+        assert_eq!(status(client(&stranger).await), StatusCode::NOT_FOUND);
+
+        let profile = |claims: &Claims| {
+            get_profile_trends(
+                Path(id.to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+                Query(params(None)),
+            )
+        };
+        assert_eq!(status(profile(&parent).await), StatusCode::OK);
+        assert_eq!(status(profile(&stranger).await), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_client_top_domains_need_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+        state.unified_filter.assign_profile_to_ip(
+            "192.168.1.20".parse().unwrap(),
+            DeviceProfile {
+                id: id.to_string(),
+                ..Default::default()
+            },
+        );
+        state.metrics.record_query_entry(QueryLogEntry {
+            timestamp: unix_now(),
+            domain: "games.example".to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            ..Default::default()
+        });
+
+        let params = |client: Option<&str>| TopDomainsParams {
+            hours: None,
+            client: client.map(str::to_string),
+            decision: None,
+            limit: None,
+        };
+        let public =
+            get_top_domains(State(state.clone()), Query(params(Some("192.168.1.20")))).await;
+        assert_eq!(status(public), StatusCode::BAD_REQUEST);
+
+        let top = |claims: &Claims| {
+            get_client_top_domains(
+                Path("192.168.1.20".to_string()),
+                State(state.clone()),
+                Extension(claims.clone()),
+                Query(params(None)),
+            )
+        };
+        let Ok(Json(own)) = top(&parent).await else {
+            panic!("owner can't read the client's top domains");
+        };
+        assert_eq!(own.domains[0].item, "games.example");
+        assert_eq!(status(top(&stranger).await), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_check_as_client_needs_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        login(&state, "stranger@example.com");
+        login(&state, "admin@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+        state.unified_filter.assign_profile_to_ip(
+            "192.168.1.20".parse().unwrap(),
+            DeviceProfile {
+                id: id.to_string(),
+                name: "Kids".to_string(),
+                ..Default::default()
+            },
+        );
+
+        let check = |email: Option<&str>| {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(email) = email {
+                let tokens = state.auth.login(email, "correct-horse-battery").unwrap();
+                let bearer = format!("Bearer {}", tokens.access_token);
+                headers.insert(header::AUTHORIZATION, bearer.parse().unwrap());
+            }
+            check_domain_blocking(
+                Path("example.com".to_string()),
+                Query(BlockCheckQuery {
+                    client_ip: Some("192.168.1.20".to_string()),
+                    qtype: None,
+                }),
+                State(state.clone()),
+                ClientIp(Some("10.9.9.9".parse().unwrap())),
+                headers,
+            )
+        };
+        let profile = |result: Result<Json<BlockCheckResponse>, _>| {
+            let Ok(Json(response)) = result else {
+                panic!("check failed");
+            };
+            response.profile_name
+        };
+        let kids = Some("Kids".to_string());
+        assert_ne!(profile(check(None).await), kids);
+        assert_ne!(profile(check(Some("stranger@example.com")).await), kids);
+        assert_eq!(profile(check(Some("parent@example.com")).await), kids);
+        assert_eq!(profile(check(Some("admin@example.com")).await), kids);
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_sources_require_admin() {
+        let state = Arc::new(AppState::for_tests().await);
+        let user = login(&state, "user@example.com");
+        let admin = login(&state, "admin@example.com");
+        let request = || BlocklistSourceRequest {
+            name: "Extra ads".to_string(),
+            url: "https://lists.example.com/ads.txt".to_string(),
+            category: "ads".to_string(),
+            format: default_source_format(),
+            enabled: false,
+            description: String::new(),
+            sha256: None,
+            max_bytes: None,
+            min_entries: None,
+        };
+
+        let denied = create_blocklist_source(
+            State(state.clone()),
+            Extension(user.clone()),
+            Json(request()),
+        )
+        .await;
+        assert_eq!(status(denied), StatusCode::FORBIDDEN);
+        let validated = validate_blocklist_source(
+            State(state.clone()),
+            Extension(user.clone()),
+            Json(request()),
+        )
+        .await;
+        assert_eq!(status(validated), StatusCode::FORBIDDEN);
+
+        let Ok((StatusCode::CREATED, Json(record))) = create_blocklist_source(
+            State(state.clone()),
+            Extension(admin.clone()),
+            Json(request()),
+        )
+        .await
+        else {
+            panic!("administrator could not add a source");
+        };
+
+        let id = || Path(record.id.clone());
+        let updated = update_blocklist_source(
+            id(),
+            State(state.clone()),
+            Extension(user.clone()),
+            Json(request()),
+        )
+        .await;
+        assert_eq!(status(updated), StatusCode::FORBIDDEN);
+        let preview =
+            preview_blocklist_source(id(), State(state.clone()), Extension(user.clone())).await;
+        assert_eq!(status(preview), StatusCode::FORBIDDEN);
+        let fetched =
+            fetch_blocklist_source(id(), State(state.clone()), Extension(user.clone())).await;
+        assert_eq!(status(fetched), StatusCode::FORBIDDEN);
+        let deleted =
+            delete_blocklist_source(id(), State(state.clone()), Extension(user.clone())).await;
+        assert_eq!(status(deleted), StatusCode::FORBIDDEN);
+
+        let deleted = delete_blocklist_source(id(), State(state.clone()), Extension(admin)).await;
+        assert_eq!(status(deleted), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_overrides_limited_to_owned_profiles() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let admin = login(&state, "admin@example.com");
+        let id = create_test_profile(&state, &parent, "Kids")
+            .await
+            .to_string();
+
+        let pause = |claims: &Claims, profile_id: Option<&str>| {
+            pause_blocking(
+                State(state.clone()),
+                Extension(claims.clone()),
+                Json(PauseRequest {
+                    minutes: 15,
+                    profile_id: profile_id.map(str::to_string),
+                }),
+            )
+        };
+        assert_eq!(
+            status(pause(&stranger, Some(&id)).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(pause(&parent, None).await), StatusCode::FORBIDDEN);
+        assert_eq!(status(pause(&parent, Some(&id)).await), StatusCode::OK);
+        assert_eq!(status(pause(&admin, None).await), StatusCode::OK);
+
+        let Json(visible) =
+            get_pause_status(State(state.clone()), Extension(stranger.clone())).await;
+        assert!(visible.paused);
+        assert_eq!(visible.pauses.len(), 1);
+
+        let allow = |claims: &Claims, profile_id: Option<&str>| {
+            add_temporary_allow(
+                State(state.clone()),
+                Extension(claims.clone()),
+                Json(TemporaryAllowRequest {
+                    domain: "games.example".to_string(),
+                    minutes: 30,
+                    profile_id: profile_id.map(str::to_string),
+                }),
+            )
+        };
+        assert_eq!(
+            status(allow(&stranger, Some(&id)).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(allow(&stranger, None).await), StatusCode::FORBIDDEN);
+        assert_eq!(status(allow(&parent, Some(&id)).await), StatusCode::OK);
+        let remove = |claims: &Claims| {
+            remove_temporary_allow(
+                Path("games.example".to_string()),
+                Query(OverrideScopeQuery {
+                    profile_id: Some(id.clone()),
+                }),
+                State(state.clone()),
+                Extension(claims.clone()),
+            )
+        };
+        assert_eq!(status(remove(&stranger).await), StatusCode::NOT_FOUND);
+        let Json(listed) =
+            get_temporary_allowlist(State(state.clone()), Extension(stranger.clone())).await;
+        assert!(listed.entries.is_empty());
+        assert_eq!(status(remove(&parent).await), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_logged_query_found_by_owner() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids").await;
+        let update: UpdateProfileRequest =
+            serde_json::from_value(serde_json::json!({"custom_blocklist": ["games.example"]}))
+                .unwrap();
+        assert_eq!(
+            status(
+                update_profile(
+                    Path(id.to_string()),
+                    State(state.clone()),
+                    Extension(parent.clone()),
+                    Json(update),
+                )
+                .await
+            ),
+            StatusCode::OK
+        );
+        state.profiles.assign_device("10.0.1.0/24".to_string(), &id);
+
+        let Ok(Json(resolved)) = resolve_domain(
+            Path("games.example".to_string()),
+            State(state.clone()),
+            ClientIp(Some("10.0.1.20".parse().unwrap())),
+        )
+        .await
+        else {
+            panic!("blocked lookup failed");
+        };
+        assert!(resolved.blocked);
+
+        let search = |claims: Claims| {
+            search_query_log(
+                Query(
+                    serde_json::from_value(serde_json::json!({"suffix": "games.example"})).unwrap(),
+                ),
+                State(state.clone()),
+                Extension(claims),
+            )
+        };
+        // The writer flushes partial batches every second
+        let mut entries = Vec::new();
+        for _ in 0..50 {
+            let Ok(Json(page)) = search(parent.clone()).await else {
+                panic!("query log search failed");
+            };
+            entries = page.entries;
+            if !entries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].profile_id, Some(id.to_string()));
+        assert_eq!(entries[0].user_id, Some(parent.sub.clone()));
+        assert!(entries[0].blocked);
+
+        let export = |claims: Claims| async {
+            let response = export_query_log(
+                Query(serde_json::from_value(serde_json::json!({"format": "csv"})).unwrap()),
+                State(state.clone()),
+                Extension(claims),
+            )
+            .await
+            .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        let csv = export(parent).await;
+        assert_eq!(csv.lines().count(), 2);
+        assert!(csv.contains(",games.example,10.0.1.20,"));
+
+        let Ok(Json(page)) = search(stranger.clone()).await else {
+            panic!("query log search failed");
+        };
+        assert!(page.entries.is_empty());
+        assert_eq!(export(stranger).await, QUERY_LOG_CSV_HEADER);
+    }
+
+    #[tokio::test]
+    async fn test_live_events_for_owned_profiles() {
+        let state = Arc::new(AppState::for_tests().await);
+        let parent = login(&state, "parent@example.com");
+        let stranger = login(&state, "stranger@example.com");
+        let id = create_test_profile(&state, &parent, "Kids")
+            .await
+            .to_string();
+        assert!(owned_profile_ids(&state, &stranger.sub).is_empty());
+
+        // Subscriptions are scoped the way the socket handler scopes them
+        let now = Instant::now();
+        let subscribe = |claims: &Claims| {
+            let mut subs = Subscriptions::default();
+            subs.set_profiles(owned_profile_ids(&state, &claims.sub));
+            for topic in ["queries", "alerts"] {
+                let json = format!(r#"{{"type":"subscribe","topic":"{}"}}"#, topic);
+                subs.handle(serde_json::from_str(&json).unwrap(), now);
+            }
+            subs
+        };
+        let query = LiveEvent::Query(QueryLogEntry {
+            domain: "games.example".to_string(),
+            client_ip: "10.0.1.20".to_string(),
+            profile_id: Some(id.clone()),
+            ..Default::default()
+        });
+        let alert = LiveEvent::Alert(ThreatAlert {
+            timestamp: 0,
+            domain: "malware.test".to_string(),
+            category: "malware".to_string(),
+            client_ip: "10.0.1.20".to_string(),
+            profile_id: Some(id),
+        });
+
+        let mut subs = subscribe(&parent);
+        assert!(subs.accept(&query, now));
+        assert!(subs.accept(&alert, now));
+        let mut subs = subscribe(&stranger);
+        assert!(!subs.accept(&query, now));
+        assert!(!subs.accept(&alert, now));
+    }
+
+    #[tokio::test]
+    async fn test_history_is_anonymized() {
+        let state = Arc::new(AppState::for_tests().await);
+        for client_ip in ["192.168.1.20", "2001:db8:1:2::20", "unknown"] {
+            state.metrics.record_query_entry(QueryLogEntry {
+                domain: "games.example".to_string(),
+                client_ip: client_ip.to_string(),
+                answers: vec!["203.0.113.7".to_string()],
+                profile_id: Some("kids".to_string()),
+                device_id: Some("tablet".to_string()),
+                upstream: Some("1.1.1.1".to_string()),
+                ..Default::default()
+            });
+        }
+
+        let Json(history) = get_query_history(State(state)).await;
+        let mut clients: Vec<&str> = history
+            .queries
+            .iter()
+            .map(|q| q.client_ip.as_str())
+            .collect();
+        clients.sort();
+        assert_eq!(clients, vec!["", "192.168.1.0/24", "2001:db8:1::/48"]);
+        for query in &history.queries {
+            assert_eq!(query.domain, "games.example");
+            assert!(query.answers.is_empty());
+            assert_eq!(
+                (&query.profile_id, &query.device_id, &query.upstream),
+                (&None, &None, &None)
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("example.com"), "example.com");
+        assert_eq!(csv_field("=1+1"), "'=1+1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\t=cmd"), "'\t=cmd");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        // Prefixed first, then quoted as a whole
+        assert_eq!(csv_field("\r=1,\"x\""), "\"'\r=1,\"\"x\"\"\"");
+    }
+}
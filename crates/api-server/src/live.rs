@@ -0,0 +1,456 @@
+//! Live events for WebSocket subscribers
+//!
+//! Answered queries, threat alerts and device changes are published to a
+//! broadcast channel. Each `/ws` connection keeps a [`Subscriptions`] that
+//! decides which events it forwards: topics it asked for, events from the
+//! caller's own profiles, queries matching its filter, and no more queries
+//! per second than its cap. A connection
+//! that falls behind the channel skips the oldest events and is told how
+//! many it missed.
+
+use serde::{Deserialize, Serialize};
+use shield_metrics::QueryLogEntry;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before the slowest start skipping
+pub const EVENT_BUFFER: usize = 1024;
+/// Query stream cap when a subscription doesn't name one, and the most
+/// one may ask for
+pub const DEFAULT_QUERIES_PER_SECOND: u32 = 100;
+pub const MAX_QUERIES_PER_SECOND: u32 = 1000;
+/// Stats push interval when a subscription doesn't name one, and the
+/// shortest one may ask for
+pub const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(2);
+pub const MIN_STATS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Blocked categories reported on the alerts topic
+pub const THREAT_CATEGORIES: &[&str] = &["malware", "phishing", "cryptominers"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Stats,
+    Queries,
+    Alerts,
+    Devices,
+}
+
+/// A blocked query in a threat category
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreatAlert {
+    pub timestamp: u64,
+    pub domain: String,
+    pub category: String,
+    pub client_ip: String,
+    pub profile_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceEventKind {
+    /// First query from an unknown client
+    Discovered,
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceEvent {
+    pub event: DeviceEventKind,
+    pub timestamp: u64,
+    pub device_id: String,
+    pub name: Option<String>,
+    pub ip: Option<String>,
+    /// Profile the device is assigned to
+    pub profile_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LiveEvent {
+    Query(QueryLogEntry),
+    Alert(ThreatAlert),
+    Device(DeviceEvent),
+}
+
+impl LiveEvent {
+    /// The event as a server message
+    pub fn to_json(&self) -> String {
+        match self {
+            LiveEvent::Query(entry) => event_json(Topic::Queries, entry),
+            LiveEvent::Alert(alert) => event_json(Topic::Alerts, alert),
+            LiveEvent::Device(device) => event_json(Topic::Devices, device),
+        }
+    }
+}
+
+/// Broadcast point for live events
+pub struct LiveHub {
+    tx: broadcast::Sender<Arc<LiveEvent>>,
+}
+
+impl Default for LiveHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveHub {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self { tx }
+    }
+
+    /// Publish an event; `event` only runs when someone is listening
+    pub fn publish(&self, event: impl FnOnce() -> LiveEvent) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(Arc::new(event()));
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<LiveEvent>> {
+        self.tx.subscribe()
+    }
+}
+
+/// Server-side filter on the query stream
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct QueryFilter {
+    /// Client IP
+    pub client: Option<String>,
+    pub profile: Option<String>,
+    pub blocked_only: bool,
+}
+
+impl QueryFilter {
+    pub fn matches(&self, entry: &QueryLogEntry) -> bool {
+        (!self.blocked_only || entry.blocked)
+            && self
+                .client
+                .as_ref()
+                .is_none_or(|client| *client == entry.client_ip)
+            && self
+                .profile
+                .as_ref()
+                .is_none_or(|profile| entry.profile_id.as_ref() == Some(profile))
+    }
+}
+
+/// Messages a client sends
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Subscribe {
+        topic: Topic,
+        /// Query stream only
+        #[serde(default)]
+        filter: QueryFilter,
+        /// Query stream only
+        max_per_second: Option<u32>,
+        /// Stats only
+        interval_ms: Option<u64>,
+    },
+    Unsubscribe {
+        topic: Topic,
+    },
+}
+
+/// Control messages sent to a client; events use [`event_json`]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Subscribed {
+        topic: Topic,
+    },
+    Unsubscribed {
+        topic: Topic,
+    },
+    /// Events not sent: `rate_cap` for queries over the subscription's
+    /// cap, `lagged` for events skipped because the client fell behind
+    Dropped {
+        topic: Option<Topic>,
+        reason: &'static str,
+        count: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// `{"type": "event", "topic": ..., "data": ...}`
+pub fn event_json<T: Serialize>(topic: Topic, data: &T) -> String {
+    #[derive(Serialize)]
+    struct Event<'a, T> {
+        r#type: &'static str,
+        topic: Topic,
+        data: &'a T,
+    }
+    serde_json::to_string(&Event {
+        r#type: "event",
+        topic,
+        data,
+    })
+    .unwrap_or_default()
+}
+
+/// Query stream subscription with a per-second cap
+#[derive(Debug)]
+struct QueryStream {
+    filter: QueryFilter,
+    max_per_second: u32,
+    window_start: Instant,
+    sent: u32,
+    dropped: u64,
+}
+
+/// What one connection is subscribed to
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    /// Profiles whose queries, alerts and devices the caller may see
+    profiles: HashSet<String>,
+    stats: Option<Duration>,
+    queries: Option<QueryStream>,
+    alerts: bool,
+    devices: bool,
+}
+
+impl Subscriptions {
+    /// Replace the caller's profiles, e.g. after they created one
+    pub fn set_profiles(&mut self, profiles: HashSet<String>) {
+        self.profiles = profiles;
+    }
+
+    fn owns(&self, profile_id: Option<&String>) -> bool {
+        profile_id.is_some_and(|id| self.profiles.contains(id))
+    }
+
+    /// Apply a client message and return the reply
+    pub fn handle(&mut self, message: ClientMessage, now: Instant) -> ServerMessage {
+        match message {
+            ClientMessage::Subscribe {
+                topic,
+                filter,
+                max_per_second,
+                interval_ms,
+            } => {
+                match topic {
+                    Topic::Stats => {
+                        let interval =
+                            interval_ms.map_or(DEFAULT_STATS_INTERVAL, Duration::from_millis);
+                        self.stats = Some(interval.max(MIN_STATS_INTERVAL));
+                    }
+                    Topic::Queries => {
+                        self.queries = Some(QueryStream {
+                            filter,
+                            max_per_second: max_per_second
+                                .unwrap_or(DEFAULT_QUERIES_PER_SECOND)
+                                .clamp(1, MAX_QUERIES_PER_SECOND),
+                            window_start: now,
+                            sent: 0,
+                            dropped: 0,
+                        });
+                    }
+                    Topic::Alerts => self.alerts = true,
+                    Topic::Devices => self.devices = true,
+                }
+                ServerMessage::Subscribed { topic }
+            }
+            ClientMessage::Unsubscribe { topic } => {
+                match topic {
+                    Topic::Stats => self.stats = None,
+                    Topic::Queries => self.queries = None,
+                    Topic::Alerts => self.alerts = false,
+                    Topic::Devices => self.devices = false,
+                }
+                ServerMessage::Unsubscribed { topic }
+            }
+        }
+    }
+
+    /// How often to push stats, if subscribed
+    pub fn stats_interval(&self) -> Option<Duration> {
+        self.stats
+    }
+
+    /// Whether any broadcast topic is subscribed
+    pub fn wants_events(&self) -> bool {
+        self.queries.is_some() || self.alerts || self.devices
+    }
+
+    /// Whether to forward `event`, counting queries over the cap. Events
+    /// from other users' profiles, or from no profile, are never forwarded.
+    pub fn accept(&mut self, event: &LiveEvent, now: Instant) -> bool {
+        match event {
+            LiveEvent::Alert(alert) => self.alerts && self.owns(alert.profile_id.as_ref()),
+            LiveEvent::Device(device) => self.devices && self.owns(device.profile_id.as_ref()),
+            LiveEvent::Query(entry) => {
+                if !self.owns(entry.profile_id.as_ref()) {
+                    return false;
+                }
+                let Some(stream) = self.queries.as_mut() else {
+                    return false;
+                };
+                if !stream.filter.matches(entry) {
+                    return false;
+                }
+                if now.duration_since(stream.window_start) >= Duration::from_secs(1) {
+                    stream.window_start = now;
+                    stream.sent = 0;
+                }
+                if stream.sent >= stream.max_per_second {
+                    stream.dropped += 1;
+                    return false;
+                }
+                stream.sent += 1;
+                true
+            }
+        }
+    }
+
+    /// Report of queries dropped by the cap since the last call, sent once
+    /// the window that dropped them has passed
+    pub fn take_dropped(&mut self, now: Instant) -> Option<ServerMessage> {
+        let stream = self.queries.as_mut()?;
+        if stream.dropped == 0 || now.duration_since(stream.window_start) < Duration::from_secs(1) {
+            return None;
+        }
+        let count = std::mem::take(&mut stream.dropped);
+        Some(ServerMessage::Dropped {
+            topic: Some(Topic::Queries),
+            reason: "rate_cap",
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(client: &str, profile: &str, blocked: bool) -> LiveEvent {
+        LiveEvent::Query(QueryLogEntry {
+            domain: "example.com".to_string(),
+            client_ip: client.to_string(),
+            profile_id: Some(profile.to_string()),
+            blocked,
+            ..Default::default()
+        })
+    }
+
+    fn owning(profiles: &[&str]) -> Subscriptions {
+        let mut subs = Subscriptions::default();
+        subs.set_profiles(profiles.iter().map(|p| p.to_string()).collect());
+        subs
+    }
+
+    #[test]
+    fn test_query_filter_and_cap() {
+        let now = Instant::now();
+        let mut subs = owning(&["kids", "adults"]);
+        assert!(!subs.wants_events());
+        assert!(!subs.accept(&query("10.0.0.1", "kids", true), now));
+
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type":"subscribe","topic":"queries","filter":{"profile":"kids","blocked_only":true},"max_per_second":2}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            subs.handle(message, now).to_json(),
+            r#"{"type":"subscribed","topic":"queries"}"#
+        );
+        assert!(subs.wants_events());
+
+        assert!(!subs.accept(&query("10.0.0.1", "kids", false), now));
+        assert!(!subs.accept(&query("10.0.0.1", "adults", true), now));
+        assert!(subs.accept(&query("10.0.0.1", "kids", true), now));
+        assert!(subs.accept(&query("10.0.0.2", "kids", true), now));
+        assert!(!subs.accept(&query("10.0.0.3", "kids", true), now));
+        assert!(subs.take_dropped(now).is_none());
+
+        // The next window reports what the cap dropped and starts over
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            subs.take_dropped(later).unwrap().to_json(),
+            r#"{"type":"dropped","topic":"queries","reason":"rate_cap","count":1}"#
+        );
+        assert!(subs.accept(&query("10.0.0.3", "kids", true), later));
+        assert!(subs.take_dropped(later + Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn test_other_users_events_hidden() {
+        let now = Instant::now();
+        let mut subs = owning(&["kids"]);
+        for topic in ["queries", "alerts"] {
+            let json = format!(r#"{{"type":"subscribe","topic":"{}"}}"#, topic);
+            subs.handle(serde_json::from_str(&json).unwrap(), now);
+        }
+        let alert = |profile: Option<&str>| {
+            LiveEvent::Alert(ThreatAlert {
+                timestamp: 0,
+                domain: "malware.test".to_string(),
+                category: "malware".to_string(),
+                client_ip: "10.0.0.1".to_string(),
+                profile_id: profile.map(str::to_string),
+            })
+        };
+
+        assert!(subs.accept(&query("10.0.0.1", "kids", false), now));
+        assert!(!subs.accept(&query("10.0.0.1", "neighbour", false), now));
+        assert!(!subs.accept(&LiveEvent::Query(QueryLogEntry::default()), now));
+        assert!(subs.accept(&alert(Some("kids")), now));
+        assert!(!subs.accept(&alert(Some("neighbour")), now));
+        assert!(!subs.accept(&alert(None), now));
+
+        subs.set_profiles(HashSet::from(["neighbour".to_string()]));
+        assert!(!subs.accept(&alert(Some("kids")), now));
+    }
+
+    #[test]
+    fn test_topics() {
+        let now = Instant::now();
+        let mut subs = owning(&["kids"]);
+        let subscribe = |json: &str| serde_json::from_str::<ClientMessage>(json).unwrap();
+
+        subs.handle(
+            subscribe(r#"{"type":"subscribe","topic":"stats","interval_ms":10}"#),
+            now,
+        );
+        assert_eq!(subs.stats_interval(), Some(MIN_STATS_INTERVAL));
+        assert!(!subs.wants_events());
+
+        subs.handle(subscribe(r#"{"type":"subscribe","topic":"devices"}"#), now);
+        let device = LiveEvent::Device(DeviceEvent {
+            event: DeviceEventKind::Discovered,
+            timestamp: 0,
+            device_id: "d1".to_string(),
+            name: None,
+            ip: Some("10.0.0.9".to_string()),
+            profile_id: Some("kids".to_string()),
+        });
+        assert!(subs.accept(&device, now));
+        assert!(device
+            .to_json()
+            .starts_with(r#"{"type":"event","topic":"devices","data":{"event":"discovered""#));
+
+        subs.handle(
+            subscribe(r#"{"type":"unsubscribe","topic":"devices"}"#),
+            now,
+        );
+        assert!(!subs.accept(&device, now));
+        assert!(
+            serde_json::from_str::<ClientMessage>(r#"{"type":"subscribe","topic":"nope"}"#)
+                .is_err()
+        );
+    }
+}
@@ -2,8 +2,14 @@
 //! Production-grade RESTful API for DNS protection management
 
 mod background_tasks;
+mod blocklist_sources;
+mod client_ip;
+mod dnstap;
 mod handlers;
+mod live;
+mod query_log;
 mod rate_limiter;
+mod rollups;
 mod state;
 mod webhooks;
 
@@ -74,15 +80,16 @@ async fn main() -> Result<()> {
         // Blocklist management endpoints
         .route("/api/blocklist", post(handlers::add_to_blocklist))
         .route("/api/blocklist/bulk", post(handlers::bulk_add_to_blocklist))
+        .route(
+            "/api/blocklist/history",
+            get(handlers::get_blocklist_history),
+        )
         .route(
             "/api/blocklist/:domain",
             delete(handlers::remove_from_blocklist),
         )
         // Privacy metrics endpoint
         .route("/api/privacy-metrics", get(handlers::get_privacy_metrics))
-        // Device management endpoints
-        .route("/api/devices", get(handlers::get_devices))
-        .route("/api/devices/:id", put(handlers::update_device))
         // Rate limit stats
         .route("/api/rate-limit/stats", get(handlers::rate_limit_stats))
         // Threat intelligence endpoints
@@ -90,27 +97,25 @@ async fn main() -> Result<()> {
         .route("/api/threat/check/:domain", get(handlers::threat_check))
         .route("/api/threat/feeds/stats", get(handlers::threat_feed_stats))
         // Profile management endpoints
-        .route(
-            "/api/profiles",
-            get(handlers::list_profiles).post(handlers::create_profile),
-        )
         .route("/api/profiles/stats", get(handlers::profile_stats))
-        .route(
-            "/api/profiles/:id",
-            get(handlers::get_profile).delete(handlers::delete_profile),
-        )
-        .route("/api/profiles/device", post(handlers::assign_device))
         // Unified filter management endpoints
         .route("/api/filter/stats", get(handlers::unified_filter_stats))
         .route("/api/filter/check/:domain", get(handlers::check_domain_blocking))
         .route("/api/filter/categories", get(handlers::get_blocking_categories))
+        .route("/api/filter/services", get(handlers::list_services))
         .route("/api/filter/categories/enabled", get(handlers::get_enabled_categories))
         .route("/api/filter/categories/:category", put(handlers::toggle_category))
-        .route("/api/filter/profile/ip", post(handlers::assign_profile_to_ip))
+        .route("/api/filter/presets", get(handlers::list_presets))
+        .route(
+            "/api/filter/profile/ip",
+            post(handlers::assign_profile_to_ip),
+        )
         .route("/api/filter/refresh", post(handlers::refresh_blocklists))
+        .route("/api/filter/export/rpz", get(handlers::export_rpz))
         // Real-time analytics endpoints
         .route("/api/analytics/realtime", get(handlers::get_realtime_analytics))
         .route("/api/analytics/trends", get(handlers::get_query_trends))
+        .route("/api/analytics/top", get(handlers::get_top_domains))
         .route("/api/analytics/threats", get(handlers::get_threat_summary))
         // Webhook management endpoints
         .route("/api/webhooks", get(handlers::list_webhooks).post(handlers::register_webhook))
@@ -152,6 +157,108 @@ async fn main() -> Result<()> {
                     "/api/auth/devices/:id/push-token",
                     put(handlers::auth_update_push_token),
                 )
+                // Profile management, limited to the caller's own profiles
+                .route(
+                    "/api/profiles",
+                    get(handlers::list_profiles).post(handlers::create_profile),
+                )
+                .route(
+                    "/api/profiles/:id",
+                    get(handlers::get_profile)
+                        .put(handlers::update_profile)
+                        .delete(handlers::delete_profile),
+                )
+                .route("/api/profiles/device", post(handlers::assign_device))
+                .route(
+                    "/api/profiles/:id/schedule",
+                    get(handlers::preview_profile_schedule),
+                )
+                .route("/api/profiles/:id/usage", get(handlers::get_profile_usage))
+                .route(
+                    "/api/profiles/:id/effective",
+                    get(handlers::get_effective_profile),
+                )
+                // Query log search and export, limited to the caller's profiles
+                .route("/api/query-log", get(handlers::search_query_log))
+                .route("/api/query-log/export", get(handlers::export_query_log))
+                // Blocklist source management; sources filter every user and
+                // make the server download arbitrary URLs, so changes,
+                // previews and fetches are limited to administrators
+                .route(
+                    "/api/blocklist/sources",
+                    get(handlers::list_blocklist_sources).post(handlers::create_blocklist_source),
+                )
+                .route(
+                    "/api/blocklist/sources/validate",
+                    post(handlers::validate_blocklist_source),
+                )
+                .route(
+                    "/api/blocklist/sources/:id",
+                    get(handlers::get_blocklist_source)
+                        .put(handlers::update_blocklist_source)
+                        .delete(handlers::delete_blocklist_source),
+                )
+                .route(
+                    "/api/blocklist/sources/:id/preview",
+                    post(handlers::preview_blocklist_source),
+                )
+                .route(
+                    "/api/blocklist/sources/:id/fetch",
+                    post(handlers::fetch_blocklist_source),
+                )
+                // Device registry, limited to the caller's profiles and
+                // devices not yet on any profile
+                .route(
+                    "/api/devices",
+                    get(handlers::get_devices).post(handlers::create_device),
+                )
+                .route(
+                    "/api/devices/:id",
+                    put(handlers::update_device).delete(handlers::delete_device),
+                )
+                // Range assignments on the caller's profiles
+                .route(
+                    "/api/filter/ranges",
+                    get(handlers::list_range_assignments)
+                        .post(handlers::assign_range)
+                        .delete(handlers::remove_range),
+                )
+                .route("/api/filter/ranges/check", get(handlers::check_range))
+                // Device and profile statistics, for the caller's own profiles
+                .route("/api/devices/:id/stats", get(handlers::get_device_stats))
+                .route("/api/profiles/:id/stats", get(handlers::get_profile_stats))
+                // Trends and top domains for one client or profile under the
+                // caller's profiles; the public analytics routes only serve
+                // overall ones
+                .route("/api/clients/:ip/trends", get(handlers::get_client_trends))
+                .route(
+                    "/api/clients/:ip/top",
+                    get(handlers::get_client_top_domains),
+                )
+                .route(
+                    "/api/profiles/:id/trends",
+                    get(handlers::get_profile_trends),
+                )
+                // Temporary allowlist entries and blocking pauses on the
+                // caller's profiles; global ones need an administrator
+                .route(
+                    "/api/allowlist/temporary",
+                    get(handlers::get_temporary_allowlist).post(handlers::add_temporary_allow),
+                )
+                .route(
+                    "/api/allowlist/temporary/:domain",
+                    delete(handlers::remove_temporary_allow),
+                )
+                .route(
+                    "/api/filter/pause",
+                    get(handlers::get_pause_status)
+                        .post(handlers::pause_blocking)
+                        .delete(handlers::resume_blocking),
+                )
+                .route(
+                    "/api/filter/presets/:name/apply",
+                    post(handlers::apply_preset),
+                )
                 .layer(middleware::from_fn_with_state(
                     app_state.clone(),
                     handlers::auth_middleware,
@@ -184,10 +291,14 @@ async fn main() -> Result<()> {
     // Create TCP listener
     let listener = tokio::net::TcpListener::bind(addr).await?;
 
-    // Run server with graceful shutdown
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    // Run server with graceful shutdown; connect info gives handlers the
+    // peer address for per-client filtering
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
     info!("Server shutdown complete");
     Ok(())
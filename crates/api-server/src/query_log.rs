@@ -0,0 +1,288 @@
+//! Durable query log
+//!
+//! Resolution paths hand entries to a bounded queue and a background task
+//! writes them to SQLite in batches. When the queue is full entries are
+//! dropped and counted, so a slow disk never holds up a DNS answer.
+
+use shield_db::{DbQueryLog, SqliteDb};
+use shield_dns_core::unified_filter::UnifiedFilter;
+use shield_profiles::DeviceRegistry;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Entries waiting to be written before new ones are dropped
+pub const QUEUE_CAPACITY: usize = 10_000;
+/// Largest number of entries written in one transaction
+pub const BATCH_SIZE: usize = 500;
+/// Longest time an entry waits in a partial batch
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Writer counters
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLogStats {
+    pub queued: usize,
+    pub written: u64,
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+/// Batched, back-pressured writer into the `query_log` table
+pub struct QueryLogWriter {
+    tx: mpsc::Sender<DbQueryLog>,
+    counters: Arc<Counters>,
+}
+
+impl QueryLogWriter {
+    /// Create the writer and spawn its background task
+    pub fn start(
+        db: Arc<SqliteDb>,
+        unified_filter: Arc<UnifiedFilter>,
+        devices: Arc<DeviceRegistry>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let counters = Arc::new(Counters::default());
+        let context = Context {
+            db,
+            unified_filter,
+            devices,
+            counters: counters.clone(),
+        };
+        tokio::spawn(run(rx, context));
+        Self { tx, counters }
+    }
+
+    /// Queue a query for writing; never waits. The writer fills in the
+    /// client's profile (when the filter didn't name one), device and owner.
+    pub fn record(&self, entry: DbQueryLog) {
+        if self.tx.try_send(entry).is_err() {
+            let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Query log queue full, {} entries dropped so far", dropped);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QueryLogStats {
+        QueryLogStats {
+            queued: QUEUE_CAPACITY - self.tx.capacity(),
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the background task needs to attribute and write entries
+struct Context {
+    db: Arc<SqliteDb>,
+    unified_filter: Arc<UnifiedFilter>,
+    devices: Arc<DeviceRegistry>,
+    counters: Arc<Counters>,
+}
+
+/// Collect entries into batches and write them until every sender is gone
+async fn run(mut rx: mpsc::Receiver<DbQueryLog>, context: Context) {
+    debug!("Query log writer started");
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        let room = BATCH_SIZE - batch.len();
+        tokio::select! {
+            received = rx.recv_many(&mut batch, room) => {
+                if received == 0 {
+                    if !batch.is_empty() {
+                        write_batch(std::mem::take(&mut batch), &context).await;
+                    }
+                    debug!("Query log writer shutting down");
+                    break;
+                }
+                if batch.len() >= BATCH_SIZE {
+                    let entries = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                    write_batch(entries, &context).await;
+                }
+            }
+            _ = ticker.tick() => {
+                if !batch.is_empty() {
+                    let entries = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                    write_batch(entries, &context).await;
+                }
+            }
+        }
+    }
+}
+
+/// Attribute entries to their profiles, devices and owners, and write them
+/// off the runtime
+async fn write_batch(mut entries: Vec<DbQueryLog>, context: &Context) {
+    for entry in entries.iter_mut() {
+        let client_ip = entry.client_ip.parse().ok();
+        if entry.profile_id.is_none() {
+            entry.profile_id = Some(context.unified_filter.profile_id_for_client(client_ip));
+        }
+        if entry.device_id.is_none() {
+            entry.device_id = client_ip
+                .and_then(|ip| context.devices.find_by_ip(&ip))
+                .map(|device| device.id.to_string());
+        }
+    }
+
+    let db = context.db.clone();
+    let counters = context.counters.clone();
+    let written = tokio::task::spawn_blocking(move || {
+        // Retention follows the tier of whoever owns the answering profile
+        let mut owners: HashMap<String, Option<String>> = HashMap::new();
+        for entry in entries.iter_mut() {
+            entry.user_id = entry.profile_id.as_ref().and_then(|profile_id| {
+                owners
+                    .entry(profile_id.clone())
+                    .or_insert_with_key(|id| {
+                        db.get_profile(id).ok().flatten().and_then(|p| p.user_id)
+                    })
+                    .clone()
+            });
+        }
+        match db.log_queries(&entries) {
+            Ok(count) => {
+                counters.written.fetch_add(count as u64, Ordering::Relaxed);
+                count
+            }
+            Err(e) => {
+                counters
+                    .failed
+                    .fetch_add(entries.len() as u64, Ordering::Relaxed);
+                warn!("Failed to write {} query log entries: {}", entries.len(), e);
+                0
+            }
+        }
+    })
+    .await;
+
+    if let Ok(count) = written {
+        debug!("Wrote {} query log entries", count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use shield_db::{DbProfile, DbUser};
+    use shield_dns_core::filter::FilterEngine;
+    use shield_dns_core::unified_filter::DeviceProfile;
+    use std::net::IpAddr;
+
+    #[tokio::test]
+    async fn test_writer_attributes_owner() {
+        let db = Arc::new(SqliteDb::new(":memory:").unwrap());
+        db.create_user(&DbUser {
+            id: "pro-user".to_string(),
+            email: "pro@example.com".to_string(),
+            password_hash: "hash".to_string(),
+            tier: "pro".to_string(),
+            email_verified: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+        .unwrap();
+        db.create_profile(&DbProfile {
+            id: "kids".to_string(),
+            user_id: Some("pro-user".to_string()),
+            name: "Kids".to_string(),
+            protection_level: "kid".to_string(),
+            blocked_categories: vec![],
+            custom_blocklist: vec![],
+            custom_allowlist: vec![],
+            time_rules: "[]".to_string(),
+            device_ids: vec![],
+            timezone: "UTC".to_string(),
+            quotas: "[]".to_string(),
+            parent_id: None,
+            allowed_categories: vec![],
+            dnstap_opt_out: false,
+            enabled: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+        .unwrap();
+
+        let filter = Arc::new(UnifiedFilter::new(Arc::new(FilterEngine::new())));
+        let kid_ip: IpAddr = "192.168.1.20".parse().unwrap();
+        filter.assign_profile_to_ip(
+            kid_ip,
+            DeviceProfile {
+                id: "kids".to_string(),
+                ..Default::default()
+            },
+        );
+
+        let writer = QueryLogWriter::start(db.clone(), filter, Arc::new(DeviceRegistry::new()));
+        let entry = |domain: &str, client_ip: &str| DbQueryLog {
+            id: 0,
+            domain: domain.to_string(),
+            client_ip: client_ip.to_string(),
+            blocked: false,
+            cached: false,
+            response_time_ms: 0,
+            timestamp: Utc::now(),
+            user_id: None,
+            qtype: Some(1),
+            reason: None,
+            category: None,
+            profile_id: None,
+            device_id: None,
+            rcode: Some(0),
+            answers: vec![],
+            upstream: None,
+            dnssec: None,
+            protocol: Some("doh".to_string()),
+        };
+        writer.record(entry("games.example", "192.168.1.20"));
+        writer.record(entry("news.example", "unknown"));
+
+        // Dropping the writer closes the queue; the task flushes what is left
+        let counters = writer.counters.clone();
+        drop(writer);
+        for _ in 0..100 {
+            if counters.written.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Unassigned clients fall back to the default profile, which has no owner
+        let mut owners: Vec<(String, Option<String>, Option<String>)> = db
+            .get_recent_queries(10)
+            .unwrap()
+            .into_iter()
+            .map(|q| (q.domain, q.profile_id, q.user_id))
+            .collect();
+        owners.sort();
+        assert_eq!(
+            owners,
+            vec![
+                (
+                    "games.example".to_string(),
+                    Some("kids".to_string()),
+                    Some("pro-user".to_string())
+                ),
+                (
+                    "news.example".to_string(),
+                    Some("default".to_string()),
+                    None
+                )
+            ]
+        );
+    }
+}
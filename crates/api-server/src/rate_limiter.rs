@@ -50,6 +50,8 @@ pub struct RateLimiter {
     config: RateLimiterConfig,
     entries: DashMap<IpAddr, RequestEntry>,
     last_cleanup: std::sync::atomic::AtomicU64,
+    /// Requests refused since startup
+    limited: std::sync::atomic::AtomicU64,
 }
 
 impl RateLimiter {
@@ -64,6 +66,7 @@ impl RateLimiter {
             config,
             entries: DashMap::new(),
             last_cleanup: std::sync::atomic::AtomicU64::new(0),
+            limited: std::sync::atomic::AtomicU64::new(0),
         }
     }
 
@@ -93,6 +96,8 @@ impl RateLimiter {
                 .window
                 .saturating_sub(now.duration_since(entry.window_start));
             warn!("Rate limit exceeded for IP: {:?}", ip);
+            self.limited
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             RateLimitResult::Limited {
                 retry_after_secs: retry_after.as_secs(),
                 limit: self.config.max_requests,
@@ -142,6 +147,7 @@ impl RateLimiter {
             tracked_ips: self.entries.len(),
             max_requests: self.config.max_requests,
             window_secs: self.config.window.as_secs(),
+            limited_requests: self.limited.load(std::sync::atomic::Ordering::Relaxed),
         }
     }
 }
@@ -171,6 +177,8 @@ pub struct RateLimiterStats {
     pub tracked_ips: usize,
     pub max_requests: u32,
     pub window_secs: u64,
+    /// Requests refused since startup
+    pub limited_requests: u64,
 }
 
 /// Error response for rate limiting
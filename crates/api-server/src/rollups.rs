@@ -0,0 +1,471 @@
+//! Persistent query rollups
+//!
+//! Per-minute counts collected by `MetricsCollector` are flushed into the
+//! `query_rollups` table at minute, hour and day granularity (hour and day
+//! for domain series). Old data is downsampled by dropping fine-grained
+//! buckets once they age out; the coarser buckets already hold their totals.
+//! Domain series hold full counts until their bucket is complete, when
+//! downsampling trims each device and profile to its busiest domains.
+
+use serde::Serialize;
+use shield_db::{DbError, DbRollup, SqliteDb};
+use shield_metrics::rollup::{
+    scoped_key, Dimension, Granularity, RollupCounts, RollupKey, KEY_SEPARATOR,
+};
+use shield_metrics::{ClientStats, MetricsCollector, NamedCount};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// How long each granularity is kept
+pub const MINUTE_RETENTION_SECS: u64 = 2 * 86_400;
+pub const HOUR_RETENTION_SECS: u64 = 90 * 86_400;
+pub const DAY_RETENTION_SECS: u64 = 2 * 365 * 86_400;
+
+/// Domains kept per device or profile in each completed hour or day bucket;
+/// the quieter ones are dropped so domain series stay bounded
+pub const DOMAINS_PER_BUCKET: usize = 25;
+
+/// Counts for one bucket of a series
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RollupPoint {
+    /// Unix timestamp the bucket starts at
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub counts: RollupCounts,
+}
+
+/// Rollup series backed by SQLite plus the counts not yet flushed
+pub struct RollupStore {
+    db: Arc<SqliteDb>,
+    metrics: Arc<MetricsCollector>,
+}
+
+impl RollupStore {
+    pub fn new(db: Arc<SqliteDb>, metrics: Arc<MetricsCollector>) -> Self {
+        Self { db, metrics }
+    }
+
+    /// Write pending minute counts into the minute, hour and day buckets.
+    /// Returns the number of minute buckets written.
+    pub fn flush(&self) -> Result<usize, DbError> {
+        let pending = self.metrics.rollups().drain();
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut merged: HashMap<(Granularity, RollupKey), RollupCounts> = HashMap::new();
+        for (key, counts) in &pending {
+            for &granularity in key.dimension.granularities() {
+                let bucket = RollupKey {
+                    bucket: granularity.bucket(key.bucket),
+                    ..key.clone()
+                };
+                merged.entry((granularity, bucket)).or_default().add(counts);
+            }
+        }
+        let rows: Vec<DbRollup> = merged
+            .into_iter()
+            .map(|((granularity, key), counts)| DbRollup {
+                granularity: granularity.as_str().to_string(),
+                bucket: key.bucket as i64,
+                dimension: key.dimension.as_str().to_string(),
+                key: key.key,
+                queries: counts.queries as i64,
+                blocked: counts.blocked as i64,
+                cached: counts.cached as i64,
+            })
+            .collect();
+
+        match self.db.add_rollups(&rows) {
+            Ok(_) => Ok(pending.len()),
+            Err(e) => {
+                // Keep the counts for the next flush
+                self.metrics.rollups().restore(pending);
+                Err(e)
+            }
+        }
+    }
+
+    /// Drop buckets older than their granularity is kept for, and trim
+    /// completed domain buckets to each owner's `DOMAINS_PER_BUCKET`
+    /// busiest domains
+    pub fn downsample(&self, now: u64) -> Result<usize, DbError> {
+        let mut deleted = 0;
+        for dimension in [Dimension::DeviceDomain, Dimension::ProfileDomain] {
+            for granularity in dimension.granularities() {
+                deleted += self.db.prune_rollup_keys(
+                    granularity.as_str(),
+                    dimension.as_str(),
+                    KEY_SEPARATOR,
+                    granularity.bucket(now) as i64,
+                    DOMAINS_PER_BUCKET,
+                )?;
+            }
+        }
+        for (granularity, retention) in [
+            (Granularity::Minute, MINUTE_RETENTION_SECS),
+            (Granularity::Hour, HOUR_RETENTION_SECS),
+            (Granularity::Day, DAY_RETENTION_SECS),
+        ] {
+            let before = now.saturating_sub(retention) as i64;
+            deleted += self
+                .db
+                .delete_rollups_before(granularity.as_str(), before)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Counts per bucket over `[since, until)`, oldest first, with empty
+    /// buckets as zeros. `key: None` sums every key of the dimension.
+    pub fn series(
+        &self,
+        granularity: Granularity,
+        dimension: Dimension,
+        key: Option<&str>,
+        since: u64,
+        until: u64,
+    ) -> Result<Vec<RollupPoint>, DbError> {
+        let start = granularity.bucket(since);
+        let mut buckets: BTreeMap<u64, RollupCounts> = (start..until)
+            .step_by(granularity.seconds() as usize)
+            .map(|bucket| (bucket, RollupCounts::default()))
+            .collect();
+
+        for row in self.stored(granularity, dimension, key, start, until)? {
+            if let Some(counts) = buckets.get_mut(&(row.bucket as u64)) {
+                counts.add(&row_counts(&row));
+            }
+        }
+        for (pending, counts) in self.metrics.rollups().pending(dimension, key) {
+            if let Some(bucket) = buckets.get_mut(&granularity.bucket(pending.bucket)) {
+                bucket.add(&counts);
+            }
+        }
+
+        Ok(buckets
+            .into_iter()
+            .map(|(timestamp, counts)| RollupPoint { timestamp, counts })
+            .collect())
+    }
+
+    /// Totals per key of a dimension over `[since, until)`, most queries first
+    pub fn totals(
+        &self,
+        granularity: Granularity,
+        dimension: Dimension,
+        since: u64,
+        until: u64,
+    ) -> Result<Vec<(String, RollupCounts)>, DbError> {
+        let start = granularity.bucket(since);
+        let mut totals: HashMap<String, RollupCounts> = HashMap::new();
+        for row in self.stored(granularity, dimension, None, start, until)? {
+            totals
+                .entry(row.key.clone())
+                .or_default()
+                .add(&row_counts(&row));
+        }
+        for (pending, counts) in self.metrics.rollups().pending(dimension, None) {
+            let bucket = granularity.bucket(pending.bucket);
+            if bucket >= start && bucket < until {
+                totals.entry(pending.key).or_default().add(&counts);
+            }
+        }
+
+        let mut totals: Vec<(String, RollupCounts)> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(&b.0)));
+        Ok(totals)
+    }
+
+    /// Totals per value of a scoped dimension for one owner over
+    /// `[since, until)`, most queries first, keyed by the bare value
+    pub fn scoped_totals(
+        &self,
+        granularity: Granularity,
+        dimension: Dimension,
+        owner: &str,
+        since: u64,
+        until: u64,
+    ) -> Result<Vec<(String, RollupCounts)>, DbError> {
+        let start = granularity.bucket(since);
+        let prefix = scoped_key(owner, "");
+        let mut totals: HashMap<String, RollupCounts> = HashMap::new();
+        let stored = self.db.get_rollups_with_prefix(
+            granularity.as_str(),
+            dimension.as_str(),
+            &prefix,
+            start as i64,
+            until as i64,
+        )?;
+        for row in stored {
+            totals
+                .entry(row.key.clone())
+                .or_default()
+                .add(&row_counts(&row));
+        }
+        for (pending, counts) in self.metrics.rollups().pending_scoped(dimension, owner) {
+            let bucket = granularity.bucket(pending.bucket);
+            if bucket >= start && bucket < until {
+                totals.entry(pending.key).or_default().add(&counts);
+            }
+        }
+
+        let mut totals: Vec<(String, RollupCounts)> = totals
+            .into_iter()
+            .map(|(key, counts)| (key[prefix.len()..].to_string(), counts))
+            .collect();
+        totals.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(&b.0)));
+        Ok(totals)
+    }
+
+    /// Start of the latest bucket with queries for a key: to the minute
+    /// while minute buckets are kept, then to the hour or day
+    pub fn last_seen(&self, dimension: Dimension, key: &str) -> Result<Option<u64>, DbError> {
+        let pending = self
+            .metrics
+            .rollups()
+            .pending(dimension, Some(key))
+            .into_iter()
+            .map(|(k, _)| k.bucket)
+            .max();
+        if pending.is_some() {
+            return Ok(pending);
+        }
+        for granularity in dimension.granularities() {
+            if let Some(bucket) =
+                self.db
+                    .last_rollup_bucket(granularity.as_str(), dimension.as_str(), key)?
+            {
+                return Ok(Some(bucket as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Counts, top domains and categories for one device or profile over
+    /// `[since, until)`, keeping `limit` entries per list
+    pub fn client_stats(
+        &self,
+        dimension: Dimension,
+        key: &str,
+        since: u64,
+        until: u64,
+        limit: usize,
+    ) -> Result<ClientStats, DbError> {
+        let mut totals = RollupCounts::default();
+        for point in self.series(Granularity::Hour, dimension, Some(key), since, until)? {
+            totals.add(&point.counts);
+        }
+
+        let named = |(name, counts): (String, RollupCounts)| NamedCount {
+            name,
+            queries: counts.queries,
+            blocked: counts.blocked,
+        };
+        let (categories, domains) = match dimension.breakdowns() {
+            Some((by_category, by_domain)) => (
+                self.scoped_totals(Granularity::Hour, by_category, key, since, until)?,
+                self.scoped_totals(Granularity::Hour, by_domain, key, since, until)?,
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+        let mut blocked: Vec<NamedCount> = domains
+            .iter()
+            .filter(|(_, counts)| counts.blocked > 0)
+            .cloned()
+            .map(named)
+            .collect();
+        blocked.sort_by(|a, b| b.blocked.cmp(&a.blocked).then_with(|| a.name.cmp(&b.name)));
+        blocked.truncate(limit);
+
+        Ok(ClientStats {
+            total_queries: totals.queries,
+            blocked_queries: totals.blocked,
+            allowed_queries: totals.allowed(),
+            cached_queries: totals.cached,
+            block_rate: totals.block_rate(),
+            last_seen: self.last_seen(dimension, key)?,
+            top_domains: domains.into_iter().take(limit).map(named).collect(),
+            top_blocked_domains: blocked,
+            categories: categories.into_iter().take(limit).map(named).collect(),
+        })
+    }
+
+    fn stored(
+        &self,
+        granularity: Granularity,
+        dimension: Dimension,
+        key: Option<&str>,
+        since: u64,
+        until: u64,
+    ) -> Result<Vec<DbRollup>, DbError> {
+        self.db.get_rollups(
+            granularity.as_str(),
+            dimension.as_str(),
+            key,
+            since as i64,
+            until as i64,
+        )
+    }
+}
+
+fn row_counts(row: &DbRollup) -> RollupCounts {
+    RollupCounts {
+        queries: row.queries as u64,
+        blocked: row.blocked as u64,
+        cached: row.cached as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shield_metrics::QueryLogEntry;
+
+    #[test]
+    fn test_flush_and_series() {
+        let db = Arc::new(SqliteDb::new(":memory:").unwrap());
+        let metrics = Arc::new(MetricsCollector::new());
+        let store = RollupStore::new(db, metrics.clone());
+        let query = |timestamp: u64, blocked: bool| QueryLogEntry {
+            timestamp,
+            domain: "example.com".to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            blocked,
+            category: blocked.then(|| "ads".to_string()),
+            ..Default::default()
+        };
+
+        // Two queries in the first hour, one in the third
+        metrics.record_query_entry(query(60, true));
+        metrics.record_query_entry(query(3_000, false));
+        assert_eq!(store.flush().unwrap(), 5);
+        metrics.record_query_entry(query(7_300, true));
+
+        // The unflushed query is included and the empty hour is zero
+        let hourly = store
+            .series(Granularity::Hour, Dimension::All, None, 0, 3 * 3600)
+            .unwrap();
+        let counts: Vec<(u64, u64, u64)> = hourly
+            .iter()
+            .map(|p| (p.timestamp, p.counts.queries, p.counts.blocked))
+            .collect();
+        assert_eq!(counts, vec![(0, 2, 1), (3600, 0, 0), (7200, 1, 1)]);
+
+        let daily = store
+            .series(
+                Granularity::Day,
+                Dimension::Client,
+                Some("192.168.1.20"),
+                0,
+                86_400,
+            )
+            .unwrap();
+        assert_eq!(daily[0].counts.queries, 3);
+        let categories = store
+            .totals(Granularity::Hour, Dimension::Category, 0, 86_400)
+            .unwrap();
+        assert_eq!(categories[0].0, "ads");
+        assert_eq!(categories[0].1.blocked, 2);
+
+        // Minute buckets age out first; hour and day totals remain
+        store.flush().unwrap();
+        store.downsample(MINUTE_RETENTION_SECS + 10_000).unwrap();
+        let minutes = store
+            .series(Granularity::Minute, Dimension::All, None, 0, 7_400)
+            .unwrap();
+        assert!(minutes.iter().all(|p| p.counts.queries == 0));
+        let hourly = store
+            .series(Granularity::Hour, Dimension::All, None, 0, 3 * 3600)
+            .unwrap();
+        assert_eq!(hourly.iter().map(|p| p.counts.queries).sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn test_client_stats() {
+        let db = Arc::new(SqliteDb::new(":memory:").unwrap());
+        let metrics = Arc::new(MetricsCollector::new());
+        let store = RollupStore::new(db, metrics.clone());
+        let query = |timestamp: u64, domain: &str, category: Option<&str>| QueryLogEntry {
+            timestamp,
+            domain: domain.to_string(),
+            client_ip: "192.168.1.30".to_string(),
+            profile_id: Some("kids".to_string()),
+            device_id: Some("tablet".to_string()),
+            blocked: category.is_some(),
+            category: category.map(str::to_string),
+            ..Default::default()
+        };
+
+        metrics.record_query_entry(query(60, "ads.example", Some("ads")));
+        metrics.record_query_entry(query(120, "ads.example", Some("ads")));
+        metrics.record_query_entry(query(180, "games.example", None));
+        // More domains than a bucket keeps, all counted in full while the
+        // hour is open
+        for i in 0..DOMAINS_PER_BUCKET {
+            metrics.record_query_entry(query(240, &format!("site{i:02}.example"), None));
+        }
+        store.flush().unwrap();
+        let last = format!("site{:02}.example", DOMAINS_PER_BUCKET - 1);
+        metrics.record_query_entry(query(300, &last, None));
+        metrics.record_query_entry(query(360, &last, None));
+        store.flush().unwrap();
+        let hour_domains = || {
+            store
+                .scoped_totals(
+                    Granularity::Hour,
+                    Dimension::DeviceDomain,
+                    "tablet",
+                    0,
+                    3600,
+                )
+                .unwrap()
+        };
+        assert_eq!(hour_domains().len(), DOMAINS_PER_BUCKET + 2);
+
+        // Once the hour is over it keeps its busiest domains by full count,
+        // including one that was never busy within a single flush
+        assert_eq!(store.downsample(3_700).unwrap(), 4);
+        let domains = hour_domains();
+        assert_eq!(domains.len(), DOMAINS_PER_BUCKET);
+        assert_eq!(
+            (domains[0].0.as_str(), domains[0].1.queries),
+            (last.as_str(), 3)
+        );
+        let dropped = format!("site{:02}.example", DOMAINS_PER_BUCKET - 2);
+        assert!(domains.iter().all(|(name, _)| name != &dropped));
+
+        metrics.record_query_entry(query(4_000, "games.example", None));
+        let stats = store
+            .client_stats(Dimension::Device, "tablet", 0, 2 * 3600, 3)
+            .unwrap();
+        assert_eq!(stats.total_queries, 31);
+        assert_eq!(stats.blocked_queries, 2);
+        assert_eq!(stats.last_seen, Some(3_960));
+        let top: Vec<(&str, u64)> = stats
+            .top_domains
+            .iter()
+            .map(|d| (d.name.as_str(), d.queries))
+            .collect();
+        assert_eq!(
+            top,
+            vec![(last.as_str(), 3), ("ads.example", 2), ("games.example", 2)]
+        );
+        assert_eq!(stats.top_blocked_domains.len(), 1);
+        assert_eq!(stats.categories[0].name, "ads");
+
+        // Profiles are tracked the same way; last seen survives the flush
+        store.flush().unwrap();
+        let stats = store
+            .client_stats(Dimension::Profile, "kids", 0, 2 * 3600, 10)
+            .unwrap();
+        assert_eq!(stats.allowed_queries, 29);
+        assert_eq!(stats.last_seen, Some(3_960));
+        assert_eq!(
+            store
+                .client_stats(Dimension::Device, "phone", 0, 3600, 10)
+                .unwrap()
+                .last_seen,
+            None
+        );
+    }
+}
@@ -1,7 +1,13 @@
 //! Application state management
 
-use crate::background_tasks::{BackgroundTasks, BackgroundTasksConfig, warm_cache};
+use crate::background_tasks::{warm_cache, BackgroundTasks, BackgroundTasksConfig};
+use crate::blocklist_sources::{self, RefreshScope};
+use crate::client_ip::TrustedProxies;
+use crate::dnstap::{DnstapConfig, DnstapWriter};
+use crate::live::LiveHub;
+use crate::query_log::QueryLogWriter;
 use crate::rate_limiter::{RateLimiter, RateLimiterConfig};
+use crate::rollups::RollupStore;
 use crate::webhooks::WebhookManager;
 use shield_ai_engine::AIEngine;
 use shield_auth::AuthService;
@@ -10,6 +16,7 @@ use shield_dns_core::cache::DNSCache;
 use shield_dns_core::filter::FilterEngine;
 use shield_dns_core::resolver::Resolver;
 use shield_dns_core::unified_filter::UnifiedFilter;
+use shield_dns_core::usage::UsageRecord;
 use shield_metrics::MetricsCollector;
 use shield_ml_engine::MLEngine;
 use shield_profiles::ProfileManager;
@@ -20,6 +27,33 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tracing::{info, warn};
 
+/// Accounts allowed to change settings for every profile, by email
+#[derive(Debug, Clone, Default)]
+pub struct Admins {
+    emails: Vec<String>,
+}
+
+impl Admins {
+    /// Read `ADMIN_EMAILS`; empty (nobody) when unset
+    pub fn from_env() -> Self {
+        Self::parse(&std::env::var("ADMIN_EMAILS").unwrap_or_default())
+    }
+
+    fn parse(value: &str) -> Self {
+        let emails = value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self { emails }
+    }
+
+    pub fn contains(&self, email: &str) -> bool {
+        self.emails.iter().any(|e| e.eq_ignore_ascii_case(email))
+    }
+}
+
 /// Shared application state
 pub struct AppState {
     pub metrics: Arc<MetricsCollector>,
@@ -34,8 +68,17 @@ pub struct AppState {
     pub tiers: Arc<TierManager>,
     pub ml_engine: Arc<MLEngine>,
     pub auth: Arc<AuthService>,
-    #[allow(dead_code)]
     pub db: Arc<SqliteDb>,
+    pub query_log: Arc<QueryLogWriter>,
+    pub rollups: Arc<RollupStore>,
+    /// dnstap emitter, when an output is configured
+    pub dnstap: Option<Arc<DnstapWriter>>,
+    /// Events pushed to `/ws` subscribers
+    pub live: Arc<LiveHub>,
+    /// Proxies allowed to report the client address in forwarding headers
+    pub trusted_proxies: TrustedProxies,
+    /// Accounts allowed to change settings for every profile
+    pub admins: Admins,
     #[allow(dead_code)]
     background_tasks: Arc<BackgroundTasks>,
     pub webhooks: Arc<WebhookManager>,
@@ -70,20 +113,36 @@ impl AppState {
         // Initialize unified filter with blocklist support
         let unified_filter = Arc::new(UnifiedFilter::new(filter.clone()));
 
+        // Restore unexpired temporary allowlist entries and pauses
+        Self::load_overrides_from_db(&unified_filter, &db);
+
+        // Restore today's usage budget counters
+        Self::load_quota_usage_from_db(&unified_filter, &db);
+
+        // Initialize webhook manager for threat notifications
+        let webhooks = Arc::new(WebhookManager::new());
+        info!("Webhook manager initialized");
+
+        // Import blocklist sources from the JSON file on first boot
+        blocklist_sources::import_json_sources(&db, blocklist_sources::BLOCKLIST_CONFIG_PATH);
+        blocklist_sources::restore_global_categories(&unified_filter, &db);
+
         // Fetch blocklists asynchronously (non-blocking)
         let uf_clone = unified_filter.clone();
+        let db_clone = db.clone();
+        let webhooks_clone = webhooks.clone();
         tokio::spawn(async move {
-            match uf_clone.init_blocklists("config/blocklist-sources.json").await {
-                Ok(stats) => {
-                    info!(
-                        "Blocklists loaded: {} domains from {} sources",
-                        stats.total_domains, stats.sources_loaded
-                    );
-                }
-                Err(e) => {
-                    warn!("Failed to load blocklists, using defaults: {}", e);
-                }
-            }
+            let stats = blocklist_sources::refresh_blocklists(
+                &uf_clone,
+                &db_clone,
+                &webhooks_clone,
+                RefreshScope::All,
+            )
+            .await;
+            info!(
+                "Blocklists loaded: {} domains from {} sources",
+                stats.total_domains, stats.sources_loaded
+            );
         });
 
         // Initialize AI engine
@@ -98,8 +157,10 @@ impl AppState {
         let ml_engine = Arc::new(MLEngine::new());
         info!("ML engine initialized");
 
-        // Initialize profile manager with SQLite persistence
-        let profiles = Arc::new(ProfileManager::with_sqlite(db.clone()));
+        // Initialize profile manager with SQLite persistence; profiles drive
+        // the unified filter's device assignments
+        let profiles =
+            Arc::new(ProfileManager::with_sqlite(db.clone()).with_filter(unified_filter.clone()));
         info!("Profile manager initialized (SQLite-backed)");
 
         // Initialize tier manager with SQLite persistence
@@ -122,6 +183,18 @@ impl AppState {
 
         // Initialize metrics
         let metrics = Arc::new(MetricsCollector::new());
+        let rollups = Arc::new(RollupStore::new(db.clone(), metrics.clone()));
+
+        // Start the durable query log writer
+        let query_log = Arc::new(QueryLogWriter::start(
+            db.clone(),
+            unified_filter.clone(),
+            profiles.devices().clone(),
+        ));
+        info!("Query log writer initialized");
+
+        // Start dnstap output if a collector or file is configured
+        let dnstap = DnstapConfig::from_env().map(|config| Arc::new(DnstapWriter::start(config)));
 
         // Wrap resolver in Arc for sharing
         let resolver = Arc::new(resolver);
@@ -129,7 +202,15 @@ impl AppState {
         // Initialize background tasks (blocklist auto-refresh, metrics, etc.)
         let bg_config = BackgroundTasksConfig::default();
         let background_tasks = Arc::new(BackgroundTasks::new(bg_config));
-        background_tasks.start(unified_filter.clone(), metrics.clone());
+        background_tasks.start(
+            unified_filter.clone(),
+            metrics.clone(),
+            db.clone(),
+            webhooks.clone(),
+            profiles.devices().clone(),
+            tiers.clone(),
+        );
+        background_tasks.start_rollup_flush(rollups.clone());
         info!("Background tasks initialized (blocklist refresh every 6 hours)");
 
         // Start cache warming in background
@@ -140,10 +221,6 @@ impl AppState {
             warm_cache(&resolver_for_warming).await;
         });
 
-        // Initialize webhook manager for threat notifications
-        let webhooks = Arc::new(WebhookManager::new());
-        info!("Webhook manager initialized");
-
         info!(
             "Application state initialized - blocklist: {} domains",
             filter.blocklist_size()
@@ -163,11 +240,67 @@ impl AppState {
             ml_engine,
             auth,
             db,
+            query_log,
+            rollups,
+            dnstap,
+            live: Arc::new(LiveHub::new()),
+            trusted_proxies: TrustedProxies::from_env(),
+            admins: Admins::from_env(),
             background_tasks,
             webhooks,
         })
     }
 
+    /// Whether the account may change settings for every profile
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.auth
+            .get_user(user_id)
+            .is_some_and(|user| self.admins.contains(&user.email))
+    }
+
+    /// State over an in-memory database, with no background tasks, blocklist
+    /// downloads or cache warming
+    #[cfg(test)]
+    pub async fn for_tests() -> Self {
+        let db = Arc::new(SqliteDb::new(":memory:").unwrap());
+        let filter = Arc::new(FilterEngine::new());
+        let cache = Arc::new(DNSCache::new(1_000, Duration::from_secs(300)));
+        let resolver = Arc::new(Resolver::new(cache, filter.clone()).await.unwrap());
+        let unified_filter = Arc::new(UnifiedFilter::new(filter.clone()));
+        let profiles =
+            Arc::new(ProfileManager::with_sqlite(db.clone()).with_filter(unified_filter.clone()));
+        let metrics = Arc::new(MetricsCollector::new());
+        let query_log = Arc::new(QueryLogWriter::start(
+            db.clone(),
+            unified_filter.clone(),
+            profiles.devices().clone(),
+        ));
+
+        Self {
+            rollups: Arc::new(RollupStore::new(db.clone(), metrics.clone())),
+            metrics,
+            start_time: Instant::now(),
+            resolver,
+            filter,
+            unified_filter,
+            ai_engine: Arc::new(AIEngine::new().await.unwrap()),
+            rate_limiter: Arc::new(RateLimiter::new()),
+            threat_intel: Arc::new(ThreatIntelEngine::new().await.unwrap()),
+            profiles,
+            tiers: Arc::new(TierManager::with_sqlite(db.clone())),
+            ml_engine: Arc::new(MLEngine::new()),
+            auth: Arc::new(AuthService::with_sqlite("test-secret", db.clone())),
+            db,
+            query_log,
+            dnstap: None,
+            live: Arc::new(LiveHub::new()),
+            trusted_proxies: TrustedProxies::default(),
+            admins: Admins::parse("admin@example.com"),
+            background_tasks: Arc::new(BackgroundTasks::new(BackgroundTasksConfig::default())),
+            webhooks: Arc::new(WebhookManager::new()),
+        }
+    }
+
     /// Load blocklists from config directory
     fn load_blocklists(filter: &FilterEngine) {
         let blocklist_dir = Path::new("config/blocklists");
@@ -232,4 +365,77 @@ impl AppState {
             }
         }
     }
+
+    /// Load usage budget counters saved before the last shutdown
+    fn load_quota_usage_from_db(unified_filter: &UnifiedFilter, db: &SqliteDb) {
+        // Profile-local days run up to a day either side of UTC
+        let since = (chrono::Utc::now() - chrono::Duration::days(1))
+            .date_naive()
+            .to_string();
+        match db.get_quota_usage(&since) {
+            Ok(saved) => {
+                let records: Vec<UsageRecord> = saved
+                    .into_iter()
+                    .filter_map(|record| {
+                        Some(UsageRecord {
+                            profile_id: record.profile_id,
+                            target: serde_json::from_str(&record.target).ok()?,
+                            day: record.day.parse().ok()?,
+                            used_secs: record.used_secs,
+                            last_seen: record.last_seen,
+                            notified: record.notified,
+                        })
+                    })
+                    .collect();
+                if !records.is_empty() {
+                    info!(
+                        "Loaded {} usage budget counters from database",
+                        records.len()
+                    );
+                }
+                unified_filter.usage().restore(records);
+            }
+            Err(e) => {
+                warn!("Failed to load usage budget counters from database: {}", e);
+            }
+        }
+    }
+
+    /// Load unexpired temporary allowlist entries and blocking pauses from database
+    fn load_overrides_from_db(unified_filter: &UnifiedFilter, db: &SqliteDb) {
+        let now = chrono::Utc::now();
+        let overrides = unified_filter.overrides();
+
+        match db.get_temporary_allows() {
+            Ok(entries) => {
+                let mut count = 0;
+                for entry in entries.into_iter().filter(|e| e.expires_at > now) {
+                    overrides.allow(&entry.domain, entry.profile_id.as_deref(), entry.expires_at);
+                    count += 1;
+                }
+                if count > 0 {
+                    info!("Loaded {} temporary allowlist entries from database", count);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to load temporary allowlist from database: {}", e);
+            }
+        }
+
+        match db.get_blocking_pauses() {
+            Ok(pauses) => {
+                let mut count = 0;
+                for pause in pauses.into_iter().filter(|p| p.expires_at > now) {
+                    overrides.pause(pause.profile_id.as_deref(), pause.expires_at);
+                    count += 1;
+                }
+                if count > 0 {
+                    info!("Loaded {} blocking pauses from database", count);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to load blocking pauses from database: {}", e);
+            }
+        }
+    }
 }
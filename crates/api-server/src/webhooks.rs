@@ -4,10 +4,12 @@
 
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use shield_dns_core::blocklist_fetcher::RefreshDiff;
+use shield_dns_core::usage::QuotaExhausted;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info};
 
 /// Webhook configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +43,8 @@ pub enum WebhookEvent {
     HighRiskDetected,
     /// Blocklist updated
     BlocklistUpdated,
+    /// A profile spent its daily usage budget for a service or category
+    QuotaExhausted,
     /// All events
     All,
 }
@@ -55,6 +59,12 @@ pub struct ThreatNotification {
     pub client_ip: Option<String>,
     pub risk_score: Option<f64>,
     pub details: Option<String>,
+    /// Per-source and per-category changes for `blocklist_updated` events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<RefreshDiff>,
+    /// Profile, target and reset time for `quota_exhausted` events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaExhausted>,
 }
 
 /// Webhook manager for handling notifications
@@ -122,6 +132,7 @@ impl WebhookManager {
     }
 
     /// Update a webhook
+    #[allow(dead_code)]
     pub fn update(&self, config: WebhookConfig) -> bool {
         let mut webhooks = self.webhooks.write();
         if let Some(webhook) = webhooks.iter_mut().find(|w| w.id == config.id) {
@@ -144,28 +155,60 @@ impl WebhookManager {
     }
 
     /// Notify webhooks of a high-risk detection
+    #[allow(dead_code)]
     pub async fn notify_high_risk(&self, notification: ThreatNotification) {
         self.send_notifications(&WebhookEvent::HighRiskDetected, &notification).await;
     }
 
     /// Notify webhooks of blocklist update
-    pub async fn notify_blocklist_updated(&self, domains_added: usize, sources_updated: usize) {
+    pub async fn notify_blocklist_updated(&self, diff: &RefreshDiff, sources_updated: usize) {
         let notification = ThreatNotification {
             event: "blocklist_updated".to_string(),
             timestamp: Self::current_timestamp(),
-            domain: format!("{} domains from {} sources", domains_added, sources_updated),
+            domain: format!(
+                "+{} / -{} domains from {} sources",
+                diff.added, diff.removed, sources_updated
+            ),
             category: "system".to_string(),
             client_ip: None,
             risk_score: None,
             details: Some(format!(
-                "Blocklist refreshed with {} new domains from {} sources",
-                domains_added, sources_updated
+                "Blocklist refreshed with {} new and {} removed domains across {} categories",
+                diff.added,
+                diff.removed,
+                diff.by_category.len()
             )),
+            diff: Some(diff.clone()),
+            quota: None,
         };
 
         self.send_notifications(&WebhookEvent::BlocklistUpdated, &notification).await;
     }
 
+    /// Notify webhooks that a profile's daily budget ran out
+    pub async fn notify_quota_exhausted(&self, event: &QuotaExhausted) {
+        let notification = ThreatNotification {
+            event: "quota_exhausted".to_string(),
+            timestamp: Self::current_timestamp(),
+            domain: event.target.name().to_string(),
+            category: "quota".to_string(),
+            client_ip: None,
+            risk_score: None,
+            details: Some(format!(
+                "Profile '{}' used its {} minute daily budget for {}; blocked until {}",
+                event.profile_name,
+                event.daily_minutes,
+                event.target.name(),
+                event.resets_at.to_rfc3339()
+            )),
+            diff: None,
+            quota: Some(event.clone()),
+        };
+
+        self.send_notifications(&WebhookEvent::QuotaExhausted, &notification)
+            .await;
+    }
+
     /// Send notifications to matching webhooks
     async fn send_notifications(&self, event: &WebhookEvent, notification: &ThreatNotification) {
         let webhooks = self.webhooks.read().clone();
@@ -29,6 +29,29 @@ pub struct DbDevice {
     pub created_at: DateTime<Utc>,
 }
 
+/// Network device in the device registry, stored in SQLite
+///
+/// Identifier lists are JSON arrays; an identifier belongs to at most one
+/// device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbNetworkDevice {
+    pub id: String,
+    pub name: String,
+    pub device_type: String,
+    pub ips: Vec<String>,
+    pub cidrs: Vec<String>,
+    pub client_ids: Vec<String>,
+    pub macs: Vec<String>,
+    /// App registration (`devices.id`) for Shield running on this device
+    pub registration_id: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Account that added or claimed the device
+    pub user_id: Option<String>,
+}
+
 /// Refresh token stored in SQLite
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DbRefreshToken {
@@ -65,6 +88,64 @@ pub struct DbQueryLog {
     pub cached: bool,
     pub response_time_ms: i64,
     pub timestamp: DateTime<Utc>,
+    /// Owner of the profile that answered the query, for retention
+    pub user_id: Option<String>,
+    /// Query type (1 = A, 28 = AAAA, ...), when known
+    pub qtype: Option<u16>,
+    /// Filter reason, e.g. `category_block`
+    pub reason: Option<String>,
+    pub category: Option<String>,
+    pub profile_id: Option<String>,
+    /// Device registry ID of the client
+    pub device_id: Option<String>,
+    /// Response code sent to the client (0 = NOERROR, 3 = NXDOMAIN)
+    pub rcode: Option<u16>,
+    /// Answer record data
+    pub answers: Vec<String>,
+    /// Upstream that answered; `None` when answered locally
+    pub upstream: Option<String>,
+    /// DNSSEC status of upstream answers
+    pub dnssec: Option<String>,
+    /// `udp`, `tcp`, `dot`, `doh` or `api`
+    pub protocol: Option<String>,
+}
+
+/// Query counters for one time bucket of a rollup series
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbRollup {
+    /// `minute`, `hour` or `day`
+    pub granularity: String,
+    /// Unix timestamp the bucket starts at
+    pub bucket: i64,
+    /// `all`, `client`, `profile` or `category`
+    pub dimension: String,
+    /// Client IP, profile ID or category; empty for `all`
+    pub key: String,
+    pub queries: i64,
+    pub blocked: i64,
+    pub cached: i64,
+}
+
+/// Query log search criteria; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct QueryLogFilter {
+    /// Domain contains this text
+    pub domain: Option<String>,
+    /// Domain is this name or a subdomain of it
+    pub domain_suffix: Option<String>,
+    pub client_ip: Option<String>,
+    pub device_id: Option<String>,
+    pub profile_id: Option<String>,
+    pub blocked: Option<bool>,
+    pub reason: Option<String>,
+    pub category: Option<String>,
+    pub qtype: Option<u16>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only entries older than this ID (pagination cursor)
+    pub before_id: Option<i64>,
+    /// Only entries answered by a profile this account owns
+    pub user_id: Option<String>,
 }
 
 /// Domain embedding stored in Qdrant
@@ -91,7 +172,8 @@ pub struct ThreatVector {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DbProfile {
     pub id: String,
-    pub user_id: String,
+    /// Owning account; `None` for profiles created without one
+    pub user_id: Option<String>,
     pub name: String,
     pub protection_level: String,
     pub blocked_categories: Vec<String>,
@@ -99,6 +181,15 @@ pub struct DbProfile {
     pub custom_allowlist: Vec<String>,
     pub time_rules: String, // JSON serialized TimeRule array
     pub device_ids: Vec<String>,
+    /// IANA timezone the time rules run in
+    pub timezone: String,
+    pub quotas: String, // JSON serialized UsageQuota array
+    /// Profile this one inherits lists, categories and rules from
+    pub parent_id: Option<String>,
+    /// Categories unblocked even when a parent blocks them
+    pub allowed_categories: Vec<String>,
+    /// Leave the profile's queries out of dnstap output
+    pub dnstap_opt_out: bool,
     pub enabled: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
@@ -129,3 +220,65 @@ pub struct DbUsage {
     pub devices_count: i64,
     pub updated_at: DateTime<Utc>,
 }
+
+/// Expiring allowlist entry stored in SQLite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbTemporaryAllow {
+    pub domain: String,
+    pub profile_id: Option<String>, // None applies to every profile
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Daily usage budget counter stored in SQLite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbQuotaUsage {
+    pub profile_id: String,
+    pub target: String, // JSON serialized QuotaTarget
+    pub day: String,    // profile-local day, YYYY-MM-DD
+    pub used_secs: i64,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub notified: bool,
+}
+
+/// Timed blocking pause stored in SQLite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbBlockingPause {
+    pub profile_id: Option<String>, // None pauses every profile
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Blocklist refresh record stored in SQLite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbBlocklistRefresh {
+    pub id: i64,
+    pub refreshed_at: DateTime<Utc>,
+    pub total_domains: i64,
+    pub sources_loaded: i64,
+    pub sources_failed: i64,
+    pub domains_added: i64,
+    pub domains_removed: i64,
+    pub diff: String, // JSON serialized per-source and per-category diff
+}
+
+/// Blocklist source stored in SQLite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbBlocklistSource {
+    pub id: String,
+    pub name: String,
+    pub url: String, // http(s):// or file://
+    pub category: String,
+    pub format: String, // "hosts", "domains", "adblock", "rpz", "regex"
+    pub enabled: bool,
+    pub description: String,
+    pub sha256: Option<String>,
+    pub max_bytes: Option<i64>,
+    pub min_entries: Option<i64>,
+    pub last_status: Option<String>, // freshness of the last load, e.g. "fresh", "failed"
+    pub last_error: Option<String>,
+    pub last_entries: i64,
+    pub last_checked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
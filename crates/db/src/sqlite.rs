@@ -4,12 +4,15 @@
 //! - Users and authentication
 //! - Devices and sessions
 //! - Blocklists and allowlists
+//! - Temporary allowlist entries and blocking pauses
 //! - Query logs
 //! - User profiles
+//! - Blocklist sources and refresh history
+//! - Application settings
 
 use crate::error::DbError;
 use crate::models::*;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
@@ -79,6 +82,24 @@ impl SqliteDb {
             );
             CREATE INDEX IF NOT EXISTS idx_devices_user ON devices(user_id);
 
+            -- Network device registry
+            CREATE TABLE IF NOT EXISTS network_devices (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                device_type TEXT NOT NULL DEFAULT 'other',
+                ips TEXT NOT NULL DEFAULT '[]',
+                cidrs TEXT NOT NULL DEFAULT '[]',
+                client_ids TEXT NOT NULL DEFAULT '[]',
+                macs TEXT NOT NULL DEFAULT '[]',
+                registration_id TEXT,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                user_id TEXT REFERENCES users(id) ON DELETE SET NULL,
+                FOREIGN KEY (registration_id) REFERENCES devices(id) ON DELETE SET NULL
+            );
+
             -- Refresh tokens table
             CREATE TABLE IF NOT EXISTS refresh_tokens (
                 token TEXT PRIMARY KEY,
@@ -104,6 +125,33 @@ impl SqliteDb {
                 added_at TEXT NOT NULL
             );
 
+            -- Expiring allowlist entries ('' profile_id = every profile)
+            CREATE TABLE IF NOT EXISTS temporary_allowlist (
+                domain TEXT NOT NULL,
+                profile_id TEXT NOT NULL DEFAULT '',
+                expires_at TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (domain, profile_id)
+            );
+
+            -- Timed blocking pauses ('' profile_id = every profile)
+            CREATE TABLE IF NOT EXISTS blocking_pauses (
+                profile_id TEXT PRIMARY KEY,
+                expires_at TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+
+            -- Daily usage budget counters per profile and target
+            CREATE TABLE IF NOT EXISTS quota_usage (
+                profile_id TEXT NOT NULL,
+                target TEXT NOT NULL,
+                day TEXT NOT NULL,
+                used_secs INTEGER NOT NULL DEFAULT 0,
+                last_seen TEXT,
+                notified INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (profile_id, target)
+            );
+
             -- Query log table (with automatic cleanup)
             CREATE TABLE IF NOT EXISTS query_log (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -112,15 +160,38 @@ impl SqliteDb {
                 blocked INTEGER DEFAULT 0,
                 cached INTEGER DEFAULT 0,
                 response_time_ms INTEGER,
-                timestamp TEXT NOT NULL
+                timestamp TEXT NOT NULL,
+                user_id TEXT,
+                qtype INTEGER,
+                reason TEXT,
+                category TEXT,
+                profile_id TEXT,
+                device_id TEXT,
+                rcode INTEGER,
+                answers TEXT NOT NULL DEFAULT '[]',
+                upstream TEXT,
+                dnssec TEXT,
+                protocol TEXT
             );
             CREATE INDEX IF NOT EXISTS idx_query_timestamp ON query_log(timestamp);
             CREATE INDEX IF NOT EXISTS idx_query_domain ON query_log(domain);
 
+            -- Query counters per minute/hour/day bucket ('' key = all queries)
+            CREATE TABLE IF NOT EXISTS query_rollups (
+                granularity TEXT NOT NULL,
+                bucket INTEGER NOT NULL,
+                dimension TEXT NOT NULL,
+                key TEXT NOT NULL,
+                queries INTEGER NOT NULL DEFAULT 0,
+                blocked INTEGER NOT NULL DEFAULT 0,
+                cached INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (granularity, dimension, key, bucket)
+            );
+
             -- Profiles table
             CREATE TABLE IF NOT EXISTS profiles (
                 id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
+                user_id TEXT,
                 name TEXT NOT NULL,
                 protection_level TEXT DEFAULT 'balanced',
                 blocked_categories TEXT DEFAULT '[]',
@@ -128,6 +199,11 @@ impl SqliteDb {
                 custom_allowlist TEXT DEFAULT '[]',
                 time_rules TEXT DEFAULT '[]',
                 device_ids TEXT DEFAULT '[]',
+                timezone TEXT NOT NULL DEFAULT 'UTC',
+                quotas TEXT NOT NULL DEFAULT '[]',
+                parent_id TEXT,
+                allowed_categories TEXT NOT NULL DEFAULT '[]',
+                dnstap_opt_out INTEGER NOT NULL DEFAULT 0,
                 enabled INTEGER DEFAULT 1,
                 created_at TEXT NOT NULL,
                 updated_at TEXT NOT NULL,
@@ -162,13 +238,187 @@ impl SqliteDb {
                 PRIMARY KEY (user_id, month),
                 FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
             );
+
+            -- Blocklist refresh history (bounded, newest rows kept)
+            CREATE TABLE IF NOT EXISTS blocklist_refresh_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                refreshed_at TEXT NOT NULL,
+                total_domains INTEGER DEFAULT 0,
+                sources_loaded INTEGER DEFAULT 0,
+                sources_failed INTEGER DEFAULT 0,
+                domains_added INTEGER DEFAULT 0,
+                domains_removed INTEGER DEFAULT 0,
+                diff TEXT DEFAULT '{}'
+            );
+
+            -- Blocklist sources (replaces config/blocklist-sources.json)
+            CREATE TABLE IF NOT EXISTS blocklist_sources (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                url TEXT UNIQUE NOT NULL,
+                category TEXT NOT NULL,
+                format TEXT NOT NULL,
+                enabled INTEGER DEFAULT 1,
+                description TEXT DEFAULT '',
+                sha256 TEXT,
+                max_bytes INTEGER,
+                min_entries INTEGER,
+                last_status TEXT,
+                last_error TEXT,
+                last_entries INTEGER DEFAULT 0,
+                last_checked_at TEXT,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+
+            -- Key-value application settings
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
         "#,
         )?;
 
+        // Columns added after their table was first released
+        Self::ensure_column(&conn, "profiles", "timezone", "TEXT NOT NULL DEFAULT 'UTC'")?;
+        Self::ensure_column(&conn, "profiles", "quotas", "TEXT NOT NULL DEFAULT '[]'")?;
+        Self::ensure_column(&conn, "profiles", "parent_id", "TEXT")?;
+        Self::ensure_column(
+            &conn,
+            "profiles",
+            "allowed_categories",
+            "TEXT NOT NULL DEFAULT '[]'",
+        )?;
+        Self::ensure_column(
+            &conn,
+            "profiles",
+            "dnstap_opt_out",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Self::relax_profile_owner(&conn)?;
+        Self::ensure_column(
+            &conn,
+            "network_devices",
+            "user_id",
+            "TEXT REFERENCES users(id) ON DELETE SET NULL",
+        )?;
+        Self::ensure_column(&conn, "query_log", "user_id", "TEXT")?;
+        for column in [
+            "reason",
+            "category",
+            "profile_id",
+            "device_id",
+            "upstream",
+            "dnssec",
+            "protocol",
+        ] {
+            Self::ensure_column(&conn, "query_log", column, "TEXT")?;
+        }
+        Self::ensure_column(&conn, "query_log", "qtype", "INTEGER")?;
+        Self::ensure_column(&conn, "query_log", "rcode", "INTEGER")?;
+        Self::ensure_column(&conn, "query_log", "answers", "TEXT NOT NULL DEFAULT '[]'")?;
+        // Indexes for the query log search filters on columns added above
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_query_user ON query_log(user_id, timestamp);
+             CREATE INDEX IF NOT EXISTS idx_query_profile ON query_log(profile_id, id);
+             CREATE INDEX IF NOT EXISTS idx_query_device ON query_log(device_id, id);
+             CREATE INDEX IF NOT EXISTS idx_query_reason ON query_log(reason, id);",
+        )?;
+        Self::release_placeholder_owners(&conn)?;
+
         info!("SQLite schema initialized");
         Ok(())
     }
 
+    /// Add a column to an existing table if it isn't there yet
+    fn ensure_column(
+        conn: &SqliteConn,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<(), DbError> {
+        let exists = conn
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?
+            .iter()
+            .any(|name| name == column);
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )?;
+            info!("Added column {}.{}", table, column);
+        }
+        Ok(())
+    }
+
+    /// Make `profiles.user_id` nullable on tables created when every profile
+    /// needed an account
+    ///
+    /// SQLite can't drop a NOT NULL constraint in place, so the table is rebuilt.
+    fn relax_profile_owner(conn: &SqliteConn) -> Result<(), DbError> {
+        let owner_required = conn
+            .prepare("PRAGMA table_info(profiles)")?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(1)?, row.get::<_, bool>(3)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .any(|(name, not_null)| name == "user_id" && not_null);
+        if !owner_required {
+            return Ok(());
+        }
+
+        let schema: String = conn.query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'profiles'",
+            [],
+            |row| row.get(0),
+        )?;
+        let schema = schema
+            .replacen("CREATE TABLE profiles", "CREATE TABLE profiles_rebuild", 1)
+            .replacen("user_id TEXT NOT NULL", "user_id TEXT", 1);
+        conn.execute_batch(&format!(
+            "BEGIN;
+             {};
+             INSERT INTO profiles_rebuild SELECT * FROM profiles;
+             DROP TABLE profiles;
+             ALTER TABLE profiles_rebuild RENAME TO profiles;
+             CREATE INDEX IF NOT EXISTS idx_profiles_user ON profiles(user_id);
+             COMMIT;",
+            schema
+        ))?;
+        info!("Made profiles.user_id nullable");
+        Ok(())
+    }
+
+    /// Hand profiles and query log entries owned by the placeholder account
+    /// once created for records without an owner back to no owner, and drop
+    /// the placeholder
+    ///
+    /// Placeholders are recognisable by a password hash no login can match.
+    fn release_placeholder_owners(conn: &SqliteConn) -> Result<(), DbError> {
+        let released = conn.execute(
+            "UPDATE profiles SET user_id = NULL
+             WHERE user_id IN (SELECT id FROM users WHERE password_hash = '!')",
+            [],
+        )?;
+        conn.execute(
+            "UPDATE query_log SET user_id = NULL
+             WHERE user_id IN (SELECT id FROM users WHERE password_hash = '!')",
+            [],
+        )?;
+        let removed = conn.execute("DELETE FROM users WHERE password_hash = '!'", [])?;
+        if removed > 0 {
+            info!(
+                "Removed {} placeholder users; {} profiles no longer have an owner",
+                removed, released
+            );
+        }
+        Ok(())
+    }
+
     // =========================================================================
     // User Operations
     // =========================================================================
@@ -299,28 +549,45 @@ impl SqliteDb {
         )?;
 
         let devices = stmt
-            .query_map(params![user_id], |row| {
-                Ok(DbDevice {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    device_name: row.get(2)?,
-                    platform: row.get(3)?,
-                    push_token: row.get(4)?,
-                    os_version: row.get(5)?,
-                    app_version: row.get(6)?,
-                    last_seen: DateTime::parse_from_rfc3339(&row.get::<_, String>(7)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                })
-            })?
+            .query_map(params![user_id], Self::device_from_row)?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(devices)
     }
 
+    /// Get a device registration by ID
+    pub fn get_device(&self, id: &str) -> Result<Option<DbDevice>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, device_name, platform, push_token, os_version, app_version, last_seen, created_at
+             FROM devices WHERE id = ?1",
+        )?;
+
+        let device = stmt
+            .query_row(params![id], Self::device_from_row)
+            .optional()?;
+
+        Ok(device)
+    }
+
+    fn device_from_row(row: &rusqlite::Row) -> rusqlite::Result<DbDevice> {
+        Ok(DbDevice {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            device_name: row.get(2)?,
+            platform: row.get(3)?,
+            push_token: row.get(4)?,
+            os_version: row.get(5)?,
+            app_version: row.get(6)?,
+            last_seen: DateTime::parse_from_rfc3339(&row.get::<_, String>(7)?)
+                .unwrap()
+                .with_timezone(&Utc),
+            created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(8)?)
+                .unwrap()
+                .with_timezone(&Utc),
+        })
+    }
+
     /// Update device push token
     pub fn update_device_push_token(&self, id: &str, push_token: &str) -> Result<(), DbError> {
         let conn = self.conn()?;
@@ -341,6 +608,86 @@ impl SqliteDb {
         Ok(())
     }
 
+    // =========================================================================
+    // Network Device Registry Operations
+    // =========================================================================
+
+    /// Insert or update a registry device
+    pub fn upsert_network_device(&self, device: &DbNetworkDevice) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO network_devices (id, name, device_type, ips, cidrs, client_ids, macs,
+             registration_id, first_seen, last_seen, created_at, updated_at, user_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+             ON CONFLICT(id) DO UPDATE SET name = ?2, device_type = ?3, ips = ?4, cidrs = ?5,
+             client_ids = ?6, macs = ?7, registration_id = ?8, first_seen = ?9, last_seen = ?10,
+             updated_at = ?12, user_id = ?13",
+            params![
+                device.id,
+                device.name,
+                device.device_type,
+                serde_json::to_string(&device.ips).unwrap_or_default(),
+                serde_json::to_string(&device.cidrs).unwrap_or_default(),
+                serde_json::to_string(&device.client_ids).unwrap_or_default(),
+                serde_json::to_string(&device.macs).unwrap_or_default(),
+                device.registration_id,
+                device.first_seen.to_rfc3339(),
+                device.last_seen.to_rfc3339(),
+                device.created_at.to_rfc3339(),
+                device.updated_at.to_rfc3339(),
+                device.user_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Get every registry device
+    pub fn get_network_devices(&self) -> Result<Vec<DbNetworkDevice>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, device_type, ips, cidrs, client_ids, macs, registration_id,
+             first_seen, last_seen, created_at, updated_at, user_id
+             FROM network_devices ORDER BY created_at",
+        )?;
+
+        let devices = stmt
+            .query_map([], |row| {
+                let time = |idx: usize| -> rusqlite::Result<DateTime<Utc>> {
+                    Ok(DateTime::parse_from_rfc3339(&row.get::<_, String>(idx)?)
+                        .unwrap()
+                        .with_timezone(&Utc))
+                };
+                let list = |idx: usize| -> rusqlite::Result<Vec<String>> {
+                    Ok(serde_json::from_str(&row.get::<_, String>(idx)?).unwrap_or_default())
+                };
+                Ok(DbNetworkDevice {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    device_type: row.get(2)?,
+                    ips: list(3)?,
+                    cidrs: list(4)?,
+                    client_ids: list(5)?,
+                    macs: list(6)?,
+                    registration_id: row.get(7)?,
+                    first_seen: time(8)?,
+                    last_seen: time(9)?,
+                    created_at: time(10)?,
+                    updated_at: time(11)?,
+                    user_id: row.get(12)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(devices)
+    }
+
+    /// Delete a registry device
+    pub fn delete_network_device(&self, id: &str) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM network_devices WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
     // =========================================================================
     // Token Operations
     // =========================================================================
@@ -525,37 +872,307 @@ impl SqliteDb {
     }
 
     // =========================================================================
-    // Query Log Operations
+    // Temporary Allowlist and Blocking Pauses
     // =========================================================================
 
-    /// Log a DNS query
-    pub fn log_query(&self, log: &DbQueryLog) -> Result<(), DbError> {
+    /// Add or replace an expiring allowlist entry
+    pub fn upsert_temporary_allow(&self, entry: &DbTemporaryAllow) -> Result<(), DbError> {
         let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO query_log (domain, client_ip, blocked, cached, response_time_ms, timestamp)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT OR REPLACE INTO temporary_allowlist (domain, profile_id, expires_at, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
             params![
-                log.domain,
-                log.client_ip,
-                log.blocked,
-                log.cached,
-                log.response_time_ms,
-                log.timestamp.to_rfc3339(),
+                entry.domain,
+                entry.profile_id.as_deref().unwrap_or(""),
+                expiry_text(&entry.expires_at),
+                entry.created_at.to_rfc3339(),
             ],
         )?;
         Ok(())
     }
 
-    /// Get recent queries
-    pub fn get_recent_queries(&self, limit: usize) -> Result<Vec<DbQueryLog>, DbError> {
+    /// Remove an expiring allowlist entry
+    pub fn delete_temporary_allow(
+        &self,
+        domain: &str,
+        profile_id: Option<&str>,
+    ) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute(
+            "DELETE FROM temporary_allowlist WHERE domain = ?1 AND profile_id = ?2",
+            params![domain, profile_id.unwrap_or("")],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Get all expiring allowlist entries, including expired ones not yet purged
+    pub fn get_temporary_allows(&self) -> Result<Vec<DbTemporaryAllow>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT domain, profile_id, expires_at, created_at FROM temporary_allowlist ORDER BY expires_at",
+        )?;
+
+        let entries = stmt
+            .query_map([], |row| {
+                Ok(DbTemporaryAllow {
+                    domain: row.get(0)?,
+                    profile_id: Some(row.get::<_, String>(1)?).filter(|p| !p.is_empty()),
+                    expires_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)
+                        .unwrap()
+                        .with_timezone(&Utc),
+                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(3)?)
+                        .unwrap()
+                        .with_timezone(&Utc),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    /// Save usage budget counters, replacing each profile and target's row
+    pub fn upsert_quota_usage(&self, records: &[DbQuotaUsage]) -> Result<usize, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO quota_usage (profile_id, target, day, used_secs, last_seen, notified)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for record in records {
+                stmt.execute(params![
+                    record.profile_id,
+                    record.target,
+                    record.day,
+                    record.used_secs,
+                    record.last_seen.map(|t| t.to_rfc3339()),
+                    record.notified,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(records.len())
+    }
+
+    /// Get saved usage budget counters on or after a day (`YYYY-MM-DD`)
+    pub fn get_quota_usage(&self, since_day: &str) -> Result<Vec<DbQuotaUsage>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT profile_id, target, day, used_secs, last_seen, notified FROM quota_usage WHERE day >= ?1",
+        )?;
+
+        let records = stmt
+            .query_map(params![since_day], |row| {
+                Ok(DbQuotaUsage {
+                    profile_id: row.get(0)?,
+                    target: row.get(1)?,
+                    day: row.get(2)?,
+                    used_secs: row.get(3)?,
+                    last_seen: row
+                        .get::<_, Option<String>>(4)?
+                        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                        .map(|t| t.with_timezone(&Utc)),
+                    notified: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(records)
+    }
+
+    /// Delete usage budget counters from before a day (`YYYY-MM-DD`)
+    pub fn delete_quota_usage_before(&self, day: &str) -> Result<usize, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM quota_usage WHERE day < ?1", params![day])?;
+        Ok(deleted)
+    }
+
+    /// Start or replace a blocking pause
+    pub fn upsert_blocking_pause(&self, pause: &DbBlockingPause) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO blocking_pauses (profile_id, expires_at, created_at)
+             VALUES (?1, ?2, ?3)",
+            params![
+                pause.profile_id.as_deref().unwrap_or(""),
+                expiry_text(&pause.expires_at),
+                pause.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// End a blocking pause
+    pub fn delete_blocking_pause(&self, profile_id: Option<&str>) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute(
+            "DELETE FROM blocking_pauses WHERE profile_id = ?1",
+            params![profile_id.unwrap_or("")],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Get all blocking pauses, including expired ones not yet purged
+    pub fn get_blocking_pauses(&self) -> Result<Vec<DbBlockingPause>, DbError> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, domain, client_ip, blocked, cached, response_time_ms, timestamp
-             FROM query_log ORDER BY timestamp DESC LIMIT ?1",
+            "SELECT profile_id, expires_at, created_at FROM blocking_pauses ORDER BY expires_at",
+        )?;
+
+        let pauses = stmt
+            .query_map([], |row| {
+                Ok(DbBlockingPause {
+                    profile_id: Some(row.get::<_, String>(0)?).filter(|p| !p.is_empty()),
+                    expires_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
+                        .unwrap()
+                        .with_timezone(&Utc),
+                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(2)?)
+                        .unwrap()
+                        .with_timezone(&Utc),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(pauses)
+    }
+
+    /// Delete allowlist entries and pauses that expired at or before `now`
+    pub fn delete_expired_overrides(&self, now: DateTime<Utc>) -> Result<usize, DbError> {
+        let conn = self.conn()?;
+        let now = expiry_text(&now);
+        let allows = conn.execute(
+            "DELETE FROM temporary_allowlist WHERE expires_at <= ?1",
+            params![now],
         )?;
+        let pauses = conn.execute(
+            "DELETE FROM blocking_pauses WHERE expires_at <= ?1",
+            params![now],
+        )?;
+        Ok(allows + pauses)
+    }
+
+    // =========================================================================
+    // Query Log Operations
+    // =========================================================================
+
+    /// Log a DNS query
+    pub fn log_query(&self, log: &DbQueryLog) -> Result<(), DbError> {
+        self.log_queries(std::slice::from_ref(log)).map(|_| ())
+    }
+
+    /// Log a batch of DNS queries in a single transaction
+    pub fn log_queries(&self, logs: &[DbQueryLog]) -> Result<usize, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO query_log (domain, client_ip, blocked, cached, response_time_ms, timestamp,
+                 user_id, qtype, reason, category, profile_id, device_id, rcode, answers, upstream, dnssec, protocol)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+            )?;
+            for log in logs {
+                stmt.execute(params![
+                    log.domain,
+                    log.client_ip,
+                    log.blocked,
+                    log.cached,
+                    log.response_time_ms,
+                    log.timestamp.to_rfc3339(),
+                    log.user_id,
+                    log.qtype,
+                    log.reason,
+                    log.category,
+                    log.profile_id,
+                    log.device_id,
+                    log.rcode,
+                    serde_json::to_string(&log.answers).unwrap_or_default(),
+                    log.upstream,
+                    log.dnssec,
+                    log.protocol,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(logs.len())
+    }
+
+    /// Get recent queries
+    pub fn get_recent_queries(&self, limit: usize) -> Result<Vec<DbQueryLog>, DbError> {
+        self.search_queries(&QueryLogFilter::default(), limit)
+    }
+
+    /// Search the query log, newest first
+    pub fn search_queries(
+        &self,
+        filter: &QueryLogFilter,
+        limit: usize,
+    ) -> Result<Vec<DbQueryLog>, DbError> {
+        let mut clauses: Vec<&str> = Vec::new();
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+        if let Some(ref domain) = filter.domain {
+            clauses.push("instr(domain, ?) > 0");
+            values.push(Box::new(domain.to_lowercase()));
+        }
+        if let Some(ref suffix) = filter.domain_suffix {
+            let suffix = suffix.trim_start_matches('.').to_lowercase();
+            clauses.push("(domain = ? OR substr(domain, -length(?)) = ?)");
+            let dotted = format!(".{}", suffix);
+            values.push(Box::new(suffix));
+            values.push(Box::new(dotted.clone()));
+            values.push(Box::new(dotted));
+        }
+        let exact = [
+            ("client_ip = ?", &filter.client_ip),
+            ("device_id = ?", &filter.device_id),
+            ("profile_id = ?", &filter.profile_id),
+            ("reason = ?", &filter.reason),
+            ("category = ?", &filter.category),
+            ("user_id = ?", &filter.user_id),
+        ];
+        for (clause, value) in exact {
+            if let Some(value) = value {
+                clauses.push(clause);
+                values.push(Box::new(value.clone()));
+            }
+        }
+        if let Some(blocked) = filter.blocked {
+            clauses.push("blocked = ?");
+            values.push(Box::new(blocked));
+        }
+        if let Some(qtype) = filter.qtype {
+            clauses.push("qtype = ?");
+            values.push(Box::new(qtype));
+        }
+        if let Some(since) = filter.since {
+            clauses.push("timestamp >= ?");
+            values.push(Box::new(since.to_rfc3339()));
+        }
+        if let Some(until) = filter.until {
+            clauses.push("timestamp < ?");
+            values.push(Box::new(until.to_rfc3339()));
+        }
+        if let Some(before_id) = filter.before_id {
+            clauses.push("id < ?");
+            values.push(Box::new(before_id));
+        }
+        values.push(Box::new(limit as i64));
+
+        let where_clause = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, domain, client_ip, blocked, cached, response_time_ms, timestamp, user_id,
+             qtype, reason, category, profile_id, device_id, rcode, answers, upstream, dnssec, protocol
+             FROM query_log {} ORDER BY id DESC LIMIT ?",
+            where_clause
+        ))?;
 
         let logs = stmt
-            .query_map(params![limit as i64], |row| {
+            .query_map(rusqlite::params_from_iter(values.iter()), |row| {
                 Ok(DbQueryLog {
                     id: row.get(0)?,
                     domain: row.get(1)?,
@@ -566,6 +1183,17 @@ impl SqliteDb {
                     timestamp: DateTime::parse_from_rfc3339(&row.get::<_, String>(6)?)
                         .unwrap()
                         .with_timezone(&Utc),
+                    user_id: row.get(7)?,
+                    qtype: row.get(8)?,
+                    reason: row.get(9)?,
+                    category: row.get(10)?,
+                    profile_id: row.get(11)?,
+                    device_id: row.get(12)?,
+                    rcode: row.get(13)?,
+                    answers: serde_json::from_str(&row.get::<_, String>(14)?).unwrap_or_default(),
+                    upstream: row.get(15)?,
+                    dnssec: row.get(16)?,
+                    protocol: row.get(17)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
@@ -606,17 +1234,219 @@ impl SqliteDb {
         Ok(deleted)
     }
 
+    /// Clean query logs attributed to one user (`None` = unattributed) older than N days
+    pub fn clean_old_user_queries(
+        &self,
+        user_id: Option<&str>,
+        days: i64,
+    ) -> Result<usize, DbError> {
+        let conn = self.conn()?;
+        let cutoff = Utc::now() - chrono::Duration::days(days);
+        let deleted = conn.execute(
+            "DELETE FROM query_log WHERE user_id IS ?1 AND timestamp < ?2",
+            params![user_id, cutoff.to_rfc3339()],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Users with entries in the query log (`None` = unattributed entries)
+    pub fn get_query_log_users(&self) -> Result<Vec<Option<String>>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT DISTINCT user_id FROM query_log")?;
+        let users = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
     // =========================================================================
-    // Profile Operations
+    // Query Rollup Operations
     // =========================================================================
 
-    /// Create a new profile
-    pub fn create_profile(&self, profile: &DbProfile) -> Result<(), DbError> {
+    /// Add counts to rollup buckets, creating them as needed
+    pub fn add_rollups(&self, rollups: &[DbRollup]) -> Result<usize, DbError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO query_rollups (granularity, bucket, dimension, key, queries, blocked, cached)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (granularity, dimension, key, bucket) DO UPDATE SET
+                    queries = queries + excluded.queries,
+                    blocked = blocked + excluded.blocked,
+                    cached = cached + excluded.cached",
+            )?;
+            for rollup in rollups {
+                stmt.execute(params![
+                    rollup.granularity,
+                    rollup.bucket,
+                    rollup.dimension,
+                    rollup.key,
+                    rollup.queries,
+                    rollup.blocked,
+                    rollup.cached,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(rollups.len())
+    }
+
+    /// Rollup buckets in `[since, until)`, oldest first; `key: None` returns every key
+    pub fn get_rollups(
+        &self,
+        granularity: &str,
+        dimension: &str,
+        key: Option<&str>,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<DbRollup>, DbError> {
         let conn = self.conn()?;
-        conn.execute(
+        let mut stmt = conn.prepare(
+            "SELECT granularity, bucket, dimension, key, queries, blocked, cached
+             FROM query_rollups
+             WHERE granularity = ?1 AND dimension = ?2 AND (?3 IS NULL OR key = ?3)
+               AND bucket >= ?4 AND bucket < ?5
+             ORDER BY bucket, key",
+        )?;
+
+        let rollups = stmt
+            .query_map(params![granularity, dimension, key, since, until], |row| {
+                Ok(DbRollup {
+                    granularity: row.get(0)?,
+                    bucket: row.get(1)?,
+                    dimension: row.get(2)?,
+                    key: row.get(3)?,
+                    queries: row.get(4)?,
+                    blocked: row.get(5)?,
+                    cached: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(rollups)
+    }
+
+    /// Rollup buckets in `[since, until)` whose key starts with `prefix`,
+    /// oldest first
+    pub fn get_rollups_with_prefix(
+        &self,
+        granularity: &str,
+        dimension: &str,
+        prefix: &str,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<DbRollup>, DbError> {
+        // Keys sharing the prefix sort between it and the prefix with its
+        // last character incremented, so the primary key index serves this
+        let mut upper = prefix.to_string();
+        if let Some(last) = upper.pop() {
+            upper.push(char::from_u32(last as u32 + 1).unwrap_or(char::MAX));
+        }
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT granularity, bucket, dimension, key, queries, blocked, cached
+             FROM query_rollups
+             WHERE granularity = ?1 AND dimension = ?2 AND key >= ?3 AND key < ?4
+               AND bucket >= ?5 AND bucket < ?6
+             ORDER BY bucket, key",
+        )?;
+
+        let rollups = stmt
+            .query_map(
+                params![granularity, dimension, prefix, upper, since, until],
+                |row| {
+                    Ok(DbRollup {
+                        granularity: row.get(0)?,
+                        bucket: row.get(1)?,
+                        dimension: row.get(2)?,
+                        key: row.get(3)?,
+                        queries: row.get(4)?,
+                        blocked: row.get(5)?,
+                        cached: row.get(6)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(rollups)
+    }
+
+    /// Start of the latest bucket with queries for one key
+    pub fn last_rollup_bucket(
+        &self,
+        granularity: &str,
+        dimension: &str,
+        key: &str,
+    ) -> Result<Option<i64>, DbError> {
+        let conn = self.conn()?;
+        let bucket = conn.query_row(
+            "SELECT MAX(bucket) FROM query_rollups
+             WHERE granularity = ?1 AND dimension = ?2 AND key = ?3 AND queries > 0",
+            params![granularity, dimension, key],
+            |row| row.get(0),
+        )?;
+        Ok(bucket)
+    }
+
+    /// Keep only the `keep` busiest keys per owner in each bucket of a
+    /// scoped dimension that starts before `before`, deleting the rest.
+    /// The owner is the part of the key before `separator`.
+    pub fn prune_rollup_keys(
+        &self,
+        granularity: &str,
+        dimension: &str,
+        separator: char,
+        before: i64,
+        keep: usize,
+    ) -> Result<usize, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute(
+            "DELETE FROM query_rollups WHERE rowid IN (
+                SELECT rowid FROM (
+                    SELECT rowid, ROW_NUMBER() OVER (
+                        PARTITION BY bucket, substr(key, 1, instr(key, ?3) - 1)
+                        ORDER BY queries DESC, key
+                    ) AS rank
+                    FROM query_rollups
+                    WHERE granularity = ?1 AND dimension = ?2 AND bucket < ?4
+                )
+                WHERE rank > ?5
+            )",
+            params![
+                granularity,
+                dimension,
+                separator.to_string(),
+                before,
+                keep as i64
+            ],
+        )?;
+        Ok(deleted)
+    }
+
+    /// Delete rollup buckets of one granularity that start before `before`
+    pub fn delete_rollups_before(&self, granularity: &str, before: i64) -> Result<usize, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute(
+            "DELETE FROM query_rollups WHERE granularity = ?1 AND bucket < ?2",
+            params![granularity, before],
+        )?;
+        Ok(deleted)
+    }
+
+    // =========================================================================
+    // Profile Operations
+    // =========================================================================
+
+    /// Create a new profile
+    pub fn create_profile(&self, profile: &DbProfile) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
             "INSERT INTO profiles (id, user_id, name, protection_level, blocked_categories,
-             custom_blocklist, custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+             custom_blocklist, custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at, timezone,
+             quotas, parent_id, allowed_categories, dnstap_opt_out)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
             params![
                 profile.id,
                 profile.user_id,
@@ -630,46 +1460,59 @@ impl SqliteDb {
                 profile.enabled,
                 profile.created_at.to_rfc3339(),
                 profile.updated_at.to_rfc3339(),
+                profile.timezone,
+                profile.quotas,
+                profile.parent_id,
+                serde_json::to_string(&profile.allowed_categories).unwrap_or_default(),
+                profile.dnstap_opt_out,
             ],
         )?;
-        debug!("Created profile: {} for user {}", profile.name, profile.user_id);
+        debug!(
+            "Created profile: {} for user {:?}",
+            profile.name, profile.user_id
+        );
         Ok(())
     }
 
+    fn profile_from_row(row: &rusqlite::Row) -> rusqlite::Result<DbProfile> {
+        Ok(DbProfile {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            name: row.get(2)?,
+            protection_level: row.get(3)?,
+            blocked_categories: serde_json::from_str(&row.get::<_, String>(4)?).unwrap_or_default(),
+            custom_blocklist: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+            custom_allowlist: serde_json::from_str(&row.get::<_, String>(6)?).unwrap_or_default(),
+            time_rules: row.get(7)?,
+            device_ids: serde_json::from_str(&row.get::<_, String>(8)?).unwrap_or_default(),
+            enabled: row.get(9)?,
+            created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
+                .unwrap()
+                .with_timezone(&Utc),
+            updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(11)?)
+                .unwrap()
+                .with_timezone(&Utc),
+            timezone: row.get(12)?,
+            quotas: row.get(13)?,
+            parent_id: row.get(14)?,
+            allowed_categories: serde_json::from_str(&row.get::<_, String>(15)?)
+                .unwrap_or_default(),
+            dnstap_opt_out: row.get(16)?,
+        })
+    }
+
     /// Get profile by ID
     pub fn get_profile(&self, id: &str) -> Result<Option<DbProfile>, DbError> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, protection_level, blocked_categories, custom_blocklist,
-             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at
+             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at, timezone, quotas,
+             parent_id, allowed_categories, dnstap_opt_out
              FROM profiles WHERE id = ?1",
         )?;
 
         let profile = stmt
-            .query_row(params![id], |row| {
-                Ok(DbProfile {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    name: row.get(2)?,
-                    protection_level: row.get(3)?,
-                    blocked_categories: serde_json::from_str(&row.get::<_, String>(4)?)
-                        .unwrap_or_default(),
-                    custom_blocklist: serde_json::from_str(&row.get::<_, String>(5)?)
-                        .unwrap_or_default(),
-                    custom_allowlist: serde_json::from_str(&row.get::<_, String>(6)?)
-                        .unwrap_or_default(),
-                    time_rules: row.get(7)?,
-                    device_ids: serde_json::from_str(&row.get::<_, String>(8)?)
-                        .unwrap_or_default(),
-                    enabled: row.get(9)?,
-                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                    updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(11)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                })
-            })
+            .query_row(params![id], Self::profile_from_row)
             .optional()?;
 
         Ok(profile)
@@ -680,35 +1523,13 @@ impl SqliteDb {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, protection_level, blocked_categories, custom_blocklist,
-             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at
+             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at, timezone, quotas,
+             parent_id, allowed_categories, dnstap_opt_out
              FROM profiles WHERE user_id = ?1",
         )?;
 
         let profiles = stmt
-            .query_map(params![user_id], |row| {
-                Ok(DbProfile {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    name: row.get(2)?,
-                    protection_level: row.get(3)?,
-                    blocked_categories: serde_json::from_str(&row.get::<_, String>(4)?)
-                        .unwrap_or_default(),
-                    custom_blocklist: serde_json::from_str(&row.get::<_, String>(5)?)
-                        .unwrap_or_default(),
-                    custom_allowlist: serde_json::from_str(&row.get::<_, String>(6)?)
-                        .unwrap_or_default(),
-                    time_rules: row.get(7)?,
-                    device_ids: serde_json::from_str(&row.get::<_, String>(8)?)
-                        .unwrap_or_default(),
-                    enabled: row.get(9)?,
-                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                    updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(11)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                })
-            })?
+            .query_map(params![user_id], Self::profile_from_row)?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(profiles)
@@ -719,35 +1540,13 @@ impl SqliteDb {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, protection_level, blocked_categories, custom_blocklist,
-             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at
+             custom_allowlist, time_rules, device_ids, enabled, created_at, updated_at, timezone, quotas,
+             parent_id, allowed_categories, dnstap_opt_out
              FROM profiles",
         )?;
 
         let profiles = stmt
-            .query_map([], |row| {
-                Ok(DbProfile {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    name: row.get(2)?,
-                    protection_level: row.get(3)?,
-                    blocked_categories: serde_json::from_str(&row.get::<_, String>(4)?)
-                        .unwrap_or_default(),
-                    custom_blocklist: serde_json::from_str(&row.get::<_, String>(5)?)
-                        .unwrap_or_default(),
-                    custom_allowlist: serde_json::from_str(&row.get::<_, String>(6)?)
-                        .unwrap_or_default(),
-                    time_rules: row.get(7)?,
-                    device_ids: serde_json::from_str(&row.get::<_, String>(8)?)
-                        .unwrap_or_default(),
-                    enabled: row.get(9)?,
-                    created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                    updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(11)?)
-                        .unwrap()
-                        .with_timezone(&Utc),
-                })
-            })?
+            .query_map([], Self::profile_from_row)?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(profiles)
@@ -759,7 +1558,8 @@ impl SqliteDb {
         conn.execute(
             "UPDATE profiles SET name = ?1, protection_level = ?2, blocked_categories = ?3,
              custom_blocklist = ?4, custom_allowlist = ?5, time_rules = ?6, device_ids = ?7,
-             enabled = ?8, updated_at = ?9 WHERE id = ?10",
+             enabled = ?8, updated_at = ?9, timezone = ?11, quotas = ?12,
+             parent_id = ?13, allowed_categories = ?14, dnstap_opt_out = ?15 WHERE id = ?10",
             params![
                 profile.name,
                 profile.protection_level,
@@ -771,6 +1571,11 @@ impl SqliteDb {
                 profile.enabled,
                 Utc::now().to_rfc3339(),
                 profile.id,
+                profile.timezone,
+                profile.quotas,
+                profile.parent_id,
+                serde_json::to_string(&profile.allowed_categories).unwrap_or_default(),
+                profile.dnstap_opt_out,
             ],
         )?;
         Ok(())
@@ -991,6 +1796,250 @@ impl SqliteDb {
         )?;
         Ok(())
     }
+
+    // =========================================================================
+    // Blocklist Refresh History
+    // =========================================================================
+
+    /// Record a blocklist refresh, keeping only the newest `keep` entries
+    pub fn record_blocklist_refresh(
+        &self,
+        refresh: &DbBlocklistRefresh,
+        keep: usize,
+    ) -> Result<i64, DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO blocklist_refresh_history
+             (refreshed_at, total_domains, sources_loaded, sources_failed, domains_added, domains_removed, diff)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                refresh.refreshed_at.to_rfc3339(),
+                refresh.total_domains,
+                refresh.sources_loaded,
+                refresh.sources_failed,
+                refresh.domains_added,
+                refresh.domains_removed,
+                refresh.diff,
+            ],
+        )?;
+        let id = conn.last_insert_rowid();
+
+        let pruned = conn.execute(
+            "DELETE FROM blocklist_refresh_history WHERE id NOT IN
+             (SELECT id FROM blocklist_refresh_history ORDER BY id DESC LIMIT ?1)",
+            params![keep as i64],
+        )?;
+        if pruned > 0 {
+            debug!("Pruned {} old blocklist refresh records", pruned);
+        }
+        Ok(id)
+    }
+
+    /// Get the most recent blocklist refreshes, newest first
+    pub fn get_blocklist_history(&self, limit: usize) -> Result<Vec<DbBlocklistRefresh>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, refreshed_at, total_domains, sources_loaded, sources_failed,
+                    domains_added, domains_removed, diff
+             FROM blocklist_refresh_history ORDER BY id DESC LIMIT ?1",
+        )?;
+
+        let history = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(DbBlocklistRefresh {
+                    id: row.get(0)?,
+                    refreshed_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
+                        .unwrap()
+                        .with_timezone(&Utc),
+                    total_domains: row.get(2)?,
+                    sources_loaded: row.get(3)?,
+                    sources_failed: row.get(4)?,
+                    domains_added: row.get(5)?,
+                    domains_removed: row.get(6)?,
+                    diff: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(history)
+    }
+
+    // =========================================================================
+    // Blocklist Source Operations
+    // =========================================================================
+
+    /// Create a blocklist source
+    pub fn create_blocklist_source(&self, source: &DbBlocklistSource) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO blocklist_sources (id, name, url, category, format, enabled, description,
+             sha256, max_bytes, min_entries, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                source.id,
+                source.name,
+                source.url,
+                source.category,
+                source.format,
+                source.enabled,
+                source.description,
+                source.sha256,
+                source.max_bytes,
+                source.min_entries,
+                source.created_at.to_rfc3339(),
+                source.updated_at.to_rfc3339(),
+            ],
+        )?;
+        debug!("Created blocklist source: {} ({})", source.name, source.url);
+        Ok(())
+    }
+
+    /// Get a blocklist source by ID
+    pub fn get_blocklist_source(&self, id: &str) -> Result<Option<DbBlocklistSource>, DbError> {
+        let conn = self.conn()?;
+        let source = conn
+            .query_row(
+                &format!("{} WHERE id = ?1", BLOCKLIST_SOURCE_SELECT),
+                params![id],
+                row_to_blocklist_source,
+            )
+            .optional()?;
+        Ok(source)
+    }
+
+    /// Get all blocklist sources in creation order
+    pub fn get_blocklist_sources(&self) -> Result<Vec<DbBlocklistSource>, DbError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "{} ORDER BY created_at, rowid",
+            BLOCKLIST_SOURCE_SELECT
+        ))?;
+        let sources = stmt
+            .query_map([], row_to_blocklist_source)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sources)
+    }
+
+    /// Update a blocklist source's configuration
+    pub fn update_blocklist_source(&self, source: &DbBlocklistSource) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE blocklist_sources SET name = ?1, url = ?2, category = ?3, format = ?4,
+             enabled = ?5, description = ?6, sha256 = ?7, max_bytes = ?8, min_entries = ?9,
+             updated_at = ?10 WHERE id = ?11",
+            params![
+                source.name,
+                source.url,
+                source.category,
+                source.format,
+                source.enabled,
+                source.description,
+                source.sha256,
+                source.max_bytes,
+                source.min_entries,
+                Utc::now().to_rfc3339(),
+                source.id,
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Delete a blocklist source
+    pub fn delete_blocklist_source(&self, id: &str) -> Result<bool, DbError> {
+        let conn = self.conn()?;
+        let deleted = conn.execute("DELETE FROM blocklist_sources WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Record the outcome of the last load of a source
+    pub fn update_blocklist_source_status(
+        &self,
+        url: &str,
+        status: &str,
+        error: Option<&str>,
+        entries: i64,
+        checked_at: Option<DateTime<Utc>>,
+    ) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE blocklist_sources SET last_status = ?1, last_error = ?2, last_entries = ?3,
+             last_checked_at = ?4 WHERE url = ?5",
+            params![
+                status,
+                error,
+                entries,
+                checked_at.map(|t| t.to_rfc3339()),
+                url,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // =========================================================================
+    // Settings
+    // =========================================================================
+
+    /// Get a setting value
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>, DbError> {
+        let conn = self.conn()?;
+        let value = conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Set a setting value
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), DbError> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO settings (key, value, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key) DO UPDATE SET value = ?2, updated_at = ?3",
+            params![key, value, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+}
+
+/// Fixed-width UTC timestamp so expiry columns compare correctly as text
+fn expiry_text(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+const BLOCKLIST_SOURCE_SELECT: &str =
+    "SELECT id, name, url, category, format, enabled, description, sha256, max_bytes, min_entries,
+     last_status, last_error, last_entries, last_checked_at, created_at, updated_at
+     FROM blocklist_sources";
+
+fn row_to_blocklist_source(row: &rusqlite::Row) -> Result<DbBlocklistSource, rusqlite::Error> {
+    Ok(DbBlocklistSource {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        url: row.get(2)?,
+        category: row.get(3)?,
+        format: row.get(4)?,
+        enabled: row.get(5)?,
+        description: row.get(6)?,
+        sha256: row.get(7)?,
+        max_bytes: row.get(8)?,
+        min_entries: row.get(9)?,
+        last_status: row.get(10)?,
+        last_error: row.get(11)?,
+        last_entries: row.get(12)?,
+        last_checked_at: row
+            .get::<_, Option<String>>(13)?
+            .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+            .map(|t| t.with_timezone(&Utc)),
+        created_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(14)?)
+            .unwrap()
+            .with_timezone(&Utc),
+        updated_at: DateTime::parse_from_rfc3339(&row.get::<_, String>(15)?)
+            .unwrap()
+            .with_timezone(&Utc),
+    })
 }
 
 /// Query statistics
@@ -1068,4 +2117,596 @@ mod tests {
         let by_email = db.get_user_by_email("test@example.com").unwrap().unwrap();
         assert_eq!(by_email.id, "user-123");
     }
+
+    #[test]
+    fn test_blocklist_refresh_history() {
+        let db = SqliteDb::new(":memory:").unwrap();
+
+        for added in 1..=5 {
+            let refresh = DbBlocklistRefresh {
+                id: 0,
+                refreshed_at: Utc::now(),
+                total_domains: 100,
+                sources_loaded: 2,
+                sources_failed: 0,
+                domains_added: added,
+                domains_removed: 0,
+                diff: "{}".to_string(),
+            };
+            db.record_blocklist_refresh(&refresh, 3).unwrap();
+        }
+
+        // Only the newest three are kept, newest first
+        let history = db.get_blocklist_history(10).unwrap();
+        let added: Vec<i64> = history.iter().map(|r| r.domains_added).collect();
+        assert_eq!(added, vec![5, 4, 3]);
+        assert_eq!(db.get_blocklist_history(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_profile_timezone() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        db.create_user(&DbUser {
+            id: "user-1".to_string(),
+            email: "parent@example.com".to_string(),
+            password_hash: "hash".to_string(),
+            tier: "free".to_string(),
+            email_verified: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+        .unwrap();
+        let mut profile = DbProfile {
+            id: "profile-1".to_string(),
+            user_id: Some("user-1".to_string()),
+            name: "Kids".to_string(),
+            protection_level: "kid".to_string(),
+            blocked_categories: vec!["adult".to_string()],
+            custom_blocklist: vec![],
+            custom_allowlist: vec![],
+            time_rules: "[]".to_string(),
+            device_ids: vec!["192.168.1.20".to_string()],
+            timezone: "Europe/London".to_string(),
+            quotas: "[]".to_string(),
+            parent_id: None,
+            allowed_categories: vec![],
+            dnstap_opt_out: false,
+            enabled: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        db.create_profile(&profile).unwrap();
+        assert_eq!(
+            db.get_profile("profile-1").unwrap().unwrap().timezone,
+            "Europe/London"
+        );
+
+        profile.timezone = "Asia/Tokyo".to_string();
+        db.update_profile(&profile).unwrap();
+        assert_eq!(db.get_all_profiles().unwrap()[0].timezone, "Asia/Tokyo");
+
+        // Re-running the migration on an up-to-date table is a no-op
+        let conn = db.conn().unwrap();
+        SqliteDb::ensure_column(&conn, "profiles", "timezone", "TEXT NOT NULL DEFAULT 'UTC'")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_quota_usage() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let record = |day: &str, used_secs: i64| DbQuotaUsage {
+            profile_id: "kid".to_string(),
+            target: r#"{"kind":"service","value":"youtube"}"#.to_string(),
+            day: day.to_string(),
+            used_secs,
+            last_seen: Some(Utc::now()),
+            notified: false,
+        };
+        db.upsert_quota_usage(&[record("2024-01-09", 600)]).unwrap();
+        db.upsert_quota_usage(&[record("2024-01-10", 120)]).unwrap();
+
+        let saved = db.get_quota_usage("2024-01-09").unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(
+            (saved[0].day.as_str(), saved[0].used_secs),
+            ("2024-01-10", 120)
+        );
+        assert!(db.get_quota_usage("2024-01-11").unwrap().is_empty());
+        assert_eq!(db.delete_quota_usage_before("2024-01-11").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_profiles_without_owner() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let conn = db.conn().unwrap();
+        // Tables from before profiles could be created without an account
+        conn.execute_batch(
+            "DROP TABLE profiles;
+             CREATE TABLE profiles (
+                 id TEXT PRIMARY KEY,
+                 user_id TEXT NOT NULL,
+                 name TEXT NOT NULL,
+                 protection_level TEXT DEFAULT 'balanced',
+                 blocked_categories TEXT DEFAULT '[]',
+                 custom_blocklist TEXT DEFAULT '[]',
+                 custom_allowlist TEXT DEFAULT '[]',
+                 time_rules TEXT DEFAULT '[]',
+                 device_ids TEXT DEFAULT '[]',
+                 enabled INTEGER DEFAULT 1,
+                 created_at TEXT NOT NULL,
+                 updated_at TEXT NOT NULL,
+                 FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+             );",
+        )
+        .unwrap();
+        for (column, definition) in [
+            ("timezone", "TEXT NOT NULL DEFAULT 'UTC'"),
+            ("quotas", "TEXT NOT NULL DEFAULT '[]'"),
+            ("parent_id", "TEXT"),
+            ("allowed_categories", "TEXT NOT NULL DEFAULT '[]'"),
+            ("dnstap_opt_out", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            SqliteDb::ensure_column(&conn, "profiles", column, definition).unwrap();
+        }
+        SqliteDb::relax_profile_owner(&conn).unwrap();
+
+        // Owned by the placeholder account earlier versions created
+        conn.execute_batch(
+            "INSERT INTO users (id, email, password_hash, tier, email_verified, created_at, updated_at)
+             VALUES ('default', 'default@local', '!', 'free', 0, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+             INSERT INTO profiles (id, user_id, name, created_at, updated_at)
+             VALUES ('legacy', 'default', 'Legacy', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+             INSERT INTO query_log (domain, client_ip, user_id, timestamp)
+             VALUES ('example.com', '10.0.0.1', 'default', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+        SqliteDb::release_placeholder_owners(&conn).unwrap();
+        drop(conn);
+        assert_eq!(db.get_profile("legacy").unwrap().unwrap().user_id, None);
+        assert_eq!(db.get_query_log_users().unwrap(), vec![None]);
+
+        db.create_profile(&DbProfile {
+            id: "profile-1".to_string(),
+            user_id: None,
+            name: "Household".to_string(),
+            protection_level: "adult".to_string(),
+            blocked_categories: vec![],
+            custom_blocklist: vec![],
+            custom_allowlist: vec![],
+            time_rules: "[]".to_string(),
+            device_ids: vec![],
+            timezone: "UTC".to_string(),
+            quotas: "[]".to_string(),
+            parent_id: None,
+            allowed_categories: vec![],
+            dnstap_opt_out: false,
+            enabled: true,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+        .unwrap();
+        assert_eq!(db.get_profile("profile-1").unwrap().unwrap().user_id, None);
+        assert!(db.get_user_by_email("default@local").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_network_devices() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let now = Utc::now();
+        db.create_user(&DbUser {
+            id: "user-1".to_string(),
+            email: "parent@example.com".to_string(),
+            password_hash: "hash".to_string(),
+            tier: "free".to_string(),
+            email_verified: true,
+            created_at: now,
+            updated_at: now,
+        })
+        .unwrap();
+        db.create_device(&DbDevice {
+            id: "app-1".to_string(),
+            user_id: "user-1".to_string(),
+            device_name: "Sam's iPhone".to_string(),
+            platform: "ios".to_string(),
+            push_token: None,
+            os_version: None,
+            app_version: None,
+            last_seen: now,
+            created_at: now,
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_device("app-1").unwrap().unwrap().device_name,
+            "Sam's iPhone"
+        );
+
+        let mut device = DbNetworkDevice {
+            id: "device-1".to_string(),
+            name: "Sam's iPhone".to_string(),
+            device_type: "phone".to_string(),
+            ips: vec!["192.168.1.20".to_string()],
+            cidrs: vec![],
+            client_ids: vec!["sam-phone".to_string()],
+            macs: vec![],
+            registration_id: Some("app-1".to_string()),
+            first_seen: now,
+            last_seen: now,
+            created_at: now,
+            updated_at: now,
+            user_id: Some("user-1".to_string()),
+        };
+        db.upsert_network_device(&device).unwrap();
+        device.ips.push("fd00::20".to_string());
+        db.upsert_network_device(&device).unwrap();
+
+        let stored = db.get_network_devices().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].ips, vec!["192.168.1.20", "fd00::20"]);
+        assert_eq!(stored[0].registration_id.as_deref(), Some("app-1"));
+        assert_eq!(stored[0].user_id.as_deref(), Some("user-1"));
+
+        // Removing the app registration keeps the device
+        db.conn()
+            .unwrap()
+            .execute("DELETE FROM devices WHERE id = 'app-1'", [])
+            .unwrap();
+        assert_eq!(db.get_network_devices().unwrap()[0].registration_id, None);
+        // Deleting the account leaves the device unclaimed
+        db.conn()
+            .unwrap()
+            .execute("DELETE FROM users WHERE id = 'user-1'", [])
+            .unwrap();
+        assert_eq!(db.get_network_devices().unwrap()[0].user_id, None);
+        assert!(db.delete_network_device("device-1").unwrap());
+        assert!(!db.delete_network_device("device-1").unwrap());
+    }
+
+    #[test]
+    fn test_query_log_retention() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let entry = |domain: &str, user_id: Option<&str>, days_ago: i64| DbQueryLog {
+            id: 0,
+            domain: domain.to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            blocked: false,
+            cached: false,
+            response_time_ms: 3,
+            timestamp: Utc::now() - chrono::Duration::days(days_ago),
+            user_id: user_id.map(str::to_string),
+            qtype: Some(1),
+            reason: Some("default_allow".to_string()),
+            category: None,
+            profile_id: None,
+            device_id: None,
+            rcode: Some(0),
+            answers: vec!["93.184.216.34".to_string()],
+            upstream: Some("cloudflare".to_string()),
+            dnssec: Some("unvalidated".to_string()),
+            protocol: Some("doh".to_string()),
+        };
+        let batch = vec![
+            entry("old-free.com", None, 3),
+            entry("new-free.com", None, 0),
+            entry("old-pro.com", Some("pro-user"), 3),
+        ];
+        assert_eq!(db.log_queries(&batch).unwrap(), 3);
+        db.log_query(&entry("ancient-pro.com", Some("pro-user"), 40))
+            .unwrap();
+
+        let mut users = db.get_query_log_users().unwrap();
+        users.sort();
+        assert_eq!(users, vec![None, Some("pro-user".to_string())]);
+
+        // Each owner is pruned on their own schedule
+        assert_eq!(db.clean_old_user_queries(None, 1).unwrap(), 1);
+        assert_eq!(db.clean_old_user_queries(Some("pro-user"), 30).unwrap(), 1);
+        let mut left: Vec<String> = db
+            .get_recent_queries(10)
+            .unwrap()
+            .into_iter()
+            .map(|q| q.domain)
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["new-free.com", "old-pro.com"]);
+
+        // Enriched fields survive the round trip
+        let stored = &db.get_recent_queries(1).unwrap()[0];
+        assert_eq!(stored.answers, vec!["93.184.216.34"]);
+        assert_eq!(
+            (stored.rcode, stored.protocol.as_deref()),
+            (Some(0), Some("doh"))
+        );
+    }
+
+    #[test]
+    fn test_query_rollups() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let rollup = |bucket: i64, key: &str, queries: i64, blocked: i64| DbRollup {
+            granularity: "hour".to_string(),
+            bucket,
+            dimension: "category".to_string(),
+            key: key.to_string(),
+            queries,
+            blocked,
+            cached: 0,
+        };
+        db.add_rollups(&[
+            rollup(3600, "ads", 5, 5),
+            rollup(7200, "ads", 1, 1),
+            rollup(3600, "malware", 2, 2),
+        ])
+        .unwrap();
+        // Counts for an existing bucket are added, not replaced
+        db.add_rollups(&[rollup(3600, "ads", 3, 2)]).unwrap();
+
+        let ads = db
+            .get_rollups("hour", "category", Some("ads"), 0, 10_000)
+            .unwrap();
+        let counts: Vec<(i64, i64, i64)> = ads
+            .iter()
+            .map(|r| (r.bucket, r.queries, r.blocked))
+            .collect();
+        assert_eq!(counts, vec![(3600, 8, 7), (7200, 1, 1)]);
+        assert_eq!(
+            db.get_rollups("hour", "category", None, 0, 7200)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(db
+            .get_rollups("day", "category", None, 0, 10_000)
+            .unwrap()
+            .is_empty());
+
+        let scoped = |key: &str| DbRollup {
+            dimension: "device_category".to_string(),
+            key: key.to_string(),
+            ..rollup(7200, "", 1, 0)
+        };
+        db.add_rollups(&[
+            scoped("tablet|ads"),
+            scoped("tablet|games"),
+            scoped("tablets|ads"),
+            scoped("phone|ads"),
+        ])
+        .unwrap();
+        let tablet = db
+            .get_rollups_with_prefix("hour", "device_category", "tablet|", 0, 10_000)
+            .unwrap();
+        assert_eq!(
+            tablet.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(),
+            vec!["tablet|ads", "tablet|games"]
+        );
+        assert_eq!(
+            db.last_rollup_bucket("hour", "category", "ads").unwrap(),
+            Some(7200)
+        );
+        assert_eq!(
+            db.last_rollup_bucket("hour", "category", "games").unwrap(),
+            None
+        );
+
+        assert_eq!(db.delete_rollups_before("hour", 7200).unwrap(), 2);
+        assert_eq!(
+            db.get_rollups("hour", "category", None, 0, 10_000)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Pruning keeps each owner's busiest keys per bucket
+        db.add_rollups(&[scoped("tablet|games")]).unwrap();
+        assert_eq!(
+            db.prune_rollup_keys("hour", "device_category", '|', 10_000, 1)
+                .unwrap(),
+            1
+        );
+        let kept = db
+            .get_rollups("hour", "device_category", None, 0, 10_000)
+            .unwrap();
+        assert_eq!(
+            kept.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(),
+            vec!["phone|ads", "tablets|ads", "tablet|games"]
+        );
+    }
+
+    #[test]
+    fn test_query_log_search() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let entry = |domain: &str,
+                     blocked: bool,
+                     category: Option<&str>,
+                     device_id: Option<&str>| DbQueryLog {
+            id: 0,
+            domain: domain.to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            blocked,
+            cached: false,
+            response_time_ms: 0,
+            timestamp: Utc::now(),
+            user_id: None,
+            qtype: Some(if blocked { 1 } else { 28 }),
+            reason: Some(
+                if blocked {
+                    "category_block"
+                } else {
+                    "default_allow"
+                }
+                .to_string(),
+            ),
+            category: category.map(str::to_string),
+            profile_id: None,
+            device_id: device_id.map(str::to_string),
+            rcode: Some(if blocked { 3 } else { 0 }),
+            answers: vec![],
+            upstream: None,
+            dnssec: None,
+            protocol: Some("udp".to_string()),
+        };
+        db.log_queries(&[
+            entry("ads.example.com", true, Some("ads"), Some("tv")),
+            entry("example.com", false, None, Some("tv")),
+            entry("notexample.com", false, None, None),
+            entry("tracker.net", true, Some("tracking"), Some("phone")),
+        ])
+        .unwrap();
+
+        let domains = |filter: QueryLogFilter, limit: usize| -> Vec<String> {
+            db.search_queries(&filter, limit)
+                .unwrap()
+                .into_iter()
+                .map(|q| q.domain)
+                .collect()
+        };
+
+        // Suffix matches the name and its subdomains only
+        let suffix = QueryLogFilter {
+            domain_suffix: Some("Example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(domains(suffix, 10), vec!["example.com", "ads.example.com"]);
+        let contains = QueryLogFilter {
+            domain: Some("example".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(domains(contains, 10).len(), 3);
+
+        let blocked_tv = QueryLogFilter {
+            blocked: Some(true),
+            device_id: Some("tv".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(domains(blocked_tv, 10), vec!["ads.example.com"]);
+        let by_reason = QueryLogFilter {
+            reason: Some("category_block".to_string()),
+            category: Some("tracking".to_string()),
+            qtype: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(domains(by_reason, 10), vec!["tracker.net"]);
+        let future = QueryLogFilter {
+            since: Some(Utc::now() + chrono::Duration::minutes(1)),
+            ..Default::default()
+        };
+        assert!(domains(future, 10).is_empty());
+
+        // Walk pages with the last ID as the cursor
+        let first = db.search_queries(&QueryLogFilter::default(), 3).unwrap();
+        let next = QueryLogFilter {
+            before_id: first.last().map(|q| q.id),
+            ..Default::default()
+        };
+        assert_eq!(first.len(), 3);
+        assert_eq!(domains(next, 3), vec!["ads.example.com"]);
+
+        // Owner scoping leaves out other accounts' and unowned entries
+        db.log_queries(&[DbQueryLog {
+            user_id: Some("user-1".to_string()),
+            ..entry("owned.example", false, None, None)
+        }])
+        .unwrap();
+        let owned = QueryLogFilter {
+            user_id: Some("user-1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(domains(owned, 10), vec!["owned.example"]);
+    }
+
+    #[test]
+    fn test_temporary_overrides() {
+        let db = SqliteDb::new(":memory:").unwrap();
+        let now = Utc::now();
+
+        for (domain, profile_id, minutes) in [
+            ("youtube.com", None, 30),
+            ("youtube.com", Some("kid"), 10),
+            ("games.example", Some("kid"), -1),
+        ] {
+            db.upsert_temporary_allow(&DbTemporaryAllow {
+                domain: domain.to_string(),
+                profile_id: profile_id.map(str::to_string),
+                expires_at: now + chrono::Duration::minutes(minutes),
+                created_at: now,
+            })
+            .unwrap();
+        }
+        db.upsert_blocking_pause(&DbBlockingPause {
+            profile_id: None,
+            expires_at: now + chrono::Duration::minutes(5),
+            created_at: now,
+        })
+        .unwrap();
+
+        let allows = db.get_temporary_allows().unwrap();
+        assert_eq!(allows.len(), 3);
+        assert_eq!(allows[0].domain, "games.example");
+        assert_eq!(db.get_blocking_pauses().unwrap()[0].profile_id, None);
+
+        // Only the already-expired entry is purged
+        assert_eq!(db.delete_expired_overrides(now).unwrap(), 1);
+        assert!(db
+            .delete_temporary_allow("youtube.com", Some("kid"))
+            .unwrap());
+        assert!(!db
+            .delete_temporary_allow("youtube.com", Some("kid"))
+            .unwrap());
+        assert_eq!(db.get_temporary_allows().unwrap()[0].profile_id, None);
+
+        assert_eq!(
+            db.delete_expired_overrides(now + chrono::Duration::hours(1))
+                .unwrap(),
+            2
+        );
+        assert!(!db.delete_blocking_pause(None).unwrap());
+    }
+
+    #[test]
+    fn test_blocklist_source_operations() {
+        let db = SqliteDb::new(":memory:").unwrap();
+
+        let mut source = DbBlocklistSource {
+            id: "src-1".to_string(),
+            name: "Ads".to_string(),
+            url: "https://lists.example.com/ads.txt".to_string(),
+            category: "ads".to_string(),
+            format: "domains".to_string(),
+            enabled: true,
+            description: String::new(),
+            sha256: None,
+            max_bytes: None,
+            min_entries: Some(10),
+            last_status: None,
+            last_error: None,
+            last_entries: 0,
+            last_checked_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        db.create_blocklist_source(&source).unwrap();
+
+        // URLs are unique
+        source.id = "src-2".to_string();
+        assert!(db.create_blocklist_source(&source).is_err());
+        source.id = "src-1".to_string();
+
+        source.enabled = false;
+        assert!(db.update_blocklist_source(&source).unwrap());
+        db.update_blocklist_source_status(&source.url, "failed", Some("HTTP 404"), 0, None)
+            .unwrap();
+
+        let found = db.get_blocklist_source("src-1").unwrap().unwrap();
+        assert!(!found.enabled);
+        assert_eq!(found.min_entries, Some(10));
+        assert_eq!(found.last_status.as_deref(), Some("failed"));
+        assert_eq!(found.last_error.as_deref(), Some("HTTP 404"));
+        assert_eq!(db.get_blocklist_sources().unwrap().len(), 1);
+
+        assert!(db.delete_blocklist_source("src-1").unwrap());
+        assert!(db.get_blocklist_source("src-1").unwrap().is_none());
+
+        assert!(db.get_setting("imported").unwrap().is_none());
+        db.set_setting("imported", "1").unwrap();
+        db.set_setting("imported", "2").unwrap();
+        assert_eq!(db.get_setting("imported").unwrap().as_deref(), Some("2"));
+    }
 }
@@ -0,0 +1,1010 @@
+//! AdBlock / AdGuard DNS filter rule syntax
+//!
+//! Parses the DNS subset of the AdGuard filtering syntax and evaluates it with
+//! AdGuard precedence:
+//!
+//! 1. `$badfilter` rules disable every rule with the same text
+//! 2. `@@...$important` > `...$important` > `@@...` > plain blocking rules
+//!
+//! Supported: `||domain^`, `|domain^`, domain-only and hosts-style lines,
+//! `*` wildcards, `/regex/` rules, exceptions (`@@`) and the `important`,
+//! `badfilter`, `client`, `dnstype`, `denyallow` and `ctag` modifiers. Rules
+//! with modifiers we don't understand (browser-only options like
+//! `$third-party`) are skipped, as AdGuard Home does.
+
+use ahash::{AHashMap, AHashSet};
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// What a matching rule does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Block,
+    Allow,
+}
+
+/// Domain part of a rule
+#[derive(Debug, Clone)]
+pub enum RulePattern {
+    /// `||example.com^` - the domain and all subdomains
+    Subtree(String),
+    /// `|example.com^`, `example.com` or hosts syntax - the domain only
+    Exact(String),
+    /// `|*.example.com^` or `||*.example.com^` - subdomains only
+    Subdomains(String),
+    /// `/regex/` or a wildcard pattern compiled to a regex
+    Regex(Regex),
+}
+
+impl RulePattern {
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            RulePattern::Subtree(d) => is_subdomain_or_equal(domain, d),
+            RulePattern::Exact(d) => domain == d,
+            RulePattern::Subdomains(d) => domain != d && is_subdomain_or_equal(domain, d),
+            RulePattern::Regex(re) => re.is_match(domain),
+        }
+    }
+}
+
+/// One entry of a `|`-separated modifier list, optionally negated with `~`
+#[derive(Debug, Clone, PartialEq)]
+struct ListEntry<T> {
+    value: T,
+    negated: bool,
+}
+
+/// Client selector used by `$client`
+#[derive(Debug, Clone, PartialEq)]
+enum ClientSelector {
+    Ip(IpAddr),
+    Net(IpNet),
+    Name(String),
+}
+
+/// Parsed rule modifiers
+#[derive(Debug, Clone, Default)]
+pub struct RuleModifiers {
+    pub important: bool,
+    pub badfilter: bool,
+    client: Vec<ListEntry<ClientSelector>>,
+    dnstype: Vec<ListEntry<u16>>,
+    denyallow: Vec<String>,
+    ctag: Vec<ListEntry<String>>,
+}
+
+impl RuleModifiers {
+    /// Whether the rule only applies to some clients, query types or subdomains
+    pub fn has_conditions(&self) -> bool {
+        !self.client.is_empty()
+            || !self.dnstype.is_empty()
+            || !self.denyallow.is_empty()
+            || !self.ctag.is_empty()
+    }
+
+    /// Whether the rule carries no modifiers at all
+    pub fn is_empty(&self) -> bool {
+        !self.important
+            && !self.badfilter
+            && self.client.is_empty()
+            && self.dnstype.is_empty()
+            && self.denyallow.is_empty()
+            && self.ctag.is_empty()
+    }
+}
+
+/// A parsed filter rule
+#[derive(Debug, Clone)]
+pub struct AdblockRule {
+    /// Original rule text, used for `$badfilter` matching and reporting
+    pub text: String,
+    pub action: RuleAction,
+    pub pattern: RulePattern,
+    pub modifiers: RuleModifiers,
+}
+
+impl AdblockRule {
+    /// A plain rule that can be stored in the domain trie instead of the rule engine
+    ///
+    /// Returns the trie pattern (`*.domain` or `domain`) when the rule is a
+    /// modifier-free blocking rule on a literal domain.
+    pub fn as_simple_block(&self) -> Option<String> {
+        if self.action != RuleAction::Block || !self.modifiers.is_empty() {
+            return None;
+        }
+        match &self.pattern {
+            RulePattern::Subtree(d) => Some(format!("*.{}", d)),
+            RulePattern::Exact(d) => Some(d.clone()),
+            RulePattern::Subdomains(_) | RulePattern::Regex(_) => None,
+        }
+    }
+
+    /// Key used to match this rule against `$badfilter` rules
+    fn badfilter_key(&self) -> String {
+        badfilter_key(&self.text)
+    }
+
+    /// Rank used to pick a winner among matching rules (higher wins)
+    pub fn precedence(&self) -> u8 {
+        match (self.modifiers.important, self.action) {
+            (true, RuleAction::Allow) => 3,
+            (true, RuleAction::Block) => 2,
+            (false, RuleAction::Allow) => 1,
+            (false, RuleAction::Block) => 0,
+        }
+    }
+
+    /// Check modifiers against the request context
+    fn applies_to(&self, domain: &str, ctx: &RuleContext) -> bool {
+        let m = &self.modifiers;
+
+        if !m.denyallow.is_empty() && m.denyallow.iter().any(|d| is_subdomain_or_equal(domain, d)) {
+            return false;
+        }
+
+        if !m.client.is_empty() {
+            let matches = |sel: &ClientSelector| match sel {
+                ClientSelector::Ip(ip) => ctx.client_ip == Some(*ip),
+                ClientSelector::Net(net) => ctx.client_ip.is_some_and(|ip| net.contains(&ip)),
+                ClientSelector::Name(name) => ctx
+                    .client_name
+                    .as_deref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(name)),
+            };
+            if !list_allows(&m.client, matches) {
+                return false;
+            }
+        }
+
+        if !m.dnstype.is_empty() {
+            let qtype = ctx.qtype;
+            if !list_allows(&m.dnstype, |t| qtype == Some(*t)) {
+                return false;
+            }
+        }
+
+        if !m.ctag.is_empty() && !list_allows(&m.ctag, |t| ctx.ctags.iter().any(|c| c == t)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Evaluate a `|`-separated list with `~` negations
+///
+/// The context must match at least one positive entry (if there are any)
+/// and none of the negated ones.
+fn list_allows<T>(entries: &[ListEntry<T>], mut matches: impl FnMut(&T) -> bool) -> bool {
+    let mut has_positive = false;
+    let mut positive_hit = false;
+    for entry in entries {
+        let hit = matches(&entry.value);
+        if entry.negated {
+            if hit {
+                return false;
+            }
+        } else {
+            has_positive = true;
+            positive_hit |= hit;
+        }
+    }
+    !has_positive || positive_hit
+}
+
+#[inline]
+fn is_subdomain_or_equal(domain: &str, parent: &str) -> bool {
+    domain == parent
+        || (domain.len() > parent.len()
+            && domain.ends_with(parent)
+            && domain.as_bytes()[domain.len() - parent.len() - 1] == b'.')
+}
+
+/// Normalize rule text for `$badfilter` comparison: drop the `badfilter`
+/// modifier and sort the rest so option order doesn't matter
+fn badfilter_key(text: &str) -> String {
+    let (pattern, options) = split_options(text);
+    let Some(options) = options else {
+        return pattern.to_lowercase();
+    };
+    let mut opts: Vec<String> = options
+        .split(',')
+        .map(|o| o.trim().to_lowercase())
+        .filter(|o| !o.is_empty() && o != "badfilter")
+        .collect();
+    opts.sort();
+    if opts.is_empty() {
+        pattern.to_lowercase()
+    } else {
+        format!("{}${}", pattern.to_lowercase(), opts.join(","))
+    }
+}
+
+/// Split a rule into pattern and modifier text at the last unescaped `$`
+/// that isn't part of a regex
+fn split_options(text: &str) -> (&str, Option<&str>) {
+    if text.starts_with('/') || text.starts_with("@@/") {
+        // Regex rules: options start after the closing slash
+        let body_start = text.find('/').unwrap_or(0);
+        if let Some(end) = text[body_start + 1..].rfind('/') {
+            let end = body_start + 1 + end;
+            let rest = &text[end + 1..];
+            if let Some(opts) = rest.strip_prefix('$') {
+                return (&text[..=end], Some(opts));
+            }
+            return (text, None);
+        }
+    }
+    match text.rfind('$') {
+        Some(pos) => (&text[..pos], Some(&text[pos + 1..])),
+        None => (text, None),
+    }
+}
+
+/// DNS record type names accepted by `$dnstype`
+pub fn qtype_from_name(name: &str) -> Option<u16> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "NAPTR" => 35,
+        "DS" => 43,
+        "DNSKEY" => 48,
+        "SVCB" => 64,
+        "HTTPS" => 65,
+        "ANY" => 255,
+        _ => return None,
+    })
+}
+
+/// Why a line did not produce a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Empty line or comment
+    Comment,
+    /// Cosmetic/browser rule or unsupported modifier
+    Unsupported,
+    /// Malformed rule
+    Invalid,
+}
+
+/// Parse a single line of an AdBlock/AdGuard list
+pub fn parse_rule(line: &str) -> Result<AdblockRule, SkipReason> {
+    let text = line.trim();
+
+    if text.is_empty() || text.starts_with('!') || text.starts_with('[') {
+        return Err(SkipReason::Comment);
+    }
+    // `#` starts a comment, but `##`/`#@#`/`#$#` inside a line are cosmetic rules
+    if text.starts_with('#') {
+        return Err(SkipReason::Comment);
+    }
+    if text.contains("##") || text.contains("#@#") || text.contains("#$#") || text.contains("#?#") {
+        return Err(SkipReason::Unsupported);
+    }
+
+    // Hosts syntax inside adblock lists: "0.0.0.0 example.com"
+    let mut parts = text.split_whitespace();
+    if let (Some(first), Some(second)) = (parts.next(), parts.next()) {
+        if first.parse::<IpAddr>().is_ok() {
+            let domain = second.to_lowercase();
+            if !is_valid_domain(&domain) || domain == "localhost" {
+                return Err(SkipReason::Invalid);
+            }
+            return Ok(AdblockRule {
+                text: text.to_string(),
+                action: RuleAction::Block,
+                pattern: RulePattern::Exact(domain),
+                modifiers: RuleModifiers::default(),
+            });
+        }
+        // Whitespace is only valid inside quoted modifier values
+        if !text.contains('$') {
+            return Err(SkipReason::Invalid);
+        }
+    }
+
+    let (action, body) = match text.strip_prefix("@@") {
+        Some(rest) => (RuleAction::Allow, rest),
+        None => (RuleAction::Block, text),
+    };
+
+    let (pattern_text, options) = split_options(body);
+    let modifiers = match options {
+        Some(opts) => parse_modifiers(opts)?,
+        None => RuleModifiers::default(),
+    };
+    let pattern = parse_pattern(pattern_text)?;
+
+    Ok(AdblockRule {
+        text: text.to_string(),
+        action,
+        pattern,
+        modifiers,
+    })
+}
+
+fn parse_modifiers(options: &str) -> Result<RuleModifiers, SkipReason> {
+    let mut m = RuleModifiers::default();
+    for option in options.split(',') {
+        let option = option.trim();
+        let (name, value) = match option.split_once('=') {
+            Some((n, v)) => (n.trim().to_ascii_lowercase(), Some(v.trim())),
+            None => (option.to_ascii_lowercase(), None),
+        };
+        match (name.as_str(), value) {
+            ("important", None) => m.important = true,
+            ("badfilter", None) => m.badfilter = true,
+            ("client", Some(v)) => {
+                for raw in split_list(v) {
+                    let (negated, raw) = strip_negation(&raw);
+                    let raw = raw
+                        .trim_matches(|c| c == '\'' || c == '"')
+                        .replace("\\'", "'");
+                    let value = if let Ok(ip) = raw.parse::<IpAddr>() {
+                        ClientSelector::Ip(ip)
+                    } else if let Ok(net) = raw.parse::<IpNet>() {
+                        ClientSelector::Net(net)
+                    } else {
+                        ClientSelector::Name(raw)
+                    };
+                    m.client.push(ListEntry { value, negated });
+                }
+            }
+            ("dnstype", Some(v)) => {
+                for raw in split_list(v) {
+                    let (negated, raw) = strip_negation(&raw);
+                    let value = qtype_from_name(raw).ok_or(SkipReason::Invalid)?;
+                    m.dnstype.push(ListEntry { value, negated });
+                }
+            }
+            ("denyallow", Some(v)) => {
+                for raw in split_list(v) {
+                    let domain = raw.to_lowercase();
+                    if domain.starts_with('~') || !is_valid_domain(&domain) {
+                        return Err(SkipReason::Invalid);
+                    }
+                    m.denyallow.push(domain);
+                }
+            }
+            ("ctag", Some(v)) => {
+                for raw in split_list(v) {
+                    let (negated, raw) = strip_negation(&raw);
+                    m.ctag.push(ListEntry {
+                        value: raw.to_lowercase(),
+                        negated,
+                    });
+                }
+            }
+            _ => return Err(SkipReason::Unsupported),
+        }
+    }
+    Ok(m)
+}
+
+/// Split a `|`-separated modifier value, honouring quoted client names
+fn split_list(value: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    current.push('\\');
+                    current.push(next);
+                }
+            }
+            '\'' | '"' if quote.is_none() => {
+                quote = Some(c);
+                current.push(c);
+            }
+            c if Some(c) == quote => {
+                quote = None;
+                current.push(c);
+            }
+            '|' if quote.is_none() => {
+                if !current.trim().is_empty() {
+                    out.push(current.trim().to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        out.push(current.trim().to_string());
+    }
+    out
+}
+
+fn strip_negation(value: &str) -> (bool, &str) {
+    match value.strip_prefix('~') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    }
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+}
+
+fn parse_pattern(text: &str) -> Result<RulePattern, SkipReason> {
+    if text.is_empty() {
+        return Err(SkipReason::Invalid);
+    }
+
+    // Regex rule: /pattern/
+    if text.len() > 2 && text.starts_with('/') && text.ends_with('/') {
+        let re = Regex::new(&format!("(?i){}", &text[1..text.len() - 1]))
+            .map_err(|_| SkipReason::Invalid)?;
+        return Ok(RulePattern::Regex(re));
+    }
+
+    let lower = text.to_lowercase();
+
+    // Browser URL rules (paths, schemes) have no DNS meaning
+    if lower.contains('/') || lower.contains(':') {
+        return Err(SkipReason::Unsupported);
+    }
+
+    let (anchor_domain, rest) = if let Some(r) = lower.strip_prefix("||") {
+        (true, r)
+    } else {
+        (false, lower.as_str())
+    };
+    let (anchor_start, rest) = match rest.strip_prefix('|') {
+        Some(r) if !anchor_domain => (true, r),
+        _ => (false, rest),
+    };
+    let (anchor_end, core) = match rest.strip_suffix('^').or_else(|| rest.strip_suffix('|')) {
+        Some(r) => (true, r),
+        None => (false, rest),
+    };
+
+    if core.is_empty() {
+        return Err(SkipReason::Invalid);
+    }
+
+    if !core.contains('*') && !core.contains('^') && is_valid_domain(core) {
+        if anchor_domain && anchor_end {
+            return Ok(RulePattern::Subtree(core.to_string()));
+        }
+        if (anchor_start && anchor_end) || (!anchor_domain && !anchor_start && !anchor_end) {
+            // Domain-only syntax matches the domain itself, like hosts files
+            return Ok(RulePattern::Exact(core.to_string()));
+        }
+    }
+
+    // A leading `*.` on an anchored literal only matches subdomains; index
+    // it by domain instead of compiling a regex
+    if let Some(domain) = core.strip_prefix("*.") {
+        if (anchor_domain || anchor_start) && anchor_end && is_valid_domain(domain) {
+            return Ok(RulePattern::Subdomains(domain.to_string()));
+        }
+    }
+
+    // Everything else becomes a regex over the full domain name
+    let mut re = String::from("^");
+    if anchor_domain {
+        re.push_str(r"(?:[^.]+\.)*");
+    } else if !anchor_start {
+        re.push_str(".*");
+    }
+    for c in core.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '^' => re.push_str(r"(?:\.|$)"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if !anchor_end {
+        re.push_str(".*");
+    }
+    re.push('$');
+    Regex::new(&re)
+        .map(RulePattern::Regex)
+        .map_err(|_| SkipReason::Invalid)
+}
+
+/// Request attributes that rule modifiers can match on
+#[derive(Debug, Clone, Default)]
+pub struct RuleContext {
+    pub client_ip: Option<IpAddr>,
+    pub client_name: Option<String>,
+    /// Numeric query type (1 = A, 28 = AAAA, ...), if known
+    pub qtype: Option<u16>,
+    /// Client tags for `$ctag` (e.g. `device_phone`, `user_child`)
+    pub ctags: Vec<String>,
+}
+
+/// Rule that won an evaluation
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    pub action: RuleAction,
+    pub important: bool,
+    /// Category of the list the rule came from
+    pub category: String,
+    /// Original rule text
+    pub rule: String,
+}
+
+#[derive(Debug, Clone)]
+struct StoredRule {
+    rule: AdblockRule,
+    category: Arc<str>,
+    badfilter_key: String,
+}
+
+impl StoredRule {
+    fn to_match(&self) -> RuleMatch {
+        RuleMatch {
+            action: self.rule.action,
+            important: self.rule.modifiers.important,
+            category: self.category.to_string(),
+            rule: self.rule.text.clone(),
+        }
+    }
+}
+
+/// Indexed rule set with AdGuard precedence
+#[derive(Debug, Default, Clone)]
+pub struct AdblockEngine {
+    rules: Vec<StoredRule>,
+    /// Literal-domain rules indexed by their domain (subtree and exact)
+    by_domain: AHashMap<String, Vec<usize>>,
+    /// Regex and wildcard rules, scanned linearly
+    regex_rules: Vec<usize>,
+    /// Keys of rules disabled by `$badfilter`
+    badfilters: AHashSet<String>,
+}
+
+impl AdblockEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule for a list category. `$badfilter` rules are recorded
+    /// rather than stored.
+    pub fn add_rule(&mut self, rule: AdblockRule, category: &str) {
+        if rule.modifiers.badfilter {
+            self.badfilters.insert(rule.badfilter_key());
+            return;
+        }
+
+        let idx = self.rules.len();
+        match &rule.pattern {
+            RulePattern::Subtree(d) | RulePattern::Exact(d) | RulePattern::Subdomains(d) => {
+                self.by_domain.entry(d.clone()).or_default().push(idx)
+            }
+            RulePattern::Regex(_) => self.regex_rules.push(idx),
+        }
+        let badfilter_key = rule.badfilter_key();
+        self.rules.push(StoredRule {
+            rule,
+            category: Arc::from(category),
+            badfilter_key,
+        });
+    }
+
+    /// Trie patterns disabled by `$badfilter` (for lists whose plain rules
+    /// were stored in the domain trie)
+    pub fn badfiltered_simple_patterns(&self) -> Vec<String> {
+        self.badfilters
+            .iter()
+            .filter_map(|key| parse_rule(key).ok())
+            .filter_map(|rule| rule.as_simple_block())
+            .collect()
+    }
+
+    /// Whether a rule with this text was disabled by `$badfilter`
+    pub fn is_badfiltered(&self, text: &str) -> bool {
+        self.badfilters.contains(&badfilter_key(text))
+    }
+
+    /// Number of stored rules (excluding `$badfilter` entries)
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Visit every stored rule with its list category
+    pub fn for_each_rule<'a>(&'a self, mut f: impl FnMut(&'a AdblockRule, &'a str)) {
+        for stored in &self.rules {
+            if !self.badfilters.contains(&stored.badfilter_key) {
+                f(&stored.rule, &stored.category);
+            }
+        }
+    }
+
+    /// Visit every stored rule, including ones disabled by `$badfilter`
+    pub fn for_each_stored_rule(&self, mut f: impl FnMut(&AdblockRule, &str)) {
+        for stored in &self.rules {
+            f(&stored.rule, &stored.category);
+        }
+    }
+
+    /// Drop all rules from a category
+    pub fn remove_category(&mut self, category: &str) {
+        let kept: Vec<StoredRule> = std::mem::take(&mut self.rules)
+            .into_iter()
+            .filter(|r| &*r.category != category)
+            .collect();
+        self.by_domain.clear();
+        self.regex_rules.clear();
+        for (idx, stored) in kept.iter().enumerate() {
+            match &stored.rule.pattern {
+                RulePattern::Subtree(d) | RulePattern::Exact(d) | RulePattern::Subdomains(d) => {
+                    self.by_domain.entry(d.clone()).or_default().push(idx)
+                }
+                RulePattern::Regex(_) => self.regex_rules.push(idx),
+            }
+        }
+        self.rules = kept;
+    }
+
+    /// Evaluate a domain against rules from the given categories
+    ///
+    /// Returns the winning rule by AdGuard precedence, or `None` if no rule
+    /// applies.
+    pub fn evaluate(
+        &self,
+        domain: &str,
+        ctx: &RuleContext,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<RuleMatch> {
+        let mut best: Option<&StoredRule> = None;
+        self.for_each_match(domain, ctx, category_enabled, |stored| {
+            if best.is_none_or(|b| stored.rule.precedence() > b.rule.precedence()) {
+                best = Some(stored);
+            }
+        });
+        best.map(StoredRule::to_match)
+    }
+
+    /// Every rule that applies to a query, in any category, winner first
+    ///
+    /// Rules are ordered by AdGuard precedence; among equals the one
+    /// `evaluate` would pick comes first.
+    pub fn matching_rules(&self, domain: &str, ctx: &RuleContext) -> Vec<RuleMatch> {
+        let mut matched: Vec<&StoredRule> = Vec::new();
+        self.for_each_match(domain, ctx, |_| true, |stored| matched.push(stored));
+        matched.sort_by_key(|stored| std::cmp::Reverse(stored.rule.precedence()));
+        matched.into_iter().map(StoredRule::to_match).collect()
+    }
+
+    /// Visit every active rule from an enabled category that applies to a query
+    fn for_each_match<'a>(
+        &'a self,
+        domain: &str,
+        ctx: &RuleContext,
+        category_enabled: impl Fn(&str) -> bool,
+        mut f: impl FnMut(&'a StoredRule),
+    ) {
+        let mut consider = |idx: usize| {
+            let stored = &self.rules[idx];
+            if category_enabled(&stored.category)
+                && !self.badfilters.contains(&stored.badfilter_key)
+                && stored.rule.pattern.matches(domain)
+                && stored.rule.applies_to(domain, ctx)
+            {
+                f(stored);
+            }
+        };
+
+        // Literal rules: probe the domain and each parent suffix
+        let mut suffix = domain;
+        loop {
+            if let Some(indices) = self.by_domain.get(suffix) {
+                for &idx in indices {
+                    consider(idx);
+                }
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => break,
+            }
+        }
+
+        for &idx in &self.regex_rules {
+            consider(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADGUARD_FIXTURE: &str = include_str!("../testdata/adguard-dns-sample.txt");
+
+    fn engine_from(list: &str, category: &str) -> AdblockEngine {
+        let mut engine = AdblockEngine::new();
+        for line in list.lines() {
+            if let Ok(rule) = parse_rule(line) {
+                engine.add_rule(rule, category);
+            }
+        }
+        engine
+    }
+
+    fn verdict(engine: &AdblockEngine, domain: &str, ctx: &RuleContext) -> Option<RuleAction> {
+        engine.evaluate(domain, ctx, |_| true).map(|m| m.action)
+    }
+
+    #[test]
+    fn test_parse_basic_forms() {
+        let rule = parse_rule("||ads.example.com^").unwrap();
+        assert_eq!(
+            rule.as_simple_block(),
+            Some("*.ads.example.com".to_string())
+        );
+
+        let rule = parse_rule("0.0.0.0 tracker.example.org").unwrap();
+        assert_eq!(
+            rule.as_simple_block(),
+            Some("tracker.example.org".to_string())
+        );
+
+        let rule = parse_rule("example.net").unwrap();
+        assert!(matches!(rule.pattern, RulePattern::Exact(ref d) if d == "example.net"));
+
+        let rule = parse_rule("@@||good.example.com^").unwrap();
+        assert_eq!(rule.action, RuleAction::Allow);
+        assert!(rule.as_simple_block().is_none());
+
+        assert_eq!(parse_rule("! comment").unwrap_err(), SkipReason::Comment);
+        assert_eq!(
+            parse_rule("example.com##.banner").unwrap_err(),
+            SkipReason::Unsupported
+        );
+        assert_eq!(
+            parse_rule("||example.com^$third-party").unwrap_err(),
+            SkipReason::Unsupported
+        );
+        assert_eq!(
+            parse_rule("||example.com/ads/*").unwrap_err(),
+            SkipReason::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_wildcard_and_regex() {
+        let engine = engine_from("||ad*.example.com^\n/^track[0-9]+\\./", "ads");
+        let ctx = RuleContext::default();
+
+        assert_eq!(
+            verdict(&engine, "ad1.example.com", &ctx),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(
+            verdict(&engine, "x.adserver.example.com", &ctx),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(
+            verdict(&engine, "track42.example.org", &ctx),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "www.example.com", &ctx), None);
+    }
+
+    #[test]
+    fn test_subdomains_only_pattern() {
+        let rule = parse_rule("|*.example.com^").unwrap();
+        assert!(matches!(rule.pattern, RulePattern::Subdomains(ref d) if d == "example.com"));
+        assert!(rule.as_simple_block().is_none());
+
+        let engine = engine_from("|*.example.com^\n@@||*.ok.example.org^", "ads");
+        let ctx = RuleContext::default();
+        assert_eq!(
+            verdict(&engine, "a.b.example.com", &ctx),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "example.com", &ctx), None);
+        assert_eq!(
+            verdict(&engine, "x.ok.example.org", &ctx),
+            Some(RuleAction::Allow)
+        );
+        assert_eq!(verdict(&engine, "ok.example.org", &ctx), None);
+    }
+
+    #[test]
+    fn test_exception_and_important_precedence() {
+        let list = "||ads.example.com^\n@@||ads.example.com^\n||tracker.example.com^$important\n@@||tracker.example.com^\n||cdn.example.com^$important\n@@||cdn.example.com^$important";
+        let engine = engine_from(list, "ads");
+        let ctx = RuleContext::default();
+
+        // Exception beats plain block
+        assert_eq!(
+            verdict(&engine, "ads.example.com", &ctx),
+            Some(RuleAction::Allow)
+        );
+        // $important block beats plain exception
+        assert_eq!(
+            verdict(&engine, "tracker.example.com", &ctx),
+            Some(RuleAction::Block)
+        );
+        // $important exception beats $important block
+        assert_eq!(
+            verdict(&engine, "cdn.example.com", &ctx),
+            Some(RuleAction::Allow)
+        );
+
+        // Every applying rule is listed, winner first
+        let rules: Vec<String> = engine
+            .matching_rules("tracker.example.com", &ctx)
+            .into_iter()
+            .map(|m| m.rule)
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "||tracker.example.com^$important",
+                "@@||tracker.example.com^"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_badfilter() {
+        let engine = engine_from(
+            "||ads.example.com^$dnstype=AAAA\n||ads.example.com^$badfilter,dnstype=AAAA",
+            "ads",
+        );
+        let ctx = RuleContext {
+            qtype: Some(28),
+            ..Default::default()
+        };
+        assert_eq!(verdict(&engine, "ads.example.com", &ctx), None);
+
+        let engine = engine_from("||plain.example.com^$badfilter", "ads");
+        assert_eq!(
+            engine.badfiltered_simple_patterns(),
+            vec!["*.plain.example.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_client_modifier() {
+        let engine = engine_from(
+            "||games.example.com^$client=192.168.1.0/24|~192.168.1.5\n||social.example.com^$client='Kid\\'s tablet'",
+            "social",
+        );
+
+        let kid = RuleContext {
+            client_ip: Some("192.168.1.20".parse().unwrap()),
+            ..Default::default()
+        };
+        let parent = RuleContext {
+            client_ip: Some("192.168.1.5".parse().unwrap()),
+            ..Default::default()
+        };
+        let named = RuleContext {
+            client_name: Some("kid's tablet".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            verdict(&engine, "games.example.com", &kid),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "games.example.com", &parent), None);
+        assert_eq!(
+            verdict(&engine, "social.example.com", &named),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "social.example.com", &kid), None);
+    }
+
+    #[test]
+    fn test_dnstype_denyallow_ctag() {
+        let engine = engine_from(
+            "||ipv6only.example.com^$dnstype=AAAA\n||example.org^$denyallow=cdn.example.org\n||video.example.com^$ctag=device_tv|~user_admin",
+            "custom",
+        );
+
+        let aaaa = RuleContext {
+            qtype: Some(28),
+            ..Default::default()
+        };
+        let a = RuleContext {
+            qtype: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict(&engine, "ipv6only.example.com", &aaaa),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "ipv6only.example.com", &a), None);
+
+        assert_eq!(
+            verdict(&engine, "www.example.org", &a),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "img.cdn.example.org", &a), None);
+
+        let tv = RuleContext {
+            ctags: vec!["device_tv".to_string()],
+            ..Default::default()
+        };
+        let admin_tv = RuleContext {
+            ctags: vec!["device_tv".to_string(), "user_admin".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict(&engine, "video.example.com", &tv),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(verdict(&engine, "video.example.com", &admin_tv), None);
+        assert_eq!(verdict(&engine, "video.example.com", &a), None);
+    }
+
+    #[test]
+    fn test_real_world_fixture() {
+        let mut parsed = 0;
+        let mut skipped = 0;
+        for line in ADGUARD_FIXTURE.lines() {
+            match parse_rule(line) {
+                Ok(_) => parsed += 1,
+                Err(SkipReason::Comment) => {}
+                Err(_) => skipped += 1,
+            }
+        }
+        assert!(parsed >= 20, "parsed {} rules", parsed);
+        assert!(skipped >= 3, "skipped {} rules", skipped);
+
+        let engine = engine_from(ADGUARD_FIXTURE, "ads");
+        let ctx = RuleContext::default();
+
+        assert_eq!(
+            verdict(&engine, "pagead2.googlesyndication.com", &ctx),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(
+            verdict(&engine, "stats.g.doubleclick.net", &ctx),
+            Some(RuleAction::Block)
+        );
+        // Whitelisted in the fixture
+        assert_eq!(
+            verdict(&engine, "www.googleadservices.com", &ctx),
+            Some(RuleAction::Allow)
+        );
+        // Disabled by $badfilter in the fixture
+        assert_eq!(verdict(&engine, "ads.reddit.com", &ctx), None);
+        // $important block wins over the exception
+        assert_eq!(
+            verdict(&engine, "app.adjust.com", &ctx),
+            Some(RuleAction::Block)
+        );
+
+        let lan = RuleContext {
+            client_ip: Some("10.1.2.3".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            verdict(&engine, "pagead2.googlesyndication.com", &lan),
+            Some(RuleAction::Allow)
+        );
+        assert_eq!(verdict(&engine, "github.com", &ctx), None);
+    }
+}
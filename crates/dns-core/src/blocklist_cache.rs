@@ -0,0 +1,143 @@
+//! On-disk last-known-good cache for blocklist sources
+//!
+//! Each source gets a body file and a small JSON metadata file holding the
+//! validators (ETag, Last-Modified) needed for conditional requests.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Metadata stored next to a cached list body
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheMeta {
+    /// Source URL the body was fetched from
+    pub url: String,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// Unix timestamp the server last served or confirmed (HTTP 304) this body
+    pub fetched_at: u64,
+    /// Hex SHA-256 of the body
+    pub sha256: String,
+    /// Entries parsed from the body
+    pub entries: usize,
+}
+
+/// Cached list body with its metadata
+#[derive(Debug, Clone)]
+pub struct CachedList {
+    pub meta: CacheMeta,
+    pub body: String,
+}
+
+/// Directory-backed cache keyed by source URL
+#[derive(Debug, Clone)]
+pub struct BlocklistCache {
+    dir: PathBuf,
+}
+
+impl BlocklistCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = &sha256_hex(url.as_bytes())[..16];
+        (
+            self.dir.join(format!("{}.list", key)),
+            self.dir.join(format!("{}.meta.json", key)),
+        )
+    }
+
+    /// Load the cached copy of a source, if any
+    pub fn load(&self, url: &str) -> Option<CachedList> {
+        let (body_path, meta_path) = self.paths(url);
+        let meta: CacheMeta =
+            serde_json::from_str(&std::fs::read_to_string(meta_path).ok()?).ok()?;
+        if meta.url != url {
+            return None;
+        }
+        let body = std::fs::read_to_string(body_path).ok()?;
+        if sha256_hex(body.as_bytes()) != meta.sha256 {
+            warn!("Discarding corrupt blocklist cache entry for {}", url);
+            return None;
+        }
+        Some(CachedList { meta, body })
+    }
+
+    /// Store a good copy of a source, replacing any previous one
+    pub fn store(&self, meta: &CacheMeta, body: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let (body_path, meta_path) = self.paths(&meta.url);
+        let meta_json = serde_json::to_string_pretty(meta)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+
+        // Write to temporary files first so a crash never leaves a torn entry
+        write_atomic(&body_path, body.as_bytes())?;
+        write_atomic(&meta_path, meta_json.as_bytes())
+    }
+
+    /// Update metadata only (e.g. refreshed validators after a 304)
+    pub fn touch(&self, meta: &CacheMeta) -> std::io::Result<()> {
+        let (_, meta_path) = self.paths(&meta.url);
+        let meta_json = serde_json::to_string_pretty(meta)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+        write_atomic(&meta_path, meta_json.as_bytes())
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Lowercase hex SHA-256 digest
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_and_load() {
+        let dir = std::env::temp_dir().join(format!("shield-cache-test-{}", std::process::id()));
+        let cache = BlocklistCache::new(&dir);
+        let url = "https://lists.example.com/ads.txt";
+        assert!(cache.load(url).is_none());
+
+        let body = "ads.example.com\ntracker.example.com\n";
+        let meta = CacheMeta {
+            url: url.to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            fetched_at: 1,
+            sha256: sha256_hex(body.as_bytes()),
+            entries: 2,
+        };
+        cache.store(&meta, body).unwrap();
+
+        let cached = cache.load(url).unwrap();
+        assert_eq!(cached.body, body);
+        assert_eq!(cached.meta.etag.as_deref(), Some("\"abc\""));
+        assert!(cache.load("https://lists.example.com/other.txt").is_none());
+
+        // A body that no longer matches its digest is ignored
+        let (body_path, _) = cache.paths(url);
+        std::fs::write(body_path, "tampered.example.com\n").unwrap();
+        assert!(cache.load(url).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,13 +2,21 @@
 //!
 //! Fetches blocklists from configured sources and maintains them
 
-use ahash::AHashSet;
-use parking_lot::RwLock;
+use crate::adblock::{self, AdblockEngine, RuleAction, RuleContext, RuleMatch};
+use crate::blocklist_cache::{sha256_hex, BlocklistCache, CacheMeta};
+use crate::domain_trie::{CategorySet, DomainTrie, MatchKind};
+use crate::rpz::{self, RewriteTable, RpzAction, RpzWriter};
+use ahash::{AHashMap, AHashSet};
+use arc_swap::ArcSwap;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
+
+/// List formats understood by the loader
+pub const SUPPORTED_FORMATS: &[&str] = &["hosts", "domains", "adblock", "rpz", "regex"];
 
 /// Blocklist source configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +28,53 @@ pub struct BlocklistSource {
     pub enabled: bool,
     #[serde(default)]
     pub description: String,
+    /// Expected hex SHA-256 of the list body; mismatching downloads are rejected
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Maximum accepted body size in bytes (default 64 MiB)
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Minimum number of entries for a download to be trusted
+    #[serde(default)]
+    pub min_entries: Option<usize>,
+}
+
+impl BlocklistSource {
+    /// Check the source is well-formed before it is stored or fetched
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() || self.name.len() > 128 {
+            return Err("name must be 1-128 characters".to_string());
+        }
+        if !(self.url.starts_with("https://")
+            || self.url.starts_with("http://")
+            || self.url.starts_with("file://"))
+        {
+            return Err("url must use http://, https:// or file://".to_string());
+        }
+        if self.category.is_empty()
+            || self.category.len() > 32
+            || !self
+                .category
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        {
+            return Err("category must be 1-32 lowercase letters, digits, '-' or '_'".to_string());
+        }
+        if !SUPPORTED_FORMATS.contains(&self.format.as_str()) {
+            return Err(format!(
+                "unsupported format '{}' (expected one of {})",
+                self.format,
+                SUPPORTED_FORMATS.join(", ")
+            ));
+        }
+        if let Some(sha256) = &self.sha256 {
+            let sha256 = sha256.trim();
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("sha256 must be 64 hex characters".to_string());
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Blocklist sources configuration file
@@ -34,15 +89,88 @@ pub struct BlocklistConfig {
     pub categories: HashMap<String, CategoryConfig>,
     #[serde(default)]
     pub presets: HashMap<String, PresetConfig>,
+    /// Directory for last-known-good copies of each source (empty disables)
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: String,
+    /// Directory `file://` sources must live under (empty disables them)
+    #[serde(default = "default_file_root")]
+    pub file_root: String,
+    /// Allow http(s) sources on loopback, private and link-local addresses
+    #[serde(default)]
+    pub allow_private_sources: bool,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self {
+            sources: Vec::new(),
+            update_interval_hours: default_update_interval(),
+            last_updated: None,
+            categories: HashMap::new(),
+            presets: HashMap::new(),
+            cache_dir: default_cache_dir(),
+            file_root: default_file_root(),
+            allow_private_sources: false,
+        }
+    }
 }
 
 fn default_update_interval() -> u32 {
     24
 }
 
+fn default_cache_dir() -> String {
+    "data/blocklist-cache".to_string()
+}
+
+fn default_file_root() -> String {
+    "config/blocklists".to_string()
+}
+
+/// Default cap on a single list download
+const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Redirects followed per download, each checked like the original URL
+const MAX_REDIRECTS: usize = 5;
+
+/// Whether an address is reachable on the public internet
+///
+/// Loopback, private, link-local, shared (CGNAT), unique-local and other
+/// special-purpose ranges are refused so a source URL can't be used to
+/// reach the server's own network or cloud metadata endpoints.
+fn is_public_ip(ip: std::net::IpAddr) -> bool {
+    use std::net::IpAddr;
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0)
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || first == 0x2001 && v6.segments()[1] == 0x0db8)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CategoryConfig {
     pub description: String,
+    /// Attribution priority when a domain is on several lists (1 wins over 2)
     #[serde(default = "default_priority")]
     pub priority: u8,
 }
@@ -51,12 +179,156 @@ fn default_priority() -> u8 {
     2
 }
 
+/// Built-in category priorities, used for categories the config doesn't list
+const DEFAULT_CATEGORY_PRIORITIES: &[(&str, u8)] = &[
+    ("malware", 1),
+    ("phishing", 1),
+    ("cryptominers", 2),
+    ("spam", 2),
+    ("adult", 3),
+    ("gambling", 3),
+    ("ads", 4),
+    ("tracking", 4),
+    ("social", 4),
+];
+
+/// Priority of categories with no configured or built-in priority
+const UNRANKED_PRIORITY: u8 = u8::MAX;
+
+/// Position in the built-in ranking, which breaks ties between categories
+/// configured with the same priority
+fn builtin_rank(category: &str) -> usize {
+    DEFAULT_CATEGORY_PRIORITIES
+        .iter()
+        .position(|(name, _)| *name == category)
+        .unwrap_or(DEFAULT_CATEGORY_PRIORITIES.len())
+}
+
+fn default_category_priorities() -> HashMap<String, u8> {
+    DEFAULT_CATEGORY_PRIORITIES
+        .iter()
+        .map(|(name, priority)| (name.to_string(), *priority))
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PresetConfig {
     pub description: String,
     pub enabled_categories: Vec<String>,
 }
 
+/// Where a source's loaded entries came from on the last refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFreshness {
+    /// Downloaded a new copy
+    Fresh,
+    /// Server confirmed the cached copy is current (HTTP 304)
+    NotModified,
+    /// Download failed or looked wrong; last-known-good copy in use
+    Cached,
+    /// Not fetched this time; the cached or previously loaded copy is kept
+    Retained,
+    /// Download failed and no cached copy exists
+    Failed,
+}
+
+impl SourceFreshness {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceFreshness::Fresh => "fresh",
+            SourceFreshness::NotModified => "not_modified",
+            SourceFreshness::Cached => "cached",
+            SourceFreshness::Retained => "retained",
+            SourceFreshness::Failed => "failed",
+        }
+    }
+}
+
+/// Per-source result of the last refresh
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub url: String,
+    pub category: String,
+    pub freshness: SourceFreshness,
+    pub entries: usize,
+    /// Unix timestamp the loaded copy was last confirmed by the server
+    pub checked_at: Option<u64>,
+    /// Seconds since `checked_at`
+    pub age_secs: Option<u64>,
+    /// Why the download was not used
+    pub error: Option<String>,
+}
+
+impl SourceStatus {
+    fn pending(source: &BlocklistSource) -> Self {
+        Self {
+            name: source.name.clone(),
+            url: source.url.clone(),
+            category: source.category.clone(),
+            freshness: SourceFreshness::Failed,
+            entries: 0,
+            checked_at: None,
+            age_secs: None,
+            error: None,
+        }
+    }
+}
+
+/// What a source would load, without publishing it
+#[derive(Debug, Clone, Serialize)]
+pub struct SourcePreview {
+    pub entries: usize,
+    /// Entries that go to the domain index
+    pub domains: usize,
+    /// Entries that need the rule engine or rewrite table
+    pub rules: usize,
+    /// First entries in sort order (at most [`DIFF_SAMPLE_SIZE`])
+    pub sample: Vec<String>,
+}
+
+/// Which part of the policy a traced entry lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceMatchKind {
+    /// Plain entry in the domain index
+    Domain,
+    /// AdBlock rule evaluated by the rule engine
+    Rule,
+    /// RPZ NODATA or local-data policy
+    Rewrite,
+}
+
+/// Where a loaded entry came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryOrigin {
+    /// Source name, or "custom" for domains added at runtime
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// 1-based line in the downloaded list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+/// A loaded entry matching a traced query
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceMatch {
+    pub kind: TraceMatchKind,
+    /// Pattern, rule text or RPZ record
+    pub entry: String,
+    pub category: String,
+    /// block, allow, nodata or local_data
+    pub action: &'static str,
+    /// Whether the category takes part in the evaluation
+    pub enabled: bool,
+    /// Lists the entry was loaded from (empty for in-memory lists)
+    pub origins: Vec<EntryOrigin>,
+    /// Whether this entry decided the result
+    pub winner: bool,
+}
+
 /// Statistics for blocklist loading
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct BlocklistStats {
@@ -65,22 +337,392 @@ pub struct BlocklistStats {
     pub sources_loaded: usize,
     pub sources_failed: usize,
     pub last_update: Option<u64>,
+    /// Freshness of each enabled source
+    pub sources: Vec<SourceStatus>,
+    /// Entries added and removed since the previous refresh
+    pub diff: RefreshDiff,
+}
+
+/// Maximum entries listed on each side of a [`ListDiff`]
+pub const DIFF_SAMPLE_SIZE: usize = 20;
+
+/// Entries added to and removed from a list between two refreshes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListDiff {
+    pub added: usize,
+    pub removed: usize,
+    /// First added entries in sort order (at most [`DIFF_SAMPLE_SIZE`])
+    pub added_sample: Vec<String>,
+    /// First removed entries in sort order (at most [`DIFF_SAMPLE_SIZE`])
+    pub removed_sample: Vec<String>,
+}
+
+impl ListDiff {
+    /// Build a diff from entry hashes, naming samples from `names`
+    fn from_hashes(added: &[u64], removed: &[u64], names: &AHashMap<u64, String>) -> Self {
+        let named = |hashes: &[u64]| -> Vec<&str> {
+            hashes
+                .iter()
+                .filter_map(|h| names.get(h))
+                .map(|n| n.as_str())
+                .collect()
+        };
+        Self {
+            added: added.len(),
+            removed: removed.len(),
+            added_sample: Self::sample(named(added)),
+            removed_sample: Self::sample(named(removed)),
+        }
+    }
+
+    fn sample(mut entries: Vec<&str>) -> Vec<String> {
+        if entries.len() > DIFF_SAMPLE_SIZE {
+            entries.select_nth_unstable(DIFF_SAMPLE_SIZE);
+            entries.truncate(DIFF_SAMPLE_SIZE);
+        }
+        entries.sort_unstable();
+        entries.into_iter().map(|e| e.to_string()).collect()
+    }
+
+    /// True if nothing was added or removed
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0
+    }
+}
+
+/// What a refresh changed, per source and per category
+///
+/// Sources and categories without changes are omitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefreshDiff {
+    /// Entries added across all categories
+    pub added: usize,
+    /// Entries removed across all categories
+    pub removed: usize,
+    /// Changes keyed by source name
+    pub by_source: BTreeMap<String, ListDiff>,
+    /// Changes keyed by category
+    pub by_category: BTreeMap<String, ListDiff>,
+}
+
+impl RefreshDiff {
+    /// True if the refresh changed nothing
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0
+    }
+}
+
+/// Hash identifying a diff key, so sources don't hold a second copy of
+/// every loaded pattern
+fn entry_hash(key: &str) -> u64 {
+    static HASHER: std::sync::OnceLock<ahash::RandomState> = std::sync::OnceLock::new();
+    HASHER.get_or_init(ahash::RandomState::new).hash_one(key)
+}
+
+/// Entries one source contributed on the last refresh, kept so the next
+/// refresh can be diffed against it
+#[derive(Debug, Clone, Default)]
+struct SourceEntries {
+    name: String,
+    category: String,
+    /// List format the entries were parsed as
+    format: String,
+    /// Hashes of patterns held in the trie (`example.com`, `*.example.com`)
+    /// and the line each was first listed on
+    patterns: AHashMap<u64, usize>,
+    /// Hashes of rule-engine and rewrite entries, keyed by rule text, with
+    /// their line
+    rules: AHashMap<u64, usize>,
+    /// `$badfilter` rules, which the rule engine keeps no text of
+    badfilters: Vec<String>,
+    /// When the loaded copy was last confirmed by its server
+    checked_at: Option<u64>,
+}
+
+impl SourceEntries {
+    fn new(name: &str, category: &str, format: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            category: category.to_string(),
+            format: format.to_string(),
+            ..Self::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.patterns.len() + self.rules.len()
+    }
+
+    fn hashes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.patterns.keys().chain(self.rules.keys()).copied()
+    }
+
+    fn add_pattern(&mut self, pattern: &str, line: usize) {
+        self.patterns.entry(entry_hash(pattern)).or_insert(line);
+    }
+
+    fn add_rule(&mut self, key: &str, line: usize) {
+        self.rules.entry(entry_hash(key)).or_insert(line);
+    }
+
+    fn hash_set(entries: Option<&SourceEntries>) -> AHashSet<u64> {
+        entries.map(|e| e.hashes().collect()).unwrap_or_default()
+    }
+}
+
+/// Visit the diff key of every entry in a policy: trie patterns, rule text
+/// (and the RPZ record an exception came from) and RPZ rewrites
+fn for_each_entry_key(
+    index: &DomainTrie,
+    rules: &AdblockEngine,
+    rewrites: &RewriteTable,
+    mut f: impl FnMut(&str),
+) {
+    index.for_each(|domain, kind, _| match kind {
+        MatchKind::Exact => f(domain),
+        MatchKind::Subtree => f(&format!("*.{}", domain)),
+    });
+    rules.for_each_stored_rule(|rule, _| {
+        f(&rule.text);
+        if let Some(key) = rpz_rule_key(&rule.text) {
+            f(&key);
+        }
+    });
+    rewrites.for_each(|domain, wildcard, _, action| {
+        let pattern = if wildcard {
+            format!("*.{}", domain)
+        } else {
+            domain.to_string()
+        };
+        f(&rpz_entry_key(&pattern, action));
+    });
+}
+
+/// Parse a line of a Pi-hole style regex list as an AdBlock `/regex/` rule
+///
+/// Lines using Pi-hole's `;querytype=`, `;invert` or `;reply=` options
+/// have no rule engine equivalent and are skipped.
+fn parse_regex_line(line: &str) -> Option<adblock::AdblockRule> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if [";querytype=", ";invert", ";reply="]
+        .iter()
+        .any(|option| line.contains(option))
+    {
+        return None;
+    }
+    adblock::parse_rule(&format!("/{}/", line))
+        .ok()
+        .filter(|rule| matches!(rule.pattern, adblock::RulePattern::Regex(_)))
+}
+
+/// Diff key for an RPZ policy that lives outside the trie
+fn rpz_entry_key(pattern: &str, action: &RpzAction) -> String {
+    match action {
+        RpzAction::Nxdomain => format!("{} CNAME .", pattern),
+        RpzAction::Nodata => format!("{} CNAME *.", pattern),
+        RpzAction::Passthru => format!("{} CNAME rpz-passthru.", pattern),
+        RpzAction::Drop => format!("{} CNAME rpz-drop.", pattern),
+        RpzAction::LocalData(records) => {
+            let data: Vec<String> = records
+                .iter()
+                .map(|r| format!("TYPE{} {}", r.record_type, r.data))
+                .collect();
+            format!("{} {}", pattern, data.join("; "))
+        }
+    }
+}
+
+/// Diff key of the RPZ record a rule was loaded from: PASSTHRU for
+/// `@@|name^` exceptions, NXDOMAIN for `|*.domain^` wildcard blocks
+fn rpz_rule_key(rule: &str) -> Option<String> {
+    if let Some(rest) = rule.strip_prefix("@@|") {
+        let pattern = rest.strip_suffix('^')?;
+        return Some(rpz_entry_key(pattern, &RpzAction::Passthru));
+    }
+    let pattern = rule.strip_prefix('|')?.strip_suffix('^')?;
+    pattern
+        .starts_with("*.")
+        .then(|| rpz_entry_key(pattern, &RpzAction::Nxdomain))
+}
+
+/// Outcome of a conditional download
+enum Download {
+    NotModified,
+    Body {
+        body: String,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Immutable filtering policy read by the query path
+///
+/// Refreshes and edits build a complete new snapshot and swap it in whole,
+/// so readers never take a lock or see a partially loaded list. Runtime
+/// edits only replace the small `custom` and `removed` overlays and share
+/// the list index, rules and rewrites with the previous snapshot.
+#[derive(Debug, Default)]
+struct PolicySnapshot {
+    /// All list entries, tagged by category
+    index: Arc<DomainTrie>,
+    /// Domains added at runtime, layered over `index`
+    custom: Arc<DomainTrie>,
+    /// List patterns removed at runtime, hidden until the next rebuild
+    removed: Arc<AHashSet<String>>,
+    /// AdBlock rules that need the rule engine (exceptions, modifiers, regex)
+    rules: Arc<AdblockEngine>,
+    /// RPZ NODATA and local-data policies
+    rewrites: Arc<RewriteTable>,
+    /// Enabled categories as interned in `index`
+    enabled_mask: CategorySet,
+    /// Enabled categories as interned in `custom`
+    custom_mask: CategorySet,
+}
+
+impl PolicySnapshot {
+    fn category_enabled(&self, category: &str) -> bool {
+        self.index
+            .category_id(category)
+            .is_some_and(|id| self.enabled_mask.contains(id))
+            || self
+                .custom
+                .category_id(category)
+                .is_some_and(|id| self.custom_mask.contains(id))
+    }
+
+    /// List entries covering a domain, most specific first, without the
+    /// patterns removed at runtime
+    fn list_entries(&self, domain: &str) -> Vec<(String, MatchKind, CategorySet)> {
+        let mut entries = self.index.matching_entries(domain);
+        entries.retain(|(pattern, _, _)| !self.removed.contains(pattern));
+        entries
+    }
+
+    /// Categories of the list entries covering a domain
+    fn list_hits(&self, domain: &str) -> CategorySet {
+        if self.removed.is_empty() {
+            return self.index.lookup(domain).categories();
+        }
+        self.list_entries(domain)
+            .into_iter()
+            .fold(CategorySet::EMPTY, |acc, (_, _, set)| acc.union(set))
+    }
+
+    /// Whether a domain matches an entry in an enabled category
+    fn matches_enabled(&self, domain: &str) -> bool {
+        self.list_hits(domain).intersects(self.enabled_mask)
+            || self.custom.matches_any(domain, self.custom_mask)
+    }
+
+    /// Every category with a list or custom entry covering a domain
+    fn matching_categories(&self, domain: &str) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .list_hits(domain)
+            .iter()
+            .filter_map(|id| self.index.category_name(id))
+            .collect();
+        for name in self
+            .custom
+            .lookup(domain)
+            .categories()
+            .iter()
+            .filter_map(|id| self.custom.category_name(id))
+        {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Combined category set of a list pattern's domain, minus removed kinds
+    fn list_set_at(&self, domain: &str) -> CategorySet {
+        let subtree = format!("*.{}", domain);
+        self.list_entries(domain)
+            .into_iter()
+            .filter(|(pattern, _, _)| *pattern == domain || *pattern == subtree)
+            .fold(CategorySet::EMPTY, |acc, (_, _, set)| acc.union(set))
+    }
+
+    /// Distinct domains with an entry in an enabled category
+    ///
+    /// The list count is kept by the trie; only the small overlays are walked.
+    fn blocked_count(&self) -> usize {
+        let mut count = self.index.count_matching(self.enabled_mask);
+
+        let mut removed_domains: Vec<&str> = self
+            .removed
+            .iter()
+            .map(|p| p.strip_prefix("*.").unwrap_or(p))
+            .collect();
+        removed_domains.sort_unstable();
+        removed_domains.dedup();
+        for domain in removed_domains {
+            let before = self
+                .index
+                .matching_entries(domain)
+                .into_iter()
+                .filter(|(p, _, _)| p == domain || p.strip_prefix("*.") == Some(domain))
+                .fold(CategorySet::EMPTY, |acc, (_, _, set)| acc.union(set));
+            if before.intersects(self.enabled_mask)
+                && !self.list_set_at(domain).intersects(self.enabled_mask)
+            {
+                count -= 1;
+            }
+        }
+
+        let mut custom_domains = AHashSet::new();
+        self.custom.for_each(|domain, _, set| {
+            if set.intersects(self.custom_mask) {
+                custom_domains.insert(domain.to_string());
+            }
+        });
+        count
+            + custom_domains
+                .iter()
+                .filter(|domain| !self.list_set_at(domain).intersects(self.enabled_mask))
+                .count()
+    }
+}
+
+/// Policy being assembled off the query path
+#[derive(Debug, Default)]
+struct StagedPolicy {
+    index: DomainTrie,
+    rules: AdblockEngine,
+    rewrites: RewriteTable,
 }
 
 /// Category-aware blocklist manager
 pub struct BlocklistManager {
-    /// Domains by category for quick lookup
-    domains_by_category: Arc<RwLock<HashMap<String, AHashSet<String>>>>,
-    /// All blocked domains (flat set for O(1) lookup)
-    all_blocked: Arc<RwLock<AHashSet<String>>>,
-    /// Wildcard patterns by category
-    wildcards_by_category: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    /// Published policy, replaced atomically
+    policy: ArcSwap<PolicySnapshot>,
+    /// Serializes publishers so concurrent edits are not lost
+    publish_lock: Mutex<()>,
+    /// Domains added at runtime (defaults, API), merged into every rebuild
+    custom: Arc<RwLock<DomainTrie>>,
     /// Currently enabled categories
     enabled_categories: Arc<RwLock<AHashSet<String>>>,
+    /// Attribution priority per category (lower wins)
+    category_priorities: Arc<RwLock<HashMap<String, u8>>>,
     /// Statistics
     stats: Arc<RwLock<BlocklistStats>>,
     /// Last fetch time
     last_fetch: Arc<RwLock<Option<Instant>>>,
+    /// Entries loaded from each source on the last refresh, keyed by URL
+    source_entries: Arc<RwLock<HashMap<String, SourceEntries>>>,
+    /// Serializes refreshes so they don't interleave per-source state
+    refresh_lock: tokio::sync::Mutex<()>,
 }
 
 impl BlocklistManager {
@@ -88,17 +730,20 @@ impl BlocklistManager {
     pub fn new() -> Self {
         info!("Initializing BlocklistManager");
         Self {
-            domains_by_category: Arc::new(RwLock::new(HashMap::new())),
-            all_blocked: Arc::new(RwLock::new(AHashSet::new())),
-            wildcards_by_category: Arc::new(RwLock::new(HashMap::new())),
+            policy: ArcSwap::from_pointee(PolicySnapshot::default()),
+            publish_lock: Mutex::new(()),
+            custom: Arc::new(RwLock::new(DomainTrie::new())),
             enabled_categories: Arc::new(RwLock::new(AHashSet::from_iter(vec![
                 "ads".to_string(),
                 "malware".to_string(),
                 "phishing".to_string(),
                 "tracking".to_string(),
             ]))),
+            category_priorities: Arc::new(RwLock::new(default_category_priorities())),
             stats: Arc::new(RwLock::new(BlocklistStats::default())),
             last_fetch: Arc::new(RwLock::new(None)),
+            source_entries: Arc::new(RwLock::new(HashMap::new())),
+            refresh_lock: tokio::sync::Mutex::new(()),
         }
     }
 
@@ -113,18 +758,43 @@ impl BlocklistManager {
     /// Fetch all enabled blocklists from remote sources
     pub async fn fetch_blocklists(&self, config: &BlocklistConfig) -> BlocklistStats {
         info!("Fetching blocklists from {} sources", config.sources.len());
+        self.refresh(config, |_| true).await
+    }
+
+    /// Fetch one source now; the others keep their cached or loaded copies
+    pub async fn fetch_source(&self, config: &BlocklistConfig, url: &str) -> BlocklistStats {
+        info!("Fetching blocklist source {}", url);
+        self.refresh(config, |source| source.url == url).await
+    }
+
+    /// Rebuild from cached or loaded copies without any downloads
+    ///
+    /// Used after sources are edited, disabled or removed.
+    pub async fn reload_blocklists(&self, config: &BlocklistConfig) -> BlocklistStats {
+        info!("Reloading blocklists from {} sources", config.sources.len());
+        self.refresh(config, |_| false).await
+    }
+
+    /// Rebuild the policy from `config`, downloading sources selected by
+    /// `fetch` and reusing the cached or loaded copy of the rest
+    async fn refresh(
+        &self,
+        config: &BlocklistConfig,
+        fetch: impl Fn(&BlocklistSource) -> bool,
+    ) -> BlocklistStats {
+        let _refresh = self.refresh_lock.lock().await;
+        self.set_category_priorities(&config.categories);
 
         let mut stats = BlocklistStats::default();
-        let client = match reqwest::Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-        {
-            Ok(c) => c,
-            Err(e) => {
-                error!("Failed to create HTTP client: {}", e);
-                return stats;
-            }
-        };
+
+        // Everything is staged and published at the end so queries never
+        // see a half-loaded policy
+        let mut staged = StagedPolicy::default();
+        let cache = (!config.cache_dir.is_empty()).then(|| BlocklistCache::new(&config.cache_dir));
+        let mut loaded: HashMap<String, SourceEntries> = HashMap::new();
+        // Sources served from the published policy, restored in one pass
+        // once every list is staged
+        let mut restored: Vec<String> = Vec::new();
 
         for source in &config.sources {
             if !source.enabled {
@@ -132,89 +802,833 @@ impl BlocklistManager {
                 continue;
             }
 
-            match self.fetch_single_source(&client, source).await {
-                Ok(count) => {
-                    info!("Loaded {} domains from {} ({})", count, source.name, source.category);
-                    *stats.by_category.entry(source.category.clone()).or_insert(0) += count;
-                    stats.sources_loaded += 1;
+            let fetched = fetch(source);
+            let previous = self
+                .source_entries
+                .read()
+                .get(&source.url)
+                .filter(|p| p.category == source.category && p.format == source.format)
+                .cloned();
+            let mut entries = SourceEntries::new(&source.name, &source.category, &source.format);
+            let mut status = if fetched {
+                self.fetch_single_source(source, config, cache.as_ref(), &mut staged, &mut entries)
+                    .await
+            } else if previous.is_some() {
+                // Already loaded; no need to parse the cached copy again
+                SourceStatus::pending(source)
+            } else {
+                self.load_cached(source, cache.as_ref(), &mut staged, &mut entries)
+            };
+
+            if status.freshness == SourceFreshness::Failed {
+                // Keep serving what the source provided last time
+                if let Some(previous) = previous {
+                    entries = previous;
+                    entries.name = source.name.clone();
+                    // Intern now so category ids keep the source order
+                    staged.index.intern_category(&source.category);
+                    restored.push(source.url.clone());
+                    if !fetched {
+                        status.freshness = SourceFreshness::Retained;
+                        status.entries = entries.len();
+                        status.checked_at = entries.checked_at;
+                        status.age_secs = entries.checked_at.map(|t| unix_now().saturating_sub(t));
+                        status.error = None;
+                    }
                 }
-                Err(e) => {
-                    warn!("Failed to fetch {}: {}", source.name, e);
+            }
+
+            match status.freshness {
+                SourceFreshness::Failed => {
+                    warn!(
+                        "Failed to load {}: {}",
+                        source.name,
+                        status.error.as_deref().unwrap_or("unknown error")
+                    );
                     stats.sources_failed += 1;
                 }
+                freshness => {
+                    info!(
+                        "Loaded {} domains from {} ({}, {:?})",
+                        status.entries, source.name, source.category, freshness
+                    );
+                    *stats
+                        .by_category
+                        .entry(source.category.clone())
+                        .or_insert(0) += status.entries;
+                    stats.sources_loaded += 1;
+                }
+            }
+            if entries.checked_at.is_none() {
+                entries.checked_at = status.checked_at;
             }
+            stats.sources.push(status);
+            loaded.insert(source.url.clone(), entries);
         }
 
-        // Rebuild the flat blocked set from enabled categories
-        self.rebuild_blocked_set();
+        let sources: Vec<&SourceEntries> =
+            restored.iter().filter_map(|url| loaded.get(url)).collect();
+        Self::restore(&self.policy.load(), &mut staged, &sources);
 
-        stats.total_domains = self.all_blocked.read().len();
-        stats.last_update = Some(
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        );
+        let previous = std::mem::take(&mut *self.source_entries.write());
+        stats.diff = Self::diff_entries(&previous, &loaded, &self.policy.load(), &staged);
+        *self.source_entries.write() = loaded;
+
+        {
+            let _guard = self.publish_lock.lock();
+            self.publish(staged);
+        }
+
+        stats.total_domains = self.blocked_count();
+        stats.last_update = Some(unix_now());
 
         *self.stats.write() = stats.clone();
         *self.last_fetch.write() = Some(Instant::now());
 
         info!(
-            "Blocklist fetch complete: {} domains across {} categories",
+            "Blocklist refresh complete: {} domains across {} categories (+{} -{})",
             stats.total_domains,
-            stats.by_category.len()
+            stats.by_category.len(),
+            stats.diff.added,
+            stats.diff.removed
         );
 
         stats
     }
 
-    /// Fetch a single blocklist source
+    /// Load a source's last-known-good copy without downloading it
+    fn load_cached(
+        &self,
+        source: &BlocklistSource,
+        cache: Option<&BlocklistCache>,
+        staged: &mut StagedPolicy,
+        entries: &mut SourceEntries,
+    ) -> SourceStatus {
+        let mut status = SourceStatus::pending(source);
+        match cache.and_then(|c| c.load(&source.url)) {
+            Some(cached) => {
+                self.ingest(&cached.body, &source.format, staged, entries);
+                status.freshness = SourceFreshness::Retained;
+                status.entries = entries.len();
+                status.checked_at = Some(cached.meta.fetched_at);
+                status.age_secs = Some(unix_now().saturating_sub(cached.meta.fetched_at));
+            }
+            None => status.error = Some("not fetched yet".to_string()),
+        }
+        status
+    }
+
+    /// Download and parse a source without publishing it
+    ///
+    /// Runs the same validation, size, pin and plausibility checks as a
+    /// refresh, so a source can be checked before it is saved.
+    pub async fn preview_source(
+        &self,
+        source: &BlocklistSource,
+        config: &BlocklistConfig,
+    ) -> Result<SourcePreview, String> {
+        source.validate()?;
+
+        let body = match self.download(source, config, None).await? {
+            Download::Body { body, .. } => body,
+            Download::NotModified => return Err("HTTP 304 without a cached copy".to_string()),
+        };
+        self.check_plausible(source, self.count_entries(&body, &source.format), None)?;
+
+        let mut staged = StagedPolicy::default();
+        let mut entries = SourceEntries::new(&source.name, &source.category, &source.format);
+        self.ingest(&body, &source.format, &mut staged, &mut entries);
+        let mut keys = Vec::new();
+        for_each_entry_key(&staged.index, &staged.rules, &staged.rewrites, |key| {
+            if entries.patterns.contains_key(&entry_hash(key))
+                || entries.rules.contains_key(&entry_hash(key))
+            {
+                keys.push(key.to_string());
+            }
+        });
+        keys.extend(entries.badfilters.iter().cloned());
+        Ok(SourcePreview {
+            entries: entries.len(),
+            domains: entries.patterns.len(),
+            rules: entries.rules.len(),
+            sample: ListDiff::sample(keys.iter().map(|k| k.as_str()).collect()),
+        })
+    }
+
+    /// Fetch a single blocklist source, falling back to its cached copy
+    ///
+    /// Uses conditional requests when a cached copy exists. Downloads that
+    /// fail, exceed the size limit, miss the SHA-256 pin or shrink suspiciously
+    /// are discarded in favour of the last-known-good copy.
     async fn fetch_single_source(
         &self,
-        client: &reqwest::Client,
         source: &BlocklistSource,
-    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
-        let response = client.get(&source.url).send().await?;
+        config: &BlocklistConfig,
+        cache: Option<&BlocklistCache>,
+        staged: &mut StagedPolicy,
+        entries: &mut SourceEntries,
+    ) -> SourceStatus {
+        let cached = cache.and_then(|c| c.load(&source.url));
+        let now = unix_now();
+        let mut status = SourceStatus::pending(source);
+
+        let error = match self
+            .download(source, config, cached.as_ref().map(|c| &c.meta))
+            .await
+        {
+            Ok(Download::NotModified) => match &cached {
+                Some(cached) => {
+                    let mut meta = cached.meta.clone();
+                    meta.fetched_at = now;
+                    if let Some(cache) = cache {
+                        if let Err(e) = cache.touch(&meta) {
+                            warn!("Failed to update cache metadata for {}: {}", source.name, e);
+                        }
+                    }
+                    status.freshness = SourceFreshness::NotModified;
+                    self.ingest(&cached.body, &source.format, staged, entries);
+                    status.entries = entries.len();
+                    status.checked_at = Some(now);
+                    status.age_secs = Some(0);
+                    return status;
+                }
+                None => "HTTP 304 without a cached copy".to_string(),
+            },
+            Ok(Download::Body {
+                body,
+                etag,
+                last_modified,
+            }) => {
+                let count = self.count_entries(&body, &source.format);
+                match self.check_plausible(source, count, cached.as_ref().map(|c| c.meta.entries)) {
+                    Ok(()) => {
+                        if let Some(cache) = cache {
+                            let meta = CacheMeta {
+                                url: source.url.clone(),
+                                etag,
+                                last_modified,
+                                fetched_at: now,
+                                sha256: sha256_hex(body.as_bytes()),
+                                entries: count,
+                            };
+                            if let Err(e) = cache.store(&meta, &body) {
+                                warn!("Failed to cache {}: {}", source.name, e);
+                            }
+                        }
+                        status.freshness = SourceFreshness::Fresh;
+                        self.ingest(&body, &source.format, staged, entries);
+                        status.entries = entries.len();
+                        status.checked_at = Some(now);
+                        status.age_secs = Some(0);
+                        return status;
+                    }
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
 
+        // Fall back to the last-known-good copy
+        if let Some(cached) = cached {
+            warn!("Using cached copy of {}: {}", source.name, error);
+            status.freshness = SourceFreshness::Cached;
+            self.ingest(&cached.body, &source.format, staged, entries);
+            status.entries = entries.len();
+            status.checked_at = Some(cached.meta.fetched_at);
+            status.age_secs = Some(now.saturating_sub(cached.meta.fetched_at));
+        }
+        status.error = Some(error);
+        status
+    }
+
+    /// Download a source with conditional headers and a size limit
+    ///
+    /// Unless `allow_private_sources` is set, the host of the URL and of
+    /// every redirect is resolved first and refused if any address is not
+    /// public; the connection is then pinned to the checked addresses.
+    async fn download(
+        &self,
+        source: &BlocklistSource,
+        config: &BlocklistConfig,
+        cached: Option<&CacheMeta>,
+    ) -> Result<Download, String> {
+        let max_bytes = source.max_bytes.unwrap_or(DEFAULT_MAX_BYTES);
+        if let Some(path) = source.url.strip_prefix("file://") {
+            let bytes = Self::read_local(path, &config.file_root, max_bytes)?;
+            Self::check_pin(source, &bytes)?;
+            return Ok(Download::Body {
+                body: String::from_utf8_lossy(&bytes).into_owned(),
+                etag: None,
+                last_modified: None,
+            });
+        }
+
+        let mut url = reqwest::Url::parse(&source.url).map_err(|e| e.to_string())?;
+        let mut redirects = 0;
+        let mut response = loop {
+            let client = Self::checked_client(&url, config.allow_private_sources).await?;
+            let mut request = client.get(url.clone());
+            if let Some(meta) = cached {
+                if let Some(etag) = &meta.etag {
+                    request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &meta.last_modified {
+                    request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            let response = request.send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_redirection()
+                || response.status() == reqwest::StatusCode::NOT_MODIFIED
+            {
+                break response;
+            }
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("HTTP {} without a Location header", response.status()))?;
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(format!("more than {} redirects", MAX_REDIRECTS));
+            }
+            url = url
+                .join(location)
+                .map_err(|e| format!("bad redirect: {}", e))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(format!("redirect to unsupported scheme {}", url.scheme()));
+            }
+        };
+
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(Download::NotModified);
+        }
         if !response.status().is_success() {
-            return Err(format!("HTTP {}", response.status()).into());
+            return Err(format!("HTTP {}", response.status()));
+        }
+
+        let header = |name: reqwest::header::HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(format!("body exceeds {} bytes", max_bytes));
         }
 
-        let content = response.text().await?;
-        let domains = self.parse_blocklist(&content, &source.format);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            if bytes.len() as u64 + chunk.len() as u64 > max_bytes {
+                return Err(format!("body exceeds {} bytes", max_bytes));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
 
-        let count = domains.len();
+        Self::check_pin(source, &bytes)?;
 
-        // Add to category-specific set
-        {
-            let mut by_cat = self.domains_by_category.write();
-            let cat_set = by_cat.entry(source.category.clone()).or_insert_with(AHashSet::new);
-            for domain in domains {
-                if domain.starts_with("*.") {
-                    // Handle wildcard patterns
-                    let mut wildcards = self.wildcards_by_category.write();
-                    let wc_list = wildcards.entry(source.category.clone()).or_insert_with(Vec::new);
-                    wc_list.push(domain);
-                } else {
-                    cat_set.insert(domain);
+        Ok(Download::Body {
+            body: String::from_utf8_lossy(&bytes).into_owned(),
+            etag,
+            last_modified,
+        })
+    }
+
+    /// HTTP client for one request to `url`, with redirects left to the caller
+    ///
+    /// Resolves the host and refuses non-public addresses unless
+    /// `allow_private` is set; the client only connects to the addresses
+    /// that were checked, so a second lookup can't swap them.
+    async fn checked_client(
+        url: &reqwest::Url,
+        allow_private: bool,
+    ) -> Result<reqwest::Client, String> {
+        let builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .redirect(reqwest::redirect::Policy::none());
+        let builder = if allow_private {
+            builder
+        } else {
+            let host = url.host_str().ok_or("url has no host")?;
+            let port = url.port_or_known_default().ok_or("url has no port")?;
+            let addrs: Vec<std::net::SocketAddr> =
+                tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+                    .await
+                    .map_err(|e| format!("{}: {}", host, e))?
+                    .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} did not resolve", host));
+            }
+            if let Some(addr) = addrs.iter().find(|a| !is_public_ip(a.ip())) {
+                return Err(format!(
+                    "{} resolves to non-public address {}",
+                    host,
+                    addr.ip()
+                ));
+            }
+            builder.resolve_to_addrs(host, &addrs)
+        };
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    /// Reject a body that doesn't match the source's SHA-256 pin
+    fn check_pin(source: &BlocklistSource, bytes: &[u8]) -> Result<(), String> {
+        if let Some(expected) = &source.sha256 {
+            let actual = sha256_hex(bytes);
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(format!("SHA-256 mismatch (got {})", actual));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a `file://` source, which must resolve to a file under `root`
+    fn read_local(path: &str, root: &str, max_bytes: u64) -> Result<Vec<u8>, String> {
+        if root.is_empty() {
+            return Err("file:// sources are disabled".to_string());
+        }
+        let root = std::fs::canonicalize(root).map_err(|e| format!("file root {}: {}", root, e))?;
+        let path = std::fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        if !path.starts_with(&root) {
+            return Err(format!("{} is outside {}", path.display(), root.display()));
+        }
+        let len = std::fs::metadata(&path).map_err(|e| e.to_string())?.len();
+        if len > max_bytes {
+            return Err(format!("body exceeds {} bytes", max_bytes));
+        }
+        std::fs::read(&path).map_err(|e| e.to_string())
+    }
+
+    /// Reject downloads that are empty or much smaller than the last good copy
+    fn check_plausible(
+        &self,
+        source: &BlocklistSource,
+        entries: usize,
+        previous: Option<usize>,
+    ) -> Result<(), String> {
+        if entries == 0 {
+            return Err("list has no entries".to_string());
+        }
+        if let Some(min) = source.min_entries {
+            if entries < min {
+                return Err(format!(
+                    "suspiciously small list: {} entries (minimum {})",
+                    entries, min
+                ));
+            }
+        }
+        if let Some(previous) = previous {
+            if entries * 2 < previous {
+                return Err(format!(
+                    "suspiciously small list: {} entries (previously {})",
+                    entries, previous
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the entries a list body would load, without loading it
+    fn count_entries(&self, content: &str, format: &str) -> usize {
+        match format {
+            "adblock" => content
+                .lines()
+                .filter(|l| adblock::parse_rule(l).is_ok())
+                .count(),
+            "rpz" => rpz::parse_zone(content).rules.len(),
+            "regex" => content
+                .lines()
+                .filter(|l| parse_regex_line(l).is_some())
+                .count(),
+            _ => content
+                .lines()
+                .filter(|l| self.parse_line(l, format).is_some())
+                .count(),
+        }
+    }
+
+    /// Load list content into the index and a rule engine
+    ///
+    /// Plain entries go to the trie; AdBlock rules with exceptions, modifiers
+    /// or patterns and regex list lines go to the rule engine; RPZ NODATA and
+    /// local-data policies go to the rewrite table. Every loaded entry is
+    /// recorded in `entries`.
+    fn ingest(
+        &self,
+        content: &str,
+        format: &str,
+        staged: &mut StagedPolicy,
+        entries: &mut SourceEntries,
+    ) {
+        let category = entries.category.clone();
+        // Categories holding only rules still need a bit in the enabled mask
+        staged.index.intern_category(&category);
+        if format == "rpz" {
+            self.ingest_rpz(content, staged, entries);
+            return;
+        }
+        if format == "regex" {
+            for (index, line) in content.lines().enumerate() {
+                if let Some(rule) = parse_regex_line(line) {
+                    entries.add_rule(&rule.text, index + 1);
+                    staged.rules.add_rule(rule, &category);
                 }
             }
+            return;
+        }
+        if format != "adblock" {
+            // Exact and wildcard entries share the category-tagged index
+            for (index, line) in content.lines().enumerate() {
+                if let Some(domain) = self.parse_line(line, format) {
+                    staged.index.insert(&domain, &category);
+                    entries.add_pattern(&domain, index + 1);
+                }
+            }
+            return;
         }
 
-        Ok(count)
+        let mut skipped = 0;
+        for (index, line) in content.lines().enumerate() {
+            match adblock::parse_rule(line) {
+                Ok(rule) => match rule.as_simple_block() {
+                    Some(pattern) => {
+                        staged.index.insert(&pattern, &category);
+                        entries.add_pattern(&pattern, index + 1);
+                    }
+                    None => {
+                        entries.add_rule(&rule.text, index + 1);
+                        if rule.modifiers.badfilter {
+                            entries.badfilters.push(rule.text.clone());
+                        }
+                        staged.rules.add_rule(rule, &category);
+                    }
+                },
+                Err(adblock::SkipReason::Comment) => {}
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            debug!("Skipped {} unsupported rules in {} list", skipped, category);
+        }
+    }
+
+    /// Load an RPZ zone: blocking actions go to the trie, PASSTHRU becomes an
+    /// exception rule, NODATA and local data become rewrites
+    ///
+    /// The trie has no subdomains-only entries, so blocking `*.domain`
+    /// triggers are loaded as `|*.domain^` rules instead.
+    fn ingest_rpz(&self, content: &str, staged: &mut StagedPolicy, entries: &mut SourceEntries) {
+        let category = entries.category.clone();
+        let zone = rpz::parse_zone(content);
+        if zone.skipped > 0 {
+            debug!(
+                "Skipped {} unsupported RPZ records in {} zone",
+                zone.skipped, category
+            );
+        }
+
+        for rule in zone.rules {
+            let pattern = if rule.wildcard {
+                format!("*.{}", rule.domain)
+            } else {
+                rule.domain.clone()
+            };
+            match rule.action {
+                RpzAction::Nxdomain | RpzAction::Drop if !rule.wildcard => {
+                    staged
+                        .index
+                        .insert_domain(&rule.domain, MatchKind::Exact, &category);
+                    entries.add_pattern(&pattern, rule.line);
+                }
+                action @ (RpzAction::Nxdomain | RpzAction::Drop | RpzAction::Passthru) => {
+                    let text = match action {
+                        RpzAction::Passthru => format!("@@|{}^", pattern),
+                        _ => format!("|{}^", pattern),
+                    };
+                    if let Ok(parsed) = adblock::parse_rule(&text) {
+                        entries.add_rule(&rpz_rule_key(&text).unwrap_or(text), rule.line);
+                        staged.rules.add_rule(parsed, &category);
+                    }
+                }
+                action @ (RpzAction::Nodata | RpzAction::LocalData(_)) => {
+                    entries.add_rule(&rpz_entry_key(&pattern, &action), rule.line);
+                    staged
+                        .rewrites
+                        .insert(&rule.domain, rule.wildcard, action, &category);
+                }
+            }
+        }
+    }
+
+    /// Load list content from memory (e.g. a bundled or uploaded list)
+    ///
+    /// The list stays loaded until the next refresh replaces all lists.
+    pub fn load_list(&self, content: &str, format: &str, category: &str) -> usize {
+        let mut entries = SourceEntries::new("", category, format);
+        self.update(|staged| self.ingest(content, format, staged, &mut entries));
+        entries.len()
+    }
+
+    /// Re-add the entries of sources that weren't reloaded from the
+    /// published policy
+    ///
+    /// Only hashes are kept per source, so trie patterns, rules, RPZ
+    /// exceptions and rewrites are copied from the current snapshot wherever
+    /// their key belongs to one of the sources. The snapshot is walked once
+    /// however many sources are restored.
+    fn restore(policy: &PolicySnapshot, staged: &mut StagedPolicy, sources: &[&SourceEntries]) {
+        if sources.is_empty() {
+            return;
+        }
+
+        let indexed: Vec<_> = sources
+            .iter()
+            .filter_map(|e| policy.index.category_id(&e.category).map(|id| (id, *e)))
+            .collect();
+        if !indexed.is_empty() {
+            policy.index.for_each(|domain, kind, set| {
+                let mut key = None;
+                for (id, entries) in &indexed {
+                    if !set.contains(*id) {
+                        continue;
+                    }
+                    let key = *key.get_or_insert_with(|| match kind {
+                        MatchKind::Exact => entry_hash(domain),
+                        MatchKind::Subtree => entry_hash(&format!("*.{}", domain)),
+                    });
+                    if entries.patterns.contains_key(&key) {
+                        staged.index.insert_domain(domain, kind, &entries.category);
+                    }
+                }
+            });
+        }
+
+        policy.rules.for_each_stored_rule(|rule, category| {
+            let mut keys = None;
+            for entries in sources.iter().filter(|e| e.category == category) {
+                let (text, rpz) = keys.get_or_insert_with(|| {
+                    (
+                        entry_hash(&rule.text),
+                        rpz_rule_key(&rule.text).map(|k| entry_hash(&k)),
+                    )
+                });
+                let owned = entries.rules.contains_key(text)
+                    || rpz.is_some_and(|k| entries.rules.contains_key(&k));
+                if owned {
+                    // Sources of one category share the rule; add it once
+                    staged.rules.add_rule(rule.clone(), category);
+                    break;
+                }
+            }
+        });
+
+        policy
+            .rewrites
+            .for_each(|domain, wildcard, category, action| {
+                let mut key = None;
+                for entries in sources.iter().filter(|e| e.category == category) {
+                    let key = *key.get_or_insert_with(|| {
+                        let pattern = if wildcard {
+                            format!("*.{}", domain)
+                        } else {
+                            domain.to_string()
+                        };
+                        entry_hash(&rpz_entry_key(&pattern, action))
+                    });
+                    if entries.rules.contains_key(&key) {
+                        staged
+                            .rewrites
+                            .insert(domain, wildcard, action.clone(), category);
+                        break;
+                    }
+                }
+            });
+
+        for entries in sources {
+            for text in &entries.badfilters {
+                if let Ok(rule) = adblock::parse_rule(text) {
+                    staged.rules.add_rule(rule, &entries.category);
+                }
+            }
+        }
     }
 
-    /// Parse blocklist content based on format
-    fn parse_blocklist(&self, content: &str, format: &str) -> Vec<String> {
-        let mut domains = Vec::new();
+    /// Diff the entries loaded by a refresh against the previous refresh
+    ///
+    /// A category's entries are the union of its sources, so an entry dropped
+    /// by one list but still served by another is not reported as removed.
+    /// Entries are compared by hash; sample names are recovered from the
+    /// policy they were loaded into (`old` for removals, `new` for additions).
+    fn diff_entries(
+        previous: &HashMap<String, SourceEntries>,
+        loaded: &HashMap<String, SourceEntries>,
+        old: &PolicySnapshot,
+        new: &StagedPolicy,
+    ) -> RefreshDiff {
+        fn changes(before: &AHashSet<u64>, after: &AHashSet<u64>) -> (Vec<u64>, Vec<u64>) {
+            (
+                after.difference(before).copied().collect(),
+                before.difference(after).copied().collect(),
+            )
+        }
+
+        let urls: BTreeSet<&String> = previous.keys().chain(loaded.keys()).collect();
+        let mut by_source = Vec::new();
+        for url in urls {
+            let before = previous.get(url);
+            let after = loaded.get(url);
+            let (added, removed) = changes(
+                &SourceEntries::hash_set(before),
+                &SourceEntries::hash_set(after),
+            );
+            if !added.is_empty() || !removed.is_empty() {
+                let name = after.or(before).map(|e| e.name.clone()).unwrap_or_default();
+                by_source.push((name, added, removed));
+            }
+        }
 
-        for line in content.lines() {
-            if let Some(domain) = self.parse_line(line, format) {
-                domains.push(domain);
+        let mut categories: BTreeMap<&str, (AHashSet<u64>, AHashSet<u64>)> = BTreeMap::new();
+        for entries in previous.values() {
+            categories
+                .entry(&entries.category)
+                .or_default()
+                .0
+                .extend(entries.hashes());
+        }
+        for entries in loaded.values() {
+            categories
+                .entry(&entries.category)
+                .or_default()
+                .1
+                .extend(entries.hashes());
+        }
+        let by_category: Vec<(&str, Vec<u64>, Vec<u64>)> = categories
+            .iter()
+            .map(|(category, (before, after))| {
+                let (added, removed) = changes(before, after);
+                (*category, added, removed)
+            })
+            .filter(|(_, added, removed)| !added.is_empty() || !removed.is_empty())
+            .collect();
+
+        // Only changed entries need a name, and there are few of those
+        let wanted: AHashSet<u64> = by_source
+            .iter()
+            .flat_map(|(_, a, r)| a.iter().chain(r))
+            .copied()
+            .collect();
+        let mut names: AHashMap<u64, String> = AHashMap::new();
+        if !wanted.is_empty() {
+            let mut name = |key: &str| {
+                let hash = entry_hash(key);
+                if wanted.contains(&hash) {
+                    names.entry(hash).or_insert_with(|| key.to_string());
+                }
+            };
+            for_each_entry_key(&old.index, &old.rules, &old.rewrites, &mut name);
+            for_each_entry_key(&new.index, &new.rules, &new.rewrites, &mut name);
+            for entries in previous.values().chain(loaded.values()) {
+                entries.badfilters.iter().for_each(|text| name(text));
             }
         }
 
-        domains
+        let mut diff = RefreshDiff::default();
+        for (name, added, removed) in by_source {
+            diff.by_source
+                .insert(name, ListDiff::from_hashes(&added, &removed, &names));
+        }
+        for (category, added, removed) in by_category {
+            let category_diff = ListDiff::from_hashes(&added, &removed, &names);
+            diff.added += category_diff.added;
+            diff.removed += category_diff.removed;
+            diff.by_category.insert(category.to_string(), category_diff);
+        }
+        diff
+    }
+
+    /// Edit a copy of the published lists and publish the result
+    ///
+    /// Copies the whole list policy, so it is only used to load lists.
+    /// Single-domain edits go through [`Self::publish_overlay`].
+    fn update(&self, edit: impl FnOnce(&mut StagedPolicy)) {
+        let _guard = self.publish_lock.lock();
+        let current = self.policy.load();
+        let mut staged = StagedPolicy {
+            index: (*current.index).clone(),
+            rules: (*current.rules).clone(),
+            rewrites: (*current.rewrites).clone(),
+        };
+        // The copy is rebuilt anyway, so runtime removals are folded into it
+        for pattern in current.removed.iter() {
+            staged.index.remove(pattern, None);
+        }
+        edit(&mut staged);
+        self.publish(staged);
+    }
+
+    /// Finish a staged policy and swap it in for readers
+    ///
+    /// Applies `$badfilter` rules, layers the runtime-added domains on top
+    /// and computes the enabled masks. Callers must hold `publish_lock`.
+    fn publish(&self, mut staged: StagedPolicy) {
+        let patterns = staged.rules.badfiltered_simple_patterns();
+        for pattern in &patterns {
+            staged.index.remove(pattern, None);
+        }
+        if !patterns.is_empty() {
+            debug!("Applied {} $badfilter rules", patterns.len());
+        }
+
+        let custom = self.custom.read().clone();
+        let (enabled_mask, custom_mask) = self.enabled_masks(&staged.index, &custom);
+        self.policy.store(Arc::new(PolicySnapshot {
+            index: Arc::new(staged.index),
+            custom: Arc::new(custom),
+            removed: Arc::default(),
+            rules: Arc::new(staged.rules),
+            rewrites: Arc::new(staged.rewrites),
+            enabled_mask,
+            custom_mask,
+        }));
+    }
+
+    /// Republish the runtime overlays, sharing the lists with the current
+    /// snapshot, optionally hiding one more list pattern
+    fn publish_overlay(&self, removed: Option<String>) {
+        let _guard = self.publish_lock.lock();
+        let current = self.policy.load();
+        let custom = self.custom.read().clone();
+        let removed = match removed {
+            Some(pattern) => {
+                let mut set = (*current.removed).clone();
+                set.insert(pattern);
+                Arc::new(set)
+            }
+            None => current.removed.clone(),
+        };
+        let (enabled_mask, custom_mask) = self.enabled_masks(&current.index, &custom);
+        self.policy.store(Arc::new(PolicySnapshot {
+            index: current.index.clone(),
+            custom: Arc::new(custom),
+            removed,
+            rules: current.rules.clone(),
+            rewrites: current.rewrites.clone(),
+            enabled_mask,
+            custom_mask,
+        }));
+    }
+
+    /// Enabled categories as interned in the list index and the custom overlay
+    fn enabled_masks(&self, index: &DomainTrie, custom: &DomainTrie) -> (CategorySet, CategorySet) {
+        let enabled = self.enabled_categories.read();
+        (
+            index.category_set(enabled.iter().map(|c| c.as_str())),
+            custom.category_set(enabled.iter().map(|c| c.as_str())),
+        )
     }
 
     /// Parse a single line based on format
@@ -264,23 +1678,12 @@ impl BlocklistManager {
         None
     }
 
-    /// Parse AdBlock format: "||example.com^"
+    /// Parse a plain AdBlock blocking rule into a trie pattern
+    ///
+    /// `||example.com^` becomes `*.example.com`; rules that need the rule
+    /// engine (exceptions, modifiers, regex) return `None`.
     fn parse_adblock_line(&self, line: &str) -> Option<String> {
-        // Skip complex rules with options
-        if line.contains('$') || line.contains('/') || line.contains('*') && !line.starts_with("||") {
-            return None;
-        }
-
-        if line.starts_with("||") && line.ends_with('^') {
-            let domain = line
-                .trim_start_matches("||")
-                .trim_end_matches('^')
-                .to_lowercase();
-            if !domain.is_empty() && domain.contains('.') {
-                return Some(domain);
-            }
-        }
-        None
+        adblock::parse_rule(line).ok()?.as_simple_block()
     }
 
     /// Parse plain domain format
@@ -295,119 +1698,420 @@ impl BlocklistManager {
         }
     }
 
-    /// Rebuild the flat blocked set from enabled categories
-    fn rebuild_blocked_set(&self) {
-        let enabled = self.enabled_categories.read();
-        let by_cat = self.domains_by_category.read();
-
-        let mut blocked = self.all_blocked.write();
-        blocked.clear();
-
-        for cat in enabled.iter() {
-            if let Some(cat_domains) = by_cat.get(cat) {
-                for domain in cat_domains.iter() {
-                    blocked.insert(domain.clone());
-                }
-            }
-        }
+    /// Republish the current policy with a recomputed enabled mask
+    fn refresh_enabled_mask(&self) {
+        let _guard = self.publish_lock.lock();
+        let current = self.policy.load();
+        let (enabled_mask, custom_mask) = self.enabled_masks(&current.index, &current.custom);
+        self.policy.store(Arc::new(PolicySnapshot {
+            index: current.index.clone(),
+            custom: current.custom.clone(),
+            removed: current.removed.clone(),
+            rules: current.rules.clone(),
+            rewrites: current.rewrites.clone(),
+            enabled_mask,
+            custom_mask,
+        }));
     }
 
     /// Check if a domain is blocked (considers enabled categories)
     pub fn is_blocked(&self, domain: &str) -> bool {
         let domain_lower = domain.to_lowercase();
+        let policy = self.policy.load();
+        if !policy.rules.is_empty() {
+            if let Some(rule) = policy
+                .rules
+                .evaluate(&domain_lower, &RuleContext::default(), |c| {
+                    policy.category_enabled(c)
+                })
+            {
+                return rule.action == RuleAction::Block;
+            }
+        }
+        policy.matches_enabled(&domain_lower)
+    }
 
-        // Check exact match first (O(1))
-        if self.all_blocked.read().contains(&domain_lower) {
-            return true;
+    /// Evaluate AdBlock rules (exceptions, modifiers, regex) for a query
+    ///
+    /// `category_enabled` selects which lists take part. Returns the winning
+    /// rule, or `None` if only the plain index applies.
+    pub fn evaluate_rules(
+        &self,
+        domain: &str,
+        ctx: &RuleContext,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<RuleMatch> {
+        let policy = self.policy.load();
+        if policy.rules.is_empty() {
+            return None;
         }
+        policy.rules.evaluate(domain, ctx, category_enabled)
+    }
 
-        // Check wildcard patterns
-        let enabled = self.enabled_categories.read();
-        let wildcards = self.wildcards_by_category.read();
+    /// Number of rules held by the AdBlock rule engine
+    pub fn rule_count(&self) -> usize {
+        self.policy.load().rules.len()
+    }
+
+    /// Find an RPZ NODATA or local-data policy for a domain
+    ///
+    /// Returns the policy's category and action.
+    pub fn policy_rewrite(
+        &self,
+        domain: &str,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<(String, RpzAction)> {
+        let policy = self.policy.load();
+        if policy.rewrites.is_empty() {
+            return None;
+        }
+        policy
+            .rewrites
+            .lookup(domain, category_enabled)
+            .map(|(category, action)| (category.to_string(), action.clone()))
+    }
+
+    /// Write the policy of all enabled categories to an RPZ zone
+    ///
+    /// Policies are added in evaluation order, so an owner name listed by
+    /// several stages gets the action the filter would apply: rules by
+    /// AdGuard precedence, then rewrites, then list and custom entries.
+    /// Rule-engine entries with modifiers or patterns have no RPZ equivalent
+    /// and are skipped; returns how many were left out.
+    pub fn export_rpz(&self, writer: &mut RpzWriter) -> usize {
+        let policy = self.policy.load();
+
+        let mut skipped = 0;
+        let mut rules = Vec::new();
+        policy.rules.for_each_rule(|rule, category| {
+            if policy.category_enabled(category) {
+                rules.push(rule);
+            }
+        });
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.precedence()));
+        for rule in rules {
+            let action = match rule.action {
+                RuleAction::Allow => RpzAction::Passthru,
+                RuleAction::Block => RpzAction::Nxdomain,
+            };
+            match &rule.pattern {
+                _ if rule.modifiers.has_conditions() => skipped += 1,
+                adblock::RulePattern::Subtree(d) => writer.add_subtree(d, &action),
+                adblock::RulePattern::Exact(d) => writer.add(d, &action),
+                adblock::RulePattern::Subdomains(d) => writer.add(&format!("*.{}", d), &action),
+                adblock::RulePattern::Regex(_) => skipped += 1,
+            }
+        }
 
-        for cat in enabled.iter() {
-            if let Some(patterns) = wildcards.get(cat) {
-                for pattern in patterns {
-                    if self.matches_wildcard(&domain_lower, pattern) {
-                        return true;
+        policy
+            .rewrites
+            .for_each(|domain, wildcard, category, action| {
+                if policy.category_enabled(category) {
+                    if wildcard {
+                        writer.add(&format!("*.{}", domain), action);
+                    } else {
+                        writer.add(domain, action);
                     }
                 }
+            });
+
+        let add_entry = |writer: &mut RpzWriter, domain: &str, kind: MatchKind| match kind {
+            MatchKind::Exact => writer.add(domain, &RpzAction::Nxdomain),
+            MatchKind::Subtree => writer.add_subtree(domain, &RpzAction::Nxdomain),
+        };
+        policy.index.for_each(|domain, kind, set| {
+            let pattern = match kind {
+                MatchKind::Exact => domain.to_string(),
+                MatchKind::Subtree => format!("*.{}", domain),
+            };
+            if set.intersects(policy.enabled_mask) && !policy.removed.contains(&pattern) {
+                add_entry(writer, domain, kind);
             }
-        }
+        });
+        policy.custom.for_each(|domain, kind, set| {
+            if set.intersects(policy.custom_mask) {
+                add_entry(writer, domain, kind);
+            }
+        });
 
-        false
+        skipped
     }
 
     /// Check if a domain is blocked by a specific category
     pub fn is_blocked_by_category(&self, domain: &str, category: &str) -> bool {
         let domain_lower = domain.to_lowercase();
+        let policy = self.policy.load();
+        policy
+            .matching_categories(&domain_lower)
+            .contains(&category)
+    }
 
-        if let Some(cat_domains) = self.domains_by_category.read().get(category) {
-            if cat_domains.contains(&domain_lower) {
-                return true;
+    /// Get which category blocks a domain
+    ///
+    /// When several enabled lists match, the highest-priority category wins.
+    pub fn get_blocking_category(&self, domain: &str) -> Option<String> {
+        let domain_lower = domain.to_lowercase();
+        let policy = self.policy.load();
+        let mut matched = None;
+        if !policy.rules.is_empty() {
+            if let Some(rule) = policy
+                .rules
+                .evaluate(&domain_lower, &RuleContext::default(), |c| {
+                    policy.category_enabled(c)
+                })
+            {
+                match rule.action {
+                    RuleAction::Block => matched = Some(rule.category),
+                    RuleAction::Allow => return None,
+                }
             }
         }
+        self.highest_priority_hit(&policy, &domain_lower, matched.as_deref(), |c| {
+            policy.category_enabled(c)
+        })
+    }
 
-        // Check wildcards for this category
-        if let Some(patterns) = self.wildcards_by_category.read().get(category) {
-            for pattern in patterns {
-                if self.matches_wildcard(&domain_lower, pattern) {
-                    return true;
-                }
-            }
+    /// Pick the category to report for a blocked domain
+    ///
+    /// Considers `matched` (e.g. the category of a winning rule) together
+    /// with every list in a `category_enabled` category that contains the
+    /// domain, and returns the one with the highest priority. Ties go to the
+    /// built-in ranking, then to the alphabetically first name, so the answer
+    /// is stable.
+    pub fn attribute_category(
+        &self,
+        domain: &str,
+        matched: Option<&str>,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        let policy = self.policy.load();
+        self.highest_priority_hit(&policy, &domain.to_lowercase(), matched, category_enabled)
+    }
+
+    fn highest_priority_hit(
+        &self,
+        policy: &PolicySnapshot,
+        domain: &str,
+        matched: Option<&str>,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        let priorities = self.category_priorities.read();
+        policy
+            .matching_categories(domain)
+            .into_iter()
+            .filter(|name| category_enabled(name))
+            .chain(matched)
+            .min_by_key(|name| Self::category_order(&priorities, name))
+            .map(|name| name.to_string())
+    }
+
+    /// Sort key putting the category reported for a domain first: priority,
+    /// then the built-in ranking, then the name
+    fn category_order<'a>(
+        priorities: &HashMap<String, u8>,
+        category: &'a str,
+    ) -> (u8, usize, &'a str) {
+        (
+            Self::priority_in(priorities, category),
+            builtin_rank(category),
+            category,
+        )
+    }
+
+    /// Every category with a list or block rule matching the domain,
+    /// enabled or not, in the order [`Self::attribute_category`] prefers them
+    pub fn domain_categories(&self, domain: &str) -> Vec<String> {
+        let domain = domain.to_lowercase();
+        let policy = self.policy.load();
+        let mut categories: Vec<String> = policy
+            .matching_categories(&domain)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        if !policy.rules.is_empty() {
+            categories.extend(
+                policy
+                    .rules
+                    .matching_rules(&domain, &RuleContext::default())
+                    .into_iter()
+                    .filter(|m| m.action == RuleAction::Block)
+                    .map(|m| m.category),
+            );
         }
 
-        false
+        let priorities = self.category_priorities.read();
+        categories.sort_by(|a, b| {
+            Self::category_order(&priorities, a).cmp(&Self::category_order(&priorities, b))
+        });
+        categories.dedup();
+        categories
     }
 
-    /// Get which category blocks a domain
-    pub fn get_blocking_category(&self, domain: &str) -> Option<String> {
-        let domain_lower = domain.to_lowercase();
-        let enabled = self.enabled_categories.read();
+    fn priority_in(priorities: &HashMap<String, u8>, category: &str) -> u8 {
+        priorities
+            .get(category)
+            .copied()
+            .unwrap_or(UNRANKED_PRIORITY)
+    }
 
-        // Check exact matches
-        for cat in enabled.iter() {
-            if let Some(cat_domains) = self.domains_by_category.read().get(cat) {
-                if cat_domains.contains(&domain_lower) {
-                    return Some(cat.clone());
+    /// Every loaded entry matching a query, with the lists it came from
+    ///
+    /// Domain entries and rewrites are listed most specific first, rules in
+    /// precedence order with the rule engine's pick first. `winner` is left
+    /// for the caller, which knows which stage decided the query.
+    pub fn trace_matches(
+        &self,
+        domain: &str,
+        ctx: &RuleContext,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Vec<TraceMatch> {
+        let domain = domain.to_lowercase();
+        let policy = self.policy.load();
+        let sources = self.source_entries.read();
+        let listed = policy.list_entries(&domain);
+        let custom_entries = policy.custom.matching_entries(&domain);
+
+        let origins = |category: &str, keys: &[String], in_index: bool| {
+            let mut origins: Vec<EntryOrigin> = sources
+                .iter()
+                .filter(|(_, entries)| entries.category == category)
+                .filter_map(|(url, entries)| {
+                    let lines = if in_index {
+                        &entries.patterns
+                    } else {
+                        &entries.rules
+                    };
+                    keys.iter()
+                        .find_map(|key| lines.get(&entry_hash(key)))
+                        .map(|&line| EntryOrigin {
+                            source: entries.name.clone(),
+                            url: Some(url.clone()),
+                            line: Some(line),
+                        })
+                })
+                .collect();
+            origins.sort_by(|a, b| a.source.cmp(&b.source));
+            origins
+        };
+
+        // List and custom patterns merged, exact first, then subtrees from
+        // the domain up
+        let mut patterns: Vec<(String, MatchKind)> = listed
+            .iter()
+            .chain(&custom_entries)
+            .map(|(p, k, _)| (p.clone(), *k))
+            .collect();
+        patterns.sort_by_key(|(p, k)| (*k == MatchKind::Subtree, std::cmp::Reverse(p.len())));
+        patterns.dedup();
+
+        let mut matches = Vec::new();
+        for (pattern, _) in patterns {
+            let names =
+                |entries: &[(String, MatchKind, CategorySet)], trie: &DomainTrie| -> Vec<String> {
+                    entries
+                        .iter()
+                        .filter(|(p, _, _)| *p == pattern)
+                        .flat_map(|(_, _, set)| set.iter())
+                        .filter_map(|id| trie.category_name(id))
+                        .map(str::to_string)
+                        .collect()
+                };
+            let listed_categories = names(&listed, &policy.index);
+            let custom_categories = names(&custom_entries, &policy.custom);
+            let mut categories = listed_categories.clone();
+            for category in &custom_categories {
+                if !categories.contains(category) {
+                    categories.push(category.clone());
                 }
             }
-        }
 
-        // Check wildcards
-        for cat in enabled.iter() {
-            if let Some(patterns) = self.wildcards_by_category.read().get(cat) {
-                for pattern in patterns {
-                    if self.matches_wildcard(&domain_lower, pattern) {
-                        return Some(cat.clone());
-                    }
+            for category in &categories {
+                let mut entry_origins = if listed_categories.contains(category) {
+                    origins(category, std::slice::from_ref(&pattern), true)
+                } else {
+                    Vec::new()
+                };
+                if custom_categories.contains(category) {
+                    entry_origins.push(EntryOrigin {
+                        source: "custom".to_string(),
+                        url: None,
+                        line: None,
+                    });
                 }
+                matches.push(TraceMatch {
+                    kind: TraceMatchKind::Domain,
+                    entry: pattern.clone(),
+                    category: category.to_string(),
+                    action: "block",
+                    enabled: category_enabled(category),
+                    origins: entry_origins,
+                    winner: false,
+                });
             }
         }
 
-        None
+        for rule in policy.rules.matching_rules(&domain, ctx) {
+            let keys: Vec<String> = std::iter::once(rule.rule.clone())
+                .chain(rpz_rule_key(&rule.rule))
+                .collect();
+            matches.push(TraceMatch {
+                kind: TraceMatchKind::Rule,
+                origins: origins(&rule.category, &keys, false),
+                enabled: category_enabled(&rule.category),
+                action: match rule.action {
+                    RuleAction::Block => "block",
+                    RuleAction::Allow => "allow",
+                },
+                entry: rule.rule,
+                category: rule.category,
+                winner: false,
+            });
+        }
+
+        for (pattern, category, action) in policy.rewrites.matching(&domain) {
+            let key = rpz_entry_key(&pattern, action);
+            matches.push(TraceMatch {
+                kind: TraceMatchKind::Rewrite,
+                origins: origins(category, std::slice::from_ref(&key), false),
+                entry: key,
+                category: category.to_string(),
+                action: match action {
+                    RpzAction::LocalData(_) => "local_data",
+                    RpzAction::Passthru => "allow",
+                    RpzAction::Nodata => "nodata",
+                    RpzAction::Nxdomain | RpzAction::Drop => "block",
+                },
+                enabled: category_enabled(category),
+                winner: false,
+            });
+        }
+        matches
     }
 
-    /// Check wildcard pattern match
-    #[inline(always)]
-    fn matches_wildcard(&self, domain: &str, pattern: &str) -> bool {
-        if let Some(suffix) = pattern.strip_prefix("*.") {
-            domain.ends_with(suffix) || domain == suffix
-        } else {
-            domain == pattern
+    /// Apply configured category priorities on top of the built-in ones
+    pub fn set_category_priorities(&self, categories: &HashMap<String, CategoryConfig>) {
+        let mut priorities = default_category_priorities();
+        for (name, category) in categories {
+            priorities.insert(name.clone(), category.priority);
         }
+        *self.category_priorities.write() = priorities;
+    }
+
+    /// Attribution priority of a category (lower wins)
+    pub fn category_priority(&self, category: &str) -> u8 {
+        Self::priority_in(&self.category_priorities.read(), category)
     }
 
     /// Enable a category
     pub fn enable_category(&self, category: &str) {
         self.enabled_categories.write().insert(category.to_string());
-        self.rebuild_blocked_set();
+        self.refresh_enabled_mask();
     }
 
     /// Disable a category
     pub fn disable_category(&self, category: &str) {
         self.enabled_categories.write().remove(category);
-        self.rebuild_blocked_set();
+        self.refresh_enabled_mask();
     }
 
     /// Set enabled categories from list
@@ -418,7 +2122,7 @@ impl BlocklistManager {
             enabled.insert(cat.clone());
         }
         drop(enabled);
-        self.rebuild_blocked_set();
+        self.refresh_enabled_mask();
     }
 
     /// Get enabled categories
@@ -431,39 +2135,47 @@ impl BlocklistManager {
         self.stats.read().clone()
     }
 
-    /// Get total blocked domain count
+    /// Get total blocked domain count (distinct entries in enabled categories)
     pub fn blocked_count(&self) -> usize {
-        self.all_blocked.read().len()
+        self.policy.load().blocked_count()
     }
 
     /// Add a custom domain to a category
+    ///
+    /// Custom domains are kept across refreshes.
     pub fn add_domain(&self, domain: &str, category: &str) {
-        let domain_lower = domain.to_lowercase();
-
-        // Add to category
-        self.domains_by_category
-            .write()
-            .entry(category.to_string())
-            .or_insert_with(AHashSet::new)
-            .insert(domain_lower.clone());
+        self.add_domains(&[domain], category);
+    }
 
-        // Add to blocked set if category is enabled
-        if self.enabled_categories.read().contains(category) {
-            self.all_blocked.write().insert(domain_lower);
+    /// Add custom domains to a category, publishing once
+    pub fn add_domains(&self, domains: &[&str], category: &str) {
+        {
+            let mut custom = self.custom.write();
+            for domain in domains {
+                custom.insert(domain, category);
+            }
         }
+        self.publish_overlay(None);
     }
 
     /// Remove a domain from all categories
+    ///
+    /// A list that provides the domain adds it back on its next refresh.
     pub fn remove_domain(&self, domain: &str) {
-        let domain_lower = domain.to_lowercase();
-
-        // Remove from all categories
-        for (_, domains) in self.domains_by_category.write().iter_mut() {
-            domains.remove(&domain_lower);
-        }
-
-        // Remove from blocked set
-        self.all_blocked.write().remove(&domain_lower);
+        self.custom.write().remove(domain, None);
+        let (kind, bare) = DomainTrie::parse_pattern(domain);
+        let pattern = match kind {
+            MatchKind::Exact => bare.clone(),
+            MatchKind::Subtree => format!("*.{}", bare),
+        };
+        let listed = self
+            .policy
+            .load()
+            .index
+            .matching_entries(&bare)
+            .iter()
+            .any(|(p, _, _)| *p == pattern);
+        self.publish_overlay(listed.then_some(pattern));
     }
 
     /// Load embedded/default blocklist (common ad domains)
@@ -560,14 +2272,13 @@ impl BlocklistManager {
         ];
 
         // Add to categories
-        for domain in default_ads {
-            self.add_domain(domain, "ads");
-        }
-        for domain in default_tracking {
-            self.add_domain(domain, "tracking");
-        }
+        self.add_domains(&default_ads, "ads");
+        self.add_domains(&default_tracking, "tracking");
 
-        info!("Loaded {} default domains", default_ads.len() + default_tracking.len());
+        info!(
+            "Loaded {} default domains",
+            default_ads.len() + default_tracking.len()
+        );
     }
 }
 
@@ -602,9 +2313,62 @@ mod tests {
 
         assert_eq!(
             manager.parse_adblock_line("||ads.example.com^"),
-            Some("ads.example.com".to_string())
+            Some("*.ads.example.com".to_string())
         );
         assert_eq!(manager.parse_adblock_line("! comment"), None);
+        assert_eq!(manager.parse_adblock_line("@@||ads.example.com^"), None);
+    }
+
+    #[test]
+    fn test_adblock_list_loading() {
+        let manager = BlocklistManager::new();
+        let list = include_str!("../testdata/adguard-dns-sample.txt");
+        manager.load_list(list, "adblock", "ads");
+
+        assert!(manager.rule_count() > 0);
+        // Plain rules land in the trie with subdomain matching
+        assert!(manager.is_blocked("stats.g.doubleclick.net"));
+        // Exceptions override plain blocks from the same list
+        assert!(!manager.is_blocked("www.googleadservices.com"));
+        assert!(manager.is_blocked("partner.googleadservices.com"));
+        // $important beats the exception
+        assert!(manager.is_blocked("app.adjust.com"));
+        // $badfilter removes the plain rule from the trie
+        assert!(!manager.is_blocked("ads.reddit.com"));
+        // Regex rules go through the rule engine
+        assert!(manager.is_blocked("ad12.mediaserver.net"));
+        assert_eq!(
+            manager.get_blocking_category("ad12.mediaserver.net"),
+            Some("ads".to_string())
+        );
+        assert_eq!(
+            manager.get_blocking_category("www.googleadservices.com"),
+            None
+        );
+
+        // Rule engine honours enabled categories
+        manager.disable_category("ads");
+        assert!(!manager.is_blocked("ad12.mediaserver.net"));
+    }
+
+    #[test]
+    fn test_regex_list_loading() {
+        let manager = BlocklistManager::new();
+        let list = "# Pi-hole regex list\n\
+                    ^ad[sx]?[0-9]*[_.-]\n\
+                    (^|\\.)telemetry\\.example\\.org$\n\
+                    ^track;querytype=AAAA\n\
+                    [unclosed\n";
+        assert_eq!(manager.load_list(list, "regex", "ads"), 2);
+
+        assert!(manager.is_blocked("ads1.example.com"));
+        assert!(manager.is_blocked("eu.telemetry.example.org"));
+        assert!(!manager.is_blocked("example.org"));
+        assert!(!manager.is_blocked("tracker.example.com"));
+        assert_eq!(
+            manager.get_blocking_category("ads1.example.com"),
+            Some("ads".to_string())
+        );
     }
 
     #[test]
@@ -628,4 +2392,545 @@ mod tests {
         manager.enable_category("adult");
         assert!(manager.is_blocked("adult.example.com"));
     }
+
+    /// Minimal HTTP server answering each connection with the next scripted
+    /// response; returns the URL and the request heads it received
+    async fn scripted_server(
+        responses: Vec<String>,
+    ) -> (String, Arc<parking_lot::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                seen.lock()
+                    .push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        (url, requests)
+    }
+
+    fn ok_response(body: &str, etag: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            etag,
+            body
+        )
+    }
+
+    fn test_config(url: &str, cache_dir: &std::path::Path) -> BlocklistConfig {
+        BlocklistConfig {
+            sources: vec![BlocklistSource {
+                name: "Test list".to_string(),
+                url: url.to_string(),
+                category: "ads".to_string(),
+                format: "domains".to_string(),
+                enabled: true,
+                description: String::new(),
+                sha256: None,
+                max_bytes: None,
+                min_entries: None,
+            }],
+            cache_dir: cache_dir.to_string_lossy().into_owned(),
+            // The scripted test servers listen on loopback
+            allow_private_sources: true,
+            ..BlocklistConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_fetch_and_cache_fallback() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("shield-fetch-test-{}", std::process::id()));
+        let body = "a.example.com\nb.example.com\nc.example.com\nd.example.com\n";
+        let (url, requests) = scripted_server(vec![
+            ok_response(body, "\"v1\""),
+            "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string(),
+            ok_response("a.example.com\n", "\"v2\""),
+        ])
+        .await;
+        let config = test_config(&url, &cache_dir);
+
+        // First fetch downloads and caches the list
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Fresh);
+        assert_eq!(stats.sources[0].entries, 4);
+
+        // Second fetch sends the ETag and reuses the cached body on 304
+        let stats = manager.fetch_blocklists(&config).await;
+        assert!(requests.lock()[1].contains("if-none-match: \"v1\""));
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::NotModified);
+        assert!(manager.is_blocked("d.example.com"));
+
+        // A list that shrank to a quarter is rejected in favour of the cache
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Cached);
+        assert!(stats.sources[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("suspiciously small"));
+        assert_eq!(stats.sources_loaded, 1);
+
+        // After a restart with the source unreachable, the cached copy still loads
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Cached);
+        assert_eq!(stats.sources[0].entries, 4);
+        assert!(manager.is_blocked("c.example.com"));
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_pin_mismatch_and_oversize() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("shield-pin-test-{}", std::process::id()));
+        let body = "pinned.example.com\n";
+        let (url, _) = scripted_server(vec![
+            ok_response(body, "\"p1\""),
+            ok_response(body, "\"p1\""),
+            ok_response(body, "\"p1\""),
+        ])
+        .await;
+
+        let mut config = test_config(&url, &cache_dir);
+        config.sources[0].sha256 = Some("00".repeat(32));
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Failed);
+        assert!(stats.sources[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("SHA-256"));
+        assert_eq!(stats.sources_failed, 1);
+        assert!(!manager.is_blocked("pinned.example.com"));
+
+        config.sources[0].sha256 = None;
+        config.sources[0].max_bytes = Some(4);
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Failed);
+
+        config.sources[0].max_bytes = None;
+        config.sources[0].sha256 = Some(sha256_hex(body.as_bytes()).to_uppercase());
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Fresh);
+        assert!(manager.is_blocked("pinned.example.com"));
+
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_diff_unloads_dropped_entries() {
+        let (url, _) = scripted_server(vec![
+            ok_response("a.example.com\nb.example.com\nc.example.com\n", "\"d1\""),
+            ok_response(
+                "b.example.com\nc.example.com\nd.example.com\ne.example.com\n",
+                "\"d2\"",
+            ),
+        ])
+        .await;
+        // An empty cache directory disables the last-known-good cache
+        let config = test_config(&url, std::path::Path::new(""));
+
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.diff.added, 3);
+        assert_eq!(stats.diff.by_source["Test list"].added, 3);
+
+        let stats = manager.fetch_blocklists(&config).await;
+        let ads = &stats.diff.by_category["ads"];
+        assert_eq!((stats.diff.added, stats.diff.removed), (2, 1));
+        assert_eq!(ads.added_sample, vec!["d.example.com", "e.example.com"]);
+        assert_eq!(ads.removed_sample, vec!["a.example.com"]);
+        assert!(!manager.is_blocked("a.example.com"));
+        assert!(manager.is_blocked("e.example.com"));
+
+        // A failed refresh keeps the previous entries and reports no change
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources_failed, 1);
+        assert!(stats.diff.is_empty());
+        assert!(manager.is_blocked("b.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_refresh_keeps_rpz_exceptions_and_rewrites() {
+        let zone = "$ORIGIN rpz.example.net.\n\
+                    *.bad.example CNAME .\n\
+                    ok.bad.example CNAME rpz-passthru.\n\
+                    quiet.example CNAME *.\n\
+                    portal.example A 10.0.0.10\n";
+        let (url, _) = scripted_server(vec![ok_response(zone, "\"z1\"")]).await;
+        let mut config = test_config(&url, std::path::Path::new(""));
+        config.sources[0].format = "rpz".to_string();
+
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.diff.added, 4);
+
+        // The server is gone, so every entry comes from the previous snapshot
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources_failed, 1);
+        assert!(stats.diff.is_empty());
+        assert!(manager.is_blocked("x.bad.example"));
+        assert!(!manager.is_blocked("bad.example"));
+        assert!(!manager.is_blocked("ok.bad.example"));
+        assert_eq!(
+            manager.policy_rewrite("quiet.example", |_| true),
+            Some(("ads".to_string(), RpzAction::Nodata))
+        );
+        assert!(manager.policy_rewrite("portal.example", |_| true).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_publishes_complete_snapshot() {
+        let (url, _) = scripted_server(vec![ok_response(
+            "a.example.com\nb.example.com\n",
+            "\"s1\"",
+        )])
+        .await;
+        let config = test_config(&url, std::path::Path::new(""));
+
+        let manager = BlocklistManager::new();
+        manager.add_domain("custom.example.com", "ads");
+        let before = manager.policy.load_full();
+
+        manager.fetch_blocklists(&config).await;
+
+        // Readers holding the old snapshot are unaffected by the refresh
+        assert!(!before.index.contains("a.example.com"));
+        assert!(before.custom.contains("custom.example.com"));
+
+        // The new snapshot has every list entry plus runtime-added domains
+        assert!(manager.is_blocked("a.example.com"));
+        assert!(manager.is_blocked("b.example.com"));
+        assert!(manager.is_blocked("custom.example.com"));
+        assert_eq!(manager.blocked_count(), 3);
+
+        // Runtime edits share the list index instead of copying it
+        let lists = manager.policy.load().index.clone();
+        manager.add_domain("a.example.com", "ads");
+        manager.add_domain("more.example.com", "ads");
+        manager.remove_domain("b.example.com");
+        assert!(Arc::ptr_eq(&lists, &manager.policy.load().index));
+        assert!(!manager.is_blocked("b.example.com"));
+        assert!(manager.is_blocked("more.example.com"));
+        assert_eq!(manager.blocked_count(), 3);
+        assert_eq!(manager.domain_categories("a.example.com"), vec!["ads"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_sources_and_partial_refresh() {
+        let root = std::env::temp_dir().join(format!("shield-file-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let list = root.join("local.txt");
+        std::fs::write(&list, "local.example.com\n*.wild.example\n").unwrap();
+
+        let mut config = test_config(
+            &format!("file://{}", list.display()),
+            std::path::Path::new(""),
+        );
+        config.file_root = root.to_string_lossy().into_owned();
+        assert!(config.sources[0].validate().is_ok());
+
+        let manager = BlocklistManager::new();
+        let preview = manager
+            .preview_source(&config.sources[0], &config)
+            .await
+            .unwrap();
+        assert_eq!(preview.entries, 2);
+        assert_eq!(preview.sample, vec!["*.wild.example", "local.example.com"]);
+        assert!(!manager.is_blocked("local.example.com"));
+
+        let stats = manager.fetch_source(&config, &config.sources[0].url).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Fresh);
+        assert!(manager.is_blocked("x.wild.example"));
+
+        // A reload without downloads keeps the loaded entries
+        std::fs::write(&list, "other.example.com\n").unwrap();
+        let stats = manager.reload_blocklists(&config).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Retained);
+        assert!(stats.diff.is_empty());
+        assert!(manager.is_blocked("local.example.com"));
+
+        // Disabling a source unloads it on the next reload
+        config.sources[0].enabled = false;
+        let stats = manager.reload_blocklists(&config).await;
+        assert_eq!(stats.diff.removed, 2);
+        assert!(!manager.is_blocked("local.example.com"));
+
+        // Files outside the root are refused
+        let mut outside = test_config("file:///etc/hosts", std::path::Path::new(""));
+        outside.file_root = root.to_string_lossy().into_owned();
+        let err = manager
+            .preview_source(&outside.sources[0], &outside)
+            .await
+            .unwrap_err();
+        assert!(err.contains("outside"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partial_refresh_reuses_loaded_sources() {
+        let root = std::env::temp_dir().join(format!("shield-reuse-test-{}", std::process::id()));
+        let cache_dir = root.join("cache");
+        std::fs::create_dir_all(&root).unwrap();
+        let ads = root.join("ads.txt");
+        let malware = root.join("malware.txt");
+        std::fs::write(&ads, "ads.example.com\n").unwrap();
+        std::fs::write(&malware, "bad.example.com\n*.evil.example\n").unwrap();
+
+        let mut config = test_config(&format!("file://{}", ads.display()), &cache_dir);
+        config.file_root = root.to_string_lossy().into_owned();
+        let mut source = config.sources[0].clone();
+        source.name = "Malware list".to_string();
+        source.url = format!("file://{}", malware.display());
+        source.category = "malware".to_string();
+        config.sources.push(source);
+
+        let manager = BlocklistManager::new();
+        let stats = manager.fetch_blocklists(&config).await;
+        assert_eq!(stats.sources_loaded, 2);
+
+        // Fetching one source serves the other from the loaded policy
+        // rather than parsing its cached copy again
+        let cache = BlocklistCache::new(&cache_dir);
+        let cached = cache.load(&config.sources[1].url).unwrap();
+        cache.store(&cached.meta, "other.example.com\n").unwrap();
+        let stats = manager.fetch_source(&config, &config.sources[0].url).await;
+        assert_eq!(stats.sources[1].freshness, SourceFreshness::Retained);
+        assert_eq!(stats.sources[1].entries, 2);
+        assert!(stats.diff.is_empty());
+        assert!(manager.is_blocked("x.evil.example"));
+        assert!(!manager.is_blocked("other.example.com"));
+
+        // A failed source and a kept one are both restored
+        std::fs::remove_file(&ads).unwrap();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+        let stats = manager.fetch_source(&config, &config.sources[0].url).await;
+        assert_eq!(stats.sources[0].freshness, SourceFreshness::Failed);
+        assert!(stats.diff.is_empty());
+        assert!(manager.is_blocked("ads.example.com"));
+        assert!(manager.is_blocked("bad.example.com"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trace_matches() {
+        let root = std::env::temp_dir().join(format!("shield-trace-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let list = root.join("rules.txt");
+        std::fs::write(
+            &list,
+            "! Title: test\n||ads.example.com^\n@@||ok.ads.example.com^\n",
+        )
+        .unwrap();
+
+        let mut config = test_config(
+            &format!("file://{}", list.display()),
+            std::path::Path::new(""),
+        );
+        config.file_root = root.to_string_lossy().into_owned();
+        config.sources[0].format = "adblock".to_string();
+        let manager = BlocklistManager::new();
+        manager.fetch_blocklists(&config).await;
+        manager.add_domain("ads.example.com", "malware");
+
+        let matches =
+            manager.trace_matches("ads.example.com", &RuleContext::default(), |c| c == "ads");
+        let summary: Vec<(&str, &str, bool)> = matches
+            .iter()
+            .map(|m| (m.entry.as_str(), m.category.as_str(), m.enabled))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("ads.example.com", "malware", false),
+                ("*.ads.example.com", "ads", true)
+            ]
+        );
+        assert_eq!(matches[0].origins[0].source, "custom");
+        assert_eq!(matches[1].origins[0].source, "Test list");
+        assert_eq!(matches[1].origins[0].line, Some(2));
+
+        let matches =
+            manager.trace_matches("ok.ads.example.com", &RuleContext::default(), |_| true);
+        let rule = matches
+            .iter()
+            .find(|m| m.kind == TraceMatchKind::Rule)
+            .unwrap();
+        assert_eq!(
+            (rule.entry.as_str(), rule.action),
+            ("@@||ok.ads.example.com^", "allow")
+        );
+        assert_eq!(rule.origins[0].line, Some(3));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_private_sources_refused() {
+        let (url, requests) = scripted_server(vec![ok_response("a.example.com\n", "\"x\"")]).await;
+        let mut config = test_config(&url, std::path::Path::new(""));
+        config.allow_private_sources = false;
+
+        let manager = BlocklistManager::new();
+        let err = manager
+            .preview_source(&config.sources[0], &config)
+            .await
+            .unwrap_err();
+        assert!(err.contains("non-public address 127.0.0.1"));
+        assert!(requests.lock().is_empty());
+
+        for ip in [
+            "10.1.2.3",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+        assert!(is_public_ip("2606:4700::1111".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_source_validation() {
+        let mut source = test_config("https://lists.example.com/a.txt", std::path::Path::new(""))
+            .sources
+            .remove(0);
+        assert!(source.validate().is_ok());
+
+        source.url = "ftp://lists.example.com/a.txt".to_string();
+        assert!(source.validate().is_err());
+        source.url = "https://lists.example.com/a.txt".to_string();
+
+        source.format = "csv".to_string();
+        assert!(source
+            .validate()
+            .unwrap_err()
+            .contains("unsupported format"));
+        source.format = "adblock".to_string();
+
+        source.category = "Ads!".to_string();
+        assert!(source.validate().is_err());
+        source.category = "ads".to_string();
+
+        source.sha256 = Some("abc".to_string());
+        assert!(source.validate().is_err());
+    }
+
+    #[test]
+    fn test_category_priority() {
+        let manager = BlocklistManager::new();
+        manager.add_domain("overlap.example.com", "ads");
+        manager.add_domain("overlap.example.com", "tracking");
+        manager.add_domain("overlap.example.com", "malware");
+
+        assert_eq!(
+            manager.get_blocking_category("overlap.example.com"),
+            Some("malware".to_string())
+        );
+        // Equal priorities fall back to the built-in ranking, then the name
+        manager.disable_category("malware");
+        assert_eq!(
+            manager.get_blocking_category("overlap.example.com"),
+            Some("ads".to_string())
+        );
+
+        // Configured priorities override the built-in ones
+        let mut categories = HashMap::new();
+        categories.insert(
+            "tracking".to_string(),
+            CategoryConfig {
+                description: "Tracking".to_string(),
+                priority: 1,
+            },
+        );
+        manager.set_category_priorities(&categories);
+        assert_eq!(manager.category_priority("tracking"), 1);
+        assert_eq!(manager.category_priority("malware"), 1);
+        assert_eq!(manager.category_priority("unknown"), UNRANKED_PRIORITY);
+        assert_eq!(
+            manager.get_blocking_category("overlap.example.com"),
+            Some("tracking".to_string())
+        );
+        assert_eq!(
+            manager.attribute_category("overlap.example.com", Some("malware"), |c| c == "ads"),
+            Some("malware".to_string())
+        );
+    }
+
+    #[test]
+    fn test_shipped_config_attributes_threats_first() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../config/blocklist-sources.json"
+        );
+        let config = BlocklistManager::load_config(path).unwrap();
+        let manager = BlocklistManager::new();
+        manager.set_category_priorities(&config.categories);
+        for category in ["ads", "tracking"] {
+            for threat in ["malware", "phishing"] {
+                assert!(manager.category_priority(threat) < manager.category_priority(category));
+            }
+        }
+
+        manager.add_domain("overlap.example.com", "ads");
+        manager.add_domain("overlap.example.com", "malware");
+        assert_eq!(
+            manager.get_blocking_category("overlap.example.com"),
+            Some("malware".to_string())
+        );
+
+        // A configured tie still goes to the built-in ranking
+        let mut tied = config.categories.clone();
+        tied.get_mut("ads").unwrap().priority = 1;
+        manager.set_category_priorities(&tied);
+        assert_eq!(
+            manager.get_blocking_category("overlap.example.com"),
+            Some("malware".to_string())
+        );
+        assert_eq!(
+            manager.domain_categories("overlap.example.com"),
+            vec!["malware".to_string(), "ads".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_wildcard_blocking() {
+        let manager = BlocklistManager::new();
+        manager.add_domain("*.badnet.example", "malware");
+
+        assert!(manager.is_blocked("badnet.example"));
+        assert!(manager.is_blocked("c2.badnet.example"));
+        assert!(!manager.is_blocked("notbadnet.example"));
+        assert!(manager.is_blocked_by_category("c2.badnet.example", "malware"));
+        assert!(!manager.is_blocked_by_category("c2.badnet.example", "ads"));
+        assert_eq!(
+            manager.get_blocking_category("c2.badnet.example"),
+            Some("malware".to_string())
+        );
+
+        manager.remove_domain("*.badnet.example");
+        assert!(!manager.is_blocked("c2.badnet.example"));
+    }
 }
@@ -0,0 +1,327 @@
+//! dnstap encoding
+//!
+//! Builds `dnstap.Dnstap` protobuf payloads for client queries and
+//! responses and wraps them in Frame Streams data and control frames.
+//! Transport (sockets, files) is left to the caller.
+
+use crate::resolver::Protocol;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Frame Streams content type of dnstap payloads
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Largest control frame accepted from a collector
+pub const MAX_CONTROL_FRAME: usize = 512;
+
+// dnstap.proto field numbers and enum values
+const DNSTAP_IDENTITY: u32 = 1;
+const DNSTAP_VERSION: u32 = 2;
+const DNSTAP_EXTRA: u32 = 3;
+const DNSTAP_MESSAGE: u32 = 14;
+const DNSTAP_TYPE: u32 = 15;
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+
+const MESSAGE_TYPE: u32 = 1;
+const MESSAGE_SOCKET_FAMILY: u32 = 2;
+const MESSAGE_SOCKET_PROTOCOL: u32 = 3;
+const MESSAGE_QUERY_ADDRESS: u32 = 4;
+const MESSAGE_QUERY_TIME_SEC: u32 = 8;
+const MESSAGE_QUERY_TIME_NSEC: u32 = 9;
+const MESSAGE_QUERY_MESSAGE: u32 = 10;
+const MESSAGE_RESPONSE_TIME_SEC: u32 = 12;
+const MESSAGE_RESPONSE_TIME_NSEC: u32 = 13;
+const MESSAGE_RESPONSE_MESSAGE: u32 = 14;
+
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_BYTES: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+/// Frame Streams control field carrying a content type
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+/// Which side of a client exchange a message records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ClientQuery,
+    ClientResponse,
+}
+
+impl MessageType {
+    fn code(self) -> u64 {
+        match self {
+            MessageType::ClientQuery => 5,
+            MessageType::ClientResponse => 6,
+        }
+    }
+}
+
+/// One client query or response
+#[derive(Debug, Clone)]
+pub struct Message<'a> {
+    pub kind: MessageType,
+    pub protocol: Protocol,
+    pub client_ip: Option<IpAddr>,
+    pub query_time: SystemTime,
+    /// Only set on responses
+    pub response_time: Option<SystemTime>,
+    pub query_message: Option<&'a [u8]>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+/// dnstap `SocketProtocol` for a protocol; the REST endpoint has none and
+/// is marked in the payload's `extra` field instead
+fn socket_protocol(protocol: Protocol) -> Option<u64> {
+    match protocol {
+        Protocol::Udp => Some(1),
+        Protocol::Tcp => Some(2),
+        Protocol::Dot => Some(3),
+        Protocol::Doh => Some(4),
+        Protocol::Api => None,
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    put_varint(out, u64::from(field << 3 | wire_type));
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    put_key(out, field, WIRE_VARINT);
+    put_varint(out, value);
+}
+
+fn put_fixed32(out: &mut Vec<u8>, field: u32, value: u32) {
+    put_key(out, field, WIRE_FIXED32);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    put_key(out, field, WIRE_BYTES);
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn put_time(out: &mut Vec<u8>, sec_field: u32, nsec_field: u32, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_uint(out, sec_field, since_epoch.as_secs());
+    put_fixed32(out, nsec_field, since_epoch.subsec_nanos());
+}
+
+/// Encode a `dnstap.Dnstap` payload holding `message`
+pub fn encode(identity: &[u8], version: &[u8], message: &Message) -> Vec<u8> {
+    let mut inner = Vec::with_capacity(256);
+    put_uint(&mut inner, MESSAGE_TYPE, message.kind.code());
+    if let Some(ip) = message.client_ip {
+        let (family, address) = match ip {
+            IpAddr::V4(v4) => (SOCKET_FAMILY_INET, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (SOCKET_FAMILY_INET6, v6.octets().to_vec()),
+        };
+        put_uint(&mut inner, MESSAGE_SOCKET_FAMILY, family);
+        if let Some(protocol) = socket_protocol(message.protocol) {
+            put_uint(&mut inner, MESSAGE_SOCKET_PROTOCOL, protocol);
+        }
+        put_bytes(&mut inner, MESSAGE_QUERY_ADDRESS, &address);
+    } else if let Some(protocol) = socket_protocol(message.protocol) {
+        put_uint(&mut inner, MESSAGE_SOCKET_PROTOCOL, protocol);
+    }
+    put_time(
+        &mut inner,
+        MESSAGE_QUERY_TIME_SEC,
+        MESSAGE_QUERY_TIME_NSEC,
+        message.query_time,
+    );
+    if let Some(query) = message.query_message {
+        put_bytes(&mut inner, MESSAGE_QUERY_MESSAGE, query);
+    }
+    if let Some(time) = message.response_time {
+        put_time(
+            &mut inner,
+            MESSAGE_RESPONSE_TIME_SEC,
+            MESSAGE_RESPONSE_TIME_NSEC,
+            time,
+        );
+    }
+    if let Some(response) = message.response_message {
+        put_bytes(&mut inner, MESSAGE_RESPONSE_MESSAGE, response);
+    }
+
+    let mut out = Vec::with_capacity(inner.len() + identity.len() + version.len() + 16);
+    if !identity.is_empty() {
+        put_bytes(&mut out, DNSTAP_IDENTITY, identity);
+    }
+    if !version.is_empty() {
+        put_bytes(&mut out, DNSTAP_VERSION, version);
+    }
+    if message.protocol == Protocol::Api {
+        put_bytes(&mut out, DNSTAP_EXTRA, b"api");
+    }
+    put_bytes(&mut out, DNSTAP_MESSAGE, &inner);
+    put_uint(&mut out, DNSTAP_TYPE, DNSTAP_TYPE_MESSAGE);
+    out
+}
+
+// ============================================================================
+// Frame Streams
+// ============================================================================
+
+/// Frame Streams control frame types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlType {
+    Accept = 1,
+    Start = 2,
+    Stop = 3,
+    Ready = 4,
+    Finish = 5,
+}
+
+impl ControlType {
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(ControlType::Accept),
+            2 => Some(ControlType::Start),
+            3 => Some(ControlType::Stop),
+            4 => Some(ControlType::Ready),
+            5 => Some(ControlType::Finish),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded control frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Control {
+    pub kind: ControlType,
+    pub content_types: Vec<Vec<u8>>,
+}
+
+/// Length-prefixed data frame carrying `payload`
+pub fn data_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Escaped control frame, naming the dnstap content type when asked
+pub fn control_frame(kind: ControlType, with_content_type: bool) -> Vec<u8> {
+    let mut body = (kind as u32).to_be_bytes().to_vec();
+    if with_content_type {
+        body.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+
+    let mut frame = Vec::with_capacity(body.len() + 8);
+    frame.extend_from_slice(&0u32.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Parse the body of a control frame (after the escape and length)
+pub fn parse_control(body: &[u8]) -> Option<Control> {
+    let read_u32 = |pos: usize| -> Option<u32> {
+        body.get(pos..pos + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let kind = ControlType::from_code(read_u32(0)?)?;
+    let mut content_types = Vec::new();
+    let mut pos = 4;
+    while pos < body.len() {
+        let field = read_u32(pos)?;
+        let len = read_u32(pos + 4)? as usize;
+        let value = body.get(pos + 8..pos + 8 + len)?;
+        if field == CONTROL_FIELD_CONTENT_TYPE {
+            content_types.push(value.to_vec());
+        }
+        pos += 8 + len;
+    }
+    Some(Control {
+        kind,
+        content_types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_encode_client_query() {
+        let query = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l',
+            b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        ];
+
+        let message = Message {
+            kind: MessageType::ClientQuery,
+            protocol: Protocol::Doh,
+            client_ip: Some("192.168.1.20".parse().unwrap()),
+            query_time: UNIX_EPOCH + Duration::new(1_700_000_000, 5),
+            response_time: None,
+            query_message: Some(&query),
+            response_message: None,
+        };
+        let payload = encode(b"shield", b"", &message);
+
+        let mut inner = vec![0x08, 5, 0x10, 1, 0x18, 4, 0x22, 4, 192, 168, 1, 20];
+        inner.extend_from_slice(&[0x40, 0x80, 0xE2, 0xCF, 0xAA, 0x06]);
+        inner.extend_from_slice(&[0x4D, 5, 0, 0, 0]);
+        inner.push(0x52);
+        inner.push(query.len() as u8);
+        inner.extend_from_slice(&query);
+
+        let mut expected = vec![0x0A, 6];
+        expected.extend_from_slice(b"shield");
+        expected.push(0x72);
+        expected.push(inner.len() as u8);
+        expected.extend_from_slice(&inner);
+        expected.extend_from_slice(&[0x78, 1]);
+        assert_eq!(payload, expected);
+
+        // The REST endpoint has no socket protocol or wire messages
+        let api = Message {
+            protocol: Protocol::Api,
+            client_ip: None,
+            query_message: None,
+            ..message
+        };
+        let mut expected = vec![0x0A, 6];
+        expected.extend_from_slice(b"shield");
+        expected.extend_from_slice(&[0x1A, 3, b'a', b'p', b'i', 0x72, 13, 0x08, 5]);
+        expected.extend_from_slice(&[0x40, 0x80, 0xE2, 0xCF, 0xAA, 0x06]);
+        expected.extend_from_slice(&[0x4D, 5, 0, 0, 0, 0x78, 1]);
+        assert_eq!(encode(b"shield", b"", &api), expected);
+    }
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(data_frame(b"abc"), [0, 0, 0, 3, b'a', b'b', b'c']);
+
+        let ready = control_frame(ControlType::Ready, true);
+        assert_eq!(&ready[..4], &[0, 0, 0, 0]);
+        let len = u32::from_be_bytes([ready[4], ready[5], ready[6], ready[7]]) as usize;
+        assert_eq!(len, ready.len() - 8);
+        let control = parse_control(&ready[8..]).unwrap();
+        assert_eq!(control.kind, ControlType::Ready);
+        assert_eq!(control.content_types, vec![CONTENT_TYPE.to_vec()]);
+
+        let stop = control_frame(ControlType::Stop, false);
+        assert_eq!(stop, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        assert!(parse_control(&[0, 0, 0, 9]).is_none());
+        assert!(parse_control(&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 9]).is_none());
+    }
+}
@@ -0,0 +1,794 @@
+//! Reversed-label domain trie
+//!
+//! Stores domains label by label from the TLD down ("ads.example.com" is stored
+//! as com -> example -> ads), so a lookup costs one child probe per label no
+//! matter how many entries are loaded. Nodes with few children keep them in a
+//! short list and switch to a hash map as they grow. Each node carries two category bitsets:
+//! one for exact entries and one for subtree entries (`*.example.com` or
+//! `||example.com^`), which match the node itself and everything below it.
+
+use ahash::AHashMap;
+use serde::Serialize;
+
+/// Maximum number of distinct categories a trie can tag (one bit each)
+pub const MAX_CATEGORIES: usize = 64;
+
+/// Index of an interned category name
+pub type CategoryId = u8;
+
+/// Compact set of category ids
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CategorySet(u64);
+
+impl CategorySet {
+    pub const EMPTY: CategorySet = CategorySet(0);
+
+    /// Raw bit representation
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Set containing a single category
+    #[inline]
+    pub fn single(id: CategoryId) -> Self {
+        Self(1u64 << id)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline]
+    pub fn contains(&self, id: CategoryId) -> bool {
+        self.0 & (1u64 << id) != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, id: CategoryId) {
+        self.0 |= 1u64 << id;
+    }
+
+    #[inline]
+    pub fn remove(&mut self, id: CategoryId) {
+        self.0 &= !(1u64 << id);
+    }
+
+    #[inline]
+    pub fn union(&self, other: CategorySet) -> CategorySet {
+        CategorySet(self.0 | other.0)
+    }
+
+    #[inline]
+    pub fn intersection(&self, other: CategorySet) -> CategorySet {
+        CategorySet(self.0 & other.0)
+    }
+
+    #[inline]
+    pub fn intersects(&self, other: CategorySet) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Iterate category ids in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = CategoryId> {
+        let bits = self.0;
+        (0..MAX_CATEGORIES as u8).filter(move |id| bits & (1u64 << id) != 0)
+    }
+}
+
+/// How an entry matches domains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    /// Only the domain itself
+    Exact,
+    /// The domain and all of its subdomains
+    Subtree,
+}
+
+/// Result of a trie lookup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrieMatch {
+    /// Categories with an exact entry for the domain
+    pub exact: CategorySet,
+    /// Categories with a subtree entry covering the domain
+    pub subtree: CategorySet,
+}
+
+impl TrieMatch {
+    /// All matching categories regardless of match kind
+    #[inline]
+    pub fn categories(&self) -> CategorySet {
+        self.exact.union(self.subtree)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.subtree.is_empty()
+    }
+}
+
+/// Children kept in a list before switching to a hash map; most nodes in
+/// real lists have only a few
+const SMALL_CHILDREN: usize = 8;
+
+/// Child labels of a node
+#[derive(Debug, Clone)]
+enum Children {
+    /// Scanned in order, grown one slot at a time so small nodes carry no
+    /// spare capacity
+    Small(Vec<(Box<str>, Node)>),
+    /// Boxed so the small variant doesn't pay for the map's size
+    Map(Box<AHashMap<Box<str>, Node>>),
+}
+
+impl Children {
+    #[inline]
+    fn get(&self, label: &str) -> Option<&Node> {
+        match self {
+            Children::Small(list) => list.iter().find(|(l, _)| &**l == label).map(|(_, n)| n),
+            Children::Map(map) => map.get(label),
+        }
+    }
+
+    fn get_mut(&mut self, label: &str) -> Option<&mut Node> {
+        match self {
+            Children::Small(list) => list.iter_mut().find(|(l, _)| &**l == label).map(|(_, n)| n),
+            Children::Map(map) => map.get_mut(label),
+        }
+    }
+
+    fn get_or_insert(&mut self, label: &str) -> &mut Node {
+        if let Children::Small(list) = self {
+            if list.len() >= SMALL_CHILDREN && !list.iter().any(|(l, _)| &**l == label) {
+                *self = Children::Map(Box::new(std::mem::take(list).into_iter().collect()));
+            }
+        }
+        match self {
+            Children::Small(list) => {
+                let index = match list.iter().position(|(l, _)| &**l == label) {
+                    Some(index) => index,
+                    None => {
+                        list.reserve_exact(1);
+                        list.push((label.into(), Node::default()));
+                        list.len() - 1
+                    }
+                };
+                &mut list[index].1
+            }
+            Children::Map(map) => map.entry(label.into()).or_default(),
+        }
+    }
+
+    fn remove(&mut self, label: &str) {
+        match self {
+            Children::Small(list) => list.retain(|(l, _)| &**l != label),
+            Children::Map(map) => {
+                map.remove(label);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Children::Small(list) => list.is_empty(),
+            Children::Map(map) => map.is_empty(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &Node)> {
+        let (list, map) = match self {
+            Children::Small(list) => (Some(list), None),
+            Children::Map(map) => (None, Some(&**map)),
+        };
+        list.into_iter()
+            .flatten()
+            .map(|(l, n)| (&**l, n))
+            .chain(map.into_iter().flatten().map(|(l, n)| (&**l, n)))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Node {
+    /// Child labels; boxed and optional so leaves stay small
+    children: Option<Box<Children>>,
+    exact: CategorySet,
+    subtree: CategorySet,
+}
+
+impl Node {
+    #[inline]
+    fn child(&self, label: &str) -> Option<&Node> {
+        self.children.as_ref().and_then(|c| c.get(label))
+    }
+
+    fn child_mut_or_insert(&mut self, label: &str) -> &mut Node {
+        self.children
+            .get_or_insert_with(|| Box::new(Children::Small(Vec::new())))
+            .get_or_insert(label)
+    }
+
+    fn is_prunable(&self) -> bool {
+        self.exact.is_empty()
+            && self.subtree.is_empty()
+            && self.children.as_ref().is_none_or(|c| c.is_empty())
+    }
+}
+
+/// Category-tagged domain trie
+#[derive(Debug, Default, Clone)]
+pub struct DomainTrie {
+    root: Node,
+    /// Interned category names, indexed by `CategoryId`
+    categories: Vec<String>,
+    /// Number of (domain, kind, category) entries per category
+    counts: Vec<usize>,
+    /// Number of distinct patterns (domain + match kind) with at least one category
+    entries: usize,
+    /// Number of domains per combined (exact | subtree) category set
+    domain_sets: AHashMap<CategorySet, usize>,
+}
+
+impl DomainTrie {
+    /// Create an empty trie
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Normalize a domain for storage or lookup
+    #[inline]
+    fn normalize(domain: &str) -> String {
+        domain.trim().trim_end_matches('.').to_lowercase()
+    }
+
+    /// Split a pattern into its match kind and bare domain
+    ///
+    /// `*.example.com` and `||example.com^` are subtree patterns, anything else
+    /// is an exact entry.
+    pub fn parse_pattern(pattern: &str) -> (MatchKind, String) {
+        let normalized = Self::normalize(pattern);
+        if let Some(rest) = normalized.strip_prefix("*.") {
+            (MatchKind::Subtree, rest.to_string())
+        } else if let Some(rest) = normalized
+            .strip_prefix("||")
+            .map(|r| r.trim_end_matches('^'))
+        {
+            (MatchKind::Subtree, rest.to_string())
+        } else {
+            (MatchKind::Exact, normalized)
+        }
+    }
+
+    /// Look up or intern a category, returning `None` once the trie is full
+    pub fn intern_category(&mut self, name: &str) -> Option<CategoryId> {
+        if let Some(id) = self.category_id(name) {
+            return Some(id);
+        }
+        if self.categories.len() >= MAX_CATEGORIES {
+            return None;
+        }
+        self.categories.push(name.to_string());
+        self.counts.push(0);
+        Some((self.categories.len() - 1) as CategoryId)
+    }
+
+    /// Id of an already interned category
+    pub fn category_id(&self, name: &str) -> Option<CategoryId> {
+        self.categories
+            .iter()
+            .position(|c| c == name)
+            .map(|i| i as CategoryId)
+    }
+
+    /// Name of an interned category
+    pub fn category_name(&self, id: CategoryId) -> Option<&str> {
+        self.categories.get(id as usize).map(|s| s.as_str())
+    }
+
+    /// All interned category names
+    pub fn category_names(&self) -> &[String] {
+        &self.categories
+    }
+
+    /// Build a set from category names, skipping unknown ones
+    pub fn category_set<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> CategorySet {
+        let mut set = CategorySet::EMPTY;
+        for name in names {
+            if let Some(id) = self.category_id(name) {
+                set.insert(id);
+            }
+        }
+        set
+    }
+
+    /// Insert a pattern (`example.com`, `*.example.com` or `||example.com^`)
+    ///
+    /// Returns `false` if the pattern is empty, was already present for the
+    /// category, or the category could not be interned.
+    pub fn insert(&mut self, pattern: &str, category: &str) -> bool {
+        let (kind, domain) = Self::parse_pattern(pattern);
+        self.insert_domain(&domain, kind, category)
+    }
+
+    /// Insert a bare domain with an explicit match kind
+    pub fn insert_domain(&mut self, domain: &str, kind: MatchKind, category: &str) -> bool {
+        let domain = Self::normalize(domain);
+        if domain.is_empty() {
+            return false;
+        }
+        let Some(id) = self.intern_category(category) else {
+            return false;
+        };
+
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.child_mut_or_insert(label);
+        }
+
+        let before = node.exact.union(node.subtree);
+        let set = match kind {
+            MatchKind::Exact => &mut node.exact,
+            MatchKind::Subtree => &mut node.subtree,
+        };
+        if set.contains(id) {
+            return false;
+        }
+        let was_empty = set.is_empty();
+        set.insert(id);
+        let after = node.exact.union(node.subtree);
+        self.counts[id as usize] += 1;
+        if was_empty {
+            self.entries += 1;
+        }
+        self.move_domain_set(before, after);
+        true
+    }
+
+    /// Move one domain from the `before` category set to `after`
+    fn move_domain_set(&mut self, before: CategorySet, after: CategorySet) {
+        if before == after {
+            return;
+        }
+        if !before.is_empty() {
+            if let Some(n) = self.domain_sets.get_mut(&before) {
+                *n -= 1;
+                if *n == 0 {
+                    self.domain_sets.remove(&before);
+                }
+            }
+        }
+        if !after.is_empty() {
+            *self.domain_sets.entry(after).or_insert(0) += 1;
+        }
+    }
+
+    /// Remove a pattern from one category, or from all categories if `None`
+    ///
+    /// Returns the number of entries removed.
+    pub fn remove(&mut self, pattern: &str, category: Option<&str>) -> usize {
+        let (kind, domain) = Self::parse_pattern(pattern);
+        let mask = match category {
+            Some(name) => match self.category_id(name) {
+                Some(id) => CategorySet::single(id),
+                None => return 0,
+            },
+            None => CategorySet(u64::MAX),
+        };
+        let labels: Vec<&str> = domain.rsplit('.').collect();
+        let (removed, now_empty, before, after) =
+            Self::remove_rec(&mut self.root, &labels, kind, mask);
+        for id in removed.iter() {
+            self.counts[id as usize] -= 1;
+        }
+        if now_empty {
+            self.entries -= 1;
+        }
+        self.move_domain_set(before, after);
+        removed.iter().count()
+    }
+
+    /// Recursive removal that prunes empty branches on the way back up.
+    /// Returns the removed categories, whether the pattern lost its last
+    /// category, and the domain's combined category set before and after.
+    fn remove_rec(
+        node: &mut Node,
+        labels: &[&str],
+        kind: MatchKind,
+        mask: CategorySet,
+    ) -> (CategorySet, bool, CategorySet, CategorySet) {
+        let Some((label, rest)) = labels.split_first() else {
+            let before = node.exact.union(node.subtree);
+            let set = match kind {
+                MatchKind::Exact => &mut node.exact,
+                MatchKind::Subtree => &mut node.subtree,
+            };
+            let had_entry = !set.is_empty();
+            let removed = set.intersection(mask);
+            *set = CategorySet(set.0 & !mask.0);
+            let now_empty = had_entry && set.is_empty();
+            let after = node.exact.union(node.subtree);
+            return (removed, now_empty, before, after);
+        };
+
+        let unchanged = (
+            CategorySet::EMPTY,
+            false,
+            CategorySet::EMPTY,
+            CategorySet::EMPTY,
+        );
+        let Some(children) = node.children.as_mut() else {
+            return unchanged;
+        };
+        let Some(child) = children.get_mut(label) else {
+            return unchanged;
+        };
+        let result = Self::remove_rec(child, rest, kind, mask);
+        if child.is_prunable() {
+            children.remove(label);
+        }
+        result
+    }
+
+    /// Look up a domain, collecting every matching category
+    ///
+    /// Walks at most one node per label: subtree sets are accumulated along
+    /// the path and the exact set is read from the final node.
+    #[inline]
+    pub fn lookup(&self, domain: &str) -> TrieMatch {
+        let domain = domain.trim_end_matches('.');
+        let mut result = TrieMatch::default();
+        let mut node = &self.root;
+
+        for label in domain.rsplit('.') {
+            let next = if label.bytes().any(|b| b.is_ascii_uppercase()) {
+                node.child(&label.to_ascii_lowercase())
+            } else {
+                node.child(label)
+            };
+            match next {
+                Some(n) => {
+                    node = n;
+                    result.subtree = result.subtree.union(n.subtree);
+                }
+                None => return result,
+            }
+        }
+
+        result.exact = node.exact;
+        result
+    }
+
+    /// Every entry covering a domain as (pattern, kind, categories), most
+    /// specific first: the exact entry, then subtree entries from the
+    /// domain itself up to its top-level suffix
+    pub fn matching_entries(&self, domain: &str) -> Vec<(String, MatchKind, CategorySet)> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let labels: Vec<&str> = domain.split('.').collect();
+        let mut subtree = Vec::new();
+        let mut node = &self.root;
+        let mut full_match = true;
+
+        for depth in 1..=labels.len() {
+            match node.child(labels[labels.len() - depth]) {
+                Some(n) => {
+                    node = n;
+                    if !n.subtree.is_empty() {
+                        let suffix = labels[labels.len() - depth..].join(".");
+                        subtree.push((format!("*.{}", suffix), MatchKind::Subtree, n.subtree));
+                    }
+                }
+                None => {
+                    full_match = false;
+                    break;
+                }
+            }
+        }
+
+        let mut result = Vec::with_capacity(subtree.len() + 1);
+        if full_match && !node.exact.is_empty() {
+            result.push((domain.clone(), MatchKind::Exact, node.exact));
+        }
+        result.extend(subtree.into_iter().rev());
+        result
+    }
+
+    /// Whether a domain matches any entry in the given categories
+    #[inline]
+    pub fn matches_any(&self, domain: &str, categories: CategorySet) -> bool {
+        self.lookup(domain).categories().intersects(categories)
+    }
+
+    /// Whether a domain matches any entry at all
+    #[inline]
+    pub fn contains(&self, domain: &str) -> bool {
+        !self.lookup(domain).is_empty()
+    }
+
+    /// Number of distinct patterns (`example.com` and `*.example.com` count separately)
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Number of entries tagged with a category
+    pub fn category_len(&self, name: &str) -> usize {
+        self.category_id(name)
+            .map(|id| self.counts[id as usize])
+            .unwrap_or(0)
+    }
+
+    /// Number of distinct domains tagged with at least one of the given categories
+    pub fn count_matching(&self, categories: CategorySet) -> usize {
+        self.domain_sets
+            .iter()
+            .filter(|(set, _)| set.intersects(categories))
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Visit every entry as (domain, kind, categories)
+    pub fn for_each(&self, mut f: impl FnMut(&str, MatchKind, CategorySet)) {
+        let mut labels: Vec<&str> = Vec::new();
+        Self::walk(&self.root, &mut labels, &mut f);
+    }
+
+    fn walk<'a>(
+        node: &'a Node,
+        labels: &mut Vec<&'a str>,
+        f: &mut impl FnMut(&str, MatchKind, CategorySet),
+    ) {
+        if !node.exact.is_empty() || !node.subtree.is_empty() {
+            let domain = labels.iter().rev().copied().collect::<Vec<_>>().join(".");
+            if !node.exact.is_empty() {
+                f(&domain, MatchKind::Exact, node.exact);
+            }
+            if !node.subtree.is_empty() {
+                f(&domain, MatchKind::Subtree, node.subtree);
+            }
+        }
+        if let Some(children) = &node.children {
+            for (label, child) in children.iter() {
+                labels.push(label);
+                Self::walk(child, labels, f);
+                labels.pop();
+            }
+        }
+    }
+
+    /// Drop every entry (interned categories are kept)
+    pub fn clear(&mut self) {
+        self.root = Node::default();
+        self.entries = 0;
+        self.domain_sets.clear();
+        for c in self.counts.iter_mut() {
+            *c = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match() {
+        let mut trie = DomainTrie::new();
+        assert!(trie.insert("ads.example.com", "ads"));
+
+        assert!(trie.contains("ads.example.com"));
+        assert!(trie.contains("ADS.Example.com."));
+        assert!(!trie.contains("example.com"));
+        assert!(!trie.contains("x.ads.example.com"));
+        assert_eq!(trie.len(), 1);
+    }
+
+    #[test]
+    fn test_subtree_match() {
+        let mut trie = DomainTrie::new();
+        trie.insert("*.tracker.net", "tracking");
+        trie.insert("||doubleclick.net^", "ads");
+
+        assert!(trie.contains("tracker.net"));
+        assert!(trie.contains("a.b.tracker.net"));
+        assert!(trie.contains("stats.g.doubleclick.net"));
+        // Label boundaries are respected
+        assert!(!trie.contains("badtracker.net"));
+        assert!(!trie.contains("net"));
+    }
+
+    #[test]
+    fn test_category_tagging() {
+        let mut trie = DomainTrie::new();
+        trie.insert("shared.com", "ads");
+        trie.insert("shared.com", "malware");
+        trie.insert("*.shared.com", "tracking");
+
+        let ads = trie.category_id("ads").unwrap();
+        let malware = trie.category_id("malware").unwrap();
+        let tracking = trie.category_id("tracking").unwrap();
+
+        let m = trie.lookup("shared.com");
+        assert!(m.exact.contains(ads) && m.exact.contains(malware));
+        assert!(m.subtree.contains(tracking));
+
+        let m = trie.lookup("sub.shared.com");
+        assert!(m.exact.is_empty());
+        assert_eq!(m.categories(), CategorySet::single(tracking));
+
+        assert!(trie.matches_any("shared.com", trie.category_set(["malware"])));
+        assert!(!trie.matches_any("sub.shared.com", trie.category_set(["ads"])));
+        assert_eq!(trie.category_len("ads"), 1);
+        assert_eq!(trie.len(), 2);
+
+        trie.insert("*.com", "spam");
+        let entries = trie.matching_entries("Shared.com");
+        let patterns: Vec<(&str, MatchKind)> =
+            entries.iter().map(|(p, k, _)| (p.as_str(), *k)).collect();
+        assert_eq!(
+            patterns,
+            vec![
+                ("shared.com", MatchKind::Exact),
+                ("*.shared.com", MatchKind::Subtree),
+                ("*.com", MatchKind::Subtree),
+            ]
+        );
+        assert_eq!(trie.matching_entries("sub.shared.com").len(), 2);
+        assert!(trie.matching_entries("other.net").is_empty());
+    }
+
+    #[test]
+    fn test_remove_prunes() {
+        let mut trie = DomainTrie::new();
+        trie.insert("a.example.com", "ads");
+        trie.insert("a.example.com", "tracking");
+        trie.insert("*.example.com", "ads");
+
+        assert_eq!(trie.remove("a.example.com", Some("ads")), 1);
+        assert!(trie.contains("a.example.com"));
+        assert_eq!(trie.remove("a.example.com", None), 1);
+        assert_eq!(trie.remove("*.example.com", None), 1);
+
+        assert!(!trie.contains("a.example.com"));
+        assert!(trie.is_empty());
+        assert!(trie.root.is_prunable());
+        assert_eq!(trie.category_len("ads"), 0);
+    }
+
+    #[test]
+    fn test_duplicate_insert() {
+        let mut trie = DomainTrie::new();
+        assert!(trie.insert("example.com", "ads"));
+        assert!(!trie.insert("EXAMPLE.com", "ads"));
+        assert_eq!(trie.category_len("ads"), 1);
+    }
+
+    #[test]
+    fn test_for_each_and_count() {
+        let mut trie = DomainTrie::new();
+        trie.insert("a.com", "ads");
+        trie.insert("*.b.com", "malware");
+        trie.insert("c.b.com", "ads");
+
+        let mut seen = Vec::new();
+        trie.for_each(|d, k, _| seen.push((d.to_string(), k)));
+        seen.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            seen,
+            vec![
+                ("a.com".to_string(), MatchKind::Exact),
+                ("b.com".to_string(), MatchKind::Subtree),
+                ("c.b.com".to_string(), MatchKind::Exact),
+            ]
+        );
+        assert_eq!(trie.count_matching(trie.category_set(["ads"])), 2);
+
+        trie.insert("a.com", "malware");
+        trie.remove("c.b.com", None);
+        assert_eq!(trie.count_matching(trie.category_set(["ads"])), 1);
+        assert_eq!(
+            trie.count_matching(trie.category_set(["ads", "malware"])),
+            2
+        );
+    }
+
+    /// Approximate heap bytes of a hashbrown table holding `capacity` items
+    /// of `item` bytes (buckets plus one control byte each)
+    fn table_bytes(capacity: usize, item: usize) -> usize {
+        if capacity == 0 {
+            return 0;
+        }
+        let buckets = (capacity * 8 / 7).next_power_of_two().max(4);
+        buckets * (item + 1) + 16
+    }
+
+    fn node_bytes(node: &Node) -> usize {
+        node.children.as_ref().map_or(0, |children| {
+            let item = std::mem::size_of::<(Box<str>, Node)>();
+            let slots = match &**children {
+                Children::Small(list) => list.capacity() * item,
+                Children::Map(map) => {
+                    std::mem::size_of::<AHashMap<Box<str>, Node>>()
+                        + table_bytes(map.capacity(), item)
+                }
+            };
+            std::mem::size_of::<Children>()
+                + slots
+                + children
+                    .iter()
+                    .map(|(label, child)| label.len() + node_bytes(child))
+                    .sum::<usize>()
+        })
+    }
+
+    fn set_bytes(set: &std::collections::HashSet<String>) -> usize {
+        table_bytes(set.capacity(), std::mem::size_of::<String>())
+            + set.iter().map(|d| d.capacity()).sum::<usize>()
+    }
+
+    #[test]
+    fn test_memory_against_domain_sets() {
+        use std::collections::{HashMap, HashSet};
+
+        // Shaped like real lists: a few hosts per site under a handful of
+        // TLDs, with some sites listing many
+        let domains: Vec<String> = (0..50_000)
+            .map(|i| {
+                let site = if i % 5 == 0 { i / 500 } else { i / 4 };
+                format!(
+                    "{}{}.site{}.{}",
+                    ["ads", "track", "metrics", "cdn"][i % 4],
+                    i % 7,
+                    site,
+                    ["com", "net", "org"][site % 3]
+                )
+            })
+            .collect();
+
+        let mut trie = DomainTrie::new();
+        // The sets the trie replaced: per category, plus one of everything
+        let mut by_category: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut all_blocked = HashSet::new();
+        for (i, domain) in domains.iter().enumerate() {
+            let category = if i % 10 == 0 { "malware" } else { "ads" };
+            trie.insert(domain, category);
+            by_category
+                .entry(category.to_string())
+                .or_default()
+                .insert(domain.clone());
+            all_blocked.insert(domain.clone());
+        }
+        assert_eq!(trie.len(), all_blocked.len());
+
+        let trie_bytes = node_bytes(&trie.root);
+        let set_bytes =
+            set_bytes(&all_blocked) + by_category.values().map(set_bytes).sum::<usize>();
+        // The trie needs about 69% of the sets' memory (3.37 MB against
+        // 4.90 MB, roughly 79 bytes a domain against 115): a saving of
+        // about a third, mostly from storing each domain once. Allow a few
+        // points for hasher and allocator differences.
+        assert!(
+            trie_bytes * 100 <= set_bytes * 72,
+            "trie {} bytes, sets {} bytes",
+            trie_bytes,
+            set_bytes
+        );
+    }
+
+    #[test]
+    fn test_category_limit() {
+        let mut trie = DomainTrie::new();
+        for i in 0..MAX_CATEGORIES {
+            assert!(trie.insert("x.com", &format!("cat{}", i)));
+        }
+        assert!(!trie.insert("x.com", "one-too-many"));
+    }
+}
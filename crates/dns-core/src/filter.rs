@@ -1,5 +1,6 @@
 //! Domain filtering engine
 
+use crate::domain_trie::{DomainTrie, MatchKind};
 use ahash::AHashSet;
 use parking_lot::RwLock;
 use std::sync::Arc;
@@ -14,11 +15,14 @@ pub enum FilterDecision {
     Unknown,
 }
 
+/// Category tag used for entries in the engine's blocklist trie
+const BLOCKLIST_CATEGORY: &str = "blocklist";
+
 /// Domain filtering engine
 pub struct FilterEngine {
-    blocklist: Arc<RwLock<AHashSet<String>>>,
+    /// Exact and wildcard blocklist entries in a single trie
+    blocklist: Arc<RwLock<DomainTrie>>,
     allowlist: Arc<RwLock<AHashSet<String>>>,
-    wildcard_blocklist: Arc<RwLock<Vec<String>>>,
     enabled: bool,
 }
 
@@ -27,9 +31,8 @@ impl FilterEngine {
     pub fn new() -> Self {
         info!("Initializing filter engine");
         Self {
-            blocklist: Arc::new(RwLock::new(AHashSet::new())),
+            blocklist: Arc::new(RwLock::new(DomainTrie::new())),
             allowlist: Arc::new(RwLock::new(AHashSet::new())),
-            wildcard_blocklist: Arc::new(RwLock::new(Vec::new())),
             enabled: true,
         }
     }
@@ -49,53 +52,23 @@ impl FilterEngine {
             return FilterDecision::Allow;
         }
 
-        // Check exact match blocklist (O(1) lookup)
+        // Exact and wildcard entries in one walk (O(label count))
         if self.blocklist.read().contains(&domain_lower) {
             debug!("Domain {} in blocklist", domain);
             return FilterDecision::Block;
         }
 
-        // Check wildcard patterns (less common path)
-        for pattern in self.wildcard_blocklist.read().iter() {
-            if self.matches_wildcard(&domain_lower, pattern) {
-                debug!("Domain {} matches wildcard {}", domain, pattern);
-                return FilterDecision::Block;
-            }
-        }
-
         FilterDecision::Allow
     }
 
-    /// Check if domain matches a wildcard pattern
-    #[inline(always)]
-    fn matches_wildcard(&self, domain: &str, pattern: &str) -> bool {
-        if let Some(suffix) = pattern.strip_prefix("*.") {
-            domain.ends_with(suffix) || domain == &suffix[1..]
-        } else {
-            domain == pattern
-        }
-    }
-
-    /// Add domain to blocklist
+    /// Add domain to blocklist (`*.domain` blocks the domain and all subdomains)
     pub fn add_to_blocklist(&self, domain: &str) {
-        let domain_lower = domain.to_lowercase();
-        if domain_lower.starts_with("*.") {
-            self.wildcard_blocklist.write().push(domain_lower);
-        } else {
-            self.blocklist.write().insert(domain_lower);
-        }
+        self.blocklist.write().insert(domain, BLOCKLIST_CATEGORY);
     }
 
     /// Remove domain from blocklist
     pub fn remove_from_blocklist(&self, domain: &str) {
-        let domain_lower = domain.to_lowercase();
-        if domain_lower.starts_with("*.") {
-            self.wildcard_blocklist
-                .write()
-                .retain(|d| d != &domain_lower);
-        } else {
-            self.blocklist.write().remove(&domain_lower);
-        }
+        self.blocklist.write().remove(domain, None);
     }
 
     /// Add domain to allowlist
@@ -113,6 +86,18 @@ impl FilterEngine {
         self.allowlist.read().iter().cloned().collect()
     }
 
+    /// Get all blocklist entries (wildcards as `*.domain`)
+    pub fn get_blocklist(&self) -> Vec<String> {
+        let mut entries = Vec::new();
+        self.blocklist
+            .read()
+            .for_each(|domain, kind, _| match kind {
+                MatchKind::Exact => entries.push(domain.to_string()),
+                MatchKind::Subtree => entries.push(format!("*.{}", domain)),
+            });
+        entries
+    }
+
     /// Check if a domain is blocked (convenience method) - hot path
     #[inline]
     pub fn is_blocked(&self, domain: &str) -> bool {
@@ -190,7 +175,7 @@ impl FilterEngine {
 
     /// Get blocklist size
     pub fn blocklist_size(&self) -> usize {
-        self.blocklist.read().len() + self.wildcard_blocklist.read().len()
+        self.blocklist.read().len()
     }
 
     /// Get allowlist size
@@ -208,7 +193,6 @@ impl FilterEngine {
     pub fn clear(&self) {
         self.blocklist.write().clear();
         self.allowlist.write().clear();
-        self.wildcard_blocklist.write().clear();
         info!("Cleared all filter lists");
     }
 }
@@ -1,12 +1,22 @@
 //! Shield AI DNS Core Engine
 //! Ultra-fast DNS resolver with AI-powered filtering
 
+pub mod adblock;
+pub mod blocklist_cache;
 pub mod blocklist_fetcher;
 pub mod cache;
 pub mod config;
+pub mod dnstap;
+pub mod domain_trie;
 pub mod filter;
+pub mod overrides;
+pub mod prefix_map;
 pub mod resolver;
+pub mod rpz;
+pub mod schedule;
+pub mod services;
 pub mod unified_filter;
+pub mod usage;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
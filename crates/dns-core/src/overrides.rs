@@ -0,0 +1,251 @@
+//! Temporary allowlist entries and timed blocking pauses
+//!
+//! Both apply globally or to a single profile and lapse on their own:
+//! lookups ignore anything past its expiry, and [`OverrideStore::expire`]
+//! drops expired entries from memory.
+
+use ahash::AHashMap;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::Serialize;
+
+/// Domain allowed until `expires_at`, including its subdomains
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TemporaryAllow {
+    pub domain: String,
+    /// Profile the entry applies to (`None` for every profile)
+    pub profile_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Blocking switched off until `expires_at`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockingPause {
+    /// Profile that is paused (`None` pauses every profile)
+    pub profile_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Profile scope and expiry of one temporary allowlist entry
+type AllowScope = (Option<String>, DateTime<Utc>);
+
+/// Active temporary allowlist entries and pauses
+#[derive(Debug, Default)]
+pub struct OverrideStore {
+    /// Expiry per profile, keyed by domain
+    allows: RwLock<AHashMap<String, Vec<AllowScope>>>,
+    /// Expiry keyed by profile
+    pauses: RwLock<AHashMap<Option<String>, DateTime<Utc>>>,
+}
+
+impl OverrideStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow a domain until `expires_at`, replacing any existing entry
+    pub fn allow(&self, domain: &str, profile_id: Option<&str>, expires_at: DateTime<Utc>) {
+        let mut allows = self.allows.write();
+        let scopes = allows.entry(normalize(domain)).or_default();
+        scopes.retain(|(scope, _)| scope.as_deref() != profile_id);
+        scopes.push((profile_id.map(str::to_string), expires_at));
+    }
+
+    /// Remove a temporary allowlist entry
+    pub fn remove_allow(&self, domain: &str, profile_id: Option<&str>) -> bool {
+        let domain = normalize(domain);
+        let mut allows = self.allows.write();
+        let Some(scopes) = allows.get_mut(&domain) else {
+            return false;
+        };
+        let before = scopes.len();
+        scopes.retain(|(scope, _)| scope.as_deref() != profile_id);
+        let removed = scopes.len() < before;
+        if scopes.is_empty() {
+            allows.remove(&domain);
+        }
+        removed
+    }
+
+    /// Pause blocking until `expires_at`, replacing any existing pause
+    pub fn pause(&self, profile_id: Option<&str>, expires_at: DateTime<Utc>) {
+        self.pauses
+            .write()
+            .insert(profile_id.map(str::to_string), expires_at);
+    }
+
+    /// End a pause early
+    pub fn resume(&self, profile_id: Option<&str>) -> bool {
+        self.pauses
+            .write()
+            .remove(&profile_id.map(str::to_string))
+            .is_some()
+    }
+
+    /// Active entry allowing `domain` (or a parent domain) for a profile
+    ///
+    /// Global entries apply to every profile.
+    pub fn allowed_by(
+        &self,
+        domain: &str,
+        profile_id: &str,
+        now: DateTime<Utc>,
+    ) -> Option<TemporaryAllow> {
+        let allows = self.allows.read();
+        if allows.is_empty() {
+            return None;
+        }
+
+        let mut suffix = domain;
+        loop {
+            let active = allows.get(suffix).and_then(|scopes| {
+                scopes.iter().find(|(scope, expires_at)| {
+                    *expires_at > now && scope.as_deref().is_none_or(|p| p == profile_id)
+                })
+            });
+            if let Some((scope, expires_at)) = active {
+                return Some(TemporaryAllow {
+                    domain: suffix.to_string(),
+                    profile_id: scope.clone(),
+                    expires_at: *expires_at,
+                });
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => return None,
+            }
+        }
+    }
+
+    /// Active pause covering a profile, global pauses first
+    pub fn paused(&self, profile_id: &str, now: DateTime<Utc>) -> Option<BlockingPause> {
+        let pauses = self.pauses.read();
+        if pauses.is_empty() {
+            return None;
+        }
+        [None, Some(profile_id.to_string())]
+            .into_iter()
+            .find_map(|scope| {
+                pauses
+                    .get(&scope)
+                    .filter(|&&expires_at| expires_at > now)
+                    .map(|&expires_at| BlockingPause {
+                        profile_id: scope,
+                        expires_at,
+                    })
+            })
+    }
+
+    /// Unexpired allowlist entries, soonest expiry first
+    pub fn allows(&self, now: DateTime<Utc>) -> Vec<TemporaryAllow> {
+        let mut allows: Vec<TemporaryAllow> = self
+            .allows
+            .read()
+            .iter()
+            .flat_map(|(domain, scopes)| {
+                scopes
+                    .iter()
+                    .filter(|(_, expires_at)| *expires_at > now)
+                    .map(|(profile_id, expires_at)| TemporaryAllow {
+                        domain: domain.clone(),
+                        profile_id: profile_id.clone(),
+                        expires_at: *expires_at,
+                    })
+            })
+            .collect();
+        allows.sort_by(|a, b| {
+            a.expires_at
+                .cmp(&b.expires_at)
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        allows
+    }
+
+    /// Unexpired pauses, soonest expiry first
+    pub fn pauses(&self, now: DateTime<Utc>) -> Vec<BlockingPause> {
+        let mut pauses: Vec<BlockingPause> = self
+            .pauses
+            .read()
+            .iter()
+            .filter(|(_, &expires_at)| expires_at > now)
+            .map(|(profile_id, &expires_at)| BlockingPause {
+                profile_id: profile_id.clone(),
+                expires_at,
+            })
+            .collect();
+        pauses.sort_by_key(|pause| pause.expires_at);
+        pauses
+    }
+
+    /// Drop expired entries and pauses, returning how many were removed
+    pub fn expire(&self, now: DateTime<Utc>) -> usize {
+        let mut removed = 0;
+        let mut allows = self.allows.write();
+        allows.retain(|_, scopes| {
+            let before = scopes.len();
+            scopes.retain(|(_, expires_at)| *expires_at > now);
+            removed += before - scopes.len();
+            !scopes.is_empty()
+        });
+        drop(allows);
+
+        let mut pauses = self.pauses.write();
+        let before = pauses.len();
+        pauses.retain(|_, expires_at| *expires_at > now);
+        removed += before - pauses.len();
+        removed
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_temporary_allow_scopes() {
+        let store = OverrideStore::new();
+        let now = Utc::now();
+        store.allow("YouTube.com", None, now + Duration::minutes(30));
+        store.allow("games.example", Some("kid"), now + Duration::minutes(10));
+
+        let hit = store.allowed_by("www.youtube.com", "kid", now).unwrap();
+        assert_eq!((hit.domain.as_str(), hit.profile_id), ("youtube.com", None));
+        assert!(store.allowed_by("games.example", "kid", now).is_some());
+        assert!(store.allowed_by("games.example", "default", now).is_none());
+        assert!(store.allowed_by("notyoutube.com", "kid", now).is_none());
+
+        // Entries stop applying at expiry, before they are swept
+        assert!(store
+            .allowed_by("games.example", "kid", now + Duration::minutes(11))
+            .is_none());
+        assert_eq!(store.allows(now).len(), 2);
+        assert_eq!(store.allows(now)[0].domain, "games.example");
+        assert_eq!(store.expire(now + Duration::minutes(11)), 1);
+        assert!(store.remove_allow("youtube.com", None));
+        assert!(!store.remove_allow("youtube.com", None));
+    }
+
+    #[test]
+    fn test_blocking_pause() {
+        let store = OverrideStore::new();
+        let now = Utc::now();
+        assert!(store.paused("kid", now).is_none());
+
+        store.pause(Some("kid"), now + Duration::minutes(5));
+        assert!(store.paused("kid", now).is_some());
+        assert!(store.paused("default", now).is_none());
+
+        store.pause(None, now + Duration::minutes(10));
+        assert_eq!(store.paused("kid", now).unwrap().profile_id, None);
+        assert_eq!(store.pauses(now).len(), 2);
+
+        assert_eq!(store.expire(now + Duration::minutes(6)), 1);
+        assert!(store.resume(None));
+        assert!(store.paused("default", now).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! Longest-prefix-match map from IP ranges to values
+//!
+//! Used to assign profiles to networks such as a guest VLAN or a device's
+//! IPv6 /64, where exact addresses change over time. Lookups probe one hash
+//! map entry per distinct prefix length in use, longest first.
+
+use ahash::AHashMap;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+/// How two ranges relate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+    /// Same network
+    Equal,
+    /// The existing range contains the one being checked
+    Contains,
+    /// The existing range lies inside the one being checked
+    Within,
+}
+
+/// Relation between two ranges, if they overlap
+pub fn overlap(existing: &IpNet, other: &IpNet) -> Option<Overlap> {
+    let (existing, other) = (existing.trunc(), other.trunc());
+    if existing == other {
+        Some(Overlap::Equal)
+    } else if existing.contains(&other) {
+        Some(Overlap::Contains)
+    } else if other.contains(&existing) {
+        Some(Overlap::Within)
+    } else {
+        None
+    }
+}
+
+/// Map from IP ranges to values with longest-prefix-match lookup
+#[derive(Debug, Clone)]
+pub struct PrefixMap<T> {
+    entries: AHashMap<IpNet, T>,
+    /// (is IPv6, prefix length) pairs in use
+    lengths: BTreeSet<(bool, u8)>,
+}
+
+impl<T> Default for PrefixMap<T> {
+    fn default() -> Self {
+        Self {
+            entries: AHashMap::new(),
+            lengths: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T> PrefixMap<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a range, replacing and returning any value for the same network
+    pub fn insert(&mut self, net: IpNet, value: T) -> Option<T> {
+        let net = net.trunc();
+        self.lengths.insert(Self::length_key(&net));
+        self.entries.insert(net, value)
+    }
+
+    pub fn remove(&mut self, net: &IpNet) -> Option<T> {
+        let net = net.trunc();
+        let removed = self.entries.remove(&net)?;
+        let key = Self::length_key(&net);
+        if !self.entries.keys().any(|n| Self::length_key(n) == key) {
+            self.lengths.remove(&key);
+        }
+        Some(removed)
+    }
+
+    /// Value for the most specific range containing `ip`
+    ///
+    /// IPv4-mapped IPv6 addresses (from dual-stack sockets) match IPv4 ranges.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<(IpNet, &T)> {
+        let ip = ip.to_canonical();
+        let v6 = ip.is_ipv6();
+        self.lengths
+            .range((v6, 0)..=(v6, u8::MAX))
+            .rev()
+            .find_map(|&(_, len)| {
+                let net = IpNet::new(ip, len).ok()?.trunc();
+                self.entries.get(&net).map(|value| (net, value))
+            })
+    }
+
+    pub fn get(&self, net: &IpNet) -> Option<&T> {
+        self.entries.get(&net.trunc())
+    }
+
+    /// Ranges overlapping `net`, with how each relates to it
+    pub fn overlapping(&self, net: &IpNet) -> Vec<(IpNet, Overlap, &T)> {
+        let mut found: Vec<(IpNet, Overlap, &T)> = self
+            .entries
+            .iter()
+            .filter_map(|(existing, value)| overlap(existing, net).map(|o| (*existing, o, value)))
+            .collect();
+        found.sort_by_key(|(n, _, _)| (n.prefix_len(), n.network()));
+        found
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&IpNet, &T)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn length_key(net: &IpNet) -> (bool, u8) {
+        (matches!(net, IpNet::V6(_)), net.prefix_len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut map = PrefixMap::new();
+        map.insert("10.0.0.0/8".parse().unwrap(), "lan");
+        map.insert("10.0.5.0/24".parse().unwrap(), "guest");
+        // Host bits are dropped
+        map.insert("2001:db8:1:2::99/64".parse().unwrap(), "tablet");
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(map.lookup(&ip("10.0.5.7")).map(|(_, v)| *v), Some("guest"));
+        assert_eq!(map.lookup(&ip("10.1.2.3")).map(|(_, v)| *v), Some("lan"));
+        assert!(map.lookup(&ip("192.168.1.1")).is_none());
+        assert_eq!(
+            map.lookup(&ip("::ffff:10.0.5.7")).map(|(_, v)| *v),
+            Some("guest")
+        );
+        let (net, value) = map.lookup(&ip("2001:db8:1:2:abcd::1")).unwrap();
+        assert_eq!(
+            (net.to_string().as_str(), *value),
+            ("2001:db8:1:2::/64", "tablet")
+        );
+        assert!(map.lookup(&ip("2001:db8:1:3::1")).is_none());
+
+        let overlaps: Vec<(String, Overlap)> = map
+            .overlapping(&"10.0.5.128/25".parse().unwrap())
+            .into_iter()
+            .map(|(n, o, _)| (n.to_string(), o))
+            .collect();
+        assert_eq!(
+            overlaps,
+            vec![
+                ("10.0.0.0/8".to_string(), Overlap::Contains),
+                ("10.0.5.0/24".to_string(), Overlap::Contains)
+            ]
+        );
+        assert_eq!(
+            map.overlapping(&"10.0.0.0/8".parse().unwrap())[1].1,
+            Overlap::Within
+        );
+
+        assert_eq!(map.remove(&"10.0.5.0/24".parse().unwrap()), Some("guest"));
+        assert_eq!(map.lookup(&ip("10.0.5.7")).map(|(_, v)| *v), Some("lan"));
+    }
+}
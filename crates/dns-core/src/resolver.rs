@@ -2,15 +2,107 @@
 
 use anyhow::Result;
 use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
 use hickory_resolver::TokioAsyncResolver;
+use serde::Serialize;
 use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info};
 
 use crate::cache::{CacheKey, DNSCache};
 use crate::filter::{FilterDecision, FilterEngine};
 
+/// Name of the upstream resolver group queries are forwarded to
+pub const UPSTREAM: &str = "cloudflare";
+
+/// Response codes recorded in the query log
+pub const RCODE_NOERROR: u16 = 0;
+pub const RCODE_SERVFAIL: u16 = 2;
+pub const RCODE_NXDOMAIN: u16 = 3;
+
+/// Transport a query arrived over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    /// DNS-over-TLS
+    Dot,
+    /// DNS-over-HTTPS
+    Doh,
+    /// REST resolve endpoint
+    Api,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+            Protocol::Dot => "dot",
+            Protocol::Doh => "doh",
+            Protocol::Api => "api",
+        }
+    }
+}
+
+/// DNSSEC validation state of an answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnssecStatus {
+    /// Signatures validated
+    Secure,
+    /// Zone is provably unsigned
+    Insecure,
+    /// Signatures failed validation
+    Bogus,
+    /// Answer was not validated (validation is off)
+    Unvalidated,
+}
+
+impl DnssecStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnssecStatus::Secure => "secure",
+            DnssecStatus::Insecure => "insecure",
+            DnssecStatus::Bogus => "bogus",
+            DnssecStatus::Unvalidated => "unvalidated",
+        }
+    }
+}
+
+/// Outcome of a lookup, with the details the query log records
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub ips: Vec<IpAddr>,
+    /// Response code (0 = NOERROR, 2 = SERVFAIL, 3 = NXDOMAIN)
+    pub rcode: u16,
+    /// Answered from the local cache
+    pub cached: bool,
+    /// Upstream that answered; `None` for cache hits and filtered names
+    pub upstream: Option<String>,
+    pub dnssec: DnssecStatus,
+    /// Why the lookup failed
+    pub error: Option<String>,
+    /// Time spent answering, including the cache lookup
+    pub elapsed: Duration,
+}
+
+impl Resolution {
+    fn answered(ips: Vec<IpAddr>, cached: bool) -> Self {
+        Self {
+            ips,
+            rcode: RCODE_NOERROR,
+            cached,
+            upstream: (!cached).then(|| UPSTREAM.to_string()),
+            dnssec: DnssecStatus::Unvalidated,
+            error: None,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
 /// DNS Resolver with caching and filtering
 pub struct Resolver {
     inner: Arc<TokioAsyncResolver>,
@@ -39,11 +131,30 @@ impl Resolver {
 
     /// Resolve a domain name to IP addresses
     pub async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        let resolution = self.lookup(domain).await;
+        match resolution.error {
+            Some(e) => Err(anyhow::anyhow!(e)),
+            None => Ok(resolution.ips),
+        }
+    }
+
+    /// Resolve a domain name, reporting the response code, cache use and upstream
+    pub async fn lookup(&self, domain: &str) -> Resolution {
+        let started = Instant::now();
+        let mut resolution = self.answer(domain).await;
+        resolution.elapsed = started.elapsed();
+        resolution
+    }
+
+    async fn answer(&self, domain: &str) -> Resolution {
         // Check filter first
         match self.filter.check(domain) {
             FilterDecision::Block => {
                 debug!("Domain {} blocked by filter", domain);
-                return Ok(vec![]);
+                return Resolution {
+                    upstream: None,
+                    ..Resolution::answered(vec![], false)
+                };
             }
             FilterDecision::Allow | FilterDecision::Unknown => {}
         }
@@ -53,7 +164,7 @@ impl Resolver {
         if let Some(cached) = self.cache.get(&cache_key) {
             debug!("Cache hit for {}", domain);
             let ips: Vec<IpAddr> = cached.iter().filter_map(|s| s.parse().ok()).collect();
-            return Ok(ips);
+            return Resolution::answered(ips, true);
         }
 
         // Perform actual DNS lookup
@@ -67,11 +178,21 @@ impl Resolver {
                 self.cache.insert(cache_key, records, None);
 
                 info!("Resolved {} to {:?}", domain, ips);
-                Ok(ips)
+                Resolution::answered(ips, false)
             }
             Err(e) => {
                 error!("Failed to resolve {}: {}", domain, e);
-                Err(e.into())
+                let rcode = match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+                        u16::from(*response_code)
+                    }
+                    _ => RCODE_SERVFAIL,
+                };
+                Resolution {
+                    rcode,
+                    error: Some(e.to_string()),
+                    ..Resolution::answered(vec![], false)
+                }
             }
         }
     }
@@ -91,6 +212,11 @@ impl Resolver {
         self.cache.hit_rate()
     }
 
+    /// Number of cached names
+    pub fn cache_size(&self) -> usize {
+        self.cache.len()
+    }
+
     /// Clear the DNS cache
     pub fn clear_cache(&self) {
         self.cache.clear();
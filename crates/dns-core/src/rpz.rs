@@ -0,0 +1,614 @@
+//! Response Policy Zone (RPZ) import and export
+//!
+//! Parses QNAME-trigger policies from RPZ zone files and writes the effective
+//! filter policy back out as a zone that BIND, Unbound or Knot can load.
+//!
+//! Supported actions (as CNAME targets or local data):
+//!
+//! | Record                    | Action        |
+//! |---------------------------|---------------|
+//! | `CNAME .`                 | NXDOMAIN      |
+//! | `CNAME *.`                | NODATA        |
+//! | `CNAME rpz-passthru.`     | PASSTHRU      |
+//! | `CNAME rpz-drop.`         | DROP          |
+//! | `A`, `AAAA`, `TXT`, `CNAME name.` | local data |
+//!
+//! IP, NSDNAME, NSIP and client-IP triggers are skipped. A `*.example.com`
+//! trigger covers subdomains only, as in BIND; the apex needs its own record.
+
+use crate::adblock::qtype_from_name;
+use ahash::AHashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Record returned for a local-data policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LocalRecord {
+    /// Numeric record type (1 = A, 28 = AAAA, 5 = CNAME, 16 = TXT)
+    pub record_type: u16,
+    pub ttl: u32,
+    /// Presentation-format record data
+    pub data: String,
+}
+
+/// Policy action attached to a trigger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "action", content = "records")]
+pub enum RpzAction {
+    Nxdomain,
+    Nodata,
+    Passthru,
+    Drop,
+    LocalData(Vec<LocalRecord>),
+}
+
+/// A QNAME trigger and its action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpzRule {
+    /// Trigger domain, relative to the zone origin and lowercased
+    pub domain: String,
+    /// `*.domain` trigger, matching subdomains but not the domain itself
+    pub wildcard: bool,
+    pub action: RpzAction,
+    /// 1-based line of the (first) record in the zone file
+    pub line: usize,
+}
+
+const DEFAULT_TTL: u32 = 300;
+
+/// Result of parsing a zone
+#[derive(Debug, Default)]
+pub struct RpzZone {
+    pub rules: Vec<RpzRule>,
+    /// Records that weren't QNAME triggers or had unsupported actions
+    pub skipped: usize,
+}
+
+/// Parse an RPZ zone file
+pub fn parse_zone(content: &str) -> RpzZone {
+    let mut zone = RpzZone::default();
+    let mut origin = String::new();
+    let mut default_ttl = DEFAULT_TTL;
+    let mut last_owner: Option<String> = None;
+    // Local data records for the same owner are merged into one rule
+    let mut local: AHashMap<(String, bool), usize> = AHashMap::new();
+
+    let mut lines = content.lines().enumerate();
+    while let Some((index, raw)) = lines.next() {
+        let line_number = index + 1;
+        let mut line = strip_comment(raw).to_string();
+
+        // Multi-line records (SOA) are wrapped in parentheses
+        if line.contains('(') && !line.contains(')') {
+            for (_, next) in lines.by_ref() {
+                line.push(' ');
+                line.push_str(strip_comment(next));
+                if next.contains(')') {
+                    break;
+                }
+            }
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.trim().strip_prefix("$ORIGIN") {
+            origin = rest.trim().trim_end_matches('.').to_lowercase();
+            continue;
+        }
+        if let Some(rest) = line.trim().strip_prefix("$TTL") {
+            default_ttl = rest.trim().parse().unwrap_or(DEFAULT_TTL);
+            continue;
+        }
+        if line.trim_start().starts_with('$') {
+            continue;
+        }
+
+        // A record starting with whitespace reuses the previous owner
+        let owner_inherited = line.starts_with(' ') || line.starts_with('\t');
+        let mut fields = line.split_whitespace().peekable();
+        let owner = if owner_inherited {
+            match &last_owner {
+                Some(o) => o.clone(),
+                None => continue,
+            }
+        } else {
+            match fields.next() {
+                Some(o) => o.to_string(),
+                None => continue,
+            }
+        };
+        last_owner = Some(owner.clone());
+
+        // Optional TTL and class, in either order
+        let mut ttl = default_ttl;
+        while let Some(field) = fields.peek() {
+            if let Ok(t) = field.parse::<u32>() {
+                ttl = t;
+                fields.next();
+            } else if field.eq_ignore_ascii_case("IN") {
+                fields.next();
+            } else {
+                break;
+            }
+        }
+
+        let Some(rtype) = fields.next().map(|t| t.to_ascii_uppercase()) else {
+            continue;
+        };
+        let rdata: Vec<&str> = fields.collect();
+
+        if rtype == "SOA" || rtype == "NS" {
+            continue;
+        }
+
+        let Some((domain, wildcard)) = trigger_domain(&owner, &origin) else {
+            zone.skipped += 1;
+            continue;
+        };
+
+        let action = match (rtype.as_str(), rdata.first().copied()) {
+            ("CNAME", Some(".")) => RpzAction::Nxdomain,
+            ("CNAME", Some("*.")) => RpzAction::Nodata,
+            ("CNAME", Some(t)) if t.eq_ignore_ascii_case("rpz-passthru.") => RpzAction::Passthru,
+            ("CNAME", Some(t)) if t.eq_ignore_ascii_case("rpz-drop.") => RpzAction::Drop,
+            ("CNAME", Some(t)) if t.to_ascii_lowercase().starts_with("rpz-") => {
+                // rpz-tcp-only. and friends have no DoH equivalent
+                zone.skipped += 1;
+                continue;
+            }
+            (_, Some(_)) => match qtype_from_name(&rtype) {
+                Some(record_type) => RpzAction::LocalData(vec![LocalRecord {
+                    record_type,
+                    ttl,
+                    data: rdata.join(" "),
+                }]),
+                None => {
+                    zone.skipped += 1;
+                    continue;
+                }
+            },
+            _ => {
+                zone.skipped += 1;
+                continue;
+            }
+        };
+
+        if let RpzAction::LocalData(records) = action {
+            let key = (domain.clone(), wildcard);
+            if let Some(&idx) = local.get(&key) {
+                if let RpzAction::LocalData(existing) = &mut zone.rules[idx].action {
+                    existing.extend(records);
+                }
+                continue;
+            }
+            local.insert(key, zone.rules.len());
+            zone.rules.push(RpzRule {
+                domain,
+                wildcard,
+                action: RpzAction::LocalData(records),
+                line: line_number,
+            });
+            continue;
+        }
+
+        zone.rules.push(RpzRule {
+            domain,
+            wildcard,
+            action,
+            line: line_number,
+        });
+    }
+
+    zone
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Resolve an owner name to a QNAME trigger, or `None` for other trigger types
+fn trigger_domain(owner: &str, origin: &str) -> Option<(String, bool)> {
+    let owner = owner.to_lowercase();
+    let name = if let Some(absolute) = owner.strip_suffix('.') {
+        // Absolute names must sit under the origin
+        if origin.is_empty() {
+            absolute.to_string()
+        } else {
+            absolute
+                .strip_suffix(origin)
+                .and_then(|n| n.strip_suffix('.'))?
+                .to_string()
+        }
+    } else {
+        owner
+    };
+
+    if name.is_empty() || name == "@" {
+        return None;
+    }
+    // rpz-ip, rpz-nsdname, rpz-nsip and rpz-client-ip triggers
+    if name.split('.').any(|label| label.starts_with("rpz-")) {
+        return None;
+    }
+
+    match name.strip_prefix("*.") {
+        Some(rest) if !rest.is_empty() => Some((rest.to_string(), true)),
+        Some(_) => None,
+        None => Some((name, false)),
+    }
+}
+
+/// Policy entry that needs a non-NXDOMAIN answer
+#[derive(Debug, Clone)]
+struct RewriteEntry {
+    wildcard: bool,
+    category: Arc<str>,
+    action: RpzAction,
+}
+
+/// NODATA and local-data policies, looked up by domain and parent suffixes
+#[derive(Debug, Default, Clone)]
+pub struct RewriteTable {
+    entries: AHashMap<String, Vec<RewriteEntry>>,
+}
+
+impl RewriteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rewrite for a category; `wildcard` rewrites cover subdomains only
+    pub fn insert(&mut self, domain: &str, wildcard: bool, action: RpzAction, category: &str) {
+        self.entries
+            .entry(domain.to_lowercase())
+            .or_default()
+            .push(RewriteEntry {
+                wildcard,
+                category: Arc::from(category),
+                action,
+            });
+    }
+
+    /// Find the most specific rewrite for a domain among enabled categories
+    pub fn lookup(
+        &self,
+        domain: &str,
+        category_enabled: impl Fn(&str) -> bool,
+    ) -> Option<(&str, &RpzAction)> {
+        let mut suffix = domain;
+        let mut exact = true;
+        loop {
+            if let Some(entries) = self.entries.get(suffix) {
+                let hit = entries
+                    .iter()
+                    .find(|e| e.wildcard != exact && category_enabled(&e.category));
+                if let Some(e) = hit {
+                    return Some((&e.category, &e.action));
+                }
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => return None,
+            }
+            exact = false;
+        }
+    }
+
+    /// Every rewrite covering a domain as (pattern, category, action), most
+    /// specific first, in any category
+    pub fn matching(&self, domain: &str) -> Vec<(String, &str, &RpzAction)> {
+        let mut result = Vec::new();
+        let mut suffix = domain;
+        let mut exact = true;
+        loop {
+            if let Some(entries) = self.entries.get(suffix) {
+                for e in entries.iter().filter(|e| e.wildcard != exact) {
+                    let pattern = if e.wildcard {
+                        format!("*.{}", suffix)
+                    } else {
+                        suffix.to_string()
+                    };
+                    result.push((pattern, &*e.category, &e.action));
+                }
+            }
+            match suffix.find('.') {
+                Some(pos) => suffix = &suffix[pos + 1..],
+                None => return result,
+            }
+            exact = false;
+        }
+    }
+
+    /// Visit every rewrite as (domain, wildcard, category, action)
+    pub fn for_each(&self, mut f: impl FnMut(&str, bool, &str, &RpzAction)) {
+        for (domain, entries) in &self.entries {
+            for e in entries {
+                f(domain, e.wildcard, &e.category, &e.action);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(|v| v.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Builds an RPZ zone file
+///
+/// An owner name gets one action: policies are added in the order the
+/// filter evaluates them and the first one for an owner wins.
+pub struct RpzWriter {
+    origin: String,
+    comments: Vec<String>,
+    policies: BTreeMap<String, RpzAction>,
+}
+
+impl RpzWriter {
+    /// Start a zone for `origin` (e.g. `rpz.shield.local`)
+    pub fn new(origin: &str) -> Self {
+        Self {
+            origin: origin.trim_end_matches('.').to_lowercase(),
+            comments: Vec::new(),
+            policies: BTreeMap::new(),
+        }
+    }
+
+    /// Add a comment line
+    pub fn comment(&mut self, text: &str) {
+        self.comments.push(text.to_string());
+    }
+
+    /// Add a policy for one owner name, a domain or `*.domain`, unless the
+    /// owner already has one
+    pub fn add(&mut self, owner: &str, action: &RpzAction) {
+        self.policies
+            .entry(owner.to_string())
+            .or_insert_with(|| action.clone());
+    }
+
+    /// Add a policy for a domain and all of its subdomains, which takes
+    /// both the apex and the wildcard trigger
+    pub fn add_subtree(&mut self, domain: &str, action: &RpzAction) {
+        self.add(domain, action);
+        self.add(&format!("*.{}", domain), action);
+    }
+
+    /// Number of policy records written
+    pub fn len(&self) -> usize {
+        self.policies
+            .values()
+            .map(|action| match action {
+                RpzAction::LocalData(records) => records.len(),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Render the zone with SOA and NS records
+    pub fn finish(self, serial: u32) -> String {
+        let mut out = String::with_capacity(self.policies.len() * 48 + 256);
+        let _ = writeln!(out, "$ORIGIN {}.", self.origin);
+        let _ = writeln!(out, "$TTL {}", DEFAULT_TTL);
+        let _ = writeln!(
+            out,
+            "@ SOA localhost. hostmaster.localhost. ({} 3600 600 86400 {})",
+            serial, DEFAULT_TTL
+        );
+        let _ = writeln!(out, "@ NS localhost.");
+        for text in &self.comments {
+            let _ = writeln!(out, "; {}", text);
+        }
+        for (owner, action) in &self.policies {
+            let target = match action {
+                RpzAction::Nxdomain => ".",
+                RpzAction::Nodata => "*.",
+                RpzAction::Passthru => "rpz-passthru.",
+                RpzAction::Drop => "rpz-drop.",
+                RpzAction::LocalData(records) => {
+                    for record in records {
+                        let _ = writeln!(
+                            out,
+                            "{} {} IN {} {}",
+                            owner,
+                            record.ttl,
+                            record_type_name(record.record_type),
+                            record.data
+                        );
+                    }
+                    continue;
+                }
+            };
+            let _ = writeln!(out, "{} CNAME {}", owner, target);
+        }
+        out
+    }
+}
+
+fn record_type_name(record_type: u16) -> String {
+    match record_type {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"
+$TTL 60
+$ORIGIN rpz.example.net.
+@ SOA ns1.example.net. hostmaster.example.net. (
+        2024010101 ; serial
+        3600 600 86400 60 )
+  NS ns1.example.net.
+
+; QNAME triggers
+malware.example.com     CNAME .
+*.malware.example.com   CNAME .
+tracker.example.org     CNAME *.
+good.malware.example.com CNAME rpz-passthru.
+c2.example.com.rpz.example.net. CNAME rpz-drop.
+portal.example.com  120 IN A 10.0.0.10
+                    IN AAAA fd00::10
+walled.example.com  CNAME portal.example.com.
+note.example.com    TXT "blocked by policy; see admin"
+
+; Not QNAME triggers
+32.1.2.0.192.rpz-ip      CNAME .
+ns.evil.example.rpz-nsdname CNAME .
+slow.example.com CNAME rpz-tcp-only.
+"#;
+
+    #[test]
+    fn test_parse_zone() {
+        let zone = parse_zone(ZONE);
+
+        let find = |domain: &str, wildcard: bool| {
+            zone.rules
+                .iter()
+                .find(|r| r.domain == domain && r.wildcard == wildcard)
+                .map(|r| r.action.clone())
+        };
+
+        assert_eq!(
+            find("malware.example.com", false),
+            Some(RpzAction::Nxdomain)
+        );
+        assert_eq!(find("malware.example.com", true), Some(RpzAction::Nxdomain));
+        assert_eq!(find("tracker.example.org", false), Some(RpzAction::Nodata));
+        assert_eq!(
+            find("good.malware.example.com", false),
+            Some(RpzAction::Passthru)
+        );
+        assert_eq!(find("c2.example.com", false), Some(RpzAction::Drop));
+        assert_eq!(
+            find("portal.example.com", false),
+            Some(RpzAction::LocalData(vec![
+                LocalRecord {
+                    record_type: 1,
+                    ttl: 120,
+                    data: "10.0.0.10".to_string()
+                },
+                LocalRecord {
+                    record_type: 28,
+                    ttl: 60,
+                    data: "fd00::10".to_string()
+                },
+            ]))
+        );
+        assert_eq!(
+            find("walled.example.com", false),
+            Some(RpzAction::LocalData(vec![LocalRecord {
+                record_type: 5,
+                ttl: 60,
+                data: "portal.example.com.".to_string()
+            }]))
+        );
+        assert!(matches!(
+            find("note.example.com", false),
+            Some(RpzAction::LocalData(ref r)) if r[0].data == "\"blocked by policy; see admin\""
+        ));
+        assert_eq!(zone.skipped, 3);
+
+        // Rules remember the line they were declared on
+        let line = |domain: &str| {
+            zone.rules
+                .iter()
+                .find(|r| r.domain == domain)
+                .map(|r| r.line)
+        };
+        let first = line("malware.example.com").unwrap();
+        assert_eq!(
+            ZONE.lines()
+                .nth(first - 1)
+                .map(|l| l.starts_with("malware.example.com")),
+            Some(true)
+        );
+        assert_eq!(line("tracker.example.org"), Some(first + 2));
+        assert_eq!(line("walled.example.com"), Some(first + 7));
+    }
+
+    #[test]
+    fn test_rewrite_table_lookup() {
+        let mut table = RewriteTable::new();
+        table.insert("portal.example.com", false, RpzAction::Nodata, "security");
+        table.insert("example.org", true, RpzAction::Nodata, "ads");
+
+        assert!(table.lookup("portal.example.com", |_| true).is_some());
+        assert!(table.lookup("x.portal.example.com", |_| true).is_none());
+        assert_eq!(
+            table.lookup("a.b.example.org", |_| true).map(|(c, _)| c),
+            Some("ads")
+        );
+        // A wildcard doesn't cover its apex
+        assert!(table.lookup("example.org", |_| true).is_none());
+        assert!(table.lookup("a.b.example.org", |c| c != "ads").is_none());
+
+        let matching = table.matching("a.b.example.org");
+        assert_eq!(matching.len(), 1);
+        assert_eq!(
+            (matching[0].0.as_str(), matching[0].1),
+            ("*.example.org", "ads")
+        );
+        assert!(table.matching("x.portal.example.com").is_empty());
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut writer = RpzWriter::new("rpz.shield.local.");
+        writer.add_subtree("ads.example.com", &RpzAction::Nxdomain);
+        writer.add("ok.ads.example.com", &RpzAction::Passthru);
+        // Already has a policy, so this one is dropped
+        writer.add("ok.ads.example.com", &RpzAction::Nxdomain);
+        writer.add("*.tracker.example.org", &RpzAction::Nodata);
+        writer.add(
+            "portal.example.com",
+            &RpzAction::LocalData(vec![LocalRecord {
+                record_type: 1,
+                ttl: 300,
+                data: "10.0.0.1".to_string(),
+            }]),
+        );
+        assert_eq!(writer.len(), 5);
+
+        let text = writer.finish(1);
+        assert!(text.starts_with("$ORIGIN rpz.shield.local.\n"));
+        assert!(text.contains("*.ads.example.com CNAME .\n"));
+        assert!(!text.contains("\ntracker.example.org"));
+        assert!(text.contains("ok.ads.example.com CNAME rpz-passthru.\n"));
+        assert!(!text.contains("ok.ads.example.com CNAME .\n"));
+
+        let zone = parse_zone(&text);
+        assert_eq!(zone.rules.len(), 5);
+        assert_eq!(zone.skipped, 0);
+    }
+}
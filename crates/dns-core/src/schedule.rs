@@ -0,0 +1,438 @@
+//! Time-based profile rules
+//!
+//! A [`TimeRule`] allows or blocks domain patterns, services and categories
+//! during a weekly time window, evaluated in the profile's timezone. When
+//! several active rules cover a query, [`resolve`] picks one: the highest
+//! `priority` first, then the most specific target (domain pattern, then
+//! service, then category), then block over allow, then list order.
+
+use crate::services::{self, Service};
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Weekday};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use uuid::Uuid;
+
+/// Action to take when a rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Block,
+    Allow,
+    Monitor,
+}
+
+/// Which day the part of a window after midnight belongs to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overnight {
+    /// The day the window started on, so 21:00-07:00 on Sunday covers
+    /// Sunday night through Monday morning
+    #[default]
+    StartDay,
+    /// The day it falls on: each listed day is covered up to the end time
+    /// and again from the start time. Rules saved before this field existed
+    /// keep this meaning.
+    SameDay,
+}
+
+/// Time-based rule for controlling access
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeRule {
+    /// Generated when a client submits a rule without one
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    pub name: String,
+    pub days_of_week: Vec<Weekday>,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    /// Only matters for windows crossing midnight
+    #[serde(default)]
+    pub overnight: Overnight,
+    pub action: RuleAction,
+    #[serde(default)]
+    pub domain_patterns: Vec<String>,
+    /// Blocklist categories or service groups (e.g. "social", "gaming")
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Service IDs from the catalog (e.g. "youtube")
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Higher priority wins when active rules overlap
+    #[serde(default)]
+    pub priority: i32,
+    /// Parent levels the rule was inherited across; 0 for a profile's own
+    /// rules. Nearer rules win ties before block beats allow.
+    #[serde(skip)]
+    pub depth: u32,
+}
+
+/// What part of a rule matched a query
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum RuleTarget {
+    Domain(String),
+    Service(String),
+    Category(String),
+}
+
+impl RuleTarget {
+    fn specificity(&self) -> u8 {
+        match self {
+            RuleTarget::Domain(_) => 2,
+            RuleTarget::Service(_) => 1,
+            RuleTarget::Category(_) => 0,
+        }
+    }
+}
+
+/// Active rule deciding a query
+#[derive(Debug, Clone)]
+pub struct ScheduleMatch<'a> {
+    pub rule: &'a TimeRule,
+    pub target: RuleTarget,
+}
+
+impl TimeRule {
+    pub fn new(
+        name: String,
+        days: Vec<Weekday>,
+        start: NaiveTime,
+        end: NaiveTime,
+        action: RuleAction,
+        patterns: Vec<String>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            days_of_week: days,
+            start_time: start,
+            end_time: end,
+            overnight: Overnight::StartDay,
+            action,
+            domain_patterns: patterns,
+            categories: Vec::new(),
+            services: Vec::new(),
+            priority: 0,
+            depth: 0,
+        }
+    }
+
+    /// Also cover these categories or service groups
+    pub fn with_categories(mut self, categories: Vec<String>) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    /// Also cover these services
+    pub fn with_services(mut self, services: Vec<String>) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Whether the rule names anything to match
+    pub fn has_targets(&self) -> bool {
+        !self.domain_patterns.is_empty() || !self.categories.is_empty() || !self.services.is_empty()
+    }
+
+    /// Parse a rule from a profile's saved JSON
+    ///
+    /// Rules saved before [`Overnight`] existed were evaluated with the
+    /// same-day meaning, so a stored rule without the field keeps it.
+    pub fn from_stored(mut value: serde_json::Value) -> serde_json::Result<Self> {
+        if let Some(rule) = value.as_object_mut() {
+            rule.entry("overnight")
+                .or_insert_with(|| serde_json::json!(Overnight::SameDay));
+        }
+        serde_json::from_value(value)
+    }
+
+    /// Whether the window covers `time`; windows ending before they start
+    /// wrap past midnight, and `overnight` says which day the early hours
+    /// belong to
+    pub fn is_active_at<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let current = time.time();
+        if self.start_time <= self.end_time {
+            self.days_of_week.contains(&time.weekday())
+                && current >= self.start_time
+                && current <= self.end_time
+        } else if current >= self.start_time {
+            self.days_of_week.contains(&time.weekday())
+        } else {
+            let day = match self.overnight {
+                Overnight::StartDay => time.weekday().pred(),
+                Overnight::SameDay => time.weekday(),
+            };
+            current <= self.end_time && self.days_of_week.contains(&day)
+        }
+    }
+
+    /// Whether a pattern matches the domain; `*` matches any run of characters
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        self.matching_pattern(domain).is_some()
+    }
+
+    fn matching_pattern(&self, domain: &str) -> Option<&str> {
+        self.domain_patterns
+            .iter()
+            .find(|pattern| {
+                if pattern.contains('*') {
+                    glob_match(&pattern.to_lowercase(), domain)
+                } else {
+                    domain.eq_ignore_ascii_case(pattern)
+                }
+            })
+            .map(String::as_str)
+    }
+
+    /// Most specific target covering a query
+    ///
+    /// `categories` are the blocklist categories listing the domain; the
+    /// service's group counts as a category too.
+    pub fn target_for(
+        &self,
+        domain: &str,
+        service: Option<&Service>,
+        categories: &[String],
+    ) -> Option<RuleTarget> {
+        if let Some(pattern) = self.matching_pattern(domain) {
+            return Some(RuleTarget::Domain(pattern.to_string()));
+        }
+        if let Some(service) = service {
+            if self
+                .services
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(service.id))
+            {
+                return Some(RuleTarget::Service(service.id.to_string()));
+            }
+        }
+        self.categories
+            .iter()
+            .find(|c| {
+                categories.iter().any(|hit| hit.eq_ignore_ascii_case(c))
+                    || service.is_some_and(|s| s.group.eq_ignore_ascii_case(c))
+            })
+            .map(|c| RuleTarget::Category(c.to_lowercase()))
+    }
+}
+
+/// Categories no time rule can allow
+pub const SECURITY_CATEGORIES: &[&str] = &["malware", "phishing", "cryptominers"];
+
+impl ScheduleMatch<'_> {
+    /// Whether an allow match lifts a category's block: only the category
+    /// it targets (any category for a domain or service target), and never
+    /// a security category
+    pub fn lifts(&self, category: &str) -> bool {
+        self.rule.action == RuleAction::Allow
+            && !SECURITY_CATEGORIES.contains(&category)
+            && match &self.target {
+                RuleTarget::Category(target) => target == category,
+                _ => true,
+            }
+    }
+}
+
+/// Rules active at `at`, in resolution order
+///
+/// Monitor rules are included; they never decide a query.
+pub fn active_rules<'a, Tz: TimeZone>(
+    rules: &'a [TimeRule],
+    at: &DateTime<Tz>,
+) -> Vec<&'a TimeRule> {
+    let mut active: Vec<&TimeRule> = rules.iter().filter(|rule| rule.is_active_at(at)).collect();
+    // Stable sort keeps list order among equal priorities
+    active.sort_by_key(|rule| Reverse(rule.priority));
+    active
+}
+
+/// Pick the active rule that decides a query, if any
+pub fn resolve<'a, Tz: TimeZone>(
+    rules: &'a [TimeRule],
+    at: &DateTime<Tz>,
+    domain: &str,
+    categories: &[String],
+) -> Option<ScheduleMatch<'a>> {
+    let service = services::service_for(domain);
+    rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.action != RuleAction::Monitor && rule.is_active_at(at))
+        .filter_map(|(index, rule)| {
+            rule.target_for(domain, service, categories)
+                .map(|target| (index, ScheduleMatch { rule, target }))
+        })
+        .min_by_key(|(index, m)| {
+            (
+                Reverse(m.rule.priority),
+                Reverse(m.target.specificity()),
+                m.rule.depth,
+                m.rule.action != RuleAction::Block,
+                *index,
+            )
+        })
+        .map(|(_, m)| m)
+}
+
+/// Match `text` against a pattern where `*` matches any run of characters
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let last = parts.pop().unwrap_or("");
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn rule(start: &str, end: &str) -> TimeRule {
+        TimeRule::new(
+            "test".to_string(),
+            vec![Weekday::Mon],
+            NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            NaiveTime::parse_from_str(end, "%H:%M").unwrap(),
+            RuleAction::Block,
+            vec!["*.games.example".to_string(), "Video.example".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_time_rule_window() {
+        // 2024-01-01 was a Monday
+        let at = |d, h, m| Utc.with_ymd_and_hms(2024, 1, d, h, m, 0).unwrap();
+        let day = rule("09:00", "17:00");
+        assert!(day.is_active_at(&at(1, 12, 0)));
+        assert!(!day.is_active_at(&at(1, 18, 0)));
+        assert!(!day.is_active_at(&at(2, 12, 0)));
+
+        // Monday night runs into Tuesday morning, not Monday morning
+        let night = rule("21:00", "07:00");
+        assert!(night.is_active_at(&at(1, 22, 30)));
+        assert!(night.is_active_at(&at(2, 6, 0)));
+        assert!(!night.is_active_at(&at(1, 6, 0)));
+        assert!(!night.is_active_at(&at(1, 12, 0)));
+    }
+
+    #[test]
+    fn test_stored_rules_keep_same_day_windows() {
+        let at = |d, h, m| Utc.with_ymd_and_hms(2024, 1, d, h, m, 0).unwrap();
+        let mut saved = serde_json::to_value(rule("21:00", "07:00")).unwrap();
+        saved.as_object_mut().unwrap().remove("overnight");
+
+        // Saved before `overnight` existed: Monday's early hours and evening
+        let legacy = TimeRule::from_stored(saved.clone()).unwrap();
+        assert_eq!(legacy.overnight, Overnight::SameDay);
+        assert!(legacy.is_active_at(&at(1, 6, 0)));
+        assert!(legacy.is_active_at(&at(1, 22, 30)));
+        assert!(!legacy.is_active_at(&at(2, 6, 0)));
+
+        // Submitted without it: Monday night into Tuesday morning
+        let submitted: TimeRule = serde_json::from_value(saved).unwrap();
+        assert_eq!(submitted.overnight, Overnight::StartDay);
+        assert!(submitted.is_active_at(&at(2, 6, 0)));
+
+        // Once saved with the field, it is kept
+        let resaved = serde_json::to_value(&submitted).unwrap();
+        let reloaded = TimeRule::from_stored(resaved).unwrap();
+        assert_eq!(reloaded.overnight, Overnight::StartDay);
+    }
+
+    #[test]
+    fn test_time_rule_timezone() {
+        let rule = rule("21:00", "23:00");
+        let tz: chrono_tz::Tz = "America/New_York".parse().unwrap();
+        // 02:30 UTC on Tuesday is 21:30 on Monday in New York
+        let at = Utc.with_ymd_and_hms(2024, 1, 2, 2, 30, 0).unwrap();
+        assert!(!rule.is_active_at(&at));
+        assert!(rule.is_active_at(&at.with_timezone(&tz)));
+    }
+
+    #[test]
+    fn test_time_rule_patterns() {
+        let rule = rule("00:00", "23:59");
+        assert!(rule.matches_domain("www.games.example"));
+        assert!(rule.matches_domain("video.example"));
+        assert!(!rule.matches_domain("games.example"));
+        assert!(!rule.matches_domain("www.games.example.org"));
+        assert!(glob_match("a*b*c", "aXXbYc"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn test_overlap_resolution() {
+        let at = Utc.with_ymd_and_hms(2024, 1, 1, 21, 30, 0).unwrap();
+        let window = |action, name: &str| {
+            TimeRule::new(
+                name.to_string(),
+                vec![Weekday::Mon],
+                NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+                action,
+                vec![],
+            )
+        };
+        let rules = vec![
+            window(RuleAction::Block, "school night")
+                .with_categories(vec!["gaming".into(), "social".into()]),
+            window(RuleAction::Allow, "minecraft ok").with_services(vec!["minecraft".into()]),
+            window(RuleAction::Allow, "homework").with_categories(vec!["social".into()]),
+        ];
+
+        // Service group counts as a category
+        let hit = resolve(&rules, &at, "www.roblox.com", &[]).unwrap();
+        assert_eq!(
+            (hit.rule.name.as_str(), hit.target),
+            ("school night", RuleTarget::Category("gaming".into()))
+        );
+        // A service target is more specific than a category
+        assert_eq!(
+            resolve(&rules, &at, "minecraft.net", &[])
+                .unwrap()
+                .rule
+                .name,
+            "minecraft ok"
+        );
+        // Equal specificity and priority: block wins
+        let categories = vec!["social".to_string()];
+        assert_eq!(
+            resolve(&rules, &at, "forum.example", &categories)
+                .unwrap()
+                .rule
+                .name,
+            "school night"
+        );
+        // Priority beats everything else
+        let mut rules = rules;
+        rules[2].priority = 1;
+        assert_eq!(
+            resolve(&rules, &at, "forum.example", &categories)
+                .unwrap()
+                .rule
+                .name,
+            "homework"
+        );
+        assert_eq!(active_rules(&rules, &at)[0].name, "homework");
+
+        assert!(resolve(&rules, &at, "news.example", &[]).is_none());
+        let later = Utc.with_ymd_and_hms(2024, 1, 2, 8, 0, 0).unwrap();
+        assert!(resolve(&rules, &later, "www.roblox.com", &[]).is_none());
+    }
+}
@@ -0,0 +1,91 @@
+//! Catalog of well-known online services
+//!
+//! Profile rules can target a service ("youtube") or a service group
+//! ("gaming") instead of listing every domain it uses. A domain belongs to a
+//! service when it is, or is a subdomain of, one of the service's domains.
+
+use serde::Serialize;
+
+/// A service and the domains it is reached through
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Service {
+    pub id: &'static str,
+    pub name: &'static str,
+    /// Group the service belongs to, usable wherever a category is
+    pub group: &'static str,
+    pub domains: &'static [&'static str],
+}
+
+/// Services known to the filter
+#[rustfmt::skip]
+pub const SERVICES: &[Service] = &[
+    // Social
+    Service { id: "facebook", name: "Facebook", group: "social", domains: &["facebook.com", "facebook.net", "fbcdn.net", "fb.com", "messenger.com"] },
+    Service { id: "instagram", name: "Instagram", group: "social", domains: &["instagram.com", "cdninstagram.com"] },
+    Service { id: "tiktok", name: "TikTok", group: "social", domains: &["tiktok.com", "tiktokv.com", "tiktokcdn.com", "byteoversea.com"] },
+    Service { id: "snapchat", name: "Snapchat", group: "social", domains: &["snapchat.com", "snapkit.com", "sc-cdn.net"] },
+    Service { id: "twitter", name: "X (Twitter)", group: "social", domains: &["twitter.com", "x.com", "twimg.com", "t.co"] },
+    Service { id: "reddit", name: "Reddit", group: "social", domains: &["reddit.com", "redd.it", "redditmedia.com", "redditstatic.com"] },
+    Service { id: "pinterest", name: "Pinterest", group: "social", domains: &["pinterest.com", "pinimg.com"] },
+    // Video
+    Service { id: "youtube", name: "YouTube", group: "video", domains: &["youtube.com", "youtu.be", "ytimg.com", "googlevideo.com", "youtube-nocookie.com"] },
+    Service { id: "netflix", name: "Netflix", group: "video", domains: &["netflix.com", "nflxvideo.net", "nflximg.net", "nflxext.com"] },
+    Service { id: "twitch", name: "Twitch", group: "video", domains: &["twitch.tv", "ttvnw.net", "jtvnw.net"] },
+    Service { id: "disneyplus", name: "Disney+", group: "video", domains: &["disneyplus.com", "disney-plus.net", "bamgrid.com"] },
+    // Gaming
+    Service { id: "roblox", name: "Roblox", group: "gaming", domains: &["roblox.com", "rbxcdn.com", "rbx.com"] },
+    Service { id: "fortnite", name: "Fortnite", group: "gaming", domains: &["fortnite.com", "epicgames.com", "epicgames.dev", "unrealengine.com"] },
+    Service { id: "minecraft", name: "Minecraft", group: "gaming", domains: &["minecraft.net", "mojang.com", "minecraftservices.com"] },
+    Service { id: "steam", name: "Steam", group: "gaming", domains: &["steampowered.com", "steamcommunity.com", "steamstatic.com", "steamcontent.com"] },
+    Service { id: "xbox", name: "Xbox Live", group: "gaming", domains: &["xboxlive.com", "xbox.com"] },
+    Service { id: "playstation", name: "PlayStation Network", group: "gaming", domains: &["playstation.com", "playstation.net", "sonyentertainmentnetwork.com"] },
+    // Messaging
+    Service { id: "discord", name: "Discord", group: "messaging", domains: &["discord.com", "discord.gg", "discordapp.com", "discordapp.net"] },
+    Service { id: "whatsapp", name: "WhatsApp", group: "messaging", domains: &["whatsapp.com", "whatsapp.net"] },
+    Service { id: "telegram", name: "Telegram", group: "messaging", domains: &["telegram.org", "t.me", "telegram.me"] },
+];
+
+/// Look up a service by ID
+pub fn get(id: &str) -> Option<&'static Service> {
+    SERVICES.iter().find(|s| s.id.eq_ignore_ascii_case(id))
+}
+
+/// Service a (lowercase) domain belongs to, if any
+pub fn service_for(domain: &str) -> Option<&'static Service> {
+    let domain = domain.trim_end_matches('.');
+    SERVICES.iter().find(|service| {
+        service.domains.iter().any(|d| {
+            domain == *d
+                || (domain.len() > d.len()
+                    && domain.ends_with(d)
+                    && domain.as_bytes()[domain.len() - d.len() - 1] == b'.')
+        })
+    })
+}
+
+/// Whether a name is a service group
+pub fn is_group(name: &str) -> bool {
+    SERVICES.iter().any(|s| s.group == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_lookup() {
+        assert_eq!(
+            service_for("www.youtube.com").map(|s| s.id),
+            Some("youtube")
+        );
+        assert_eq!(
+            service_for("r3---sn.googlevideo.com.").map(|s| s.id),
+            Some("youtube")
+        );
+        assert_eq!(service_for("t.co").map(|s| s.group), Some("social"));
+        assert!(service_for("notyoutube.com").is_none());
+        assert!(get("Roblox").is_some());
+        assert!(is_group("gaming"));
+        assert!(!is_group("ads"));
+    }
+}
@@ -3,9 +3,18 @@
 //! Combines global blocklists, category-based filtering, and per-device profiles
 //! into a single high-performance filter.
 
-use crate::blocklist_fetcher::{BlocklistManager, BlocklistStats};
+use crate::adblock::{RuleAction, RuleContext};
+use crate::blocklist_fetcher::{BlocklistManager, BlocklistStats, TraceMatch, TraceMatchKind};
 use crate::filter::{FilterDecision, FilterEngine};
+use crate::overrides::OverrideStore;
+use crate::prefix_map::{Overlap, PrefixMap};
+use crate::rpz::{RpzAction, RpzWriter};
+use crate::schedule::{self, TimeRule};
+use crate::services;
+use crate::usage::{QuotaTarget, UsageQuota, UsageTracker};
 use ahash::AHashMap;
+use chrono_tz::Tz;
+use ipnet::IpNet;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
@@ -25,6 +34,9 @@ pub struct FilterResult {
     pub profile_id: Option<String>,
     /// Profile name
     pub profile_name: Option<String>,
+    /// RPZ answer override (NODATA or local data), if a policy applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<RpzAction>,
 }
 
 /// Reason for the filter decision
@@ -35,18 +47,102 @@ pub enum FilterReason {
     GlobalAllowlist,
     /// Domain is on profile allowlist
     ProfileAllowlist,
+    /// Domain is temporarily allowed (globally or for the profile)
+    TemporaryAllowlist,
+    /// Blocking is paused (globally or for the profile)
+    BlockingPaused,
     /// Domain is on global blocklist
     GlobalBlocklist,
     /// Domain blocked by category (ads, malware, etc.)
     CategoryBlock,
+    /// Domain allowed by a filter list exception rule (`@@||domain^`)
+    ExceptionRule,
+    /// Answer replaced by an RPZ policy (NODATA or local data)
+    PolicyRewrite,
     /// Domain blocked by profile rules
     ProfileBlock,
-    /// Domain blocked by time-based rule
+    /// Domain blocked or allowed by a time-based rule
     TimeBasedRule,
+    /// Daily usage budget for the domain's service or category is spent
+    QuotaExceeded,
     /// No matching rules - allowed by default
     DefaultAllow,
 }
 
+impl FilterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterReason::GlobalAllowlist => "global_allowlist",
+            FilterReason::ProfileAllowlist => "profile_allowlist",
+            FilterReason::TemporaryAllowlist => "temporary_allowlist",
+            FilterReason::BlockingPaused => "blocking_paused",
+            FilterReason::GlobalBlocklist => "global_blocklist",
+            FilterReason::CategoryBlock => "category_block",
+            FilterReason::ExceptionRule => "exception_rule",
+            FilterReason::PolicyRewrite => "policy_rewrite",
+            FilterReason::ProfileBlock => "profile_block",
+            FilterReason::TimeBasedRule => "time_based_rule",
+            FilterReason::QuotaExceeded => "quota_exceeded",
+            FilterReason::DefaultAllow => "default_allow",
+        }
+    }
+}
+
+/// Stage of `UnifiedFilter::check_query`, in evaluation order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterStage {
+    GlobalAllowlist,
+    TemporaryAllowlist,
+    BlockingPause,
+    ProfileAllowlist,
+    LegacyAllowlist,
+    ProfileBlocklist,
+    TimeRules,
+    UsageQuota,
+    LegacyBlocklist,
+    FilterRules,
+    PolicyRewrite,
+    CategoryBlocklist,
+    DefaultAllow,
+}
+
+/// A stage consulted while evaluating a query
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStage {
+    pub stage: FilterStage,
+    /// Whether this stage decided the query
+    pub matched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Explanation of a filter decision
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterTrace {
+    pub result: FilterResult,
+    /// Stages consulted in order; the last one decided
+    pub stages: Vec<TraceStage>,
+    /// Every loaded entry matching the domain, including ones in categories
+    /// the profile doesn't block
+    pub matches: Vec<TraceMatch>,
+}
+
+fn record(
+    trace: &mut Option<&mut Vec<TraceStage>>,
+    stage: FilterStage,
+    matched: bool,
+    detail: impl FnOnce() -> Option<String>,
+) {
+    if let Some(stages) = trace {
+        stages.push(TraceStage {
+            stage,
+            matched,
+            detail: detail(),
+        });
+    }
+}
+
 /// Device profile configuration for filtering
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceProfile {
@@ -58,6 +154,18 @@ pub struct DeviceProfile {
     pub custom_blocklist: Vec<String>,
     /// Domains to always allow (overrides blocks)
     pub custom_allowlist: Vec<String>,
+    /// Scheduled allow/block rules, resolved by `schedule::resolve`
+    #[serde(default)]
+    pub time_rules: Vec<TimeRule>,
+    /// Daily time budgets per service or category
+    #[serde(default)]
+    pub quotas: Vec<UsageQuota>,
+    /// IANA timezone the time rules and budgets are evaluated in
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    /// Keep this profile's queries out of the dnstap stream
+    #[serde(default)]
+    pub dnstap_opt_out: bool,
     /// Whether this profile is enabled
     pub enabled: bool,
 }
@@ -75,11 +183,19 @@ impl Default for DeviceProfile {
             ],
             custom_blocklist: vec![],
             custom_allowlist: vec![],
+            time_rules: vec![],
+            quotas: vec![],
+            timezone: Tz::UTC,
+            dnstap_opt_out: false,
             enabled: true,
         }
     }
 }
 
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
 /// Unified filter combining all filtering sources
 pub struct UnifiedFilter {
     /// The blocklist manager for category-based blocking
@@ -88,12 +204,18 @@ pub struct UnifiedFilter {
     legacy_filter: Arc<FilterEngine>,
     /// Device IP to profile mapping
     device_profiles: Arc<RwLock<AHashMap<IpAddr, DeviceProfile>>>,
+    /// IP range to profile mapping, used when no exact IP matches
+    range_profiles: Arc<RwLock<PrefixMap<DeviceProfile>>>,
     /// Device ID (string) to profile mapping
     device_id_profiles: Arc<RwLock<AHashMap<String, DeviceProfile>>>,
     /// Default profile for unknown devices
     default_profile: Arc<RwLock<DeviceProfile>>,
     /// Global allowlist (always allowed, overrides everything)
     global_allowlist: Arc<RwLock<ahash::AHashSet<String>>>,
+    /// Expiring allowlist entries and blocking pauses
+    overrides: Arc<OverrideStore>,
+    /// Daily usage budget tracking
+    usage: Arc<UsageTracker>,
 }
 
 impl UnifiedFilter {
@@ -110,9 +232,12 @@ impl UnifiedFilter {
             blocklist_manager,
             legacy_filter,
             device_profiles: Arc::new(RwLock::new(AHashMap::new())),
+            range_profiles: Arc::new(RwLock::new(PrefixMap::new())),
             device_id_profiles: Arc::new(RwLock::new(AHashMap::new())),
             default_profile: Arc::new(RwLock::new(DeviceProfile::default())),
             global_allowlist: Arc::new(RwLock::new(ahash::AHashSet::new())),
+            overrides: Arc::new(OverrideStore::new()),
+            usage: Arc::new(UsageTracker::new()),
         }
     }
 
@@ -135,97 +260,438 @@ impl UnifiedFilter {
 
     /// Check a domain for a specific client IP
     pub fn check(&self, domain: &str, client_ip: Option<IpAddr>) -> FilterResult {
+        self.check_query(domain, client_ip, None)
+    }
+
+    /// Check a domain for a client IP and query type
+    ///
+    /// The query type (1 = A, 28 = AAAA, ...) is matched by `$dnstype` rules.
+    pub fn check_query(
+        &self,
+        domain: &str,
+        client_ip: Option<IpAddr>,
+        qtype: Option<u16>,
+    ) -> FilterResult {
+        self.evaluate(domain, client_ip, qtype, None)
+    }
+
+    /// Check a query and explain the decision
+    ///
+    /// Returns every stage consulted and every loaded entry matching the
+    /// domain, with the lists they came from and the one that won.
+    pub fn trace_query(
+        &self,
+        domain: &str,
+        client_ip: Option<IpAddr>,
+        qtype: Option<u16>,
+    ) -> FilterTrace {
+        let mut stages = Vec::new();
+        let result = self.evaluate(domain, client_ip, qtype, Some(&mut stages));
+
+        let profile = self.get_profile_for_client(client_ip);
+        let ctx = Self::rule_context(&profile, client_ip, qtype);
+        let mut matches = self.blocklist_manager.trace_matches(domain, &ctx, |c| {
+            profile.blocked_categories.iter().any(|b| b == c)
+        });
+
+        // Entries are listed in the order each stage considers them
+        let winner = match stages.last().map(|s| s.stage) {
+            Some(FilterStage::FilterRules) => matches
+                .iter()
+                .position(|m| m.kind == TraceMatchKind::Rule && m.enabled),
+            Some(FilterStage::PolicyRewrite) => matches
+                .iter()
+                .position(|m| m.kind == TraceMatchKind::Rewrite && m.enabled),
+            Some(FilterStage::CategoryBlocklist) => matches.iter().position(|m| {
+                m.kind == TraceMatchKind::Domain
+                    && m.enabled
+                    && result.category.as_ref() == Some(&m.category)
+            }),
+            _ => None,
+        };
+        if let Some(idx) = winner {
+            matches[idx].winner = true;
+        }
+
+        FilterTrace {
+            result,
+            stages,
+            matches,
+        }
+    }
+
+    fn rule_context(
+        profile: &DeviceProfile,
+        client_ip: Option<IpAddr>,
+        qtype: Option<u16>,
+    ) -> RuleContext {
+        RuleContext {
+            client_ip,
+            client_name: Some(profile.name.clone()),
+            qtype,
+            ctags: Vec::new(),
+        }
+    }
+
+    /// Run the filter stages in order, recording each one consulted in `trace`
+    fn evaluate(
+        &self,
+        domain: &str,
+        client_ip: Option<IpAddr>,
+        qtype: Option<u16>,
+        mut trace: Option<&mut Vec<TraceStage>>,
+    ) -> FilterResult {
         let domain_lower = domain.to_lowercase();
 
         // Step 1: Check global allowlist (highest priority)
-        if self.global_allowlist.read().contains(&domain_lower) {
+        let allowlisted = self.global_allowlist.read().contains(&domain_lower);
+        record(
+            &mut trace,
+            FilterStage::GlobalAllowlist,
+            allowlisted,
+            || None,
+        );
+        if allowlisted {
             return FilterResult {
                 decision: FilterDecision::Allow,
                 reason: FilterReason::GlobalAllowlist,
                 category: None,
                 profile_id: None,
                 profile_name: None,
+                rewrite: None,
             };
         }
 
         // Step 2: Get applicable profile
         let profile = self.get_profile_for_client(client_ip);
 
+        // Step 2a: Temporary allowlist entries (global or for the profile)
+        let now = chrono::Utc::now();
+        let temporary = self.overrides.allowed_by(&domain_lower, &profile.id, now);
+        record(
+            &mut trace,
+            FilterStage::TemporaryAllowlist,
+            temporary.is_some(),
+            || {
+                temporary
+                    .as_ref()
+                    .map(|t| format!("'{}' allowed until {}", t.domain, t.expires_at.to_rfc3339()))
+            },
+        );
+        if temporary.is_some() {
+            return FilterResult {
+                decision: FilterDecision::Allow,
+                reason: FilterReason::TemporaryAllowlist,
+                category: None,
+                profile_id: Some(profile.id.clone()),
+                profile_name: Some(profile.name.clone()),
+                rewrite: None,
+            };
+        }
+
+        // Step 2b: Timed blocking pause (global or for the profile)
+        let pause = self.overrides.paused(&profile.id, now);
+        record(
+            &mut trace,
+            FilterStage::BlockingPause,
+            pause.is_some(),
+            || {
+                pause
+                    .as_ref()
+                    .map(|p| format!("blocking paused until {}", p.expires_at.to_rfc3339()))
+            },
+        );
+        if pause.is_some() {
+            return FilterResult {
+                decision: FilterDecision::Allow,
+                reason: FilterReason::BlockingPaused,
+                category: None,
+                profile_id: Some(profile.id.clone()),
+                profile_name: Some(profile.name.clone()),
+                rewrite: None,
+            };
+        }
+
         // Step 3: Check profile allowlist
-        if profile.custom_allowlist.iter().any(|p| {
+        let allowed_by = profile.custom_allowlist.iter().find(|p| {
             domain_lower == p.to_lowercase() || domain_lower.ends_with(&format!(".{}", p.to_lowercase()))
-        }) {
+        });
+        record(
+            &mut trace,
+            FilterStage::ProfileAllowlist,
+            allowed_by.is_some(),
+            || allowed_by.map(|p| format!("'{}' on profile {} allowlist", p, profile.name)),
+        );
+        if allowed_by.is_some() {
             return FilterResult {
                 decision: FilterDecision::Allow,
                 reason: FilterReason::ProfileAllowlist,
                 category: None,
                 profile_id: Some(profile.id.clone()),
                 profile_name: Some(profile.name.clone()),
+                rewrite: None,
             };
         }
 
         // Step 4: Check legacy filter allowlist
-        if self.legacy_filter.check(&domain_lower) == FilterDecision::Allow {
-            // Check if it's explicitly allowed (not just "not blocked")
-            if self.legacy_filter.get_allowlist().contains(&domain_lower) {
-                return FilterResult {
-                    decision: FilterDecision::Allow,
-                    reason: FilterReason::GlobalAllowlist,
-                    category: None,
-                    profile_id: None,
-                    profile_name: None,
-                };
-            }
+        // (explicitly allowed, not just "not blocked")
+        let legacy_allowed = self.legacy_filter.check(&domain_lower) == FilterDecision::Allow
+            && self.legacy_filter.get_allowlist().contains(&domain_lower);
+        record(
+            &mut trace,
+            FilterStage::LegacyAllowlist,
+            legacy_allowed,
+            || None,
+        );
+        if legacy_allowed {
+            return FilterResult {
+                decision: FilterDecision::Allow,
+                reason: FilterReason::GlobalAllowlist,
+                category: None,
+                profile_id: None,
+                profile_name: None,
+                rewrite: None,
+            };
         }
 
         // Step 5: Check profile custom blocklist
-        if profile.custom_blocklist.iter().any(|p| {
+        let blocked_by = profile.custom_blocklist.iter().find(|p| {
             domain_lower == p.to_lowercase() || domain_lower.ends_with(&format!(".{}", p.to_lowercase()))
-        }) {
+        });
+        record(
+            &mut trace,
+            FilterStage::ProfileBlocklist,
+            blocked_by.is_some(),
+            || blocked_by.map(|p| format!("'{}' on profile {} blocklist", p, profile.name)),
+        );
+        if blocked_by.is_some() {
             return FilterResult {
                 decision: FilterDecision::Block,
                 reason: FilterReason::ProfileBlock,
                 category: Some("custom".to_string()),
                 profile_id: Some(profile.id.clone()),
                 profile_name: Some(profile.name.clone()),
+                rewrite: None,
             };
         }
 
+        // Categories listing the domain, for time rules and budgets that target them
+        let needs_categories = profile.time_rules.iter().any(|r| !r.categories.is_empty())
+            || profile
+                .quotas
+                .iter()
+                .any(|q| matches!(q.target, QuotaTarget::Category(_)));
+        let categories = if needs_categories {
+            self.blocklist_manager.domain_categories(&domain_lower)
+        } else {
+            Vec::new()
+        };
+
+        // Step 5a: Time-based rules active now in the profile's timezone
+        let time_rule = if profile.time_rules.is_empty() {
+            None
+        } else {
+            let local_now = now.with_timezone(&profile.timezone);
+            schedule::resolve(&profile.time_rules, &local_now, &domain_lower, &categories)
+        };
+        record(
+            &mut trace,
+            FilterStage::TimeRules,
+            time_rule.is_some(),
+            || {
+                time_rule.as_ref().map(|m| {
+                    format!(
+                        "time rule '{}' ({:?}, {:?})",
+                        m.rule.name, m.rule.action, m.target
+                    )
+                })
+            },
+        );
+        // A block rule decides the query; an allow rule only lifts the
+        // category blocks it covers and the remaining steps still run
+        if let Some(matched) = &time_rule {
+            debug!(
+                "Domain {} matched time rule '{}' for profile {}",
+                domain_lower, matched.rule.name, profile.name
+            );
+            if matched.rule.action == schedule::RuleAction::Block {
+                let category = match &matched.target {
+                    schedule::RuleTarget::Category(c) => c.clone(),
+                    _ => "custom".to_string(),
+                };
+                return FilterResult {
+                    decision: FilterDecision::Block,
+                    reason: FilterReason::TimeBasedRule,
+                    category: Some(category),
+                    profile_id: Some(profile.id.clone()),
+                    profile_name: Some(profile.name.clone()),
+                    rewrite: None,
+                };
+            }
+        }
+        let blocks_category = |c: &str| {
+            profile.blocked_categories.iter().any(|b| b == c)
+                && !time_rule.as_ref().is_some_and(|m| m.lifts(c))
+        };
+
+        // Step 5b: Daily usage budgets, unless an allow rule is in effect;
+        // only real queries count as activity, not traced ones
+        if !profile.quotas.is_empty() && time_rule.is_none() {
+            let service = services::service_for(&domain_lower);
+            let exhausted = self
+                .usage
+                .check(&profile, service, &categories, now, trace.is_none());
+            record(
+                &mut trace,
+                FilterStage::UsageQuota,
+                exhausted.is_some(),
+                || {
+                    exhausted.map(|q| {
+                        format!(
+                            "{} budget of {} minutes spent",
+                            q.target.name(),
+                            q.daily_minutes
+                        )
+                    })
+                },
+            );
+            if let Some(quota) = exhausted {
+                return FilterResult {
+                    decision: FilterDecision::Block,
+                    reason: FilterReason::QuotaExceeded,
+                    category: Some(quota.target.name().to_string()),
+                    profile_id: Some(profile.id.clone()),
+                    profile_name: Some(profile.name.clone()),
+                    rewrite: None,
+                };
+            }
+        }
+
         // Step 6: Check legacy filter blocklist
-        if self.legacy_filter.is_blocked(&domain_lower) {
+        let legacy_blocked = self.legacy_filter.is_blocked(&domain_lower);
+        record(
+            &mut trace,
+            FilterStage::LegacyBlocklist,
+            legacy_blocked,
+            || None,
+        );
+        if legacy_blocked {
             return FilterResult {
                 decision: FilterDecision::Block,
                 reason: FilterReason::GlobalBlocklist,
                 category: Some("custom".to_string()),
                 profile_id: None,
                 profile_name: None,
+                rewrite: None,
             };
         }
 
-        // Step 7: Check category-based blocklists using profile's blocked categories
-        for category in &profile.blocked_categories {
-            if self.blocklist_manager.is_blocked_by_category(&domain_lower, category) {
-                debug!(
-                    "Domain {} blocked by category {} for profile {}",
-                    domain_lower, category, profile.name
-                );
-                return FilterResult {
-                    decision: FilterDecision::Block,
-                    reason: FilterReason::CategoryBlock,
-                    category: Some(category.clone()),
-                    profile_id: Some(profile.id.clone()),
-                    profile_name: Some(profile.name.clone()),
-                };
-            }
+        // Step 7: Filter list rules (exceptions, modifiers) for the profile's categories
+        let ctx = Self::rule_context(&profile, client_ip, qtype);
+        let rule = self
+            .blocklist_manager
+            .evaluate_rules(&domain_lower, &ctx, blocks_category);
+        record(&mut trace, FilterStage::FilterRules, rule.is_some(), || {
+            rule.as_ref()
+                .map(|r| format!("rule '{}' ({})", r.rule, r.category))
+        });
+        if let Some(rule) = rule {
+            debug!(
+                "Domain {} matched rule '{}' ({})",
+                domain_lower, rule.rule, rule.category
+            );
+            let (decision, reason, category) = match rule.action {
+                // A higher-priority list holding the same domain takes the attribution
+                RuleAction::Block => (
+                    FilterDecision::Block,
+                    FilterReason::CategoryBlock,
+                    self.blocklist_manager.attribute_category(
+                        &domain_lower,
+                        Some(&rule.category),
+                        blocks_category,
+                    ),
+                ),
+                RuleAction::Allow => (FilterDecision::Allow, FilterReason::ExceptionRule, None),
+            };
+            return FilterResult {
+                decision,
+                reason,
+                category,
+                profile_id: Some(profile.id.clone()),
+                profile_name: Some(profile.name.clone()),
+                rewrite: None,
+            };
         }
 
-        // Step 8: Default allow
+        // Step 8: RPZ NODATA and local-data policies
+        let rewrite = self
+            .blocklist_manager
+            .policy_rewrite(&domain_lower, blocks_category);
+        record(
+            &mut trace,
+            FilterStage::PolicyRewrite,
+            rewrite.is_some(),
+            || {
+                rewrite
+                    .as_ref()
+                    .map(|(category, _)| format!("{} policy", category))
+            },
+        );
+        if let Some((category, action)) = rewrite {
+            // NODATA hides the name like a block; local data answers in its place
+            let decision = match action {
+                RpzAction::LocalData(_) => FilterDecision::Allow,
+                _ => FilterDecision::Block,
+            };
+            return FilterResult {
+                decision,
+                reason: FilterReason::PolicyRewrite,
+                category: Some(category),
+                profile_id: Some(profile.id.clone()),
+                profile_name: Some(profile.name.clone()),
+                rewrite: Some(action),
+            };
+        }
+
+        // Step 9: Check category-based blocklists using profile's blocked categories,
+        // attributing overlaps to the highest-priority category
+        let category =
+            self.blocklist_manager
+                .attribute_category(&domain_lower, None, blocks_category);
+        record(
+            &mut trace,
+            FilterStage::CategoryBlocklist,
+            category.is_some(),
+            || category.as_ref().map(|c| format!("{} list", c)),
+        );
+        if let Some(category) = category {
+            debug!(
+                "Domain {} blocked by category {} for profile {}",
+                domain_lower, category, profile.name
+            );
+            return FilterResult {
+                decision: FilterDecision::Block,
+                reason: FilterReason::CategoryBlock,
+                category: Some(category),
+                profile_id: Some(profile.id.clone()),
+                profile_name: Some(profile.name.clone()),
+                rewrite: None,
+            };
+        }
+
+        // Step 10: Default allow, or the allow rule's if one matched
+        record(&mut trace, FilterStage::DefaultAllow, true, || None);
         FilterResult {
             decision: FilterDecision::Allow,
-            reason: FilterReason::DefaultAllow,
+            reason: if time_rule.is_some() {
+                FilterReason::TimeBasedRule
+            } else {
+                FilterReason::DefaultAllow
+            },
             category: None,
             profile_id: Some(profile.id.clone()),
             profile_name: Some(profile.name.clone()),
+            rewrite: None,
         }
     }
 
@@ -240,17 +706,35 @@ impl UnifiedFilter {
     }
 
     /// Get the profile for a client IP (or default)
+    ///
+    /// An exact IP assignment wins over ranges; among ranges the most
+    /// specific one containing the IP wins.
     fn get_profile_for_client(&self, client_ip: Option<IpAddr>) -> DeviceProfile {
-        if let Some(ip) = client_ip {
+        if let Some(ip) = client_ip.map(|ip| ip.to_canonical()) {
             if let Some(profile) = self.device_profiles.read().get(&ip) {
                 if profile.enabled {
                     return profile.clone();
                 }
             }
+            if let Some((_, profile)) = self.range_profiles.read().lookup(&ip) {
+                if profile.enabled {
+                    return profile.clone();
+                }
+            }
         }
         self.default_profile.read().clone()
     }
 
+    /// Whether the profile that applies to a client keeps it out of dnstap
+    pub fn dnstap_opt_out(&self, client_ip: Option<IpAddr>) -> bool {
+        self.get_profile_for_client(client_ip).dnstap_opt_out
+    }
+
+    /// Id of the profile that applies to a client
+    pub fn profile_id_for_client(&self, client_ip: Option<IpAddr>) -> String {
+        self.get_profile_for_client(client_ip).id
+    }
+
     /// Assign a profile to a client IP
     pub fn assign_profile_to_ip(&self, ip: IpAddr, profile: DeviceProfile) {
         info!("Assigning profile '{}' to IP {}", profile.name, ip);
@@ -273,6 +757,55 @@ impl UnifiedFilter {
         self.device_profiles.write().remove(ip);
     }
 
+    /// Assign a profile to every client in an IP range
+    pub fn assign_profile_to_range(&self, range: IpNet, profile: DeviceProfile) {
+        info!(
+            "Assigning profile '{}' to range {}",
+            profile.name,
+            range.trunc()
+        );
+        self.range_profiles.write().insert(range, profile);
+    }
+
+    /// Remove a range assignment
+    pub fn remove_range_profile(&self, range: &IpNet) {
+        self.range_profiles.write().remove(range);
+    }
+
+    /// Every range assignment, most specific first
+    pub fn range_assignments(&self) -> Vec<RangeAssignment> {
+        let ranges = self.range_profiles.read();
+        let mut assignments: Vec<RangeAssignment> = ranges
+            .iter()
+            .map(|(range, profile)| RangeAssignment::new(*range, profile))
+            .collect();
+        assignments.sort_by_key(|a| (std::cmp::Reverse(a.range.prefix_len()), a.range.network()));
+        assignments
+    }
+
+    /// Range assignment a client IP falls under, if any
+    pub fn matching_range(&self, ip: &IpAddr) -> Option<RangeAssignment> {
+        self.range_profiles
+            .read()
+            .lookup(ip)
+            .map(|(range, profile)| RangeAssignment::new(range, profile))
+    }
+
+    /// Assigned ranges overlapping `range`
+    pub fn overlapping_ranges(&self, range: &IpNet) -> Vec<(RangeAssignment, Overlap)> {
+        self.range_profiles
+            .read()
+            .overlapping(range)
+            .into_iter()
+            .map(|(net, overlap, profile)| (RangeAssignment::new(net, profile), overlap))
+            .collect()
+    }
+
+    /// Remove device ID assignment
+    pub fn remove_device_profile(&self, device_id: &str) {
+        self.device_id_profiles.write().remove(device_id);
+    }
+
     /// Set the default profile
     pub fn set_default_profile(&self, profile: DeviceProfile) {
         info!("Setting default profile: {}", profile.name);
@@ -325,10 +858,60 @@ impl UnifiedFilter {
             legacy_blocklist_size: self.legacy_filter.blocklist_size(),
             legacy_allowlist_size: self.legacy_filter.allowlist_size(),
             assigned_devices: self.device_profiles.read().len(),
+            assigned_ranges: self.range_profiles.read().len(),
             sources_loaded: blocklist_stats.sources_loaded,
         }
     }
 
+    /// Export the effective global policy as an RPZ zone file
+    ///
+    /// Includes the global and legacy allowlists (PASSTHRU), the legacy
+    /// blocklist and every enabled category, added in the order `evaluate`
+    /// consults them so a name on several gets the action it would get
+    /// there. Per-profile policy is not represented.
+    pub fn export_rpz(&self, origin: &str) -> String {
+        let mut writer = RpzWriter::new(origin);
+        writer.comment("Generated by Shield AI");
+
+        for domain in self
+            .get_global_allowlist()
+            .iter()
+            .chain(&self.legacy_filter.get_allowlist())
+        {
+            writer.add(domain, &RpzAction::Passthru);
+        }
+        for pattern in self.legacy_filter.get_blocklist() {
+            match pattern.strip_prefix("*.") {
+                Some(domain) => writer.add_subtree(domain, &RpzAction::Nxdomain),
+                None => writer.add(&pattern, &RpzAction::Nxdomain),
+            }
+        }
+
+        let skipped = self.blocklist_manager.export_rpz(&mut writer);
+        if skipped > 0 {
+            writer.comment(&format!(
+                "{} rules with modifiers or patterns not representable in RPZ",
+                skipped
+            ));
+        }
+
+        let serial = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        writer.finish(serial)
+    }
+
+    /// Daily usage budget tracker
+    pub fn usage(&self) -> &Arc<UsageTracker> {
+        &self.usage
+    }
+
+    /// Temporary allowlist entries and blocking pauses
+    pub fn overrides(&self) -> &Arc<OverrideStore> {
+        &self.overrides
+    }
+
     /// Get the blocklist manager for direct access
     pub fn blocklist_manager(&self) -> &Arc<BlocklistManager> {
         &self.blocklist_manager
@@ -349,6 +932,13 @@ impl UnifiedFilter {
         self.blocklist_manager.disable_category(category);
     }
 
+    /// Set the globally enabled categories and the default profile's
+    /// blocked categories together
+    pub fn set_global_categories(&self, categories: &[String]) {
+        self.blocklist_manager.set_enabled_categories(categories);
+        self.default_profile.write().blocked_categories = categories.to_vec();
+    }
+
     /// Get which category blocks a domain (if any)
     pub fn get_blocking_category(&self, domain: &str) -> Option<String> {
         self.blocklist_manager.get_blocking_category(domain)
@@ -369,9 +959,28 @@ pub struct UnifiedFilterStats {
     pub legacy_blocklist_size: usize,
     pub legacy_allowlist_size: usize,
     pub assigned_devices: usize,
+    pub assigned_ranges: usize,
     pub sources_loaded: usize,
 }
 
+/// A profile assigned to an IP range
+#[derive(Debug, Clone, Serialize)]
+pub struct RangeAssignment {
+    pub range: IpNet,
+    pub profile_id: String,
+    pub profile_name: String,
+}
+
+impl RangeAssignment {
+    fn new(range: IpNet, profile: &DeviceProfile) -> Self {
+        Self {
+            range,
+            profile_id: profile.id.clone(),
+            profile_name: profile.name.clone(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -414,6 +1023,322 @@ mod tests {
         assert!(filter.is_blocked_for_client("adult.example.com", ip));
     }
 
+    #[test]
+    fn test_time_rules() {
+        use chrono::{NaiveTime, Weekday};
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        filter.add_to_blocklist("ads.games.example", "ads");
+        filter
+            .blocklist_manager()
+            .add_domain("casino.example", "gambling");
+
+        // Windows wrapping from noon to noon cover the whole week
+        let always = |action, patterns: &[&str]| {
+            TimeRule::new(
+                format!("{:?}", action),
+                vec![
+                    Weekday::Mon,
+                    Weekday::Tue,
+                    Weekday::Wed,
+                    Weekday::Thu,
+                    Weekday::Fri,
+                    Weekday::Sat,
+                    Weekday::Sun,
+                ],
+                NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                NaiveTime::from_hms_milli_opt(11, 59, 59, 999).unwrap(),
+                action,
+                patterns.iter().map(|p| p.to_string()).collect(),
+            )
+        };
+        let ip: IpAddr = "192.168.1.50".parse().unwrap();
+        filter.assign_profile_to_ip(
+            ip,
+            DeviceProfile {
+                id: "kid".to_string(),
+                name: "Kid".to_string(),
+                time_rules: vec![
+                    always(schedule::RuleAction::Monitor, &["*.example"]),
+                    always(schedule::RuleAction::Block, &["*.games.example"]),
+                    always(schedule::RuleAction::Allow, &["ads.games.example"]),
+                    always(schedule::RuleAction::Block, &[])
+                        .with_categories(vec!["gambling".to_string()]),
+                ],
+                timezone: "Europe/Berlin".parse().unwrap(),
+                ..Default::default()
+            },
+        );
+
+        let result = filter.check("play.games.example", Some(ip));
+        assert_eq!(
+            (result.decision, result.reason),
+            (FilterDecision::Block, FilterReason::TimeBasedRule)
+        );
+        assert!(filter.check("school.example", Some(ip)).decision == FilterDecision::Allow);
+        // Block wins over allow when both match, so the narrower allow rule
+        // doesn't carve an exception out of the wildcard block
+        assert!(filter.is_blocked_for_client("ads.games.example", ip));
+        assert!(
+            !filter.is_blocked_for_client("play.games.example", "192.168.1.51".parse().unwrap())
+        );
+        // Category rules apply even when the profile doesn't block the category
+        assert_eq!(
+            filter.check("casino.example", Some(ip)).category.as_deref(),
+            Some("gambling")
+        );
+    }
+
+    #[test]
+    fn test_allow_rule_lifts_only_its_category() {
+        use chrono::{NaiveTime, Weekday};
+        let filter = UnifiedFilter::new(Arc::new(FilterEngine::new()));
+        let manager = filter.blocklist_manager();
+        manager.add_domain("chat.example", "social");
+        manager.add_domain("chat.example", "malware");
+        manager.add_domain("friends.example", "social");
+        manager.add_domain("friends.example", "gambling");
+        manager.add_domain("feed.example", "social");
+
+        let all_week = vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ];
+        let homework = TimeRule::new(
+            "homework".to_string(),
+            all_week,
+            NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            NaiveTime::from_hms_milli_opt(11, 59, 59, 999).unwrap(),
+            schedule::RuleAction::Allow,
+            vec![],
+        )
+        .with_categories(vec!["social".to_string()]);
+        let ip: IpAddr = "192.168.1.60".parse().unwrap();
+        filter.assign_profile_to_ip(
+            ip,
+            DeviceProfile {
+                id: "teen".to_string(),
+                name: "Teen".to_string(),
+                blocked_categories: ["social", "malware", "gambling"]
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect(),
+                time_rules: vec![homework],
+                ..Default::default()
+            },
+        );
+
+        let result = filter.check("feed.example", Some(ip));
+        assert_eq!(
+            (result.decision, result.reason),
+            (FilterDecision::Allow, FilterReason::TimeBasedRule)
+        );
+        // Other enabled categories still block, and security ones always do
+        let result = filter.check("chat.example", Some(ip));
+        assert_eq!(
+            (result.decision, result.category.as_deref()),
+            (FilterDecision::Block, Some("malware"))
+        );
+        let result = filter.check("friends.example", Some(ip));
+        assert_eq!(
+            (result.decision, result.category.as_deref()),
+            (FilterDecision::Block, Some("gambling"))
+        );
+    }
+
+    #[test]
+    fn test_range_profiles() {
+        let filter = UnifiedFilter::new(Arc::new(FilterEngine::new()));
+        let profile = |id: &str, blocked: &str| DeviceProfile {
+            id: id.to_string(),
+            name: id.to_string(),
+            custom_blocklist: vec![blocked.to_string()],
+            ..Default::default()
+        };
+        filter.assign_profile_to_range(
+            "192.168.0.0/16".parse().unwrap(),
+            profile("lan", "lan.example"),
+        );
+        filter.assign_profile_to_range(
+            "192.168.50.0/24".parse().unwrap(),
+            profile("guest", "guest.example"),
+        );
+        filter.assign_profile_to_range(
+            "2001:db8:0:1::/64".parse().unwrap(),
+            profile("tablet", "tablet.example"),
+        );
+        filter.assign_profile_to_ip(
+            "192.168.50.9".parse().unwrap(),
+            profile("laptop", "laptop.example"),
+        );
+
+        let blocked =
+            |domain: &str, ip: &str| filter.is_blocked_for_client(domain, ip.parse().unwrap());
+        assert!(blocked("guest.example", "192.168.50.20"));
+        assert!(!blocked("lan.example", "192.168.50.20"));
+        assert!(blocked("lan.example", "192.168.1.20"));
+        // Exact IPs beat ranges
+        assert!(blocked("laptop.example", "192.168.50.9"));
+        // Privacy addresses rotate within the /64
+        assert!(blocked(
+            "tablet.example",
+            "2001:db8:0:1:1c2d:3e4f:5a6b:7c8d"
+        ));
+        assert!(!blocked("tablet.example", "2001:db8:0:2::1"));
+
+        assert_eq!(filter.range_assignments()[0].profile_id, "tablet");
+        assert_eq!(
+            filter
+                .matching_range(&"192.168.50.1".parse().unwrap())
+                .unwrap()
+                .profile_id,
+            "guest"
+        );
+        assert_eq!(
+            filter
+                .overlapping_ranges(&"192.168.50.128/25".parse().unwrap())
+                .len(),
+            2
+        );
+        filter.remove_range_profile(&"192.168.50.0/24".parse().unwrap());
+        assert!(blocked("lan.example", "192.168.50.20"));
+    }
+
+    #[test]
+    fn test_category_priority_attribution() {
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        filter
+            .blocklist_manager()
+            .add_domain("both.example.com", "ads");
+        filter
+            .blocklist_manager()
+            .add_domain("both.example.com", "malware");
+        filter
+            .blocklist_manager()
+            .load_list("||rule.example.com^", "adblock", "ads");
+        filter
+            .blocklist_manager()
+            .add_domain("rule.example.com", "phishing");
+
+        // Overlapping lists report the highest-priority category, whatever
+        // the order of the profile's categories
+        let result = filter.check("both.example.com", None);
+        assert_eq!(result.category.as_deref(), Some("malware"));
+        assert_eq!(
+            filter.check("rule.example.com", None).category.as_deref(),
+            Some("phishing")
+        );
+
+        // Categories the profile doesn't block are never reported
+        let ip: IpAddr = "192.168.1.50".parse().unwrap();
+        filter.assign_profile_to_ip(
+            ip,
+            DeviceProfile {
+                blocked_categories: vec!["ads".to_string()],
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            filter
+                .check("both.example.com", Some(ip))
+                .category
+                .as_deref(),
+            Some("ads")
+        );
+
+        filter.assign_profile_to_ip(
+            ip,
+            DeviceProfile {
+                blocked_categories: vec!["tracking".to_string()],
+                ..Default::default()
+            },
+        );
+        assert!(!filter.is_blocked_for_client("both.example.com", ip));
+    }
+
+    #[test]
+    fn test_trace_query() {
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        filter
+            .blocklist_manager()
+            .add_domain("both.example.com", "ads");
+        filter
+            .blocklist_manager()
+            .add_domain("*.example.com", "malware");
+        filter
+            .blocklist_manager()
+            .load_list("@@||safe.example.com^", "adblock", "malware");
+
+        let trace = filter.trace_query("both.example.com", None, None);
+        assert_eq!(trace.result.category.as_deref(), Some("malware"));
+        let last = trace.stages.last().unwrap();
+        assert_eq!(
+            (last.stage, last.matched),
+            (FilterStage::CategoryBlocklist, true)
+        );
+        assert!(trace.stages[..trace.stages.len() - 1]
+            .iter()
+            .all(|s| !s.matched));
+        let winners: Vec<&str> = trace
+            .matches
+            .iter()
+            .filter(|m| m.winner)
+            .map(|m| m.entry.as_str())
+            .collect();
+        assert_eq!(winners, vec!["*.example.com"]);
+        assert_eq!(trace.matches.len(), 2);
+
+        // An exception rule wins over the wildcard entry
+        let trace = filter.trace_query("safe.example.com", None, None);
+        assert_eq!(trace.result.reason, FilterReason::ExceptionRule);
+        assert_eq!(trace.stages.last().unwrap().stage, FilterStage::FilterRules);
+        let winner = trace.matches.iter().find(|m| m.winner).unwrap();
+        assert_eq!(winner.kind, TraceMatchKind::Rule);
+
+        filter.add_to_global_allowlist("both.example.com");
+        let trace = filter.trace_query("both.example.com", None, None);
+        assert_eq!(trace.stages.len(), 1);
+        assert!(trace.matches.iter().all(|m| !m.winner));
+    }
+
+    #[test]
+    fn test_temporary_overrides() {
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        let expires_at = chrono::Utc::now() + chrono::Duration::minutes(30);
+        assert!(filter.is_blocked("doubleclick.net"));
+
+        filter
+            .overrides()
+            .allow("doubleclick.net", Some("default"), expires_at);
+        let result = filter.check("ad.doubleclick.net", None);
+        assert_eq!(result.reason, FilterReason::TemporaryAllowlist);
+        assert!(!filter.is_blocked("doubleclick.net"));
+
+        // A pause on another profile doesn't apply to the default one
+        filter.overrides().pause(Some("kid"), expires_at);
+        assert!(filter.is_blocked("criteo.com"));
+        filter.overrides().pause(None, expires_at);
+        assert_eq!(
+            filter.check("criteo.com", None).reason,
+            FilterReason::BlockingPaused
+        );
+
+        filter.overrides().resume(None);
+        filter
+            .overrides()
+            .allow("doubleclick.net", Some("default"), chrono::Utc::now());
+        assert!(filter.is_blocked("criteo.com"));
+        assert!(filter.is_blocked("doubleclick.net"));
+    }
+
     #[test]
     fn test_allowlist_priority() {
         let legacy = Arc::new(FilterEngine::new());
@@ -444,6 +1369,94 @@ mod tests {
         assert!(!filter.is_blocked("github.com"), "github.com should NOT be blocked");
     }
 
+    #[test]
+    fn test_adblock_rules_per_query() {
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        filter.blocklist_manager().load_list(
+            "||cdn.example.com^\n@@||img.cdn.example.com^\n||v6.example.com^$dnstype=AAAA\n||games.example.com^$client=192.168.1.0/24",
+            "adblock",
+            "ads",
+        );
+
+        // Exception beats the plain block
+        let result = filter.check("img.cdn.example.com", None);
+        assert_eq!(result.decision, FilterDecision::Allow);
+        assert_eq!(result.reason, FilterReason::ExceptionRule);
+        assert!(filter.is_blocked("www.cdn.example.com"));
+
+        // $dnstype only applies to the given query type
+        assert_eq!(
+            filter
+                .check_query("v6.example.com", None, Some(28))
+                .decision,
+            FilterDecision::Block
+        );
+        assert_eq!(
+            filter.check_query("v6.example.com", None, Some(1)).decision,
+            FilterDecision::Allow
+        );
+
+        // $client only applies inside the subnet
+        let kid: IpAddr = "192.168.1.20".parse().unwrap();
+        let other: IpAddr = "10.0.0.20".parse().unwrap();
+        assert!(filter.is_blocked_for_client("games.example.com", kid));
+        assert!(!filter.is_blocked_for_client("games.example.com", other));
+    }
+
+    #[test]
+    fn test_rpz_policies() {
+        let legacy = Arc::new(FilterEngine::new());
+        let filter = UnifiedFilter::new(legacy);
+        filter.blocklist_manager().load_list(
+            "$ORIGIN rpz.example.net.\nbad.example.com CNAME .\n*.bad.example.com CNAME .\nok.bad.example.com CNAME rpz-passthru.\nquiet.example.com CNAME *.\nportal.example.com A 10.0.0.10\n*.wild.example.com CNAME .\n",
+            "rpz",
+            "malware",
+        );
+
+        assert!(filter.is_blocked("x.bad.example.com"));
+        // A wildcard trigger leaves its apex alone
+        assert!(filter.is_blocked("a.wild.example.com"));
+        assert!(!filter.is_blocked("wild.example.com"));
+        assert_eq!(
+            filter.check("ok.bad.example.com", None).reason,
+            FilterReason::ExceptionRule
+        );
+
+        let result = filter.check("quiet.example.com", None);
+        assert_eq!(result.decision, FilterDecision::Block);
+        assert_eq!(result.rewrite, Some(RpzAction::Nodata));
+
+        let result = filter.check("portal.example.com", None);
+        assert_eq!(result.decision, FilterDecision::Allow);
+        assert_eq!(result.reason, FilterReason::PolicyRewrite);
+        assert!(
+            matches!(result.rewrite, Some(RpzAction::LocalData(ref r)) if r[0].data == "10.0.0.10")
+        );
+
+        // Names on several stages export the action the filter applies
+        filter.add_to_global_allowlist("bad.example.com");
+        filter
+            .blocklist_manager()
+            .add_domain("ok.bad.example.com", "malware");
+        assert_eq!(
+            filter.check("ok.bad.example.com", None).reason,
+            FilterReason::ExceptionRule
+        );
+
+        let zone = filter.export_rpz("rpz.shield.local");
+        assert!(zone.contains("\nbad.example.com CNAME rpz-passthru.\n"));
+        assert!(!zone.contains("\nbad.example.com CNAME .\n"));
+        assert!(!zone.contains("\nok.bad.example.com CNAME .\n"));
+        assert!(zone.contains("*.bad.example.com CNAME .\n"));
+        assert!(zone.contains("ok.bad.example.com CNAME rpz-passthru.\n"));
+        assert!(zone.contains("quiet.example.com CNAME *.\n"));
+        assert!(zone.contains("portal.example.com 300 IN A 10.0.0.10\n"));
+        assert!(zone.contains("doubleclick.net CNAME .\n"));
+        assert!(zone.contains("*.wild.example.com CNAME .\n"));
+        assert!(!zone.contains("\nwild.example.com CNAME"));
+    }
+
     #[test]
     fn test_filter_result_details() {
         let legacy = Arc::new(FilterEngine::new());
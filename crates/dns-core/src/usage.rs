@@ -0,0 +1,429 @@
+//! Daily usage budgets (screen time)
+//!
+//! A profile can give a service, service group or blocklist category a daily
+//! budget. Usage is estimated from DNS activity: consecutive queries to a
+//! target no more than the idle gap apart count as continuous use. Once the
+//! budget is spent the target is blocked until midnight in the profile's
+//! timezone.
+//!
+//! The tracker only holds today's counters in memory; callers persist them
+//! with [`UsageTracker::take_changed`] and load them back with
+//! [`UsageTracker::restore`] so a restart doesn't hand out a fresh budget.
+
+use crate::services::Service;
+use crate::unified_filter::DeviceProfile;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use dashmap::DashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Queries further apart than this start a new session
+pub const DEFAULT_IDLE_GAP: Duration = Duration::minutes(5);
+
+/// What a budget applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum QuotaTarget {
+    /// Service ID from the catalog (e.g. "youtube")
+    Service(String),
+    /// Blocklist category or service group (e.g. "gaming")
+    Category(String),
+}
+
+impl QuotaTarget {
+    pub fn name(&self) -> &str {
+        match self {
+            QuotaTarget::Service(name) | QuotaTarget::Category(name) => name,
+        }
+    }
+
+    /// Whether a query to a domain in `service` and listed in `categories`
+    /// counts against the target
+    pub fn covers(&self, service: Option<&Service>, categories: &[String]) -> bool {
+        match self {
+            QuotaTarget::Service(id) => service.is_some_and(|s| s.id.eq_ignore_ascii_case(id)),
+            QuotaTarget::Category(name) => {
+                service.is_some_and(|s| s.group.eq_ignore_ascii_case(name))
+                    || categories.iter().any(|c| c.eq_ignore_ascii_case(name))
+            }
+        }
+    }
+}
+
+/// Daily time budget for a target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageQuota {
+    pub target: QuotaTarget,
+    pub daily_minutes: u32,
+}
+
+impl UsageQuota {
+    fn budget(&self) -> Duration {
+        Duration::minutes(self.daily_minutes as i64)
+    }
+}
+
+/// A budget that has just run out
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaExhausted {
+    pub profile_id: String,
+    pub profile_name: String,
+    pub target: QuotaTarget,
+    pub daily_minutes: u32,
+    /// Local midnight, when the target unblocks
+    pub resets_at: DateTime<Utc>,
+}
+
+/// Today's usage of one budget
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub target: QuotaTarget,
+    pub daily_minutes: u32,
+    pub used_secs: i64,
+    pub remaining_secs: i64,
+    pub exhausted: bool,
+    pub resets_at: DateTime<Utc>,
+}
+
+/// Saved usage of one budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    pub profile_id: String,
+    pub target: QuotaTarget,
+    /// Profile-local day the usage belongs to
+    pub day: NaiveDate,
+    pub used_secs: i64,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Whether the exhausted notification was already sent
+    pub notified: bool,
+}
+
+#[derive(Debug)]
+struct UsageState {
+    /// Profile-local day the usage belongs to
+    day: NaiveDate,
+    used: Duration,
+    last_seen: Option<DateTime<Utc>>,
+    notified: bool,
+    /// Changed since it was last handed out for saving
+    changed: bool,
+}
+
+impl UsageState {
+    fn new(day: NaiveDate) -> Self {
+        Self {
+            day,
+            used: Duration::zero(),
+            last_seen: None,
+            notified: false,
+            changed: true,
+        }
+    }
+}
+
+/// Tracks budget usage per profile and target
+pub struct UsageTracker {
+    idle_gap: Duration,
+    usage: DashMap<(String, QuotaTarget), UsageState>,
+    notifier: RwLock<Option<UnboundedSender<QuotaExhausted>>>,
+}
+
+impl Default for UsageTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::with_idle_gap(DEFAULT_IDLE_GAP)
+    }
+
+    pub fn with_idle_gap(idle_gap: Duration) -> Self {
+        Self {
+            idle_gap,
+            usage: DashMap::new(),
+            notifier: RwLock::new(None),
+        }
+    }
+
+    /// Send an event on `sender` whenever a budget runs out
+    pub fn set_notifier(&self, sender: UnboundedSender<QuotaExhausted>) {
+        *self.notifier.write() = Some(sender);
+    }
+
+    /// Find an exhausted budget covering a query
+    ///
+    /// With `record`, the query also counts as activity for every budget
+    /// covering it that still has time left.
+    pub fn check<'a>(
+        &self,
+        profile: &'a DeviceProfile,
+        service: Option<&Service>,
+        categories: &[String],
+        now: DateTime<Utc>,
+        record: bool,
+    ) -> Option<&'a UsageQuota> {
+        let today = now.with_timezone(&profile.timezone).date_naive();
+        let mut exhausted = None;
+        let mut events = Vec::new();
+
+        for quota in profile
+            .quotas
+            .iter()
+            .filter(|q| q.target.covers(service, categories))
+        {
+            let mut state = self
+                .usage
+                .entry((profile.id.clone(), quota.target.clone()))
+                .or_insert_with(|| UsageState::new(today));
+            if state.day != today {
+                *state = UsageState::new(today);
+            }
+            if state.used >= quota.budget() {
+                exhausted.get_or_insert(quota);
+                continue;
+            }
+            if !record {
+                continue;
+            }
+
+            if let Some(last_seen) = state.last_seen {
+                let gap = now - last_seen;
+                if gap > Duration::zero() && gap <= self.idle_gap {
+                    state.used += gap;
+                }
+            }
+            state.last_seen = Some(now);
+            state.changed = true;
+
+            // The query that spends the budget still goes through
+            if state.used >= quota.budget() && !state.notified {
+                state.notified = true;
+                events.push(QuotaExhausted {
+                    profile_id: profile.id.clone(),
+                    profile_name: profile.name.clone(),
+                    target: quota.target.clone(),
+                    daily_minutes: quota.daily_minutes,
+                    resets_at: next_midnight(&profile.timezone, today),
+                });
+            }
+        }
+
+        if !events.is_empty() {
+            if let Some(sender) = self.notifier.read().as_ref() {
+                for event in events {
+                    let _ = sender.send(event);
+                }
+            }
+        }
+        exhausted
+    }
+
+    /// Today's usage of each of a profile's budgets
+    pub fn usage(&self, profile: &DeviceProfile, now: DateTime<Utc>) -> Vec<QuotaUsage> {
+        let today = now.with_timezone(&profile.timezone).date_naive();
+        let resets_at = next_midnight(&profile.timezone, today);
+
+        profile
+            .quotas
+            .iter()
+            .map(|quota| {
+                let used = self
+                    .usage
+                    .get(&(profile.id.clone(), quota.target.clone()))
+                    .filter(|state| state.day == today)
+                    .map(|state| state.used)
+                    .unwrap_or_else(Duration::zero);
+                let remaining = (quota.budget() - used).max(Duration::zero());
+                QuotaUsage {
+                    target: quota.target.clone(),
+                    daily_minutes: quota.daily_minutes,
+                    used_secs: used.num_seconds(),
+                    remaining_secs: remaining.num_seconds(),
+                    exhausted: remaining.is_zero(),
+                    resets_at,
+                }
+            })
+            .collect()
+    }
+
+    /// Hand out the budgets that changed since the last call, for saving
+    pub fn take_changed(&self) -> Vec<UsageRecord> {
+        let mut records = Vec::new();
+        for mut entry in self.usage.iter_mut() {
+            if !entry.changed {
+                continue;
+            }
+            entry.changed = false;
+            let (profile_id, target) = entry.key().clone();
+            records.push(UsageRecord {
+                profile_id,
+                target,
+                day: entry.day,
+                used_secs: entry.used.num_seconds(),
+                last_seen: entry.last_seen,
+                notified: entry.notified,
+            });
+        }
+        records
+    }
+
+    /// Load saved usage, e.g. after a restart
+    ///
+    /// Records from earlier days are harmless: they reset on the next query.
+    pub fn restore(&self, records: impl IntoIterator<Item = UsageRecord>) {
+        for record in records {
+            self.usage.insert(
+                (record.profile_id, record.target),
+                UsageState {
+                    day: record.day,
+                    used: Duration::seconds(record.used_secs),
+                    last_seen: record.last_seen,
+                    notified: record.notified,
+                    changed: false,
+                },
+            );
+        }
+    }
+}
+
+/// Start of the day after `day` in a timezone
+fn next_midnight(tz: &Tz, day: NaiveDate) -> DateTime<Utc> {
+    let next = day
+        .succ_opt()
+        .unwrap_or(day)
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default();
+    // Where a DST change skips midnight, the day starts an hour later
+    tz.from_local_datetime(&next)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(next + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| next.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services;
+
+    fn kid() -> DeviceProfile {
+        DeviceProfile {
+            id: "kid".to_string(),
+            name: "Kid".to_string(),
+            quotas: vec![UsageQuota {
+                target: QuotaTarget::Category("gaming".to_string()),
+                daily_minutes: 10,
+            }],
+            timezone: "America/New_York".parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_quota_sessions() {
+        let tracker = UsageTracker::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tracker.set_notifier(tx);
+        let profile = kid();
+        let roblox = services::service_for("www.roblox.com");
+        // 15:00 UTC is 10:00 in New York
+        let start = Utc.with_ymd_and_hms(2024, 1, 10, 15, 0, 0).unwrap();
+        let at = |minutes: i64| start + Duration::minutes(minutes);
+
+        // Two sessions: 0-4 (4 minutes), then a 20 minute gap, then 24-30
+        for minute in [0, 2, 4, 24, 26, 28] {
+            assert!(tracker
+                .check(&profile, roblox, &[], at(minute), true)
+                .is_none());
+        }
+        assert_eq!(tracker.usage(&profile, at(28))[0].used_secs, 8 * 60);
+        // Diagnostics don't count as activity
+        assert!(tracker
+            .check(&profile, roblox, &[], at(29), false)
+            .is_none());
+
+        assert!(tracker.check(&profile, roblox, &[], at(30), true).is_none());
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.target, QuotaTarget::Category("gaming".into()));
+        // Local midnight in New York
+        assert_eq!(
+            event.resets_at,
+            Utc.with_ymd_and_hms(2024, 1, 11, 5, 0, 0).unwrap()
+        );
+
+        assert!(tracker.check(&profile, roblox, &[], at(31), true).is_some());
+        assert!(rx.try_recv().is_err());
+        assert!(tracker
+            .check(
+                &profile,
+                services::service_for("youtube.com"),
+                &[],
+                at(31),
+                true
+            )
+            .is_none());
+        let usage = &tracker.usage(&profile, at(31))[0];
+        assert!(usage.exhausted && usage.remaining_secs == 0);
+
+        // A new local day starts a fresh budget
+        let tomorrow = Utc.with_ymd_and_hms(2024, 1, 11, 5, 30, 0).unwrap();
+        assert!(tracker
+            .check(&profile, roblox, &[], tomorrow, true)
+            .is_none());
+        assert_eq!(tracker.usage(&profile, tomorrow)[0].used_secs, 0);
+    }
+
+    #[test]
+    fn test_usage_survives_restart() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let tracker = UsageTracker::new();
+        tracker.set_notifier(tx.clone());
+        let profile = kid();
+        let roblox = services::service_for("roblox.com");
+        let start = Utc.with_ymd_and_hms(2024, 1, 10, 15, 0, 0).unwrap();
+        for minute in (0..=10).step_by(2) {
+            tracker.check(
+                &profile,
+                roblox,
+                &[],
+                start + Duration::minutes(minute),
+                true,
+            );
+        }
+        assert!(rx.try_recv().is_ok());
+
+        let saved = tracker.take_changed();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].used_secs, 10 * 60);
+        assert!(saved[0].notified);
+        // Nothing changed since
+        assert!(tracker.take_changed().is_empty());
+
+        let restarted = UsageTracker::new();
+        restarted.set_notifier(tx);
+        restarted.restore(saved);
+        let later = start + Duration::minutes(11);
+        assert!(restarted
+            .check(&profile, roblox, &[], later, true)
+            .is_some());
+        assert!(restarted.usage(&profile, later)[0].exhausted);
+        // The notification isn't sent again
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_quota_target_covers() {
+        let youtube = services::service_for("youtube.com");
+        assert!(QuotaTarget::Service("youtube".into()).covers(youtube, &[]));
+        assert!(QuotaTarget::Category("video".into()).covers(youtube, &[]));
+        assert!(QuotaTarget::Category("adult".into()).covers(None, &["adult".to_string()]));
+        assert!(!QuotaTarget::Service("netflix".into()).covers(youtube, &[]));
+    }
+}
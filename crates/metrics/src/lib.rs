@@ -4,12 +4,21 @@
 //! - Query counting (total, blocked, allowed)
 //! - Cache hit/miss tracking
 //! - Response time histograms
-//! - Prometheus-compatible exports
+//! - Labeled counters and stage latency histograms in OpenMetrics format
 //! - Query history logging
+//! - Per-minute rollups by client, profile and category
+//! - Top-domain and unique-count sketches
 
+pub mod openmetrics;
+pub mod rollup;
+pub mod sketch;
+
+use openmetrics::{CounterVec, Exposition, HistogramVec, LATENCY_BUCKETS};
 use parking_lot::RwLock;
+use rollup::RollupAggregator;
 use serde::Serialize;
-use std::collections::{HashMap, VecDeque};
+use sketch::{TrafficSketches, WINDOWS, WINDOW_SECS};
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -53,23 +62,58 @@ impl ResponseTimeHistogram {
     }
 }
 
-/// Statistics for a specific client
+/// Statistics for one device or profile over a period
 #[derive(Debug, Clone, Serialize, Default)]
 pub struct ClientStats {
     pub total_queries: u64,
     pub blocked_queries: u64,
     pub allowed_queries: u64,
-    pub last_seen: i64,
+    pub cached_queries: u64,
+    pub block_rate: f64,
+    /// Unix timestamp of the latest query on record
+    pub last_seen: Option<u64>,
+    /// Most queried domains. Approximate: only the busiest domains of each
+    /// flush interval are persisted.
+    pub top_domains: Vec<NamedCount>,
+    pub top_blocked_domains: Vec<NamedCount>,
+    pub categories: Vec<NamedCount>,
+}
+
+/// Query counts for a domain or category
+#[derive(Debug, Clone, Serialize, Default, PartialEq, Eq)]
+pub struct NamedCount {
+    pub name: String,
+    pub queries: u64,
+    pub blocked: u64,
 }
 
 /// Individual query log entry
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct QueryLogEntry {
     pub timestamp: u64,
     pub domain: String,
     pub client_ip: String,
     pub blocked: bool,
     pub response_time_ms: u64,
+    /// Query type (1 = A, 28 = AAAA, ...), when known
+    pub qtype: Option<u16>,
+    /// Response code sent to the client (0 = NOERROR, 3 = NXDOMAIN)
+    pub rcode: u16,
+    /// Answer record data
+    pub answers: Vec<String>,
+    /// Filter reason, e.g. `category_block`
+    pub reason: Option<String>,
+    pub category: Option<String>,
+    pub profile_id: Option<String>,
+    /// Registry device the client maps to
+    pub device_id: Option<String>,
+    /// Upstream that answered; `None` when answered locally
+    pub upstream: Option<String>,
+    pub cached: bool,
+    /// DNSSEC status of upstream answers
+    pub dnssec: Option<String>,
+    /// `udp`, `tcp`, `dot`, `doh` or `api`
+    pub protocol: String,
 }
 
 /// Query history with circular buffer
@@ -89,19 +133,18 @@ impl QueryLog {
 
     /// Record a new query to the log
     pub fn record(&self, domain: String, client_ip: String, blocked: bool, response_time_ms: u64) {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-
-        let entry = QueryLogEntry {
-            timestamp,
+        self.push(QueryLogEntry {
+            timestamp: unix_now(),
             domain,
             client_ip,
             blocked,
             response_time_ms,
-        };
+            ..Default::default()
+        });
+    }
 
+    /// Add a fully populated entry to the log
+    pub fn push(&self, entry: QueryLogEntry) {
         let mut entries = self.entries.write();
 
         // If at capacity, remove oldest entry
@@ -119,6 +162,31 @@ impl QueryLog {
     }
 }
 
+/// Query type mnemonic for metric labels (RFC 3597 `TYPEn` for others)
+fn qtype_label(qtype: u16) -> String {
+    match qtype {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+/// Current Unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 impl Default for QueryLog {
     fn default() -> Self {
         Self::new(100)
@@ -151,10 +219,19 @@ struct MetricsCollectorInner {
     cache_hits: AtomicU64,
     cache_misses: AtomicU64,
     histogram: RwLock<ResponseTimeHistogram>,
-    client_stats: RwLock<HashMap<String, ClientStats>>,
     query_log: QueryLog,
+    rollups: RollupAggregator,
+    sketches: TrafficSketches,
+    queries: CounterVec,
+    stage_latency: HistogramVec,
+    plugin_executions: CounterVec,
 }
 
+/// Labels of `dns_queries_total`, in the order `query_labels` returns them
+const QUERY_LABELS: &[&str] = &[
+    "decision", "reason", "category", "protocol", "qtype", "upstream",
+];
+
 impl Default for MetricsCollector {
     fn default() -> Self {
         Self::new()
@@ -171,8 +248,12 @@ impl MetricsCollector {
                 cache_hits: AtomicU64::new(0),
                 cache_misses: AtomicU64::new(0),
                 histogram: RwLock::new(ResponseTimeHistogram::default()),
-                client_stats: RwLock::new(HashMap::new()),
                 query_log: QueryLog::new(100),
+                rollups: RollupAggregator::new(),
+                sketches: TrafficSketches::new(),
+                queries: CounterVec::new(QUERY_LABELS),
+                stage_latency: HistogramVec::new(&["stage"], LATENCY_BUCKETS),
+                plugin_executions: CounterVec::new(&["plugin", "result"]),
             }),
         }
     }
@@ -194,10 +275,50 @@ impl MetricsCollector {
         blocked: bool,
         response_time_ms: u64,
     ) {
-        self.record_query(blocked);
-        self.inner
-            .query_log
-            .record(domain, client_ip, blocked, response_time_ms);
+        self.record_query_entry(QueryLogEntry {
+            timestamp: unix_now(),
+            domain,
+            client_ip,
+            blocked,
+            response_time_ms,
+            ..Default::default()
+        });
+    }
+
+    /// Record a query with its full log entry
+    pub fn record_query_entry(&self, entry: QueryLogEntry) {
+        self.record_query(entry.blocked);
+        let qtype = entry.qtype.map(qtype_label);
+        let upstream = match (&entry.upstream, entry.cached) {
+            (Some(upstream), _) => upstream.as_str(),
+            (None, true) => "cache",
+            (None, false) => "none",
+        };
+        self.inner.queries.inc(&[
+            if entry.blocked { "blocked" } else { "allowed" },
+            entry.reason.as_deref().unwrap_or("none"),
+            entry.category.as_deref().unwrap_or("none"),
+            if entry.protocol.is_empty() {
+                "unknown"
+            } else {
+                &entry.protocol
+            },
+            qtype.as_deref().unwrap_or("unknown"),
+            upstream,
+        ]);
+        self.inner.rollups.record(&entry);
+        self.inner.sketches.record(&entry);
+        self.inner.query_log.push(entry);
+    }
+
+    /// Per-minute counts waiting to be persisted
+    pub fn rollups(&self) -> &RollupAggregator {
+        &self.inner.rollups
+    }
+
+    /// Top domains and unique counts over the last day
+    pub fn sketches(&self) -> &TrafficSketches {
+        &self.inner.sketches
     }
 
     pub fn record_cache_hit(&self) {
@@ -208,9 +329,22 @@ impl MetricsCollector {
         self.inner.cache_misses.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Record the total time taken to answer a query
     pub fn record_response_time(&self, duration: Duration) {
         let duration_ms = duration.as_millis() as u64;
         self.inner.histogram.write().record(duration_ms);
+        self.record_stage("total", duration);
+    }
+
+    /// Record the time one stage of answering a query took,
+    /// e.g. `filter`, `cache` or `upstream`
+    pub fn record_stage(&self, stage: &str, duration: Duration) {
+        self.inner.stage_latency.observe(&[stage], duration);
+    }
+
+    /// Count a plugin run and its result, e.g. `block` or `allow`
+    pub fn record_plugin_execution(&self, plugin: &str, result: &str) {
+        self.inner.plugin_executions.inc(&[plugin, result]);
     }
 
     /// Get recent query history
@@ -233,7 +367,8 @@ impl MetricsCollector {
         };
 
         let histogram = self.inner.histogram.read().clone();
-        let unique_clients = self.inner.client_stats.read().len();
+        let day_ago = unix_now().saturating_sub(WINDOWS as u64 * WINDOW_SECS);
+        let unique_clients = self.inner.sketches.unique_clients(day_ago) as usize;
 
         MetricsSnapshot {
             total_queries,
@@ -247,32 +382,50 @@ impl MetricsCollector {
         }
     }
 
-    pub fn to_prometheus(&self) -> String {
+    /// Write the collector's metric families
+    pub fn encode(&self, out: &mut Exposition) {
         let snapshot = self.snapshot();
-        let mut output = String::new();
-
-        output.push_str("# HELP dns_queries_total Total number of DNS queries\n");
-        output.push_str("# TYPE dns_queries_total counter\n");
-        output.push_str(&format!("dns_queries_total {}\n", snapshot.total_queries));
-
-        output.push_str("# HELP dns_queries_blocked_total Total blocked queries\n");
-        output.push_str("# TYPE dns_queries_blocked_total counter\n");
-        output.push_str(&format!(
-            "dns_queries_blocked_total {}\n",
-            snapshot.blocked_queries
-        ));
-
-        output.push_str("# HELP dns_cache_hits_total Total cache hits\n");
-        output.push_str("# TYPE dns_cache_hits_total counter\n");
-        output.push_str(&format!("dns_cache_hits_total {}\n", snapshot.cache_hits));
-
-        output.push_str("# HELP dns_cache_hit_rate Cache hit rate\n");
-        output.push_str("# TYPE dns_cache_hit_rate gauge\n");
-        output.push_str(&format!(
-            "dns_cache_hit_rate {:.4}\n",
-            snapshot.cache_hit_rate
-        ));
-
-        output
+        out.counter_vec(
+            "dns_queries",
+            "Answered DNS queries by decision, filter reason, category, protocol, query type and upstream",
+            &self.inner.queries,
+        );
+        out.counter(
+            "dns_cache_hits",
+            "Answers served from the cache",
+            snapshot.cache_hits,
+        );
+        out.counter(
+            "dns_cache_misses",
+            "Lookups that missed the cache",
+            snapshot.cache_misses,
+        );
+        out.gauge(
+            "dns_cache_hit_rate",
+            "Share of lookups served from the cache",
+            snapshot.cache_hit_rate,
+        );
+        out.histogram_vec(
+            "dns_stage_duration_seconds",
+            "Time spent in each stage of answering a query",
+            &self.inner.stage_latency,
+        );
+        out.counter_vec(
+            "dns_plugin_executions",
+            "Plugin runs by plugin and result",
+            &self.inner.plugin_executions,
+        );
+        out.gauge(
+            "dns_unique_clients",
+            "Estimated distinct clients over the last 24 hours",
+            snapshot.unique_clients,
+        );
+    }
+
+    /// The collector's metrics as a complete OpenMetrics exposition
+    pub fn to_prometheus(&self) -> String {
+        let mut out = Exposition::new();
+        self.encode(&mut out);
+        out.finish()
     }
 }
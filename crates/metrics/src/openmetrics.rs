@@ -0,0 +1,348 @@
+//! Labeled metric families and OpenMetrics text exposition
+//!
+//! `CounterVec` and `HistogramVec` hold series updated on the query path.
+//! `Exposition` writes them, along with gauges read at scrape time, in the
+//! OpenMetrics 1.0 text format.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Content type of [`Exposition::finish`] output
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Series kept per family; later label combinations are counted under
+/// `OVERFLOW_LABEL` so a bad label source cannot grow memory without bound
+pub const MAX_SERIES: usize = 10_000;
+pub const OVERFLOW_LABEL: &str = "other";
+
+/// Latency buckets in seconds, from 100µs to 5s
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Series of one family keyed by their label values
+#[derive(Debug)]
+struct Series<T> {
+    labels: &'static [&'static str],
+    series: RwLock<HashMap<Vec<String>, T>>,
+}
+
+impl<T> Series<T> {
+    fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            series: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Apply `update` to the series for `values`, creating it if needed
+    fn with(&self, values: &[&str], create: impl FnOnce() -> T, update: impl FnOnce(&T)) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        if let Some(series) = self.series.read().get(&key) {
+            update(series);
+            return;
+        }
+
+        let mut series = self.series.write();
+        let key = if series.len() < MAX_SERIES || series.contains_key(&key) {
+            key
+        } else {
+            vec![OVERFLOW_LABEL.to_string(); self.labels.len()]
+        };
+        update(series.entry(key).or_insert_with(create));
+    }
+
+    /// Series sorted by label values
+    fn sorted<R>(&self, read: impl Fn(&T) -> R) -> Vec<(Vec<String>, R)> {
+        let mut all: Vec<(Vec<String>, R)> = self
+            .series
+            .read()
+            .iter()
+            .map(|(labels, series)| (labels.clone(), read(series)))
+            .collect();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        all
+    }
+}
+
+/// Counters split by label values
+#[derive(Debug)]
+pub struct CounterVec {
+    series: Series<AtomicU64>,
+}
+
+impl CounterVec {
+    pub fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            series: Series::new(labels),
+        }
+    }
+
+    /// Add one to the counter for `values`, given in label order
+    pub fn inc(&self, values: &[&str]) {
+        self.inc_by(values, 1);
+    }
+
+    pub fn inc_by(&self, values: &[&str], amount: u64) {
+        self.series.with(
+            values,
+            || AtomicU64::new(0),
+            |counter| {
+                counter.fetch_add(amount, Ordering::Relaxed);
+            },
+        );
+    }
+
+    /// Current value for `values`
+    pub fn get(&self, values: &[&str]) -> u64 {
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.series
+            .series
+            .read()
+            .get(&key)
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    /// Observations per bucket (not cumulative); the last is `+Inf`
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+/// Cumulative histograms split by label values
+#[derive(Debug)]
+pub struct HistogramVec {
+    bounds: &'static [f64],
+    series: Series<Histogram>,
+}
+
+impl HistogramVec {
+    pub fn new(labels: &'static [&'static str], bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            series: Series::new(labels),
+        }
+    }
+
+    pub fn observe(&self, values: &[&str], duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(self.bounds.len());
+        let create = || Histogram {
+            buckets: (0..=self.bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        };
+        self.series.with(values, create, |histogram| {
+            histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            histogram.count.fetch_add(1, Ordering::Relaxed);
+            histogram
+                .sum_nanos
+                .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        });
+    }
+
+    /// Number of observations for `values`
+    pub fn count(&self, values: &[&str]) -> u64 {
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.series
+            .series
+            .read()
+            .get(&key)
+            .map_or(0, |h| h.count.load(Ordering::Relaxed))
+    }
+}
+
+/// OpenMetrics text writer
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.out, "{}=\"{}\"", label, value);
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// Unlabeled counter; `name` is the family name without `_total`
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help);
+        self.sample(&format!("{}_total", name), &[], value);
+    }
+
+    pub fn counter_vec(&mut self, name: &str, help: &str, counters: &CounterVec) {
+        self.family(name, "counter", help);
+        let sample = format!("{}_total", name);
+        let labels = counters.series.labels;
+        for (values, value) in counters.series.sorted(|c| c.load(Ordering::Relaxed)) {
+            let pairs: Vec<(&str, &str)> = labels
+                .iter()
+                .copied()
+                .zip(values.iter().map(String::as_str))
+                .collect();
+            self.sample(&sample, &pairs, value);
+        }
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    /// Gauge with one sample per set of label values
+    pub fn gauge_vec<V: Display>(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        samples: impl IntoIterator<Item = (Vec<String>, V)>,
+    ) {
+        self.family(name, "gauge", help);
+        for (values, value) in samples {
+            let pairs: Vec<(&str, &str)> = labels
+                .iter()
+                .copied()
+                .zip(values.iter().map(String::as_str))
+                .collect();
+            self.sample(name, &pairs, value);
+        }
+    }
+
+    pub fn histogram_vec(&mut self, name: &str, help: &str, histograms: &HistogramVec) {
+        self.family(name, "histogram", help);
+        let labels = histograms.series.labels;
+        let snapshots = histograms.series.sorted(|h| {
+            let buckets: Vec<u64> = h
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect();
+            (buckets, h.sum_nanos.load(Ordering::Relaxed))
+        });
+
+        let bucket_name = format!("{}_bucket", name);
+        let bounds: Vec<String> = histograms
+            .bounds
+            .iter()
+            .map(|bound| format!("{:?}", bound))
+            .chain(std::iter::once("+Inf".to_string()))
+            .collect();
+        for (values, (buckets, sum_nanos)) in snapshots {
+            let mut pairs: Vec<(&str, &str)> = labels
+                .iter()
+                .copied()
+                .zip(values.iter().map(String::as_str))
+                .collect();
+            // Count from the buckets so `+Inf` and `_count` always agree
+            let mut cumulative = 0;
+            for (observed, le) in buckets.iter().zip(&bounds) {
+                cumulative += observed;
+                pairs.push(("le", le));
+                self.sample(&bucket_name, &pairs, cumulative);
+                pairs.pop();
+            }
+            self.sample(&format!("{}_count", name), &pairs, cumulative);
+            self.sample(&format!("{}_sum", name), &pairs, sum_nanos as f64 / 1e9);
+        }
+    }
+
+    /// The exposition text, terminated by `# EOF`
+    pub fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposition_format() {
+        let counters = CounterVec::new(&["decision", "category"]);
+        counters.inc(&["blocked", "ads"]);
+        counters.inc(&["blocked", "ads"]);
+        counters.inc(&["allowed", "say \"hi\""]);
+        assert_eq!(counters.get(&["blocked", "ads"]), 2);
+
+        let latency = HistogramVec::new(&["stage"], &[0.001, 0.01]);
+        latency.observe(&["filter"], Duration::from_micros(500));
+        latency.observe(&["filter"], Duration::from_millis(5));
+        latency.observe(&["filter"], Duration::from_secs(1));
+        assert_eq!(latency.count(&["filter"]), 3);
+
+        let mut out = Exposition::new();
+        out.counter_vec("dns_queries", "Answered queries", &counters);
+        out.histogram_vec("dns_stage_duration_seconds", "Stage latency", &latency);
+        out.gauge_vec(
+            "dns_source_entries",
+            "Entries",
+            &["source"],
+            vec![(vec!["a".to_string()], 7)],
+        );
+        let text = out.finish();
+
+        let expected = "\
+# HELP dns_queries Answered queries
+# TYPE dns_queries counter
+dns_queries_total{decision=\"allowed\",category=\"say \\\"hi\\\"\"} 1
+dns_queries_total{decision=\"blocked\",category=\"ads\"} 2
+# HELP dns_stage_duration_seconds Stage latency
+# TYPE dns_stage_duration_seconds histogram
+dns_stage_duration_seconds_bucket{stage=\"filter\",le=\"0.001\"} 1
+dns_stage_duration_seconds_bucket{stage=\"filter\",le=\"0.01\"} 2
+dns_stage_duration_seconds_bucket{stage=\"filter\",le=\"+Inf\"} 3
+dns_stage_duration_seconds_count{stage=\"filter\"} 3
+dns_stage_duration_seconds_sum{stage=\"filter\"} 1.0055
+# HELP dns_source_entries Entries
+# TYPE dns_source_entries gauge
+dns_source_entries{source=\"a\"} 7
+# EOF
+";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn test_series_are_bounded() {
+        let counters = CounterVec::new(&["domain"]);
+        for i in 0..MAX_SERIES + 5 {
+            counters.inc(&[&format!("d{}", i)]);
+        }
+        assert_eq!(counters.get(&[OVERFLOW_LABEL]), 5);
+    }
+}
@@ -0,0 +1,370 @@
+//! Time-bucketed query counters
+//!
+//! Queries are counted per minute, overall and per client, profile,
+//! device and category, and per device and profile by category and
+//! domain. The counts are drained periodically into a persistent store,
+//! which adds each minute into its hour and day buckets as well.
+
+use crate::QueryLogEntry;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Bucket size of a rollup series
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 3] = [Granularity::Minute, Granularity::Hour, Granularity::Day];
+
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Granularity::Minute => 60,
+            Granularity::Hour => 3600,
+            Granularity::Day => 86_400,
+        }
+    }
+
+    /// Start of the bucket containing a Unix timestamp
+    pub fn bucket(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.seconds()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Minute => "minute",
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|g| g.as_str() == value)
+    }
+}
+
+/// Separates the owner from the counted value in scoped keys
+pub const KEY_SEPARATOR: char = '|';
+
+/// Key of a scoped dimension, e.g. a device ID and a category
+pub fn scoped_key(owner: &str, value: &str) -> String {
+    format!("{}{}{}", owner, KEY_SEPARATOR, value)
+}
+
+/// What a rollup series is counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    /// Every query (key is empty)
+    All,
+    /// Client IP address
+    Client,
+    Profile,
+    Category,
+    /// Registry device ID
+    Device,
+    /// Scoped keys: device ID and category
+    DeviceCategory,
+    /// Scoped keys: device ID and domain
+    DeviceDomain,
+    /// Scoped keys: profile ID and category
+    ProfileCategory,
+    /// Scoped keys: profile ID and domain
+    ProfileDomain,
+}
+
+impl Dimension {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dimension::All => "all",
+            Dimension::Client => "client",
+            Dimension::Profile => "profile",
+            Dimension::Category => "category",
+            Dimension::Device => "device",
+            Dimension::DeviceCategory => "device_category",
+            Dimension::DeviceDomain => "device_domain",
+            Dimension::ProfileCategory => "profile_category",
+            Dimension::ProfileDomain => "profile_domain",
+        }
+    }
+
+    /// Granularities the series is stored at. Domain series are too
+    /// numerous to keep per minute.
+    pub fn granularities(&self) -> &'static [Granularity] {
+        match self {
+            Dimension::DeviceDomain | Dimension::ProfileDomain => {
+                &[Granularity::Hour, Granularity::Day]
+            }
+            _ => &Granularity::ALL,
+        }
+    }
+
+    /// Whether keys are scoped to an owner with [`scoped_key`]
+    pub fn is_scoped(&self) -> bool {
+        matches!(
+            self,
+            Dimension::DeviceCategory
+                | Dimension::DeviceDomain
+                | Dimension::ProfileCategory
+                | Dimension::ProfileDomain
+        )
+    }
+
+    /// Whether keys are one value per domain name
+    pub fn is_domain(&self) -> bool {
+        matches!(self, Dimension::DeviceDomain | Dimension::ProfileDomain)
+    }
+
+    /// Category and domain dimensions scoped to this one's keys
+    pub fn breakdowns(&self) -> Option<(Dimension, Dimension)> {
+        match self {
+            Dimension::Device => Some((Dimension::DeviceCategory, Dimension::DeviceDomain)),
+            Dimension::Profile => Some((Dimension::ProfileCategory, Dimension::ProfileDomain)),
+            _ => None,
+        }
+    }
+}
+
+/// Counters for one bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RollupCounts {
+    pub queries: u64,
+    pub blocked: u64,
+    pub cached: u64,
+}
+
+impl RollupCounts {
+    pub fn add(&mut self, other: &RollupCounts) {
+        self.queries += other.queries;
+        self.blocked += other.blocked;
+        self.cached += other.cached;
+    }
+
+    pub fn allowed(&self) -> u64 {
+        self.queries - self.blocked
+    }
+
+    pub fn block_rate(&self) -> f64 {
+        if self.queries > 0 {
+            self.blocked as f64 / self.queries as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// One counted series bucket
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RollupKey {
+    /// Unix timestamp the bucket starts at
+    pub bucket: u64,
+    pub dimension: Dimension,
+    pub key: String,
+}
+
+/// Pending rows of one dimension, grouped by the owner of scoped keys
+/// (the whole key otherwise)
+type PendingGroups = HashMap<String, HashMap<RollupKey, RollupCounts>>;
+
+/// Group a pending row is kept under
+fn group_of(key: &RollupKey) -> &str {
+    if key.dimension.is_scoped() {
+        key.key.split(KEY_SEPARATOR).next().unwrap_or_default()
+    } else {
+        &key.key
+    }
+}
+
+/// Per-minute counters not yet written to the store
+#[derive(Debug, Default)]
+pub struct RollupAggregator {
+    pending: Mutex<HashMap<Dimension, PendingGroups>>,
+}
+
+impl RollupAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a query in its minute, overall, by client, profile, device
+    /// and category, and by the device's and profile's categories and domains
+    pub fn record(&self, entry: &QueryLogEntry) {
+        let counts = RollupCounts {
+            queries: 1,
+            blocked: entry.blocked as u64,
+            cached: entry.cached as u64,
+        };
+        let bucket = Granularity::Minute.bucket(entry.timestamp);
+        let mut keys = vec![
+            (Dimension::All, String::new()),
+            (Dimension::Client, entry.client_ip.clone()),
+        ];
+        if let Some(category) = &entry.category {
+            keys.push((Dimension::Category, category.clone()));
+        }
+        let owners = [
+            (Dimension::Profile, entry.profile_id.as_deref()),
+            (Dimension::Device, entry.device_id.as_deref()),
+        ];
+        for (dimension, owner) in owners {
+            let (Some(owner), Some((by_category, by_domain))) = (owner, dimension.breakdowns())
+            else {
+                continue;
+            };
+            keys.push((dimension, owner.to_string()));
+            if let Some(category) = &entry.category {
+                keys.push((by_category, scoped_key(owner, category)));
+            }
+            keys.push((by_domain, scoped_key(owner, &entry.domain)));
+        }
+
+        let mut pending = self.pending.lock();
+        for (dimension, key) in keys {
+            add(
+                &mut pending,
+                RollupKey {
+                    bucket,
+                    dimension,
+                    key,
+                },
+                &counts,
+            );
+        }
+    }
+
+    /// Take every pending count, leaving the aggregator empty
+    pub fn drain(&self) -> Vec<(RollupKey, RollupCounts)> {
+        std::mem::take(&mut *self.pending.lock())
+            .into_values()
+            .flat_map(|groups| groups.into_values().flatten())
+            .collect()
+    }
+
+    /// Pending counts for one dimension (and key), by minute bucket
+    pub fn pending(
+        &self,
+        dimension: Dimension,
+        key: Option<&str>,
+    ) -> Vec<(RollupKey, RollupCounts)> {
+        let pending = self.pending.lock();
+        let Some(groups) = pending.get(&dimension) else {
+            return Vec::new();
+        };
+        let rows: Box<dyn Iterator<Item = (&RollupKey, &RollupCounts)>> = match key {
+            Some(key) if !dimension.is_scoped() => Box::new(groups.get(key).into_iter().flatten()),
+            _ => Box::new(groups.values().flatten()),
+        };
+        rows.filter(|(k, _)| key.is_none_or(|key| k.key == key))
+            .map(|(k, c)| (k.clone(), *c))
+            .collect()
+    }
+
+    /// Pending counts of a scoped dimension for one owner, by minute bucket
+    pub fn pending_scoped(
+        &self,
+        dimension: Dimension,
+        owner: &str,
+    ) -> Vec<(RollupKey, RollupCounts)> {
+        self.pending
+            .lock()
+            .get(&dimension)
+            .and_then(|groups| groups.get(owner))
+            .map(|rows| rows.iter().map(|(k, c)| (k.clone(), *c)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Put counts back after a failed write
+    pub fn restore(&self, rows: Vec<(RollupKey, RollupCounts)>) {
+        let mut pending = self.pending.lock();
+        for (key, counts) in rows {
+            add(&mut pending, key, &counts);
+        }
+    }
+}
+
+fn add(pending: &mut HashMap<Dimension, PendingGroups>, key: RollupKey, counts: &RollupCounts) {
+    let groups = pending.entry(key.dimension).or_default();
+    // Look the group up by reference so only a new group allocates its name
+    let group = group_of(&key);
+    match groups.get_mut(group) {
+        Some(rows) => rows.entry(key).or_default().add(counts),
+        None => {
+            let group = group.to_string();
+            groups.insert(group, HashMap::from([(key, *counts)]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollup_aggregation() {
+        let aggregator = RollupAggregator::new();
+        let entry = |timestamp: u64, blocked: bool, category: Option<&str>| QueryLogEntry {
+            timestamp,
+            domain: "example.com".to_string(),
+            client_ip: "192.168.1.20".to_string(),
+            blocked,
+            profile_id: Some("kids".to_string()),
+            device_id: Some("tablet".to_string()),
+            category: category.map(str::to_string),
+            ..Default::default()
+        };
+        aggregator.record(&entry(120, true, Some("ads")));
+        aggregator.record(&entry(150, false, None));
+        aggregator.record(&entry(185, false, None));
+
+        let mut all = aggregator.pending(Dimension::All, None);
+        all.sort_by_key(|(k, _)| k.bucket);
+        let buckets: Vec<(u64, u64, u64)> = all
+            .iter()
+            .map(|(k, c)| (k.bucket, c.queries, c.blocked))
+            .collect();
+        assert_eq!(buckets, vec![(120, 2, 1), (180, 1, 0)]);
+        assert_eq!(
+            aggregator.pending(Dimension::Category, Some("ads")).len(),
+            1
+        );
+        assert_eq!(
+            aggregator.pending(Dimension::Profile, Some("kids")).len(),
+            2
+        );
+        assert_eq!(
+            aggregator.pending(Dimension::Device, Some("tablet")).len(),
+            2
+        );
+        let domains = aggregator.pending_scoped(Dimension::DeviceDomain, "tablet");
+        assert_eq!(domains.iter().map(|(_, c)| c.queries).sum::<u64>(), 3);
+        assert_eq!(domains[0].0.key, "tablet|example.com");
+        assert_eq!(
+            aggregator.pending_scoped(Dimension::ProfileCategory, "kids")[0]
+                .0
+                .key,
+            "kids|ads"
+        );
+        assert!(aggregator
+            .pending_scoped(Dimension::DeviceDomain, "tablet|example.com")
+            .is_empty());
+        assert_eq!(
+            aggregator
+                .pending(Dimension::DeviceDomain, Some("tablet|example.com"))
+                .len(),
+            2
+        );
+
+        let drained = aggregator.drain();
+        assert!(aggregator.pending(Dimension::All, None).is_empty());
+        aggregator.restore(drained);
+        assert_eq!(aggregator.pending(Dimension::Client, None).len(), 2);
+
+        assert_eq!(Granularity::Hour.bucket(7_265), 7_200);
+        assert_eq!(Granularity::parse("day"), Some(Granularity::Day));
+    }
+}
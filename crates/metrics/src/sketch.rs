@@ -0,0 +1,515 @@
+//! Streaming sketches for top domains and unique counts
+//!
+//! Space-Saving keeps approximate top-K counts in a fixed number of slots
+//! and HyperLogLog estimates distinct counts in a fixed register array.
+//! `TrafficSketches` keeps both per hourly window, overall and per client,
+//! so top lists and unique counts cover the last day in bounded memory.
+
+use crate::QueryLogEntry;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+/// Length of one sketch window
+pub const WINDOW_SECS: u64 = 3600;
+/// Number of windows kept (24 hours)
+pub const WINDOWS: usize = 24;
+/// Domains tracked per decision in each window
+pub const DOMAIN_SLOTS: usize = 512;
+/// Domains tracked per decision for each client in each window
+pub const CLIENT_DOMAIN_SLOTS: usize = 16;
+/// Clients with their own top lists in each window; later clients still
+/// count towards the overall lists and unique counts
+pub const MAX_CLIENTS: usize = 128;
+
+/// An item with its estimated count
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeavyHitter {
+    pub item: String,
+    pub count: u64,
+    /// How much `count` may overestimate the true count
+    pub error: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counter {
+    count: u64,
+    error: u64,
+}
+
+/// Space-Saving top-K counter
+///
+/// Every item whose true count exceeds `total / capacity` is tracked, and
+/// each count is at most `error` above the true count. Counters live in
+/// fixed slots ordered by count, so finding the one to evict is a
+/// logarithmic lookup rather than a scan of every slot.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    /// Slot of each tracked item
+    slots: HashMap<String, usize>,
+    counters: Vec<(String, Counter)>,
+    /// (count, slot) of every counter, smallest first
+    by_count: BTreeSet<(u64, usize)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            slots: HashMap::new(),
+            counters: Vec::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        if let Some(&slot) = self.slots.get(item) {
+            let counter = &mut self.counters[slot].1;
+            self.by_count.remove(&(counter.count, slot));
+            counter.count += 1;
+            self.by_count.insert((counter.count, slot));
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.push(item.to_string(), Counter { count: 1, error: 0 });
+            return;
+        }
+
+        // Replace the smallest counter; the newcomer inherits its count as error
+        let Some((min, slot)) = self.by_count.pop_first() else {
+            return;
+        };
+        let (evicted, _) = std::mem::replace(
+            &mut self.counters[slot],
+            (
+                item.to_string(),
+                Counter {
+                    count: min + 1,
+                    error: min,
+                },
+            ),
+        );
+        self.slots.remove(&evicted);
+        self.slots.insert(item.to_string(), slot);
+        self.by_count.insert((min + 1, slot));
+    }
+
+    fn push(&mut self, item: String, counter: Counter) {
+        let slot = self.counters.len();
+        self.by_count.insert((counter.count, slot));
+        self.slots.insert(item.clone(), slot);
+        self.counters.push((item, counter));
+    }
+
+    fn get(&self, item: &str) -> Option<Counter> {
+        self.slots.get(item).map(|&slot| self.counters[slot].1)
+    }
+
+    /// Smallest count an untracked item could have
+    fn floor(&self) -> u64 {
+        if self.counters.len() < self.capacity {
+            0
+        } else {
+            self.by_count.first().map_or(0, |&(count, _)| count)
+        }
+    }
+
+    /// Combine another summary into this one, keeping this one's capacity
+    pub fn merge(&mut self, other: &SpaceSaving) {
+        let (own_floor, other_floor) = (self.floor(), other.floor());
+        let missing = |floor: u64| Counter {
+            count: floor,
+            error: floor,
+        };
+
+        let mut merged: Vec<(String, Counter)> =
+            Vec::with_capacity(self.counters.len() + other.counters.len());
+        for (item, own) in &self.counters {
+            let theirs = other.get(item).unwrap_or(missing(other_floor));
+            merged.push((
+                item.clone(),
+                Counter {
+                    count: own.count + theirs.count,
+                    error: own.error + theirs.error,
+                },
+            ));
+        }
+        for (item, theirs) in &other.counters {
+            if !self.slots.contains_key(item) {
+                let own = missing(own_floor);
+                merged.push((
+                    item.clone(),
+                    Counter {
+                        count: own.count + theirs.count,
+                        error: own.error + theirs.error,
+                    },
+                ));
+            }
+        }
+
+        if merged.len() > self.capacity {
+            merged.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+            merged.truncate(self.capacity);
+        }
+        self.slots.clear();
+        self.counters.clear();
+        self.by_count.clear();
+        for (item, counter) in merged {
+            self.push(item, counter);
+        }
+    }
+
+    /// The `k` largest counts, largest first
+    pub fn top(&self, k: usize) -> Vec<HeavyHitter> {
+        let mut top: Vec<HeavyHitter> = self
+            .counters
+            .iter()
+            .map(|(item, c)| HeavyHitter {
+                item: item.clone(),
+                count: c.count,
+                error: c.error,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.item.cmp(&b.item)));
+        top.truncate(k);
+        top
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+}
+
+/// HyperLogLog distinct counter (2^12 registers, about 1.6% standard error)
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    const PRECISION: u32 = 12;
+
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << Self::PRECISION],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - Self::PRECISION)) as usize;
+        // The guard bit bounds the rank when the remaining bits are all zero
+        let rest = (hash << Self::PRECISION) | (1 << (Self::PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Estimated number of distinct items inserted
+    pub fn count(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Linear counting is more accurate while many registers are empty
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (own, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *own = (*own).max(*theirs);
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Top domains split by decision
+#[derive(Debug, Clone)]
+struct DecisionSketch {
+    blocked: SpaceSaving,
+    allowed: SpaceSaving,
+}
+
+impl DecisionSketch {
+    fn new(capacity: usize) -> Self {
+        Self {
+            blocked: SpaceSaving::new(capacity),
+            allowed: SpaceSaving::new(capacity),
+        }
+    }
+
+    fn insert(&mut self, domain: &str, blocked: bool) {
+        if blocked {
+            self.blocked.insert(domain);
+        } else {
+            self.allowed.insert(domain);
+        }
+    }
+
+    /// Merge the requested decisions (`None` for both) into `into`
+    fn merge_into(&self, into: &mut SpaceSaving, blocked: Option<bool>) {
+        if blocked != Some(false) {
+            into.merge(&self.blocked);
+        }
+        if blocked != Some(true) {
+            into.merge(&self.allowed);
+        }
+    }
+}
+
+/// Sketches for one window of traffic
+#[derive(Debug, Clone)]
+struct Window {
+    start: u64,
+    domains: DecisionSketch,
+    clients: HashMap<String, DecisionSketch>,
+    unique_domains: HyperLogLog,
+    unique_clients: HyperLogLog,
+}
+
+impl Window {
+    fn new(start: u64) -> Self {
+        Self {
+            start,
+            domains: DecisionSketch::new(DOMAIN_SLOTS),
+            clients: HashMap::new(),
+            unique_domains: HyperLogLog::new(),
+            unique_clients: HyperLogLog::new(),
+        }
+    }
+
+    fn record(&mut self, entry: &QueryLogEntry) {
+        self.domains.insert(&entry.domain, entry.blocked);
+        self.unique_domains.insert(entry.domain.as_str());
+        self.unique_clients.insert(entry.client_ip.as_str());
+
+        if let Some(client) = self.clients.get_mut(&entry.client_ip) {
+            client.insert(&entry.domain, entry.blocked);
+        } else if self.clients.len() < MAX_CLIENTS {
+            let mut client = DecisionSketch::new(CLIENT_DOMAIN_SLOTS);
+            client.insert(&entry.domain, entry.blocked);
+            self.clients.insert(entry.client_ip.clone(), client);
+        }
+    }
+}
+
+/// Hourly top-domain and unique-count sketches for the last day
+#[derive(Debug, Default)]
+pub struct TrafficSketches {
+    windows: Mutex<VecDeque<Window>>,
+}
+
+impl TrafficSketches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, entry: &QueryLogEntry) {
+        let start = entry.timestamp - entry.timestamp % WINDOW_SECS;
+        let oldest = start.saturating_sub((WINDOWS as u64 - 1) * WINDOW_SECS);
+
+        let mut windows = self.windows.lock();
+        if windows.back().is_none_or(|w| w.start < start) {
+            windows.push_back(Window::new(start));
+            while windows.front().is_some_and(|w| w.start < oldest) {
+                windows.pop_front();
+            }
+        }
+        // Late entries land in their own window if it is still kept
+        if let Some(window) = windows.iter_mut().rev().find(|w| w.start == start) {
+            window.record(entry);
+        }
+    }
+
+    /// Top `k` domains in the windows overlapping `[since, now]`.
+    /// `blocked: None` counts both decisions; `client` limits to one client.
+    pub fn top_domains(
+        &self,
+        since: u64,
+        blocked: Option<bool>,
+        client: Option<&str>,
+        k: usize,
+    ) -> Vec<HeavyHitter> {
+        let capacity = if client.is_some() {
+            CLIENT_DOMAIN_SLOTS
+        } else {
+            DOMAIN_SLOTS
+        };
+        let mut top = SpaceSaving::new(capacity);
+        for window in self
+            .windows
+            .lock()
+            .iter()
+            .filter(|w| w.start + WINDOW_SECS > since)
+        {
+            let sketch = match client {
+                Some(client) => window.clients.get(client),
+                None => Some(&window.domains),
+            };
+            if let Some(sketch) = sketch {
+                sketch.merge_into(&mut top, blocked);
+            }
+        }
+        top.top(k)
+    }
+
+    /// Estimated distinct domains queried in the windows overlapping `[since, now]`
+    pub fn unique_domains(&self, since: u64) -> u64 {
+        self.unique(since, |w| &w.unique_domains)
+    }
+
+    /// Estimated distinct clients in the windows overlapping `[since, now]`
+    pub fn unique_clients(&self, since: u64) -> u64 {
+        self.unique(since, |w| &w.unique_clients)
+    }
+
+    fn unique(&self, since: u64, sketch: impl Fn(&Window) -> &HyperLogLog) -> u64 {
+        let mut merged = HyperLogLog::new();
+        for window in self
+            .windows
+            .lock()
+            .iter()
+            .filter(|w| w.start + WINDOW_SECS > since)
+        {
+            merged.merge(sketch(window));
+        }
+        merged.count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_saving_finds_heavy_hitters() {
+        let mut sketch = SpaceSaving::new(20);
+        for i in 0..5_000 {
+            sketch.insert(&format!("noise{}.example", i));
+            if i % 3 == 0 {
+                sketch.insert("ads.example");
+            }
+            if i % 4 == 0 {
+                sketch.insert("tracker.example");
+            }
+        }
+        assert_eq!(sketch.len(), 20);
+
+        let top = sketch.top(2);
+        assert_eq!(top[0].item, "ads.example");
+        assert_eq!(top[1].item, "tracker.example");
+        // Counts never underestimate and stay within their error bound
+        assert!(top[0].count >= 1_667 && top[0].count - top[0].error <= 1_667);
+
+        let mut other = SpaceSaving::new(20);
+        for _ in 0..2_000 {
+            other.insert("tracker.example");
+        }
+        sketch.merge(&other);
+        assert_eq!(sketch.top(1)[0].item, "tracker.example");
+        assert_eq!(sketch.len(), 20);
+
+        // The smallest counter is evicted, and its count carried as error
+        let mut small = SpaceSaving::new(2);
+        for item in ["a", "a", "a", "a", "b", "b", "c"] {
+            small.insert(item);
+        }
+        let top = small.top(2);
+        assert_eq!((top[0].item.as_str(), top[0].count), ("a", 4));
+        assert_eq!(
+            top[1],
+            HeavyHitter {
+                item: "c".to_string(),
+                count: 3,
+                error: 2
+            }
+        );
+        small.insert("b");
+        assert_eq!(
+            small.top(2)[1],
+            HeavyHitter {
+                item: "b".to_string(),
+                count: 4,
+                error: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_hyperloglog_estimate() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        for i in 0..50_000 {
+            hll.insert(&format!("client-{}", i));
+        }
+        let error = (hll.count() as f64 - 50_000.0).abs() / 50_000.0;
+        assert!(error < 0.05, "estimate off by {:.3}", error);
+
+        let mut small = HyperLogLog::new();
+        for i in 0..100 {
+            small.insert(&i);
+            small.insert(&i);
+        }
+        assert!((95..=105).contains(&small.count()));
+    }
+
+    #[test]
+    fn test_traffic_windows() {
+        let sketches = TrafficSketches::new();
+        let query = |timestamp: u64, domain: &str, client: &str, blocked: bool| QueryLogEntry {
+            timestamp,
+            domain: domain.to_string(),
+            client_ip: client.to_string(),
+            blocked,
+            ..Default::default()
+        };
+        let day = 86_400 * 100;
+        sketches.record(&query(day, "old.example", "10.0.0.1", true));
+        sketches.record(&query(day + 23 * 3600, "ads.example", "10.0.0.2", true));
+        sketches.record(&query(day + 23 * 3600, "ads.example", "10.0.0.2", true));
+        sketches.record(&query(
+            day + 23 * 3600 + 60,
+            "news.example",
+            "10.0.0.3",
+            false,
+        ));
+
+        let now = day + 23 * 3600 + 120;
+        let top = sketches.top_domains(now - 3600, Some(true), None, 10);
+        assert_eq!(top.len(), 1);
+        assert_eq!((top[0].item.as_str(), top[0].count), ("ads.example", 2));
+        assert_eq!(sketches.top_domains(day, None, None, 10).len(), 3);
+        assert_eq!(
+            sketches.top_domains(day, None, Some("10.0.0.3"), 10)[0].item,
+            "news.example"
+        );
+        assert_eq!(sketches.unique_clients(now - 3600), 2);
+        assert_eq!(sketches.unique_domains(day), 3);
+
+        // A new day pushes the first window out
+        sketches.record(&query(day + 24 * 3600, "news.example", "10.0.0.3", false));
+        assert_eq!(sketches.unique_clients(0), 2);
+    }
+}
@@ -0,0 +1,556 @@
+//! Device registry
+//!
+//! Gives each device on the network a stable ID, a user-given name and type,
+//! and the identifiers its DNS queries can be attributed by: IPs, CIDR
+//! ranges, DoH/DoT client IDs and MACs. Profiles list registry IDs in
+//! `device_ids`, and the profile manager expands them into the DNS filter's
+//! device maps. Clients seen for the first time at a LAN address are
+//! registered automatically, unless the IP falls in a device's CIDR range;
+//! public addresses are never registered, so internet-facing resolvers don't
+//! collect a device per stranger.
+
+use chrono::{DateTime, Duration, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use ipnet::IpNet;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use shield_db::models::DbNetworkDevice;
+use shield_db::SqliteDb;
+use shield_dns_core::prefix_map::PrefixMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// How stale `last_seen` may get before a query refreshes it
+const SEEN_RESOLUTION: Duration = Duration::seconds(60);
+
+/// Kind of device, as chosen by the user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    Phone,
+    Tablet,
+    Computer,
+    Tv,
+    Console,
+    Iot,
+    #[default]
+    Other,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Phone => "phone",
+            DeviceType::Tablet => "tablet",
+            DeviceType::Computer => "computer",
+            DeviceType::Tv => "tv",
+            DeviceType::Console => "console",
+            DeviceType::Iot => "iot",
+            DeviceType::Other => "other",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).unwrap_or_default()
+    }
+}
+
+/// A device and the identifiers its queries arrive with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkDevice {
+    pub id: Uuid,
+    pub name: String,
+    pub device_type: DeviceType,
+    #[serde(default)]
+    pub ips: Vec<IpAddr>,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    /// DoH/DoT client IDs
+    #[serde(default)]
+    pub client_ids: Vec<String>,
+    #[serde(default)]
+    pub macs: Vec<String>,
+    /// App registration from the auth service for Shield on this device
+    pub registration_id: Option<Uuid>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Account that added or claimed the device; `None` for discovered
+    /// devices nobody has claimed yet
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+impl NetworkDevice {
+    pub fn new(name: String, device_type: DeviceType) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            device_type,
+            ips: Vec::new(),
+            cidrs: Vec::new(),
+            client_ids: Vec::new(),
+            macs: Vec::new(),
+            registration_id: None,
+            first_seen: now,
+            last_seen: now,
+            user_id: None,
+        }
+    }
+
+    /// Whether `ip` is a local address on the same network as one of the
+    /// device's addresses: the same /24 for IPv4, the same /64 for IPv6
+    pub fn shares_local_network(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let prefix = if ip.is_ipv4() { 24 } else { 64 };
+        let Ok(network) = IpNet::new(ip, prefix) else {
+            return false;
+        };
+        is_local_address(ip)
+            && self
+                .ips
+                .iter()
+                .any(|device_ip| network.contains(&device_ip.to_canonical()))
+    }
+
+    fn identifiers(&self) -> Vec<Identifier> {
+        self.ips
+            .iter()
+            .map(|ip| Identifier::Ip(*ip))
+            .chain(self.cidrs.iter().map(|net| Identifier::Cidr(net.trunc())))
+            .chain(
+                self.client_ids
+                    .iter()
+                    .map(|id| Identifier::ClientId(id.clone())),
+            )
+            .chain(self.macs.iter().map(|mac| Identifier::Mac(mac.clone())))
+            .collect()
+    }
+
+    /// Lowercase and dedupe identifiers, rejecting malformed MACs
+    fn normalize(&mut self) -> Result<(), DeviceError> {
+        self.ips = self.ips.iter().map(IpAddr::to_canonical).collect();
+        dedup(&mut self.ips);
+        self.cidrs = self.cidrs.iter().map(IpNet::trunc).collect();
+        dedup(&mut self.cidrs);
+        self.client_ids = self
+            .client_ids
+            .iter()
+            .map(|id| id.trim().to_lowercase())
+            .filter(|id| !id.is_empty())
+            .collect();
+        dedup(&mut self.client_ids);
+        self.macs = self
+            .macs
+            .iter()
+            .map(|mac| normalize_mac(mac).ok_or_else(|| DeviceError::InvalidMac(mac.clone())))
+            .collect::<Result<_, _>>()?;
+        dedup(&mut self.macs);
+        Ok(())
+    }
+
+    fn from_db(db: &DbNetworkDevice) -> Option<Self> {
+        Some(Self {
+            id: Uuid::parse_str(&db.id).ok()?,
+            name: db.name.clone(),
+            device_type: DeviceType::parse(&db.device_type),
+            ips: db.ips.iter().filter_map(|ip| ip.parse().ok()).collect(),
+            cidrs: db.cidrs.iter().filter_map(|net| net.parse().ok()).collect(),
+            client_ids: db.client_ids.clone(),
+            macs: db.macs.clone(),
+            registration_id: db
+                .registration_id
+                .as_deref()
+                .and_then(|id| Uuid::parse_str(id).ok()),
+            first_seen: db.first_seen,
+            last_seen: db.last_seen,
+            user_id: db.user_id.clone(),
+        })
+    }
+
+    fn to_db(&self) -> DbNetworkDevice {
+        DbNetworkDevice {
+            id: self.id.to_string(),
+            name: self.name.clone(),
+            device_type: self.device_type.as_str().to_string(),
+            ips: self.ips.iter().map(IpAddr::to_string).collect(),
+            cidrs: self.cidrs.iter().map(IpNet::to_string).collect(),
+            client_ids: self.client_ids.clone(),
+            macs: self.macs.clone(),
+            registration_id: self.registration_id.map(|id| id.to_string()),
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            created_at: self.first_seen,
+            updated_at: Utc::now(),
+            user_id: self.user_id.clone(),
+        }
+    }
+}
+
+/// Whether an address is on a local network: private, loopback or
+/// link-local, including IPv6 unique local addresses
+fn is_local_address(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            v6.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+fn dedup<T: Clone + Eq + std::hash::Hash>(items: &mut Vec<T>) {
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(item.clone()));
+}
+
+/// Lowercase colon-separated form of a MAC address
+fn normalize_mac(mac: &str) -> Option<String> {
+    let octets: Vec<&str> = mac.trim().split([':', '-']).collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()));
+    valid.then(|| octets.join(":").to_lowercase())
+}
+
+/// Something a query can be attributed to a device by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Identifier {
+    Ip(IpAddr),
+    Cidr(IpNet),
+    ClientId(String),
+    Mac(String),
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier::Ip(ip) => write!(f, "IP {}", ip),
+            Identifier::Cidr(net) => write!(f, "range {}", net),
+            Identifier::ClientId(id) => write!(f, "client ID '{}'", id),
+            Identifier::Mac(mac) => write!(f, "MAC {}", mac),
+        }
+    }
+}
+
+/// Why a device couldn't be saved
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DeviceError {
+    #[error("{identifier} already belongs to device {device_id}")]
+    IdentifierInUse { identifier: String, device_id: Uuid },
+    #[error("Invalid MAC address '{0}'")]
+    InvalidMac(String),
+    #[error("Device {0} not found")]
+    NotFound(Uuid),
+}
+
+/// Registry of known devices, persisted to SQLite when available
+pub struct DeviceRegistry {
+    devices: DashMap<Uuid, NetworkDevice>,
+    index: DashMap<Identifier, Uuid>,
+    /// Device CIDR ranges, for attributing addresses inside them
+    ranges: RwLock<PrefixMap<Uuid>>,
+    /// Devices discovered or seen since the last flush
+    dirty: Mutex<HashSet<Uuid>>,
+    db: Option<Arc<SqliteDb>>,
+}
+
+impl DeviceRegistry {
+    /// Create an in-memory registry
+    pub fn new() -> Self {
+        Self {
+            devices: DashMap::new(),
+            index: DashMap::new(),
+            ranges: RwLock::new(PrefixMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+            db: None,
+        }
+    }
+
+    /// Create a registry backed by SQLite, loading the stored devices
+    pub fn with_sqlite(db: Arc<SqliteDb>) -> Self {
+        let registry = Self {
+            db: Some(db.clone()),
+            ..Self::new()
+        };
+        match db.get_network_devices() {
+            Ok(stored) => {
+                for device in stored.iter().filter_map(NetworkDevice::from_db) {
+                    registry.insert(device);
+                }
+                info!("Loaded {} devices from database", registry.devices.len());
+            }
+            Err(e) => error!("Failed to load devices from database: {}", e),
+        }
+        registry
+    }
+
+    fn insert(&self, device: NetworkDevice) {
+        for identifier in device.identifiers() {
+            self.index.insert(identifier, device.id);
+        }
+        let mut ranges = self.ranges.write();
+        for net in &device.cidrs {
+            ranges.insert(*net, device.id);
+        }
+        drop(ranges);
+        self.devices.insert(device.id, device);
+    }
+
+    fn persist(&self, device: &NetworkDevice) {
+        if let Some(ref db) = self.db {
+            if let Err(e) = db.upsert_network_device(&device.to_db()) {
+                warn!("Failed to persist device {}: {}", device.id, e);
+            }
+        }
+    }
+
+    /// All devices, most recently seen first
+    pub fn list(&self) -> Vec<NetworkDevice> {
+        let mut devices: Vec<NetworkDevice> = self.devices.iter().map(|d| d.clone()).collect();
+        devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen));
+        devices
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<NetworkDevice> {
+        self.devices.get(id).map(|d| d.clone())
+    }
+
+    /// Device with this IP, or with the most specific range containing it
+    pub fn find_by_ip(&self, ip: &IpAddr) -> Option<NetworkDevice> {
+        let id = self.device_id_for_ip(ip)?;
+        self.get(&id)
+    }
+
+    fn device_id_for_ip(&self, ip: &IpAddr) -> Option<Uuid> {
+        self.index
+            .get(&Identifier::Ip(ip.to_canonical()))
+            .map(|id| *id)
+            .or_else(|| self.ranges.read().lookup(ip).map(|(_, id)| *id))
+    }
+
+    pub fn find_by_client_id(&self, client_id: &str) -> Option<NetworkDevice> {
+        let id = *self
+            .index
+            .get(&Identifier::ClientId(client_id.to_lowercase()))?;
+        self.get(&id)
+    }
+
+    /// Create or replace a device, returning the previous version
+    ///
+    /// Fails if one of its identifiers belongs to another device.
+    pub fn save(&self, mut device: NetworkDevice) -> Result<Option<NetworkDevice>, DeviceError> {
+        device.normalize()?;
+        let identifiers = device.identifiers();
+        for identifier in &identifiers {
+            if let Some(owner) = self.index.get(identifier) {
+                if *owner != device.id {
+                    return Err(DeviceError::IdentifierInUse {
+                        identifier: identifier.to_string(),
+                        device_id: *owner,
+                    });
+                }
+            }
+        }
+
+        let previous = self.devices.get(&device.id).map(|d| d.clone());
+        if let Some(ref previous) = previous {
+            device.first_seen = previous.first_seen.min(device.first_seen);
+            for identifier in previous.identifiers() {
+                if !identifiers.contains(&identifier) {
+                    self.index.remove(&identifier);
+                }
+            }
+            let mut ranges = self.ranges.write();
+            for net in previous
+                .cidrs
+                .iter()
+                .filter(|net| !device.cidrs.contains(net))
+            {
+                ranges.remove(net);
+            }
+        }
+        self.persist(&device);
+        self.insert(device);
+        Ok(previous)
+    }
+
+    /// Remove a device, returning it if it existed
+    pub fn remove(&self, id: &Uuid) -> Option<NetworkDevice> {
+        let (_, device) = self.devices.remove(id)?;
+        for identifier in device.identifiers() {
+            self.index.remove_if(&identifier, |_, owner| owner == id);
+        }
+        let mut ranges = self.ranges.write();
+        for net in &device.cidrs {
+            if ranges.get(net) == Some(id) {
+                ranges.remove(net);
+            }
+        }
+        drop(ranges);
+        self.dirty.lock().remove(id);
+        if let Some(ref db) = self.db {
+            if let Err(e) = db.delete_network_device(&id.to_string()) {
+                warn!("Failed to delete device {}: {}", id, e);
+            }
+        }
+        Some(device)
+    }
+
+    /// Record a query from `ip`, registering the client if it's new and on
+    /// a local network. Returns the device ID and whether this call
+    /// registered it, or `None` for an unknown public address.
+    ///
+    /// Only updates memory; [`flush`](Self::flush) writes the changes out.
+    pub fn observe(&self, ip: IpAddr, now: DateTime<Utc>) -> Option<(Uuid, bool)> {
+        let ip = ip.to_canonical();
+        let (id, discovered) = match self.device_id_for_ip(&ip) {
+            Some(id) => {
+                match self.devices.get_mut(&id) {
+                    Some(mut device) if now - device.last_seen >= SEEN_RESOLUTION => {
+                        device.last_seen = now;
+                    }
+                    _ => return Some((id, false)),
+                }
+                (id, false)
+            }
+            None if !is_local_address(ip) => return None,
+            None => match self.index.entry(Identifier::Ip(ip)) {
+                // Another query registered it first
+                Entry::Occupied(entry) => return Some((*entry.get(), false)),
+                Entry::Vacant(entry) => {
+                    let mut device = NetworkDevice::new(ip.to_string(), DeviceType::Other);
+                    device.ips.push(ip);
+                    device.first_seen = now;
+                    device.last_seen = now;
+                    let id = device.id;
+                    entry.insert(id);
+                    self.devices.insert(id, device);
+                    info!("Discovered new device at {}", ip);
+                    (id, true)
+                }
+            },
+        };
+        self.dirty.lock().insert(id);
+        Some((id, discovered))
+    }
+
+    /// Persist devices discovered or seen since the last flush
+    pub fn flush(&self) -> usize {
+        let dirty: Vec<Uuid> = self.dirty.lock().drain().collect();
+        let mut flushed = 0;
+        for id in dirty {
+            if let Some(device) = self.get(&id) {
+                self.persist(&device);
+                flushed += 1;
+            }
+        }
+        flushed
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_registry() {
+        let registry = DeviceRegistry::new();
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        let now = Utc::now();
+
+        // First sight registers the client; later queries reuse it
+        let (id, discovered) = registry.observe(ip, now).unwrap();
+        assert!(discovered);
+        assert_eq!(
+            registry.observe(ip, now + Duration::seconds(5)),
+            Some((id, false))
+        );
+        assert_eq!(registry.get(&id).unwrap().last_seen, now);
+        registry.observe(ip, now + Duration::minutes(2));
+        assert_eq!(
+            registry.get(&id).unwrap().last_seen,
+            now + Duration::minutes(2)
+        );
+        assert_eq!(registry.flush(), 1);
+        assert_eq!(registry.flush(), 0);
+
+        let mut phone = registry.get(&id).unwrap();
+        phone.name = "Sam's phone".to_string();
+        phone.device_type = DeviceType::Phone;
+        phone.ips = vec!["fd00::20".parse().unwrap()];
+        phone.client_ids = vec!["Sam-Phone".to_string()];
+        phone.macs = vec!["AA-BB-CC-DD-EE-FF".to_string()];
+        assert!(registry.save(phone).unwrap().is_some());
+        assert!(registry.find_by_ip(&ip).is_none());
+        let phone = registry.find_by_client_id("sam-phone").unwrap();
+        assert_eq!(phone.macs, vec!["aa:bb:cc:dd:ee:ff"]);
+
+        let mut laptop = NetworkDevice::new("Laptop".to_string(), DeviceType::Computer);
+        laptop.client_ids = vec!["sam-phone".to_string()];
+        assert!(matches!(
+            registry.save(laptop.clone()),
+            Err(DeviceError::IdentifierInUse { device_id, .. }) if device_id == id
+        ));
+        laptop.client_ids.clear();
+        laptop.macs = vec!["not-a-mac".to_string()];
+        assert_eq!(
+            registry.save(laptop).unwrap_err(),
+            DeviceError::InvalidMac("not-a-mac".to_string())
+        );
+
+        // Addresses inside a device's range are attributed to it
+        let mut phone = registry.get(&id).unwrap();
+        phone.cidrs = vec!["2001:db8:0:1::/64".parse().unwrap()];
+        registry.save(phone).unwrap();
+        let privacy_ip: IpAddr = "2001:db8:0:1:1c2d:3e4f:5a6b:7c8d".parse().unwrap();
+        assert_eq!(registry.observe(privacy_ip, now), Some((id, false)));
+        // Public clients outside every range aren't registered
+        assert_eq!(registry.observe("203.0.113.9".parse().unwrap(), now), None);
+        assert_eq!(registry.observe("2001:db8::9".parse().unwrap(), now), None);
+        let (mapped, _) = registry
+            .observe("::ffff:10.0.0.9".parse().unwrap(), now)
+            .unwrap();
+        assert_eq!(
+            registry
+                .find_by_ip(&"10.0.0.9".parse().unwrap())
+                .unwrap()
+                .id,
+            mapped
+        );
+        assert!(registry.observe("fd12::9".parse().unwrap(), now).unwrap().1);
+        assert_eq!(registry.list().len(), 3);
+
+        assert!(registry.remove(&id).is_some());
+        assert!(registry.find_by_client_id("sam-phone").is_none());
+        assert_eq!(registry.list().len(), 2);
+    }
+
+    #[test]
+    fn test_shares_local_network() {
+        let mut tablet = NetworkDevice::new("Tablet".to_string(), DeviceType::Tablet);
+        tablet.ips = vec!["192.168.1.20".parse().unwrap(), "fd00::20".parse().unwrap()];
+        assert!(tablet.shares_local_network("192.168.1.5".parse().unwrap()));
+        assert!(tablet.shares_local_network("::ffff:192.168.1.5".parse().unwrap()));
+        assert!(tablet.shares_local_network("fd00::5".parse().unwrap()));
+        assert!(!tablet.shares_local_network("192.168.2.5".parse().unwrap()));
+
+        // Public addresses never count, even in a device's network
+        let mut server = NetworkDevice::new("Server".to_string(), DeviceType::Computer);
+        server.ips = vec!["203.0.113.20".parse().unwrap()];
+        assert!(!server.shares_local_network("203.0.113.5".parse().unwrap()));
+    }
+}
@@ -1,19 +1,55 @@
 //! Shield AI Profile System
 //!
 //! User/device profiles with time-based rules and parental controls.
+//!
+//! Profiles are the single persisted profile model: the manager compiles each
+//! one into a [`DeviceProfile`] and keeps the DNS filter's device assignments
+//! in sync with it.
+//!
+//! `device_ids` hold device registry IDs (see [`devices`]), or bare IPs,
+//! CIDR ranges and client IDs for devices outside the registry.
+//!
+//! A profile can inherit from a parent (a shared "Kids" base with per-child
+//! overrides). The filter always sees the effective policy, with the parent
+//! chain flattened by [`Profile::inherit_from`].
 
-use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use dashmap::DashMap;
+use ipnet::IpNet;
 use parking_lot::RwLock;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use shield_db::models::DbProfile;
 use shield_db::SqliteDb;
+use shield_dns_core::unified_filter::{DeviceProfile, UnifiedFilter};
 use std::collections::HashSet;
+use std::net::IpAddr;
 use std::sync::Arc;
+use thiserror::Error;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
+pub mod devices;
+
+pub use devices::{DeviceError, DeviceRegistry, DeviceType, NetworkDevice};
+use shield_dns_core::schedule;
+pub use shield_dns_core::schedule::{RuleAction, TimeRule};
+pub use shield_dns_core::usage::{QuotaTarget, UsageQuota};
+
+/// Most levels in an inheritance chain, counting the profile itself
+pub const MAX_INHERITANCE_DEPTH: usize = 8;
+
+/// Why a profile can't take a parent
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InheritanceError {
+    #[error("Parent profile not found")]
+    ParentNotFound,
+    #[error("A profile cannot inherit from itself or one of its descendants")]
+    Cycle,
+    #[error("Inheritance chains are limited to {MAX_INHERITANCE_DEPTH} levels")]
+    TooDeep,
+}
+
 /// Protection levels for profiles
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -25,113 +61,89 @@ pub enum ProtectionLevel {
 }
 
 impl ProtectionLevel {
+    /// Blocklist categories blocked by default at this level
     pub fn default_block_categories(&self) -> Vec<&'static str> {
         match self {
             ProtectionLevel::Kid => vec![
+                "ads",
+                "tracking",
+                "malware",
+                "phishing",
+                "cryptominers",
                 "adult",
                 "gambling",
-                "violence",
+                "social",
+            ],
+            ProtectionLevel::Teen => vec![
+                "ads",
+                "tracking",
                 "malware",
                 "phishing",
-                "social-media",
+                "cryptominers",
+                "adult",
+                "gambling",
             ],
-            ProtectionLevel::Teen => vec!["adult", "gambling", "malware", "phishing"],
-            ProtectionLevel::Adult => vec!["malware", "phishing"],
+            ProtectionLevel::Adult => vec!["ads", "tracking", "malware", "phishing"],
             ProtectionLevel::Custom => vec![],
         }
     }
 }
 
-/// Action to take when a rule matches
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum RuleAction {
-    Block,
-    Allow,
-    Monitor,
-}
-
-/// Time-based rule for controlling access
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TimeRule {
-    pub id: Uuid,
-    pub name: String,
-    pub days_of_week: Vec<Weekday>,
-    pub start_time: NaiveTime,
-    pub end_time: NaiveTime,
-    pub action: RuleAction,
-    pub domain_patterns: Vec<String>,
-}
-
-impl TimeRule {
-    pub fn new(
-        name: String,
-        days: Vec<Weekday>,
-        start: NaiveTime,
-        end: NaiveTime,
-        action: RuleAction,
-        patterns: Vec<String>,
-    ) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            name,
-            days_of_week: days,
-            start_time: start,
-            end_time: end,
-            action,
-            domain_patterns: patterns,
-        }
-    }
-
-    pub fn is_active_at(&self, time: &DateTime<Local>) -> bool {
-        if !self.days_of_week.contains(&time.weekday()) {
-            return false;
-        }
-        let current = time.time();
-        if self.start_time <= self.end_time {
-            current >= self.start_time && current <= self.end_time
-        } else {
-            current >= self.start_time || current <= self.end_time
-        }
-    }
-
-    pub fn matches_domain(&self, domain: &str) -> bool {
-        self.domain_patterns.iter().any(|pattern| {
-            if pattern.contains('*') {
-                let regex_pattern = pattern.replace(".", "\\.").replace("*", ".*");
-                Regex::new(&format!("^{}$", regex_pattern))
-                    .map(|re| re.is_match(domain))
-                    .unwrap_or(false)
-            } else {
-                domain == pattern
-            }
-        })
-    }
-}
-
 /// User or device profile
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Profile {
     pub id: Uuid,
     pub name: String,
     pub protection_level: ProtectionLevel,
+    /// Blocklist categories blocked for the profile's devices
+    #[serde(default)]
+    pub blocked_categories: Vec<String>,
     pub custom_blocklists: Vec<String>,
     pub custom_allowlists: Vec<String>,
     pub time_rules: Vec<TimeRule>,
+    /// Daily time budgets per service or category
+    #[serde(default)]
+    pub quotas: Vec<UsageQuota>,
+    /// IANA timezone the time rules and budgets run in
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    /// Profile whose lists, categories, rules and budgets this one inherits
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+    /// Categories unblocked even when a parent blocks them
+    #[serde(default)]
+    pub allowed_categories: Vec<String>,
+    /// Keep this profile's queries out of the dnstap stream
+    #[serde(default)]
+    pub dnstap_opt_out: bool,
     pub device_ids: HashSet<String>,
     pub created_at: DateTime<Utc>,
     pub enabled: bool,
 }
 
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
 impl Profile {
     pub fn new(name: String, level: ProtectionLevel) -> Self {
         Self {
             id: Uuid::new_v4(),
             name,
             protection_level: level,
+            blocked_categories: level
+                .default_block_categories()
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
             custom_blocklists: Vec::new(),
             custom_allowlists: Vec::new(),
             time_rules: Vec::new(),
+            quotas: Vec::new(),
+            timezone: Tz::UTC,
+            parent_id: None,
+            allowed_categories: Vec::new(),
+            dnstap_opt_out: false,
             device_ids: HashSet::new(),
             created_at: Utc::now(),
             enabled: true,
@@ -161,20 +173,89 @@ impl Profile {
             return false;
         }
 
-        // Check time rules
-        let now = Local::now();
-        for rule in &self.time_rules {
-            if rule.is_active_at(&now) && rule.matches_domain(domain) {
-                match rule.action {
-                    RuleAction::Block => return false,
-                    RuleAction::Allow => return true,
-                    RuleAction::Monitor => {}
-                }
-            }
+        // Check time rules (category targets need the DNS filter's lists)
+        let now = Utc::now().with_timezone(&self.timezone);
+        match schedule::resolve(&self.time_rules, &now, domain, &[]) {
+            Some(matched) => matched.rule.action != RuleAction::Block,
+            None => true,
         }
+    }
 
-        true
+    /// Layer this profile's own settings over a parent's effective policy
+    ///
+    /// The child wins every conflict. Its allowlist drops the same entries
+    /// from the inherited blocklist and vice versa; `allowed_categories`
+    /// lift inherited category blocks; its time rules are nearer, so they win
+    /// ties in [`schedule::resolve`] even against an inherited block of equal
+    /// priority and specificity; and its budgets replace the
+    /// parent's for the same target. Name, devices, timezone, the dnstap
+    /// opt-out and the enabled flag are never inherited.
+    pub fn inherit_from(&self, parent: &Profile) -> Profile {
+        let mut effective = self.clone();
+        effective.custom_allowlists = merge_list(
+            &parent.custom_allowlists,
+            &self.custom_allowlists,
+            &self.custom_blocklists,
+        );
+        effective.custom_blocklists = merge_list(
+            &parent.custom_blocklists,
+            &self.custom_blocklists,
+            &self.custom_allowlists,
+        );
+        effective.blocked_categories =
+            merge_list(&parent.blocked_categories, &self.blocked_categories, &[]);
+        effective.blocked_categories.retain(|c| {
+            !self
+                .allowed_categories
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(c))
+        });
+        effective
+            .time_rules
+            .extend(parent.time_rules.iter().map(|rule| TimeRule {
+                depth: rule.depth + 1,
+                ..rule.clone()
+            }));
+        effective.quotas.extend(
+            parent
+                .quotas
+                .iter()
+                .filter(|q| !self.quotas.iter().any(|own| own.target == q.target))
+                .cloned(),
+        );
+        effective
     }
+
+    /// Compile the profile into the form the DNS filter evaluates
+    pub fn to_device_profile(&self) -> DeviceProfile {
+        DeviceProfile {
+            id: self.id.to_string(),
+            name: self.name.clone(),
+            blocked_categories: self.blocked_categories.clone(),
+            custom_blocklist: self.custom_blocklists.clone(),
+            custom_allowlist: self.custom_allowlists.clone(),
+            time_rules: self.time_rules.clone(),
+            quotas: self.quotas.clone(),
+            timezone: self.timezone,
+            dnstap_opt_out: self.dnstap_opt_out,
+            enabled: self.enabled,
+        }
+    }
+}
+
+/// Inherited entries minus those `overrides` contradicts, then `own`
+fn merge_list(inherited: &[String], own: &[String], overrides: &[String]) -> Vec<String> {
+    let mut merged: Vec<String> = inherited
+        .iter()
+        .filter(|e| !overrides.iter().any(|o| o.eq_ignore_ascii_case(e)))
+        .cloned()
+        .collect();
+    for entry in own {
+        if !merged.iter().any(|e| e.eq_ignore_ascii_case(entry)) {
+            merged.push(entry.clone());
+        }
+    }
+    merged
 }
 
 /// Profile manager for CRUD operations with SQLite persistence
@@ -183,6 +264,17 @@ pub struct ProfileManager {
     device_to_profile: Arc<DashMap<String, Uuid>>,
     default_profile_id: Arc<RwLock<Option<Uuid>>>,
     db: Option<Arc<SqliteDb>>,
+    /// DNS filter kept in sync with profile changes
+    filter: Option<Arc<UnifiedFilter>>,
+    /// Devices that `device_ids` can refer to
+    devices: Arc<DeviceRegistry>,
+}
+
+/// Key a device is known by in the DNS filter
+enum FilterKey {
+    Ip(IpAddr),
+    Range(IpNet),
+    Device(String),
 }
 
 impl ProfileManager {
@@ -194,6 +286,8 @@ impl ProfileManager {
             device_to_profile: Arc::new(DashMap::new()),
             default_profile_id: Arc::new(RwLock::new(None)),
             db: None,
+            filter: None,
+            devices: Arc::new(DeviceRegistry::new()),
         }
     }
 
@@ -204,7 +298,9 @@ impl ProfileManager {
             profiles: Arc::new(DashMap::new()),
             device_to_profile: Arc::new(DashMap::new()),
             default_profile_id: Arc::new(RwLock::new(None)),
+            devices: Arc::new(DeviceRegistry::with_sqlite(db.clone())),
             db: Some(db),
+            filter: None,
         };
 
         // Load existing profiles from database
@@ -212,6 +308,261 @@ impl ProfileManager {
         manager
     }
 
+    /// Drive the DNS filter from these profiles, assigning every loaded
+    /// profile to its devices
+    pub fn with_filter(mut self, filter: Arc<UnifiedFilter>) -> Self {
+        self.filter = Some(filter);
+        for profile in self.list_profiles() {
+            self.sync_profile(&profile);
+        }
+        self
+    }
+
+    /// Assign the profile's effective policy to each of its devices in the
+    /// DNS filter
+    ///
+    /// Reads the parent chain from the map, so callers must not hold a
+    /// profile guard.
+    fn sync_profile(&self, profile: &Profile) {
+        let Some(ref filter) = self.filter else {
+            return;
+        };
+        let device_profile = self.flatten(profile).to_device_profile();
+        for key in profile
+            .device_ids
+            .iter()
+            .flat_map(|id| self.filter_keys(id))
+        {
+            match key {
+                FilterKey::Ip(ip) => filter.assign_profile_to_ip(ip, device_profile.clone()),
+                FilterKey::Range(range) => {
+                    filter.assign_profile_to_range(range, device_profile.clone())
+                }
+                FilterKey::Device(id) => {
+                    filter.assign_profile_to_device(&id, device_profile.clone())
+                }
+            }
+        }
+    }
+
+    /// Filter keys for an entry in `device_ids`: a registry device's IPs,
+    /// ranges and client IDs, or the entry itself
+    fn filter_keys(&self, device_id: &str) -> Vec<FilterKey> {
+        let registered = Uuid::parse_str(device_id)
+            .ok()
+            .and_then(|id| self.devices.get(&id));
+        match registered {
+            Some(device) => Self::device_keys(&device),
+            None => {
+                if let Ok(ip) = device_id.parse::<IpAddr>() {
+                    vec![FilterKey::Ip(ip)]
+                } else if let Ok(range) = device_id.parse::<IpNet>() {
+                    vec![FilterKey::Range(range)]
+                } else {
+                    vec![FilterKey::Device(device_id.to_string())]
+                }
+            }
+        }
+    }
+
+    fn device_keys(device: &NetworkDevice) -> Vec<FilterKey> {
+        device
+            .ips
+            .iter()
+            .map(|ip| FilterKey::Ip(*ip))
+            .chain(device.cidrs.iter().map(|net| FilterKey::Range(*net)))
+            .chain(device.client_ids.iter().cloned().map(FilterKey::Device))
+            .chain(std::iter::once(FilterKey::Device(device.id.to_string())))
+            .collect()
+    }
+
+    fn unsync_keys(&self, keys: Vec<FilterKey>) {
+        let Some(ref filter) = self.filter else {
+            return;
+        };
+        for key in keys {
+            match key {
+                FilterKey::Ip(ip) => filter.remove_ip_profile(&ip),
+                FilterKey::Range(range) => filter.remove_range_profile(&range),
+                FilterKey::Device(id) => filter.remove_device_profile(&id),
+            }
+        }
+    }
+
+    /// Re-sync every profile inheriting from `id`, after its policy changed
+    fn sync_descendants(&self, id: &Uuid) {
+        let mut pending = vec![*id];
+        let mut seen = HashSet::new();
+        while let Some(parent) = pending.pop() {
+            if !seen.insert(parent) {
+                continue;
+            }
+            for child in self.children(&parent) {
+                self.sync_profile(&child);
+                pending.push(child.id);
+            }
+        }
+    }
+
+    fn children(&self, id: &Uuid) -> Vec<Profile> {
+        self.profiles
+            .iter()
+            .filter(|p| p.parent_id == Some(*id))
+            .map(|p| p.clone())
+            .collect()
+    }
+
+    /// Parent chain of a profile, nearest first
+    ///
+    /// Stops at a missing parent, a loop or [`MAX_INHERITANCE_DEPTH`].
+    pub fn ancestors(&self, profile: &Profile) -> Vec<Profile> {
+        let mut chain: Vec<Profile> = Vec::new();
+        let mut next = profile.parent_id;
+        while let Some(id) = next {
+            if id == profile.id || chain.iter().any(|p| p.id == id) {
+                warn!("Profile {} has an inheritance loop", profile.id);
+                break;
+            }
+            if chain.len() + 1 >= MAX_INHERITANCE_DEPTH {
+                warn!(
+                    "Profile {} inherits too deeply; ignoring {}",
+                    profile.id, id
+                );
+                break;
+            }
+            let Some(parent) = self.get_profile(&id) else {
+                break;
+            };
+            next = parent.parent_id;
+            chain.push(parent);
+        }
+        chain
+    }
+
+    /// Merge a profile's parent chain into it, root first
+    fn flatten(&self, profile: &Profile) -> Profile {
+        let inherited = self
+            .ancestors(profile)
+            .into_iter()
+            .rev()
+            .reduce(|parent, child| child.inherit_from(&parent));
+        match inherited {
+            Some(parent) => profile.inherit_from(&parent),
+            None => profile.clone(),
+        }
+    }
+
+    /// A profile with everything it inherits merged in, as the DNS filter
+    /// evaluates it
+    pub fn effective_profile(&self, id: &Uuid) -> Option<Profile> {
+        self.get_profile(id).map(|profile| self.flatten(&profile))
+    }
+
+    /// Check that `id` can inherit from `parent_id`
+    pub fn check_parent(&self, id: &Uuid, parent_id: &Uuid) -> Result<(), InheritanceError> {
+        let parent = self
+            .get_profile(parent_id)
+            .ok_or(InheritanceError::ParentNotFound)?;
+        let chain = self.ancestors(&parent);
+        if parent.id == *id || chain.iter().any(|p| p.id == *id) {
+            return Err(InheritanceError::Cycle);
+        }
+        // Levels above the profile, the profile itself, and below it
+        if chain.len() + 2 + self.descendant_depth(id) > MAX_INHERITANCE_DEPTH {
+            return Err(InheritanceError::TooDeep);
+        }
+        Ok(())
+    }
+
+    /// Levels of profiles inheriting from `id`
+    fn descendant_depth(&self, id: &Uuid) -> usize {
+        let mut depth = 0;
+        let mut level = vec![*id];
+        while !level.is_empty() && depth < MAX_INHERITANCE_DEPTH {
+            level = level
+                .iter()
+                .flat_map(|parent| self.children(parent))
+                .map(|child| child.id)
+                .collect();
+            if !level.is_empty() {
+                depth += 1;
+            }
+        }
+        depth
+    }
+
+    /// Clear a device's assignment in the DNS filter
+    fn unsync_device(&self, device_id: &str) {
+        self.unsync_keys(self.filter_keys(device_id));
+    }
+
+    /// The device registry
+    pub fn devices(&self) -> &Arc<DeviceRegistry> {
+        &self.devices
+    }
+
+    /// Create or update a registry device, re-pointing its profile's DNS
+    /// filtering at the device's current identifiers
+    pub fn save_device(&self, device: NetworkDevice) -> Result<NetworkDevice, DeviceError> {
+        let id = device.id;
+        if let Some(previous) = self.devices.save(device)? {
+            self.unsync_keys(Self::device_keys(&previous));
+        }
+        if let Some(profile) = self.get_device_profile(&id.to_string()) {
+            self.sync_profile(&profile);
+        }
+        // A concurrent delete can remove the device before it's read back
+        self.devices.get(&id).ok_or(DeviceError::NotFound(id))
+    }
+
+    /// Remove a registry device and its profile assignment
+    pub fn delete_device(&self, id: &Uuid) -> bool {
+        let device_id = id.to_string();
+        if let Some((_, owner)) = self.device_to_profile.remove(&device_id) {
+            self.release_device(&owner, &device_id);
+        }
+        match self.devices.remove(id) {
+            Some(device) => {
+                self.unsync_keys(Self::device_keys(&device));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Profile a registry device is assigned to, directly or through one of
+    /// its IPs or client IDs
+    pub fn profile_for_device(&self, device: &NetworkDevice) -> Option<Profile> {
+        std::iter::once(device.id.to_string())
+            .chain(device.ips.iter().map(IpAddr::to_string))
+            .chain(device.cidrs.iter().map(IpNet::to_string))
+            .chain(device.client_ids.iter().cloned())
+            .find_map(|key| self.get_device_profile(&key))
+    }
+
+    /// Remove a device from whichever profile it's assigned to
+    pub fn unassign_device(&self, device_id: &str) -> bool {
+        match self.device_to_profile.remove(device_id) {
+            Some((_, owner)) => {
+                self.release_device(&owner, device_id);
+                self.unsync_device(device_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Persist a profile change
+    fn persist(&self, profile: &Profile) {
+        if let Some(ref db) = self.db {
+            // Updates leave the stored owner untouched
+            let db_profile = Self::profile_to_db(profile, None);
+            if let Err(e) = db.update_profile(&db_profile) {
+                warn!("Failed to update profile in database: {}", e);
+            }
+        }
+    }
+
     /// Load profiles from database into memory
     fn load_from_db(&self) {
         if let Some(ref db) = self.db {
@@ -236,6 +587,31 @@ impl ProfileManager {
         }
     }
 
+    /// Parse a profile's saved time rules, skipping (and reporting) any
+    /// that no longer parse rather than dropping the whole schedule
+    fn parse_time_rules(profile_id: &str, json: &str) -> Vec<TimeRule> {
+        let values: Vec<serde_json::Value> = match serde_json::from_str(json) {
+            Ok(values) => values,
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable time rules of profile {}: {}",
+                    profile_id, e
+                );
+                return Vec::new();
+            }
+        };
+        values
+            .into_iter()
+            .filter_map(|value| match TimeRule::from_stored(value) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    warn!("Ignoring a time rule of profile {}: {}", profile_id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
     /// Convert DbProfile to Profile
     fn db_to_profile(db: &DbProfile) -> Option<Profile> {
         let id = Uuid::parse_str(&db.id).ok()?;
@@ -246,17 +622,41 @@ impl ProfileManager {
             _ => ProtectionLevel::Custom,
         };
 
-        // Parse time rules from JSON
-        let time_rules: Vec<TimeRule> =
-            serde_json::from_str(&db.time_rules).unwrap_or_default();
+        let time_rules = Self::parse_time_rules(&db.id, &db.time_rules);
+
+        // Rows saved before categories were editable only hold level defaults
+        let blocked_categories = if db.blocked_categories.is_empty() {
+            protection_level
+                .default_block_categories()
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        } else {
+            db.blocked_categories.clone()
+        };
 
         Some(Profile {
             id,
             name: db.name.clone(),
             protection_level,
+            blocked_categories,
             custom_blocklists: db.custom_blocklist.clone(),
             custom_allowlists: db.custom_allowlist.clone(),
             time_rules,
+            quotas: serde_json::from_str(&db.quotas).unwrap_or_default(),
+            timezone: db.timezone.parse().unwrap_or_else(|_| {
+                warn!(
+                    "Profile {} has unknown timezone '{}', using UTC",
+                    db.id, db.timezone
+                );
+                Tz::UTC
+            }),
+            parent_id: db
+                .parent_id
+                .as_deref()
+                .and_then(|id| Uuid::parse_str(id).ok()),
+            allowed_categories: db.allowed_categories.clone(),
+            dnstap_opt_out: db.dnstap_opt_out,
             device_ids: db.device_ids.iter().cloned().collect(),
             created_at: db.created_at,
             enabled: db.enabled,
@@ -264,10 +664,10 @@ impl ProfileManager {
     }
 
     /// Convert Profile to DbProfile
-    fn profile_to_db(profile: &Profile, user_id: &str) -> DbProfile {
+    fn profile_to_db(profile: &Profile, user_id: Option<&str>) -> DbProfile {
         DbProfile {
             id: profile.id.to_string(),
-            user_id: user_id.to_string(),
+            user_id: user_id.map(str::to_string),
             name: profile.name.clone(),
             protection_level: match profile.protection_level {
                 ProtectionLevel::Kid => "kid".to_string(),
@@ -275,16 +675,16 @@ impl ProfileManager {
                 ProtectionLevel::Adult => "adult".to_string(),
                 ProtectionLevel::Custom => "custom".to_string(),
             },
-            blocked_categories: profile
-                .protection_level
-                .default_block_categories()
-                .iter()
-                .map(|s| s.to_string())
-                .collect(),
+            blocked_categories: profile.blocked_categories.clone(),
             custom_blocklist: profile.custom_blocklists.clone(),
             custom_allowlist: profile.custom_allowlists.clone(),
             time_rules: serde_json::to_string(&profile.time_rules).unwrap_or_default(),
             device_ids: profile.device_ids.iter().cloned().collect(),
+            timezone: profile.timezone.name().to_string(),
+            quotas: serde_json::to_string(&profile.quotas).unwrap_or_default(),
+            parent_id: profile.parent_id.map(|id| id.to_string()),
+            allowed_categories: profile.allowed_categories.clone(),
+            dnstap_opt_out: profile.dnstap_opt_out,
             enabled: profile.enabled,
             created_at: profile.created_at,
             updated_at: Utc::now(),
@@ -293,7 +693,7 @@ impl ProfileManager {
 
     /// Create a profile (optionally with user_id for multi-user support)
     pub fn create_profile(&self, name: String, level: ProtectionLevel) -> Uuid {
-        self.create_profile_for_user(name, level, "default")
+        self.insert_new_profile(name, level, None)
     }
 
     /// Create a profile for a specific user
@@ -302,6 +702,16 @@ impl ProfileManager {
         name: String,
         level: ProtectionLevel,
         user_id: &str,
+    ) -> Uuid {
+        self.insert_new_profile(name, level, Some(user_id))
+    }
+
+    /// Create and persist a profile, owned by `user_id` if given
+    fn insert_new_profile(
+        &self,
+        name: String,
+        level: ProtectionLevel,
+        user_id: Option<&str>,
     ) -> Uuid {
         let profile = Profile::new(name.clone(), level);
         let id = profile.id;
@@ -358,6 +768,16 @@ impl ProfileManager {
         if let Some((_, profile)) = self.profiles.remove(id) {
             for device_id in profile.device_ids {
                 self.device_to_profile.remove(&device_id);
+                self.unsync_device(&device_id);
+            }
+
+            // Children inherit from the deleted profile's parent instead
+            for mut child in self.children(id) {
+                child.parent_id = profile.parent_id;
+                self.persist(&child);
+                self.profiles.insert(child.id, child.clone());
+                self.sync_profile(&child);
+                self.sync_descendants(&child.id);
             }
             true
         } else {
@@ -366,20 +786,38 @@ impl ProfileManager {
     }
 
     pub fn assign_device(&self, device_id: String, profile_id: &Uuid) -> bool {
-        if let Some(mut profile) = self.profiles.get_mut(profile_id) {
+        if !self.profiles.contains_key(profile_id) {
+            return false;
+        }
+
+        // A device belongs to one profile at a time
+        if let Some(previous) = self.device_to_profile.get(&device_id).map(|id| *id) {
+            if previous != *profile_id {
+                self.release_device(&previous, &device_id);
+            }
+        }
+
+        let profile = {
+            let Some(mut profile) = self.profiles.get_mut(profile_id) else {
+                return false;
+            };
             profile.device_ids.insert(device_id.clone());
-            self.device_to_profile.insert(device_id, *profile_id);
+            profile.clone()
+        };
+        self.device_to_profile.insert(device_id, *profile_id);
 
-            // Update in database
-            if let Some(ref db) = self.db {
-                let db_profile = Self::profile_to_db(&profile, "default");
-                if let Err(e) = db.update_profile(&db_profile) {
-                    warn!("Failed to update profile in database: {}", e);
-                }
+        // Update in database and the DNS filter
+        self.persist(&profile);
+        self.sync_profile(&profile);
+        true
+    }
+
+    /// Drop a device from a profile's device list
+    fn release_device(&self, profile_id: &Uuid, device_id: &str) {
+        if let Some(mut profile) = self.profiles.get_mut(profile_id) {
+            if profile.device_ids.remove(device_id) {
+                self.persist(&profile);
             }
-            true
-        } else {
-            false
         }
     }
 
@@ -391,9 +829,9 @@ impl ProfileManager {
 
     pub fn is_domain_allowed_for_device(&self, device_id: &str, domain: &str) -> bool {
         if let Some(profile) = self.get_device_profile(device_id) {
-            profile.is_domain_allowed(domain)
+            self.flatten(&profile).is_domain_allowed(domain)
         } else if let Some(default) = self.default_profile_id.read().as_ref() {
-            if let Some(profile) = self.get_profile(default) {
+            if let Some(profile) = self.effective_profile(default) {
                 return profile.is_domain_allowed(domain);
             }
             true
@@ -402,24 +840,34 @@ impl ProfileManager {
         }
     }
 
-    /// Update a profile
+    /// Update a profile, returning false if it doesn't exist
     pub fn update_profile(&self, profile: Profile) -> bool {
         let id = profile.id;
+        let Some(previous) = self.get_profile(&id) else {
+            return false;
+        };
 
         // Update in database
-        if let Some(ref db) = self.db {
-            let db_profile = Self::profile_to_db(&profile, "default");
-            if let Err(e) = db.update_profile(&db_profile) {
-                warn!("Failed to update profile in database: {}", e);
-            }
+        self.persist(&profile);
+
+        // Unassign devices dropped from the profile
+        for device_id in previous.device_ids.difference(&profile.device_ids) {
+            self.device_to_profile.remove(device_id);
+            self.unsync_device(device_id);
         }
 
-        // Update device mappings
+        // Update device mappings, taking devices over from other profiles
         for device_id in &profile.device_ids {
-            self.device_to_profile.insert(device_id.clone(), id);
+            if let Some(owner) = self.device_to_profile.insert(device_id.clone(), id) {
+                if owner != id {
+                    self.release_device(&owner, device_id);
+                }
+            }
         }
 
-        self.profiles.insert(id, profile);
+        self.profiles.insert(id, profile.clone());
+        self.sync_profile(&profile);
+        self.sync_descendants(&id);
         true
     }
 
@@ -444,3 +892,173 @@ pub struct ProfileStats {
     pub assigned_devices: usize,
     pub total_rules: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone, Weekday};
+
+    #[test]
+    fn test_saved_time_rules() {
+        let rules = r#"[
+            {"name": "bedtime", "days_of_week": ["Mon"], "start_time": "21:00:00",
+             "end_time": "07:00:00", "action": "block", "categories": ["gaming"]},
+            {"name": "broken", "days_of_week": ["Mon"], "action": "block"}
+        ]"#;
+        // The unreadable rule is skipped; the rest of the schedule stays
+        let parsed = ProfileManager::parse_time_rules("kids", rules);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].name, "bedtime");
+        assert_eq!(parsed[0].overnight, schedule::Overnight::SameDay);
+        assert!(ProfileManager::parse_time_rules("kids", "not json").is_empty());
+    }
+
+    #[test]
+    fn test_profile_inheritance() {
+        let manager = ProfileManager::new();
+        let kids = manager.create_profile("Kids".to_string(), ProtectionLevel::Kid);
+        let teen = manager.create_profile("Teen".to_string(), ProtectionLevel::Custom);
+        let older = manager.create_profile("Older".to_string(), ProtectionLevel::Custom);
+
+        let mut base = manager.get_profile(&kids).unwrap();
+        base.custom_blocklists = vec!["games.example".into(), "chat.example".into()];
+        base.custom_allowlists = vec!["school.example".into()];
+        base.time_rules = vec![TimeRule::new(
+            "bedtime".into(),
+            vec![Weekday::Mon],
+            NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            RuleAction::Block,
+            vec!["*".into()],
+        )];
+        base.quotas = vec![UsageQuota {
+            target: QuotaTarget::Category("gaming".into()),
+            daily_minutes: 60,
+        }];
+        assert!(manager.update_profile(base));
+
+        let mut child = manager.get_profile(&teen).unwrap();
+        child.parent_id = Some(kids);
+        child.custom_allowlists = vec!["chat.example".into()];
+        child.allowed_categories = vec!["social".into()];
+        child.quotas = vec![UsageQuota {
+            target: QuotaTarget::Category("gaming".into()),
+            daily_minutes: 90,
+        }];
+        assert!(manager.update_profile(child));
+
+        let effective = manager.effective_profile(&teen).unwrap();
+        // The child's allowlist overrides the parent's blocklist
+        assert_eq!(effective.custom_blocklists, vec!["games.example"]);
+        assert_eq!(
+            effective.custom_allowlists,
+            vec!["school.example", "chat.example"]
+        );
+        assert!(effective
+            .blocked_categories
+            .contains(&"gambling".to_string()));
+        assert!(!effective.blocked_categories.contains(&"social".to_string()));
+        assert_eq!(effective.time_rules.len(), 1);
+        assert_eq!(effective.time_rules[0].depth, 1);
+        assert_eq!(effective.quotas.len(), 1);
+        assert_eq!(effective.quotas[0].daily_minutes, 90);
+
+        // Grandchildren see the whole chain
+        let mut grandchild = manager.get_profile(&older).unwrap();
+        grandchild.parent_id = Some(teen);
+        assert!(manager.update_profile(grandchild));
+        let ancestors: Vec<Uuid> = manager
+            .ancestors(&manager.get_profile(&older).unwrap())
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(ancestors, vec![teen, kids]);
+        assert_eq!(
+            manager.effective_profile(&older).unwrap().custom_blocklists,
+            vec!["games.example"]
+        );
+
+        assert_eq!(
+            manager.check_parent(&kids, &older),
+            Err(InheritanceError::Cycle)
+        );
+        assert_eq!(
+            manager.check_parent(&kids, &kids),
+            Err(InheritanceError::Cycle)
+        );
+        assert_eq!(
+            manager.check_parent(&kids, &Uuid::new_v4()),
+            Err(InheritanceError::ParentNotFound)
+        );
+
+        // The grandchild's own allow beats both inherited blocks of the same
+        // priority and specificity, though block would win among equals
+        let mut grandchild = manager.get_profile(&older).unwrap();
+        grandchild.time_rules = vec![TimeRule::new(
+            "homework".into(),
+            vec![Weekday::Mon],
+            NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(23, 59, 0).unwrap(),
+            RuleAction::Allow,
+            vec!["*".into()],
+        )];
+        assert!(manager.update_profile(grandchild));
+        let effective = manager.effective_profile(&older).unwrap();
+        assert_eq!(
+            effective
+                .time_rules
+                .iter()
+                .map(|r| r.depth)
+                .collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        // Monday 22:00 UTC, inside both the bedtime block and the allow rule
+        let monday_night = Utc.with_ymd_and_hms(2024, 1, 8, 22, 0, 0).unwrap();
+        let decision =
+            schedule::resolve(&effective.time_rules, &monday_night, "chat.example", &[]).unwrap();
+        assert_eq!(decision.rule.action, RuleAction::Allow);
+        let mut same_depth = effective.time_rules.clone();
+        same_depth[1].depth = 0;
+        let decision = schedule::resolve(&same_depth, &monday_night, "chat.example", &[]).unwrap();
+        assert_eq!(decision.rule.action, RuleAction::Block);
+
+        // Deleting the middle profile reattaches its child to the base
+        assert!(manager.delete_profile(&teen));
+        assert_eq!(manager.get_profile(&older).unwrap().parent_id, Some(kids));
+    }
+
+    #[test]
+    fn test_registry_devices_drive_filter() {
+        use shield_dns_core::filter::FilterEngine;
+        let filter = Arc::new(UnifiedFilter::new(Arc::new(FilterEngine::new())));
+        let manager = ProfileManager::new().with_filter(filter.clone());
+        let kids = manager.create_profile("Kids".to_string(), ProtectionLevel::Kid);
+        let mut profile = manager.get_profile(&kids).unwrap();
+        profile.custom_blocklists = vec!["games.example".to_string()];
+        manager.update_profile(profile);
+
+        let old_ip: IpAddr = "192.168.1.20".parse().unwrap();
+        let new_ip: IpAddr = "192.168.1.21".parse().unwrap();
+        let mut tablet = NetworkDevice::new("Tablet".to_string(), DeviceType::Tablet);
+        tablet.ips = vec![old_ip];
+        tablet.client_ids = vec!["tablet".to_string()];
+        let tablet = manager.save_device(tablet).unwrap();
+        assert!(manager.assign_device(tablet.id.to_string(), &kids));
+        assert!(filter.is_blocked_for_client("games.example", old_ip));
+        assert_eq!(
+            filter.get_profile_for_device("tablet").unwrap().name,
+            "Kids"
+        );
+
+        // Moving the device to a new IP moves its profile with it
+        let mut moved = tablet.clone();
+        moved.ips = vec![new_ip];
+        manager.save_device(moved.clone()).unwrap();
+        assert!(!filter.is_blocked_for_client("games.example", old_ip));
+        assert!(filter.is_blocked_for_client("games.example", new_ip));
+        assert_eq!(manager.profile_for_device(&moved).unwrap().id, kids);
+        assert!(manager.delete_device(&tablet.id));
+        assert!(filter.get_profile_for_device("tablet").is_none());
+        assert!(manager.get_profile(&kids).unwrap().device_ids.is_empty());
+    }
+}